@@ -2,7 +2,10 @@ use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use kvs::{KvStore, KvsEngine, SledKvsEngine};
 use rand::prelude::*;
 use rand::rngs::SmallRng;
-use sled;
+use serde_json::json;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -13,7 +16,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut store, _temp_dir)| {
+            |(store, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     store.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -21,13 +24,27 @@ fn set_bench(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+    // the same keys, written with a single flush of the log
+    group.bench_function("kvs_set_many", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| {
+                let pairs = (1..(1 << 12)).map(|i| (format!("key{}", i), "value".to_string())).collect();
+                store.set_many(pairs).unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
     group.bench_function("sled", |b| {
         b.iter_batched(
             || {
                 let temp_dir = TempDir::new().unwrap();
                 (SledKvsEngine::new(sled::open(&temp_dir).unwrap()), temp_dir)
             },
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     db.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -40,10 +57,10 @@ fn set_bench(c: &mut Criterion) {
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 store
                     .set(format!("key{}", key_i), "value".to_string())
@@ -57,10 +74,10 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
+            let db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
             for key_i in 1..(1 << i) {
                 db.set(format!("key{}", key_i), "value".to_string())
                     .unwrap();
@@ -74,5 +91,210 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+fn open_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open_bench");
+    for gens in &[1, 8, 32] {
+        group.bench_with_input(format!("kvs_{}_gens", gens), gens, |b, gens| {
+            // every time the store is opened a new generation of logs is created
+            let temp_dir = TempDir::new().unwrap();
+            for gen in 0..*gens {
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                for key_i in 1..(1 << 12) {
+                    store
+                        .set(format!("key{}", key_i), format!("value{}", gen))
+                        .unwrap();
+                }
+            }
+            b.iter(|| KvStore::open(temp_dir.path()).unwrap())
+        });
+    }
+    // a store of 1M keys, opened by reading every log or from a snapshot of its index
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::builder().index_snapshot(true).open(temp_dir.path()).unwrap();
+    for key_i in 0..(1 << 20) {
+        store.set(format!("key{}", key_i), "value".to_string()).unwrap();
+    }
+    drop(store);
+    for index_snapshot in [false, true] {
+        group.bench_with_input(format!("kvs_1m_keys_snapshot_{}", index_snapshot), &index_snapshot, |b, index_snapshot| {
+            // writing the snapshot when the store is dropped isn't part of opening it
+            b.iter_with_large_drop(|| {
+                KvStore::builder().index_snapshot(*index_snapshot).open(temp_dir.path()).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn dedup_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dedup_bench");
+    // 4096 keys sharing 16 distinct 4KB values
+    let values: Vec<String> = (0..16).map(|i| format!("{:04}", i).repeat(1024)).collect();
+    let fill = |store: &KvStore| {
+        for i in 1..(1 << 12) {
+            store.set(format!("key{}", i), values[i % values.len()].clone()).unwrap();
+        }
+    };
+    for dedup in [false, true] {
+        let name = if dedup { "kvs_dedup" } else { "kvs" };
+        // the space savings are reported alongside the time taken to set the values
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::builder().dedup_values(dedup).open(temp_dir.path()).unwrap();
+        fill(&store);
+        println!("{}: disk usage {} bytes", name, store.stats().unwrap().disk_usage);
+
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let store = KvStore::builder().dedup_values(dedup).open(temp_dir.path()).unwrap();
+                    (store, temp_dir)
+                },
+                |(store, _temp_dir)| fill(&store),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn read_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_bench");
+    // random reads of 64K keys, with 1KB values, so the working set is larger than the buffer of
+    // a reader
+    #[allow(unused_mut)]
+    let mut builders = vec![("kvs_buffered", KvStore::builder())];
+    #[cfg(feature = "mmap")]
+    builders.push(("kvs_mmap", KvStore::builder().mmap(true)));
+    for (name, builder) in builders {
+        let temp_dir = TempDir::new().unwrap();
+        let store = builder.open(temp_dir.path()).unwrap();
+        let value = "v".repeat(1024);
+        for key_i in 0..(1 << 16) {
+            store.set(format!("key{}", key_i), value.clone()).unwrap();
+        }
+        let mut rng = SmallRng::from_seed([0; 32]);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                store.get(format!("key{}", rng.gen_range(0..(1 << 16)))).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+fn dump_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dump_bench");
+    // 64K keys with 100 byte values, dumped in the binary format or exported as JSON lines
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(&temp_dir.path().join("store")).unwrap();
+    let value = "v".repeat(100);
+    for key_i in 0..(1 << 16) {
+        store.set(format!("key{}", key_i), value.clone()).unwrap();
+    }
+    let dump_path = temp_dir.path().join("store.dump");
+    let json_path = temp_dir.path().join("store.jsonl");
+    let export_json = |path: &Path| {
+        let mut writer = BufWriter::new(File::create(path).unwrap());
+        for pair in store.snapshot() {
+            let (key, value) = pair.unwrap();
+            serde_json::to_writer(&mut writer, &json!({ "key": key, "value": value })).unwrap();
+            writer.write_all(b"\n").unwrap();
+        }
+        writer.into_inner().unwrap().sync_all().unwrap();
+    };
+    store.dump(&dump_path).unwrap();
+    export_json(&json_path);
+    println!(
+        "binary dump: {} bytes, json lines: {} bytes",
+        fs::metadata(&dump_path).unwrap().len(),
+        fs::metadata(&json_path).unwrap().len()
+    );
+
+    group.bench_function("binary_dump", |b| b.iter(|| store.dump(&dump_path).unwrap()));
+    group.bench_function("json_lines_dump", |b| b.iter(|| export_json(&json_path)));
+    group.bench_function("binary_restore", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| store.load_dump(&dump_path).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("json_lines_restore", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for line in BufReader::new(File::open(&json_path).unwrap()).lines() {
+                    let pair: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+                    let (key, value) = (pair["key"].as_str().unwrap(), pair["value"].as_str().unwrap());
+                    store.set(key.to_owned(), value.to_owned()).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn index_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index_bench");
+    // the index backend is chosen at compile time, run with `--features sharded-index` to
+    // compare the two
+    let name = if cfg!(feature = "sharded-index") { "kvs_sharded" } else { "kvs_dashmap" };
+    // the memory used by an index of 1M keys is reported alongside the time taken to use it
+    let rss_before = resident_bytes();
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for key_i in 0..(1 << 20) {
+        store.set(format!("key{}", key_i), "value".to_string()).unwrap();
+    }
+    if let (Some(before), Some(after)) = (rss_before, resident_bytes()) {
+        println!("{}: resident memory grew by {} bytes for 1M keys", name, after.saturating_sub(before));
+    }
+
+    let mut rng = SmallRng::from_seed([0; 32]);
+    group.bench_function(format!("{}_get", name), |b| {
+        b.iter(|| {
+            store.get(format!("key{}", rng.gen_range(0..(1 << 20)))).unwrap();
+        })
+    });
+    // readers on several threads, which contend for the shards of the index
+    group.bench_function(format!("{}_get_4_threads", name), |b| {
+        b.iter(|| {
+            std::thread::scope(|scope| {
+                for thread_i in 0..4_u64 {
+                    let store = store.clone();
+                    scope.spawn(move || {
+                        let mut rng = SmallRng::seed_from_u64(thread_i);
+                        for _ in 0..1000 {
+                            store.get(format!("key{}", rng.gen_range(0..(1 << 20)))).unwrap();
+                        }
+                    });
+                }
+            })
+        })
+    });
+    group.bench_function(format!("{}_set", name), |b| {
+        b.iter(|| {
+            store.set(format!("key{}", rng.gen_range(0..(1 << 20))), "value".to_string()).unwrap();
+        })
+    });
+    group.finish();
+}
+
+/// returns the resident memory of this process, on Linux
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // assumes the usual page size of 4KiB
+    Some(pages * 4096)
+}
+
+criterion_group!(benches, set_bench, get_bench, open_bench, dedup_bench, read_bench, index_bench, dump_bench);
 criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,91 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use kvs::{KvsClient, KvsServer, MemoryKvsEngine, Protocol, Request, SharedQueueThreadPool, ThreadPool, DEFAULT_READ_BUFFER};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+// the number of requests pipelined on a single connection in every iteration
+const REQUESTS: u64 = 1000;
+
+fn pipelined_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipelined_bench");
+    // requests per second are reported as the throughput
+    group.throughput(Throughput::Elements(REQUESTS));
+    for read_buffer in [DEFAULT_READ_BUFFER, 64 * 1024] {
+        let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(1).unwrap())
+            .read_buffer(read_buffer)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || server.serve_until(shutdown).unwrap())
+        };
+
+        let mut client = KvsClient::connect(addr).unwrap();
+        group.bench_function(format!("read_buffer_{}", read_buffer), |b| {
+            b.iter(|| {
+                for i in 0..REQUESTS {
+                    client.set_nowait(format!("key{}", i), "value".to_string()).unwrap();
+                }
+                client.flush_responses().unwrap();
+            })
+        });
+        drop(client);
+        shutdown.store(true, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+    group.finish();
+}
+
+/// the number of bytes a client sends for the `Set` requests of an iteration of
+/// `protocol_bench`, using the given `protocol`
+fn wire_bytes(protocol: Protocol) -> u64 {
+    (0..REQUESTS)
+        .map(|i| {
+            let req = Request::Set { key: format!("key{}", i), value: "value".to_string() };
+            match protocol {
+                Protocol::Json => serde_json::to_vec(&req).unwrap().len() as u64,
+                // every message is prefixed with its 4 byte length
+                Protocol::Bincode => 4 + bincode::serialized_size(&req).unwrap(),
+            }
+        })
+        .sum()
+}
+
+// the same pipelined sets, sent with each protocol. The bytes sent are reported as the
+// throughput, so that the size of the requests on the wire can be compared
+fn protocol_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("protocol_bench");
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(1).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    for protocol in [Protocol::Json, Protocol::Bincode] {
+        let bytes = wire_bytes(protocol);
+        println!("{:?}: {} requests sent in {} bytes", protocol, REQUESTS, bytes);
+        group.throughput(Throughput::Bytes(bytes));
+        let mut client = KvsClient::connect_with_protocol(addr, protocol).unwrap();
+        group.bench_function(format!("{:?}", protocol).to_lowercase(), |b| {
+            b.iter(|| {
+                for i in 0..REQUESTS {
+                    client.set_nowait(format!("key{}", i), "value".to_string()).unwrap();
+                }
+                client.flush_responses().unwrap();
+            })
+        });
+    }
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+    group.finish();
+}
+
+criterion_group!(benches, pipelined_bench, protocol_bench);
+criterion_main!(benches);
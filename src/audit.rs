@@ -0,0 +1,73 @@
+use crate::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+// how often buffered audit records are flushed to the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An append-only log of the mutating requests serviced by a [`KvsServer`].
+///
+/// Every record is a single line of `key=value` pairs, recording when the request was
+/// serviced (as milliseconds since the unix epoch), the address of the client, the operation
+/// and the key. Values are never recorded. For example:
+/// ```text
+/// ts_ms=1634567890123 peer=127.0.0.1:50422 op=SET key="mykey"
+/// ```
+///
+/// Records are buffered, so that writing them doesn't contend on file IO, and are flushed by
+/// a background thread every second.
+///
+/// [`KvsServer`]: ./struct.KvsServer.html
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl AuditLog {
+    /// opens the audit log at the given `path` for appending, creating it if it doesn't exist,
+    /// and starts the thread that periodically flushes it
+    pub(crate) fn open(path: &Path) -> Result<Arc<AuditLog>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let audit_log = Arc::new(AuditLog {
+            writer: Mutex::new(BufWriter::new(file)),
+        });
+
+        // the thread exits once the audit log is dropped
+        let weak: Weak<AuditLog> = Arc::downgrade(&audit_log);
+        thread::Builder::new()
+            .name("kvs-audit-flush".to_string())
+            .spawn(move || loop {
+                thread::sleep(FLUSH_INTERVAL);
+                match weak.upgrade() {
+                    Some(audit_log) => audit_log.flush(),
+                    None => break,
+                }
+            })?;
+        Ok(audit_log)
+    }
+
+    /// records that the client at `peer` performed the operation `op` on `key`
+    pub(crate) fn record(&self, peer: SocketAddr, op: &str, key: &str) {
+        let ts_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // the key is escaped, so that every record is a single line
+        if let Err(e) = writeln!(writer, "ts_ms={} peer={} op={} key={:?}", ts_ms, peer, op, key) {
+            error!("could not write to the audit log: {}", e);
+        }
+    }
+
+    fn flush(&self) {
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = writer.flush() {
+            error!("could not flush the audit log: {}", e);
+        }
+    }
+}
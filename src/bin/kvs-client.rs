@@ -10,9 +10,12 @@
 //!     --addr accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If --addr is not specified then connect on 127.0.0.1:4000.
 //!     Print an error and return a non-zero exit code on server error, or if IP-PORT does not parse as an address.
 //!
-//! `kvs-client get <KEY> [--addr IP-PORT]`
+//! `kvs-client get <KEY> [--default VALUE] [--binary] [--addr IP-PORT]`
 //!
 //!     Get the string value of a given string key.
+//!     If --default is specified, VALUE is printed when the key does not exist, instead of "Key not found".
+//!     If --binary is specified, the raw bytes of the value are written to stdout, without a trailing newline,
+//!     so values that aren't valid UTF-8 can be retrieved. "Key not found" is printed to stderr instead.
 //!     --addr accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If --addr is not specified then connect on 127.0.0.1:4000.
 //!     Print an error and return a non-zero exit code on server error, or if IP-PORT does not parse as an address.
 //!
@@ -22,31 +25,169 @@
 //!     --addr accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If --addr is not specified then connect on 127.0.0.1:4000.
 //!     Print an error and return a non-zero exit code on server error, or if IP-PORT does not parse as an address. A "key not found" is also treated as an error in the "rm" command.
 //!
+//! `kvs-client cas <KEY> <EXPECTED> <NEW> [--addr IP-PORT]`
+//!
+//!     Set the value of a string key to NEW, but only if its current value is EXPECTED.
+//!     Print "OK" if the value was set. If the current value isn't EXPECTED, or the key does not exist, print
+//!     "Not swapped" and return an exit code of 2.
+//!     Print an error and return an exit code of 1 on server error, or if IP-PORT does not parse as an address.
+//!
+//! `kvs-client keys <PATTERN> [--addr IP-PORT]`
+//!
+//!     Print every key that matches the glob PATTERN, one per line in ascending order. In the pattern, "*"
+//!     matches any sequence of characters and "?" matches any single character, e.g. 'user:*'. Quote the
+//!     pattern so that the shell doesn't expand it.
+//!     The server scans all of its keys to find the matching ones, so this is slow on a large store.
+//!     Print an error and return a non-zero exit code if the server's engine can't list its keys.
+//!
+//! `kvs-client len [--addr IP-PORT]`
+//!
+//!     Print the number of keys on the server.
+//!
+//! `kvs-client hotkeys [N] [--addr IP-PORT]`
+//!
+//!     Print the N (10 by default) keys that the server estimates are the most read and written, hottest first,
+//!     one per line after their estimated number of accesses.
+//!     Print an error and return a non-zero exit code if the server wasn't started with --hot-keys.
+//!
+//! `kvs-client scan <PREFIX> [--addr IP-PORT]`
+//!
+//!     Print every key that starts with PREFIX, e.g. 'user:', one per line in ascending order.
+//!     Print an error and return a non-zero exit code if the server's engine can't list its keys.
+//!
+//! `kvs-client dump <PATH> [--addr IP-PORT]`
+//!
+//!     Write every key and its value on the server into a new binary dump file at PATH, and print the number of
+//!     entries written. The keys are paged through and their values fetched in batches, so the whole store is
+//!     never held in memory. The dump is versioned and ends with a checksum, see the `kvs::dump` module.
+//!     Print an error and return a non-zero exit code if the server's engine can't list its keys.
+//!
+//! `kvs-client restore <PATH> [--addr IP-PORT]`
+//!
+//!     Set every key in the dump file at PATH to its value in the dump, in batches, and print the number of
+//!     entries restored. Keys on the server that aren't in the dump are left untouched.
+//!     The whole dump is validated first, so nothing is restored from a truncated or corrupt dump, which is
+//!     reported as an "invalid dump" error with a non-zero exit code.
+//!
+//! `kvs-client batch [--keep-going] [--addr IP-PORT]`
+//!
+//!     Read commands from stdin, one per line, and execute them over a single connection to the server.
+//!     Each line must be one of: `set <KEY> <VALUE>`, `get <KEY>` or `rm <KEY>`. Blank lines are ignored.
+//!     The result of each command is printed to stdout: the value (or "Key not found") for `get` and "OK" for `set` and `rm`.
+//!     Parse errors and server errors are reported on stderr along with their line number. By default, the
+//!     batch is aborted on the first error; if --keep-going is given the remaining lines are still executed.
+//!     A non-zero exit code is returned if any line failed.
+//!
+//! `kvs-client loginfo [--addr IP-PORT]`
+//!
+//!     Print a table of the server's log files, with the size, number of live keys and an estimate of the
+//!     bytes a compaction would reclaim of each file.
+//!     Print an error and return a non-zero exit code if the server's engine doesn't store its data in log files.
+//!
+//! `kvs-client compact [--addr IP-PORT]`
+//!
+//!     Compact the server's log files now, rather than waiting for the server to compact them on its own, e.g.
+//!     during a maintenance window. Nothing is rewritten if the logs hold no stale data.
+//!     Print an error and return a non-zero exit code if the server's engine has nothing to compact.
+//!
+//! `kvs-client stats [--reset] [--addr IP-PORT]`
+//!
+//!     Print the statistics of the server and its storage engine, e.g. the number of keys, the server's uptime
+//!     and the latencies of its requests.
+//!     If --reset is specified, the counters (the request latencies and the number of compactions) are reset to
+//!     zero once they have been printed, e.g. between the runs of a benchmark.
+//!
+//! `--auth-token TOKEN` can be given with any of the commands above, to authenticate with a server
+//! that was started with an auth token.
+//!
+//! `--protocol PROTOCOL` can be given with any of the commands above, to send the requests with
+//! the "json" (the default) or the more compact "bincode" protocol. The server must accept it.
+//!
+//! `--json` can be given with any of the commands above, except "get --binary", to print the result
+//! as a JSON object rather than as plain text, for scripts. A successful command prints
+//! `{"ok":true}`, along with the `"value"` of a get (`null` if the key does not exist), whether
+//! a cas `"swapped"` the value, the `"keys"` that matched, the `"hot_keys"` as `[key, count]` pairs,
+//! the `"count"` of keys of len, the `"logs"` of loginfo, the `"stats"`, or the number of `"entries"` dumped or restored. A failed command prints `{"ok":false,"error":"..."}` to stdout, and the
+//! exit code is non-zero. In a batch, one object is printed per line, and the objects of failed
+//! lines also have the `"line"` number.
+//!
 //! `kvs-client -V`
 //!
 //!     Print the version.
 
 
+use std::fs::File;
+use std::io::{self, BufRead, Write};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use clap::{crate_version, App, Arg, SubCommand, ArgMatches};
-use kvs::{KvsClient, KvsError, Result, Request};
+use kvs::dump::{self, DumpReader, DumpWriter};
+use kvs::{KvsClient, KvsError, LatencyStats, LogInfo, Protocol, Result, Request, Stats, DEFAULT_MAX_BATCH};
+use serde_json::json;
 use tracing::{Level};
 use tracing_subscriber::{FmtSubscriber};
 
 // the default server IP_PORT that the client will connect to if not specified on command line
 const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
 
+/// The action the client will perform once connected to the server
+#[derive(Debug)]
+enum Action {
+    /// send a single request
+    Single(Request),
+    /// get the value of a key, printing `default` if the key does not exist
+    GetOr { key: String, default: String },
+    /// get the value of a key, writing its raw bytes to stdout
+    GetBytes { key: String },
+    /// write every key and value on the server into a dump file
+    Dump { path: PathBuf },
+    /// set every key in a dump file on the server
+    Restore { path: PathBuf },
+    /// read requests from stdin, `keep_going` determines if the batch continues after an error
+    Batch { keep_going: bool },
+}
+
+/// The result of a successful request, that is printed to stdout
+#[derive(Debug)]
+enum Reply {
+    /// the value of a key, or `None` if the key does not exist
+    Value(Option<String>),
+    /// a write that succeeded
+    Done,
+    /// the layout of the server's log files
+    Logs(Vec<LogInfo>),
+    /// whether a compare and swap set the value
+    Swapped(bool),
+    /// the keys that match a pattern
+    Keys(Vec<String>),
+    /// the hottest keys, with their estimated number of accesses
+    HotKeys(Vec<(String, u64)>),
+    /// the number of keys on the server
+    Count(u64),
+    /// the statistics of the server
+    Stats(Stats),
+    /// the number of entries dumped or restored
+    Entries(u64),
+}
+
 /// ['Opt'] holds parsed and validated options from the command line
 #[derive(Debug)]
 struct Opt {
     /// the server's ip:port
     addr: SocketAddr,
-    req: Request,
+    action: Action,
+    /// the token used to authenticate with the server
+    auth_token: Option<String>,
+    /// the protocol the requests are sent with
+    protocol: Protocol,
+    /// print results as JSON objects, rather than plain text
+    json: bool,
 }
 
 impl Opt {
-    fn new(addr: SocketAddr, req: Request) -> Self {
-        Self { addr, req }
+    fn new(addr: SocketAddr, action: Action, auth_token: Option<String>) -> Self {
+        Self { addr, action, auth_token, protocol: Protocol::Json, json: false }
     }
 
     /// validates the `addr` parameter is a valid IP address and PORT
@@ -54,34 +195,109 @@ impl Opt {
     /// # Errors
     /// returns [`KvsError::Parsing`] if one of the parameters is invalid
     ///
-    fn build(addr: &str, req: Request) -> Result<Opt> {
+    fn build(addr: &str, action: Action, auth_token: Option<&str>) -> Result<Opt> {
         let addr: SocketAddr = addr
             .parse()
             .map_err(
                 |_| KvsError::Parsing(format!("could not parse {} into an IP addess and port", &addr))
             )?;
 
-        Ok(Opt::new(addr, req))
+        Ok(Opt::new(addr, action, auth_token.map(String::from)))
     }
 
     /// parses the matches from the command line into an [`Opt`] struct
     fn parse_options(matches: ArgMatches) -> Result<Self> {
+        let mut opt = Self::parse_action(&matches)?;
+        opt.json = matches.subcommand().1.is_some_and(|args| args.is_present("json"));
+        if let Some(protocol) = matches.subcommand().1.and_then(|args| args.value_of("protocol")) {
+            opt.protocol = protocol.parse()?;
+        }
+        if opt.json && matches!(opt.action, Action::GetBytes { .. }) {
+            return Err(KvsError::Parsing("--json can't be used with --binary".to_string()));
+        }
+        Ok(opt)
+    }
+
+    /// parses the subcommand, and its arguments, from the command line into an [`Opt`] struct
+    fn parse_action(matches: &ArgMatches) -> Result<Self> {
         match matches.subcommand() {
             ("set", Some(args)) => {
                 let key = args.value_of("KEY").map(String::from).unwrap();
                 let value = args.value_of("VALUE").map(String::from).unwrap();
                 let addr = args.value_of("addr").unwrap();
-                Self::build(addr, Request::Set { key, value })
+                Self::build(addr, Action::Single(Request::Set { key, value }), args.value_of("auth-token"))
             }
             ("get", Some(args)) => {
                 let key = args.value_of("KEY").map(String::from).unwrap();
                 let addr = args.value_of("addr").unwrap();
-                Self::build(addr, Request::Get { key })
+                let action = match args.value_of("default").map(String::from) {
+                    Some(default) => Action::GetOr { key, default },
+                    None if args.is_present("binary") => Action::GetBytes { key },
+                    None => Action::Single(Request::Get { key }),
+                };
+                Self::build(addr, action, args.value_of("auth-token"))
             }
             ("rm", Some(args)) => {
                 let key = args.value_of("KEY").map(String::from).unwrap();
                 let addr = args.value_of("addr").unwrap();
-                Self::build(addr, Request::Remove { key })
+                Self::build(addr, Action::Single(Request::Remove { key }), args.value_of("auth-token"))
+            }
+            ("cas", Some(args)) => {
+                let key = args.value_of("KEY").map(String::from).unwrap();
+                let expected = args.value_of("EXPECTED").map(String::from);
+                let new = args.value_of("NEW").map(String::from).unwrap();
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Single(Request::Cas { key, expected, new }), args.value_of("auth-token"))
+            }
+            ("keys", Some(args)) => {
+                let pattern = args.value_of("PATTERN").map(String::from).unwrap();
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Single(Request::Keys { pattern }), args.value_of("auth-token"))
+            }
+            ("scan", Some(args)) => {
+                let prefix = args.value_of("PREFIX").map(String::from).unwrap();
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Single(Request::ScanPrefix { prefix }), args.value_of("auth-token"))
+            }
+            ("len", Some(args)) => {
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Single(Request::Len), args.value_of("auth-token"))
+            }
+            ("hotkeys", Some(args)) => {
+                let n = args.value_of("N").unwrap();
+                let n = n
+                    .parse()
+                    .map_err(|_| KvsError::Parsing(format!("could not parse {} into a number of keys", n)))?;
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Single(Request::HotKeys { n }), args.value_of("auth-token"))
+            }
+            ("stats", Some(args)) => {
+                let req = if args.is_present("reset") { Request::ResetStats } else { Request::Stats };
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Single(req), args.value_of("auth-token"))
+            }
+            ("loginfo", Some(args)) => {
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Single(Request::LogInfo), args.value_of("auth-token"))
+            }
+            ("compact", Some(args)) => {
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Single(Request::Compact), args.value_of("auth-token"))
+            }
+            ("dump", Some(args)) => {
+                let path = args.value_of("PATH").map(PathBuf::from).unwrap();
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Dump { path }, args.value_of("auth-token"))
+            }
+            ("restore", Some(args)) => {
+                let path = args.value_of("PATH").map(PathBuf::from).unwrap();
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Restore { path }, args.value_of("auth-token"))
+            }
+            ("batch", Some(args)) => {
+                let keep_going = args.is_present("keep-going");
+                let addr = args.value_of("addr").unwrap();
+                Self::build(addr, Action::Batch { keep_going }, args.value_of("auth-token"))
             }
             _ => panic!("unknown command received"),
         }
@@ -96,6 +312,20 @@ fn main() -> Result<()> {
         .version(crate_version!())
         .author("strohs <strohs1@gmail.com>")
         .about("a multi-threaded key-value store")
+        .arg(Arg::with_name("auth-token")
+            .long("auth-token")
+            .value_name("TOKEN")
+            .help("the shared secret token used to authenticate with the server")
+            .global(true))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .help("prints the result as a JSON object, for scripts")
+            .global(true))
+        .arg(Arg::with_name("protocol")
+            .long("protocol")
+            .value_name("PROTOCOL")
+            .help("the protocol the requests are sent with, 'json' or 'bincode', defaults to json")
+            .global(true))
         .subcommands(vec![
             SubCommand::with_name("set")
                 .about("Set the value of a string key to a string")
@@ -109,6 +339,14 @@ fn main() -> Result<()> {
             SubCommand::with_name("get")
                 .about("Get the string value of a given string key")
                 .arg(Arg::with_name("KEY").required(true).index(1))
+                .arg(Arg::with_name("default")
+                    .long("default")
+                    .value_name("VALUE")
+                    .help("the value to print if the key does not exist"))
+                .arg(Arg::with_name("binary")
+                    .long("binary")
+                    .conflicts_with("default")
+                    .help("writes the raw bytes of the value to stdout, for values that aren't valid UTF-8"))
                 .arg(Arg::with_name("addr")
                     .long("addr")
                     .value_name("IP_ADDR:PORT")
@@ -122,40 +360,352 @@ fn main() -> Result<()> {
                     .value_name("IP_ADDR:PORT")
                     .help("specifies the IP_ADDRESS:PORT of the server to connect to")
                     .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("cas")
+                .about("Set the value of a key, only if its current value is the expected value")
+                .arg(Arg::with_name("KEY").required(true).index(1))
+                .arg(Arg::with_name("EXPECTED").required(true).index(2))
+                .arg(Arg::with_name("NEW").required(true).index(3))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("keys")
+                .about("Lists the keys that match a glob pattern, by scanning every key")
+                .arg(Arg::with_name("PATTERN").required(true).index(1))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("scan")
+                .about("Lists the keys that start with a prefix")
+                .arg(Arg::with_name("PREFIX").required(true).index(1))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("len")
+                .about("Prints the number of keys on the server")
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("hotkeys")
+                .about("Lists the keys that the server estimates are the most accessed")
+                .arg(Arg::with_name("N").index(1).default_value("10"))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("loginfo")
+                .about("Prints the size and live keys of each of the server's log files")
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("compact")
+                .about("Compacts the server's log files now")
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("stats")
+                .about("Prints the statistics of the server")
+                .arg(Arg::with_name("reset")
+                    .long("reset")
+                    .help("resets the counters to zero once they have been printed"))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("dump")
+                .about("Writes every key and value on the server into a binary dump file")
+                .arg(Arg::with_name("PATH").required(true).index(1))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("restore")
+                .about("Sets every key in a binary dump file on the server")
+                .arg(Arg::with_name("PATH").required(true).index(1))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
+            SubCommand::with_name("batch")
+                .about("Executes commands read from stdin, one per line, over a single connection")
+                .arg(Arg::with_name("keep-going")
+                    .long("keep-going")
+                    .help("continue executing the remaining lines after an error"))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("IP_ADDR:PORT")
+                    .help("specifies the IP_ADDRESS:PORT of the server to connect to")
+                    .default_value(DEFAULT_ADDRESS)),
         ])
         .get_matches();
 
     // parse commands into an Opt struct
-    match Opt::parse_options(matches) {
-        Ok(opt) => run(opt),
-        Err(e) => Err(e),
+    let opt = Opt::parse_options(matches)?;
+    if !opt.json {
+        return run(opt);
     }
+    // errors are printed as JSON too, so scripts only need to parse stdout
+    if let Err(e) = run(opt) {
+        println!("{}", json!({ "ok": false, "error": e.to_string() }));
+        exit(1);
+    }
+    Ok(())
 }
 
-/// runs the specified request on the [`KvsClient`]
-/// `opt` contains the server address and the request type to execute
+/// runs the specified action on a [`KvsClient`]
+/// `opt` contains the server address and the action to execute
 fn run(opt: Opt) -> Result<()> {
-    match opt.req {
-        Request::Get { key } => {
-            let mut client = KvsClient::connect(opt.addr)?;
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
+    let mut builder = KvsClient::builder().addr(opt.addr.to_string()).protocol(opt.protocol);
+    if let Some(token) = opt.auth_token {
+        builder = builder.auth_token(token);
+    }
+    let mut client = builder.connect()?;
+    match opt.action {
+        Action::Single(req) => {
+            let reply = execute(&mut client, req)?;
+            let not_swapped = matches!(reply, Reply::Swapped(false));
+            print_reply(reply, opt.json, false);
+            if not_swapped {
+                exit(2);
+            }
+            Ok(())
+        }
+        Action::GetOr { key, default } => {
+            let value = client.get_or(key, default)?;
+            if opt.json {
+                print_reply(Reply::Value(Some(value)), true, false);
             } else {
-                println!("Key not found");
+                println!("{}", value);
             }
+            Ok(())
         }
-        Request::Set { key, value } => {
-            let mut client = KvsClient::connect(opt.addr)?;
-            client.set(key, value)?;
+        Action::GetBytes { key } => {
+            match client.get_bytes(key)? {
+                Some(value) => {
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(&value)?;
+                    stdout.flush()?;
+                }
+                None => eprintln!("Key not found"),
+            }
+            Ok(())
+        }
+        Action::Dump { path } => {
+            print_reply(Reply::Entries(dump_to(&mut client, &path)?), opt.json, false);
+            Ok(())
+        }
+        Action::Restore { path } => {
+            print_reply(Reply::Entries(restore_from(&mut client, &path)?), opt.json, false);
+            Ok(())
+        }
+        Action::Batch { keep_going } => run_batch(&mut client, keep_going, opt.json),
+    }
+}
+
+/// writes every key on the server, and its value, into a new dump file at `path`, a page of
+/// keys at a time. Returns the number of entries written
+fn dump_to(client: &mut KvsClient, path: &Path) -> Result<u64> {
+    let mut writer = DumpWriter::new(File::create(path)?)?;
+    let mut cursor = None;
+    loop {
+        let (keys, next_cursor) = client.scan(cursor, DEFAULT_MAX_BATCH)?;
+        if !keys.is_empty() {
+            let values = client.get_batch(keys.clone())?;
+            // keys removed since they were listed have no value, and are skipped
+            for (key, value) in keys.iter().zip(values) {
+                if let Some(value) = value {
+                    writer.write_entry(key.as_bytes(), value.as_bytes())?;
+                }
+            }
+        }
+        cursor = next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    let entries = writer.entries();
+    writer.finish()?.sync_all()?;
+    Ok(entries)
+}
+
+/// sets every key in the dump file at `path` on the server, a batch of keys at a time, once
+/// the whole dump has been validated. Returns the number of entries restored
+fn restore_from(client: &mut KvsClient, path: &Path) -> Result<u64> {
+    let entries = dump::verify(File::open(path)?)?;
+    let mut pairs = Vec::with_capacity(DEFAULT_MAX_BATCH);
+    for entry in DumpReader::new(File::open(path)?)? {
+        let (key, value) = entry?;
+        pairs.push((String::from_utf8(key)?, String::from_utf8(value)?));
+        if pairs.len() == DEFAULT_MAX_BATCH {
+            client.multi_set(std::mem::take(&mut pairs))?;
+        }
+    }
+    if !pairs.is_empty() {
+        client.multi_set(pairs)?;
+    }
+    Ok(entries)
+}
+
+/// sends a single request to the server, and returns its result
+fn execute(client: &mut KvsClient, req: Request) -> Result<Reply> {
+    match req {
+        Request::Get { key } => Ok(Reply::Value(client.get(key)?)),
+        Request::Set { key, value } => client.set(key, value).map(|_| Reply::Done),
+        Request::Remove { key } => client.remove(key).map(|_| Reply::Done),
+        Request::Keys { pattern } => Ok(Reply::Keys(client.keys(pattern)?)),
+        Request::ScanPrefix { prefix } => Ok(Reply::Keys(client.scan_prefix(prefix)?)),
+        Request::Len => Ok(Reply::Count(client.key_count()?)),
+        Request::HotKeys { n } => Ok(Reply::HotKeys(client.hot_keys(n)?)),
+        Request::LogInfo => Ok(Reply::Logs(client.log_info()?)),
+        Request::Compact => client.compact().map(|_| Reply::Done),
+        Request::Stats => Ok(Reply::Stats(client.stats()?)),
+        Request::ResetStats => Ok(Reply::Stats(client.reset_stats()?)),
+        Request::Cas { key, expected: Some(expected), new } => {
+            Ok(Reply::Swapped(client.set_if_equals(key, expected, new)?))
+        }
+        Request::Auth { token } => client.auth(token).map(|_| Reply::Done),
+        req => Err(KvsError::StringErr(format!("{:?} is not supported by kvs-client", req))),
+    }
+}
+
+/// prints the `reply` to a request to stdout, as a JSON object if `json` is true.
+/// If `batch` is true, "OK" is printed for successful writes, which otherwise print nothing
+fn print_reply(reply: Reply, json: bool, batch: bool) {
+    if json {
+        let reply = match reply {
+            Reply::Value(value) => json!({ "ok": true, "value": value }),
+            Reply::Done => json!({ "ok": true }),
+            Reply::Logs(logs) => json!({ "ok": true, "logs": logs }),
+            Reply::Swapped(swapped) => json!({ "ok": true, "swapped": swapped }),
+            Reply::Keys(keys) => json!({ "ok": true, "keys": keys }),
+            Reply::HotKeys(keys) => json!({ "ok": true, "hot_keys": keys }),
+            Reply::Count(count) => json!({ "ok": true, "count": count }),
+            Reply::Stats(stats) => json!({ "ok": true, "stats": stats }),
+            Reply::Entries(entries) => json!({ "ok": true, "entries": entries }),
+        };
+        println!("{}", reply);
+        return;
+    }
+    match reply {
+        Reply::Value(Some(value)) => println!("{}", value),
+        Reply::Value(None) => println!("Key not found"),
+        Reply::Done if batch => println!("OK"),
+        Reply::Done => {}
+        Reply::Swapped(true) => println!("OK"),
+        Reply::Swapped(false) => println!("Not swapped"),
+        Reply::Keys(keys) => {
+            for key in keys {
+                println!("{}", key);
+            }
+        }
+        Reply::HotKeys(keys) => {
+            for (key, count) in keys {
+                println!("{:>10} {}", count, key);
+            }
+        }
+        Reply::Logs(logs) => {
+            println!("{:>10} {:>12} {:>10} {:>12}", "GEN", "SIZE", "LIVE KEYS", "DEAD BYTES");
+            for log in logs {
+                println!("{:>10} {:>12} {:>10} {:>12}", log.gen, log.size, log.live_keys, log.dead_bytes);
+            }
+        }
+        Reply::Stats(stats) => print_stats(&stats),
+        Reply::Entries(entries) => println!("{} entries", entries),
+        Reply::Count(count) => println!("{}", count),
+    }
+}
+
+/// prints the `stats` of a server, one per line
+fn print_stats(stats: &Stats) {
+    println!("keys: {}", stats.key_count);
+    println!("disk usage: {} bytes", stats.disk_usage);
+    println!("uncompacted: {} bytes", stats.uncompacted_bytes);
+    println!("reclaimable: {} bytes", stats.reclaimable_bytes);
+    println!("index memory: {} bytes", stats.index_memory_bytes);
+    println!("compactions: {}", stats.compactions);
+    println!("uptime: {}s", stats.uptime_secs);
+    println!("connections: {}", stats.connections);
+    let latency = |name: &str, latency: &LatencyStats| {
+        println!(
+            "{} requests: {} (p50 {}us, p95 {}us, p99 {}us)",
+            name, latency.count, latency.p50_micros, latency.p95_micros, latency.p99_micros
+        );
+    };
+    latency("get", &stats.get_latency);
+    latency("set", &stats.set_latency);
+    latency("remove", &stats.remove_latency);
+}
+
+/// reads commands from stdin, one per line, and executes them using the given `client`.
+/// Errors are written to stderr along with their line number, or to stdout as JSON objects if
+/// `json` is true. If `keep_going` is false, the batch stops at the first error.
+/// The process exits with a non-zero exit code if any of the lines failed
+fn run_batch(client: &mut KvsClient, keep_going: bool, json: bool) -> Result<()> {
+    let mut failed = false;
+    for (i, line) in io::stdin().lock().lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let e = match parse_line(&line).and_then(|req| execute(client, req)) {
+            Ok(reply) => {
+                print_reply(reply, json, true);
+                continue;
+            }
+            Err(e) => e,
+        };
+        if json {
+            println!("{}", json!({ "ok": false, "error": e.to_string(), "line": i + 1 }));
+        } else {
+            eprintln!("line {}: {}", i + 1, e);
         }
-        Request::Remove { key } => {
-            let mut client = KvsClient::connect(opt.addr)?;
-            client.remove(key)?;
+        failed = true;
+        if !keep_going {
+            break;
         }
     }
+    if failed {
+        exit(1);
+    }
     Ok(())
 }
 
+/// parses a single line of a batch into a [`Request`].
+/// The line must have the format: `set <KEY> <VALUE>`, `get <KEY>` or `rm <KEY>`.
+/// Everything after the key of a `set` command is treated as the value.
+///
+/// # Errors
+/// returns [`KvsError::Parsing`] if the line is not a valid command
+fn parse_line(line: &str) -> Result<Request> {
+    let line = line.trim();
+    let (cmd, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim_start();
+    match cmd {
+        "set" => match args.split_once(char::is_whitespace) {
+            Some((key, value)) => Ok(Request::Set { key: key.to_string(), value: value.trim_start().to_string() }),
+            None => Err(KvsError::Parsing(format!("expected 'set <KEY> <VALUE>' but got '{}'", line))),
+        },
+        "get" if !args.is_empty() && !args.contains(char::is_whitespace) => Ok(Request::Get { key: args.to_string() }),
+        "rm" if !args.is_empty() && !args.contains(char::is_whitespace) => Ok(Request::Remove { key: args.to_string() }),
+        _ => Err(KvsError::Parsing(format!("unknown command: '{}'", line))),
+    }
+}
+
 /// configures a tracing subscriber that will log to STDERR
 fn subscriber_config() {
     let subscriber = FmtSubscriber::builder()
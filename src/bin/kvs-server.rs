@@ -1,42 +1,138 @@
 //! The `kvs-server` executable.
 //!
 //! It supports the following command line arguments:
-///
-/// - `kvs-server [--addr IP-PORT] [--engine ENGINE-NAME]`
-///
-///   Start the server and begin listening for incoming connections. `--addr`
-///   accepts an IP address, either v4 or v6, and a port number, with the format
-///   `IP:PORT`. If `--addr` is not specified then listen on `127.0.0.1:4000`.
-///
-///   If `--engine` is specified, then `ENGINE-NAME` must be "kvs". Future versions
-///   of the server will support the "sled" engine, but it has not yet been fully integrated.
-///   If this is the first run (there is no data previously persisted) then the default
-///   value is "kvs". If there is previously persisted data then the default is the
-///   engine already in use. If data was previously persisted with a different
-///   engine than selected, print an error and exit with a non-zero exit code.
-///
-///   Print an error and return a non-zero exit code on failure to bind a socket, if
-///   `ENGINE-NAME` is invalid, if `IP-PORT` does not parse as an address.
-///
-/// - `kvs-server -V`
-///
-///   Print the version.
+//!
+//! - `kvs-server [--addr IP-PORT] [--engine ENGINE-NAME] [--auth-token TOKEN] [--max-requests N] [--keepalive SECS] [--audit-log PATH] [--pid-file PATH] [--max-get-batch N] [--max-multi-set N] [--max-connections N] [--read-only] [--read-buffer BYTES] [--max-line-len BYTES] [--max-value-mb MB] [--protocol PROTOCOL] [--passphrase-file PATH] [--hash-keys] [--index-snapshot] [--hot-keys RATE] [--idle-exit SECS] [--tls --cert PATH --key PATH]`
+//!
+//!   Start the server and begin listening for incoming connections. `--addr`
+//!   accepts an IP address, either v4 or v6, and a port number, with the format
+//!   `IP:PORT`. If `--addr` is not specified then listen on `127.0.0.1:4000`.
+//!
+//!   If `--engine` is specified, then `ENGINE-NAME` must be "kvs", "sled" or "memory". The
+//!   "sled" engine stores its data in a [sled](https://docs.rs/sled) database.
+//!   If this is the first run (there is no data previously persisted) then the default
+//!   value is "kvs". If there is previously persisted data then the default is the
+//!   engine already in use. If data was previously persisted with a different
+//!   engine than selected, print an error and exit with a non-zero exit code.
+//!
+//!   The "memory" engine keeps all data in memory, nothing is persisted and all data is lost
+//!   when the server exits. It can be used regardless of the engine previously in use.
+//!
+//!   If `--auth-token` is specified, clients must authenticate with the given `TOKEN` before
+//!   any of their other requests are accepted. The token is sent in plain text, so this only
+//!   deters casual access to the server, it is not strong security.
+//!
+//!   If `--max-requests` is specified, a connection is closed after it has sent `N` requests,
+//!   and the client must reconnect.
+//!
+//!   `--keepalive` sets how many seconds a connection may be idle before TCP keep-alive probes
+//!   are sent, so that clients that have silently gone away are detected. It defaults to 60
+//!   seconds, and 0 disables keep-alive.
+//!
+//!   If `--audit-log` is specified, every successful set and remove is recorded in the file at
+//!   `PATH`, as a line containing the time, the client's address, the operation and the key.
+//!
+//!   If `--pid-file` is specified, the process id of the server is written to `PATH` on startup,
+//!   and the file is removed when the server shuts down gracefully.
+//!
+//!   `--max-get-batch` and `--max-multi-set` limit the number of keys in a single batch get, and
+//!   of key/value pairs in a single batch set. Larger batches are rejected with a "batch too
+//!   large" error. Both default to 1000.
+//!
+//!   `--max-connections` limits the number of connections that are serviced at once. Further
+//!   connections wait until one of them closes. It defaults to the number of worker threads.
+//!
+//!   If `--read-only` is specified, every request that modifies data (e.g. a set or remove) is
+//!   rejected with a "server is read-only" error, while gets are served normally. The engine is
+//!   still opened for writing, so this is useful to keep serving reads during maintenance.
+//!
+//!   `--read-buffer` sets the size, in bytes, of the buffer that each connection's requests are
+//!   read into. A larger buffer reduces the number of reads for clients that pipeline many
+//!   small requests. It defaults to 8192 bytes.
+//!
+//!   `--max-line-len` limits the length, in bytes, of a request line of the text protocol. A
+//!   client that sends a longer line receives `ERR line too long`, and its connection is closed.
+//!   It defaults to 65536 bytes.
+//!
+//!   `--max-value-mb` limits the size, in megabytes, of the values in a request. The server stops
+//!   reading a request that is larger than that (plus 64 KiB for its key), so that a client
+//!   can't exhaust the server's memory with an enormous value. The client receives a "request
+//!   too large" error, and its connection is closed. It defaults to 64 megabytes.
+//!
+//!   By default clients may use either the JSON or the more compact bincode protocol. If
+//!   `--protocol` is specified, `PROTOCOL` must be "json" or "bincode", and clients that use the
+//!   other protocol are refused when they connect. The text protocol is always accepted.
+//!
+//!   If `--passphrase-file` is specified, or the `KVS_PASSPHRASE` environment variable is set,
+//!   the values of the "kvs" engine are encrypted at rest with a key derived from the passphrase
+//!   (the first line of the file takes precedence over the variable). The passphrase must be
+//!   given whenever an encrypted store is opened, and encryption must be enabled on an empty
+//!   store. If `--hash-keys` is specified, the keys are hashed as well, so they aren't stored in
+//!   plain text. This protects the data files, e.g. on a stolen disk or in a backup, but not
+//!   the memory of a running server. Encryption requires the server to be built with the
+//!   `crypto` feature.
+//!
+//!   If `--index-snapshot` is specified, the index of the "kvs" engine is written to a snapshot
+//!   file when the server shuts down gracefully, and loaded from it on startup, so that only the
+//!   commands written after the snapshot have to be read from the logs. A stale or corrupt
+//!   snapshot is ignored, and every log is read as usual.
+//!
+//!   If `--hot-keys` is specified, the server estimates which keys are read and written the
+//!   most, so that they can be listed with `kvs-client hotkeys`. Only 1 in `RATE` accesses is
+//!   counted, which keeps the overhead low on a busy server; 1 counts every access.
+//!
+//!   If `--idle-exit` is specified, the server shuts down gracefully once it has had no open
+//!   connection for `SECS` seconds, e.g. when it's started on demand. A connection that stays
+//!   open keeps the server running, even if it sends no requests.
+//!
+//!   If `--tls` is specified, every connection is encrypted with TLS, using the certificate
+//!   chain in the PEM file given by `--cert` and the private key in the PEM file given by
+//!   `--key`, which are both required with `--tls`. Clients must then connect over TLS, and
+//!   trust the certificate. TLS requires the server to be built with the `tls` feature.
+//!
+//!   The server shuts down gracefully when it receives a SIGTERM or SIGINT (i.e. Ctrl-C): it
+//!   stops accepting connections, removes the pid file, and exits with a zero exit code.
+//!   The "kvs" engine syncs its current log first, and logs how many bytes it flushed, whether
+//!   a compaction was allowed to finish or was skipped, and the final log generation.
+//!   On Windows only Ctrl-C is supported. The server always runs in the foreground, so it
+//!   is meant to be daemonized by an init system such as systemd.
+//!
+//!   Print an error and return a non-zero exit code on failure to bind a socket, if
+//!   `ENGINE-NAME` is invalid, if `IP-PORT` does not parse as an address.
+//!
+//! - `kvs-server init --data-dir PATH`
+//!
+//!   Initialize a data directory for the "kvs" engine, without starting the server: create
+//!   `PATH` if it doesn't exist, write the engine marker file and create the first log. This
+//!   lets storage be provisioned separately from running the server. Initializing a directory
+//!   that is already in use by the "kvs" engine leaves its data untouched. Print an error and
+//!   exit with a non-zero exit code if the directory is in use by a different engine.
+//!
+//! - `kvs-server -V`
+//!
+//!   Print the version.
 
-use std::env::current_dir;
+use std::env::{self, current_dir};
 use std::fs;
 use std::net::SocketAddr;
-use clap::{crate_version, App, Arg, arg_enum, value_t};
-use kvs::{KvsEngine, KvsError, KvStore, Result, KvsServer, ThreadPool, RayonThreadPool};
-use tracing::{warn, info, Level};
+use clap::{crate_version, App, Arg, ArgMatches, SubCommand, arg_enum, value_t};
+use kvs::{KvsEngine, KvsError, KvStore, MemoryKvsEngine, SledKvsEngine, Protocol, Result, KvsServer, ThreadPool, RayonThreadPool, DEFAULT_MAX_BATCH, DEFAULT_MAX_LINE_LEN, DEFAULT_MAX_VALUE_SIZE, DEFAULT_READ_BUFFER};
+use tracing::{error, warn, info, Level};
 use tracing_subscriber::{FmtSubscriber};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use signal_hook::consts::{SIGINT, SIGTERM};
 
 arg_enum! {
     #[allow(non_camel_case_types)]
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        memory
     }
 }
 
@@ -44,6 +140,10 @@ arg_enum! {
 const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
 const DEFAULT_ENGINE_FILE: &str = "engine";
+const DEFAULT_KEEPALIVE_SECS: &str = "60";
+
+// the environment variable that the encryption passphrase can be read from
+const PASSPHRASE_ENV: &str = "KVS_PASSPHRASE";
 
 
 /// ['Opt'] holds parsed and validated options from the command line
@@ -51,32 +151,139 @@ const DEFAULT_ENGINE_FILE: &str = "engine";
 struct Opt {
     addr: SocketAddr,
     engine: Engine,
+    auth_token: Option<String>,
+    max_requests: Option<usize>,
+    keepalive: Option<Duration>,
+    audit_log: Option<PathBuf>,
+    pid_file: Option<PathBuf>,
+    max_get_batch: usize,
+    max_multi_set: usize,
+    max_connections: Option<usize>,
+    read_only: bool,
+    read_buffer: usize,
+    max_line_len: usize,
+    max_value_size: usize,
+    // the only protocol clients may use, other than the text protocol, if they are restricted
+    protocol: Option<Protocol>,
+    passphrase: Option<String>,
+    hash_keys: bool,
+    index_snapshot: bool,
+    // 1 in this many key accesses are counted to find the hot keys, if they are tracked
+    hot_keys: Option<u32>,
+    // how long the server may go without any connection before it shuts down
+    idle_exit: Option<Duration>,
+    // the paths of the certificate chain and private key, if connections are encrypted with TLS
+    tls: Option<(PathBuf, PathBuf)>,
 }
 
 impl Opt {
-    fn new(addr: SocketAddr, engine: Engine) -> Self {
-        Self { addr, engine }
-    }
-
-    /// validates the `addr` and `requested_engine` parameters
+    /// validates the `addr`, `engine`, `max-requests`, `keepalive`, batch size, `max-connections`, `read-buffer`, `max-line-len`, `max-value-mb`, `protocol`, `hot-keys` and `idle-exit` arguments
     /// returns `Ok<Opt>` if everything is valid
     /// # Errors
     /// returns [`KvsError::Parsing`] if one of the parameters is invalid
     ///
-    fn build(addr: &str, req_engine: Engine) -> Result<Opt> {
+    fn build(matches: &ArgMatches) -> Result<Opt> {
+        let addr = matches.value_of("addr").unwrap();
         let addr: SocketAddr = addr
             .parse()
             .map_err(|_| KvsError::Parsing(format!("could not parse {} into an IP addess and port", &addr)))?;
+        // requested engine
+        let req_engine: Engine = value_t!(matches, "engine", Engine).ok().unwrap_or(DEFAULT_ENGINE);
+
+        let max_requests = matches
+            .value_of("max-requests")
+            .map(|max| parse_positive("max requests", max))
+            .transpose()?;
+        let max_get_batch = matches
+            .value_of("max-get-batch")
+            .map_or(Ok(DEFAULT_MAX_BATCH), |max| parse_positive("max get batch", max))?;
+        let max_multi_set = matches
+            .value_of("max-multi-set")
+            .map_or(Ok(DEFAULT_MAX_BATCH), |max| parse_positive("max multi set", max))?;
+        let max_connections = matches
+            .value_of("max-connections")
+            .map(|max| parse_positive("max connections", max))
+            .transpose()?;
+        let read_buffer = matches
+            .value_of("read-buffer")
+            .map_or(Ok(DEFAULT_READ_BUFFER), |bytes| parse_positive("read buffer", bytes))?;
+        let max_line_len = matches
+            .value_of("max-line-len")
+            .map_or(Ok(DEFAULT_MAX_LINE_LEN), |bytes| parse_positive("max line length", bytes))?;
+        let max_value_size = matches
+            .value_of("max-value-mb")
+            .map(|mb| parse_positive("max value size", mb))
+            .transpose()?
+            .map_or(DEFAULT_MAX_VALUE_SIZE, |mb| mb.saturating_mul(1024 * 1024));
+        let protocol = matches.value_of("protocol").map(str::parse).transpose()?;
+        let hot_keys = matches
+            .value_of("hot-keys")
+            .map(|rate| parse_positive("hot keys sample rate", rate))
+            .transpose()?
+            .map(|rate| u32::try_from(rate).unwrap_or(u32::MAX));
+        let idle_exit = matches
+            .value_of("idle-exit")
+            .map(|secs| parse_positive("idle exit", secs))
+            .transpose()?
+            .map(|secs| Duration::from_secs(secs as u64));
+
+        // the passphrase is never taken from the command line, where other users could see it
+        let passphrase = match matches.value_of("passphrase-file") {
+            Some(path) => fs::read_to_string(path)?.lines().next().map(String::from),
+            None => env::var(PASSPHRASE_ENV).ok(),
+        };
+
+        let keepalive = matches.value_of("keepalive").unwrap();
+        let keepalive = match keepalive.parse::<u64>() {
+            Ok(0) => None,
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => return Err(KvsError::Parsing(format!("keepalive must be a number of seconds, got {}", keepalive))),
+        };
 
         // the requested engine parameter, if present, must be the same as the engine currently in use
-        let engine = match current_engine()? {
+        let engine = match current_engine(&current_dir()?)? {
+            // the memory engine doesn't use any persisted data
+            _ if req_engine == Engine::memory => req_engine,
             None => req_engine, // no current engine, use the requested engine
             Some(cur_engine) if req_engine == cur_engine => cur_engine, // current engine is the same as the requested engine
             // current engine != requested engine
             Some(cur_engine) => return Err(KvsError::Parsing(format!("the requested engine: {} does not match the engine currently in use: {}", req_engine, cur_engine)))
         };
 
-        Ok(Opt::new(addr, engine))
+        Ok(Opt {
+            addr,
+            engine,
+            auth_token: matches.value_of("auth-token").map(String::from),
+            max_requests,
+            keepalive,
+            audit_log: matches.value_of("audit-log").map(PathBuf::from),
+            pid_file: matches.value_of("pid-file").map(PathBuf::from),
+            max_get_batch,
+            max_multi_set,
+            max_connections,
+            read_only: matches.is_present("read-only"),
+            read_buffer,
+            max_line_len,
+            max_value_size,
+            protocol,
+            passphrase,
+            hash_keys: matches.is_present("hash-keys"),
+            index_snapshot: matches.is_present("index-snapshot"),
+            hot_keys,
+            idle_exit,
+            // clap requires the cert and key whenever --tls is present
+            tls: matches.is_present("tls").then(|| {
+                (PathBuf::from(matches.value_of("cert").unwrap()), PathBuf::from(matches.value_of("key").unwrap()))
+            }),
+        })
+    }
+}
+
+/// parses the value of the `name`d command line argument, which must be a positive integer
+fn parse_positive(name: &str, value: &str) -> Result<usize> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(KvsError::Parsing(format!("{} must be a positive integer, got {}", name, value))),
     }
 }
 
@@ -98,15 +305,112 @@ fn main() {
         .arg(Arg::with_name("engine")
             .long("engine")
             .value_name("ENGINE_NAME")
-            .help("sets the storage engine to use, currently only 'kvs' is supported")
+            .help("sets the storage engine to use, currently 'kvs', 'sled' and 'memory' are supported")
             .default_value("kvs"))
+        .arg(Arg::with_name("auth-token")
+            .long("auth-token")
+            .value_name("TOKEN")
+            .help("requires clients to authenticate with this shared secret token"))
+        .arg(Arg::with_name("max-requests")
+            .long("max-requests")
+            .value_name("N")
+            .help("closes a connection after it has sent N requests, the client must then reconnect"))
+        .arg(Arg::with_name("keepalive")
+            .long("keepalive")
+            .value_name("SECS")
+            .help("sends TCP keep-alive probes on connections idle for SECS seconds, 0 disables keep-alive")
+            .default_value(DEFAULT_KEEPALIVE_SECS))
+        .arg(Arg::with_name("audit-log")
+            .long("audit-log")
+            .value_name("PATH")
+            .help("records every successful set and remove in an audit log at PATH"))
+        .arg(Arg::with_name("pid-file")
+            .long("pid-file")
+            .value_name("PATH")
+            .help("writes the process id to PATH, the file is removed on a graceful shutdown"))
+        .arg(Arg::with_name("max-get-batch")
+            .long("max-get-batch")
+            .value_name("N")
+            .help("rejects batch gets of more than N keys, defaults to 1000"))
+        .arg(Arg::with_name("max-multi-set")
+            .long("max-multi-set")
+            .value_name("N")
+            .help("rejects batch sets of more than N key/value pairs, defaults to 1000"))
+        .arg(Arg::with_name("max-connections")
+            .long("max-connections")
+            .value_name("N")
+            .help("services at most N connections at once, defaults to the number of worker threads"))
+        .arg(Arg::with_name("read-only")
+            .long("read-only")
+            .help("rejects every request that modifies data, only reads are served"))
+        .arg(Arg::with_name("read-buffer")
+            .long("read-buffer")
+            .value_name("BYTES")
+            .help("reads the requests of each connection into a buffer of BYTES, defaults to 8192"))
+        .arg(Arg::with_name("max-line-len")
+            .long("max-line-len")
+            .value_name("BYTES")
+            .help("closes text protocol connections that send a line longer than BYTES, defaults to 65536"))
+        .arg(Arg::with_name("max-value-mb")
+            .long("max-value-mb")
+            .value_name("MB")
+            .help("rejects requests with values larger than MB megabytes, and closes their connection, defaults to 64"))
+        .arg(Arg::with_name("protocol")
+            .long("protocol")
+            .value_name("PROTOCOL")
+            .help("only accepts clients that use this protocol, 'json' or 'bincode', rather than either"))
+        .arg(Arg::with_name("hot-keys")
+            .long("hot-keys")
+            .value_name("RATE")
+            .help("tracks the most accessed keys, counting 1 in RATE key accesses"))
+        .arg(Arg::with_name("idle-exit")
+            .long("idle-exit")
+            .value_name("SECS")
+            .help("shuts down once there has been no connection for SECS seconds"))
+        .arg(Arg::with_name("passphrase-file")
+            .long("passphrase-file")
+            .value_name("PATH")
+            .help("encrypts values at rest with the passphrase on the first line of PATH, instead of $KVS_PASSPHRASE"))
+        .arg(Arg::with_name("hash-keys")
+            .long("hash-keys")
+            .help("hashes the keys of an encrypted store, so they aren't stored in plain text"))
+        .arg(Arg::with_name("index-snapshot")
+            .long("index-snapshot")
+            .help("snapshots the index of the kvs engine on shutdown, so that it starts faster"))
+        .arg(Arg::with_name("tls")
+            .long("tls")
+            .requires_all(&["cert", "key"])
+            .help("encrypts every connection with TLS, using the certificate and key given by --cert and --key"))
+        .arg(Arg::with_name("cert")
+            .long("cert")
+            .value_name("PATH")
+            .requires("tls")
+            .help("the PEM file containing the server's TLS certificate chain"))
+        .arg(Arg::with_name("key")
+            .long("key")
+            .value_name("PATH")
+            .requires("tls")
+            .help("the PEM file containing the private key of the server's TLS certificate"))
+        .subcommand(SubCommand::with_name("init")
+            .about("initializes a data directory for the kvs engine, without starting the server")
+            .arg(Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("PATH")
+                .help("the data directory to initialize, it is created if it doesn't exist")
+                .required(true)))
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("init") {
+        let data_dir = Path::new(matches.value_of("data-dir").unwrap());
+        if let Err(e) = init(data_dir) {
+            eprintln!("{:?}", e);
+            exit(1);
+        }
+        return;
+    }
+
     // validate command line options, store them in Opt
-    let addr = matches.value_of("addr").unwrap();
-    // requested engine
-    let req_engine: Engine = value_t!(matches, "engine", Engine).ok().unwrap_or(DEFAULT_ENGINE);
-    let opt = match Opt::build(addr, req_engine) {
+    let opt = match Opt::build(&matches) {
         Ok(opt) => opt,
         Err(err) => {
             eprintln!("{:?}", err);
@@ -127,25 +431,147 @@ fn run(opt: Opt) -> Result<()> {
     info!("Storage engine: {}", opt.engine);
     info!("Listening on {}", opt.addr);
 
-    // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", opt.engine))?;
+    // write engine to engine file, unless the engine doesn't persist any data
+    if opt.engine != Engine::memory {
+        fs::write(current_dir()?.join("engine"), format!("{}", opt.engine))?;
+    }
+
+    if let Some(pid_file) = &opt.pid_file {
+        fs::write(pid_file, std::process::id().to_string())?;
+    }
+
+    // SIGTERM and SIGINT set the shutdown flag, which stops the server
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))?;
+    }
+
+    let pid_file = opt.pid_file.clone();
+    let result = match opt.engine {
+        Engine::kvs => run_with_engine(open_kvs(&current_dir()?, &opt)?, opt, shutdown),
+        Engine::memory => run_with_engine(MemoryKvsEngine::new(), opt, shutdown),
+        Engine::sled => run_with_engine(SledKvsEngine::new(sled::open(current_dir()?)?), opt, shutdown),
+    };
 
-    match opt.engine {
-        Engine::kvs => run_with_engine(KvStore::open(&current_dir()?)?, opt.addr),
-        Engine::sled => panic!("sled not currently implemented"),
-        //Engine::sled => run_with_engine(SledKvsEngine::new(sled::open(current_dir()?)?), opt.addr),
+    if let Some(pid_file) = pid_file {
+        if let Err(e) = fs::remove_file(&pid_file) {
+            warn!("could not remove the pid file {:?}: {}", pid_file, e);
+        }
     }
+    info!("kvs-server shut down");
+    result
 }
 
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
+/// initializes the `data_dir`ectory for the kvs engine: creates the directory, writes the engine
+/// file and opens a [`KvStore`] in it, which creates the first log
+/// # Errors
+/// returns [`KvsError::Parsing`] if the directory is already in use by a different engine
+fn init(data_dir: &Path) -> Result<()> {
+    fs::create_dir_all(data_dir)?;
+    match current_engine(data_dir)? {
+        Some(engine) if engine != Engine::kvs => {
+            return Err(KvsError::Parsing(format!("{} is already in use by the {} engine", data_dir.display(), engine)))
+        }
+        _ => {}
+    }
+    fs::write(data_dir.join(DEFAULT_ENGINE_FILE), format!("{}", Engine::kvs))?;
+    KvStore::open(data_dir)?;
+    info!("initialized {} for the kvs engine", data_dir.display());
+    Ok(())
+}
+
+/// opens the [`KvStore`] in `dir`, encrypted with the passphrase in the `opt`ions, if there is
+/// one, and snapshotting its index if `--index-snapshot` was given
+/// # Errors
+/// returns [`KvsError::Parsing`] if `--hash-keys` is given without a passphrase, or if there is
+/// a passphrase but the server was built without the `crypto` feature
+fn open_kvs(dir: &Path, opt: &Opt) -> Result<KvStore> {
+    let builder = KvStore::builder().index_snapshot(opt.index_snapshot);
+    match &opt.passphrase {
+        #[cfg(feature = "crypto")]
+        Some(passphrase) => {
+            info!("Values are encrypted at rest");
+            builder
+                .encryption_passphrase(passphrase.clone())
+                .hash_keys(opt.hash_keys)
+                .open(dir)
+        }
+        #[cfg(not(feature = "crypto"))]
+        Some(_) => Err(KvsError::Parsing(
+            "encryption requires kvs-server to be built with the crypto feature".to_string(),
+        )),
+        None if opt.hash_keys => Err(KvsError::Parsing("--hash-keys requires a passphrase".to_string())),
+        None => builder.open(dir),
+    }
+}
+
+fn run_with_engine<E: KvsEngine>(engine: E, opt: Opt, shutdown: Arc<AtomicBool>) -> Result<()> {
     // created a thread pool with 4 threads, backed by a shared channel
     let pool = RayonThreadPool::new(4).unwrap();
-    let server = KvsServer::new(engine, pool);
-    server.run(addr)
+    let mut server = KvsServer::new(engine.clone(), pool);
+    if let Some(token) = opt.auth_token {
+        info!("Authentication is required");
+        server = server.auth_token(token);
+    }
+    if let Some(max) = opt.max_requests {
+        info!("Connections are limited to {} requests", max);
+        server = server.max_requests_per_connection(max);
+    }
+    server = server
+        .keepalive(opt.keepalive)
+        .max_get_batch(opt.max_get_batch)
+        .max_multi_set(opt.max_multi_set)
+        .read_buffer(opt.read_buffer)
+        .max_line_len(opt.max_line_len)
+        .max_value_size(opt.max_value_size);
+    if let Some(max) = opt.max_connections {
+        info!("At most {} connections are serviced at once", max);
+        server = server.max_connections(max);
+    }
+    if let Some(protocol) = opt.protocol {
+        info!("Only the {:?} protocol is accepted", protocol);
+        server = server.protocol(protocol);
+    }
+    if opt.read_only {
+        warn!("The server is read-only, all writes will be rejected");
+        server = server.read_only(true);
+    }
+    if let Some(audit_log) = opt.audit_log {
+        info!("Auditing writes to {:?}", audit_log);
+        server = server.audit_log(audit_log);
+    }
+    if let Some(rate) = opt.hot_keys {
+        info!("Tracking hot keys, sampling 1 in {} key accesses", rate);
+        server = server.hot_keys(rate);
+    }
+    if let Some(idle) = opt.idle_exit {
+        info!("Shutting down after {}s without connections", idle.as_secs());
+        server = server.idle_exit(idle);
+    }
+    match opt.tls {
+        #[cfg(feature = "tls")]
+        Some((cert, key)) => {
+            info!("Connections are encrypted with TLS");
+            server = server.tls(kvs::tls::server_config(&cert, &key)?);
+        }
+        #[cfg(not(feature = "tls"))]
+        Some(_) => {
+            return Err(KvsError::Parsing("TLS requires kvs-server to be built with the tls feature".to_string()))
+        }
+        None => {}
+    }
+    let result = server.run_until(opt.addr, shutdown);
+
+    // the engine logs a summary of the data it flushed
+    match engine.shutdown() {
+        Ok(_) | Err(KvsError::Unsupported(_)) => {}
+        Err(e) => error!("failed to shut down the engine: {}", e),
+    }
+    result
 }
 
-/// determines if an "engine" file exists in the current directory and if so, returns a
+/// determines if an "engine" file exists in the given `dir`ectory and if so, returns a
 /// ['Engine'] variant based on the string value within the engine file.
 ///
 /// returns `Ok(None)` if an "engine" file does not (yet) exist,
@@ -153,8 +579,8 @@ fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
 /// # Errors
 /// returns ['KvsError'] if the engine file contains invalid string data
 ///
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join(DEFAULT_ENGINE_FILE);
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine = dir.join(DEFAULT_ENGINE_FILE);
     if !engine.exists() {
         return Ok(None);
     }
@@ -1,15 +1,25 @@
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::Deserialize;
-use serde_json::de::IoRead;
 use serde_json::Deserializer;
-use crate::command::{Request, Response};
-use crate::{KvsError, Result};
+use crate::command::{LogInfo, Protocol, Request, Response, Stats, BINCODE_HELLO, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::{KvsClientPool, KvsError, Result};
+use crate::server::set_keepalive;
+use crate::stream::Stream;
+use socket2::SockRef;
+use std::thread;
+use tracing::debug;
 
 /// The `KvsClient` struct is used to issue synchronous command [`Request`]s to a running [`KvsServer`].
 ///
 /// It can issue "GET", "SET", and "REMOVE" operations, and then wait for (and parse) the [`Response`] from the server.
 ///
+/// Every request borrows the client mutably until its response has been read, so a request can
+/// never read the response of another. A request that is abandoned part way, e.g. because its
+/// response timed out, leaves the connection broken: every later request on the client fails
+/// with a [`KvsError::Io`], and a new client must be connected.
+///
 /// # Example
 /// Connect to a KvsServer running at 127.0.0.1:4000 and then issue a "get" request to get the value
 /// associated with the key "mykey".
@@ -38,23 +48,221 @@ use crate::{KvsError, Result};
 /// [`KvsServer`]: ../struct.KvsServer.html
 /// [`Request`]: ./enum.Request
 /// [`Response`]: ./enum.Response
+#[derive(Debug)]
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    // the encoding of the requests and responses
+    protocol: Protocol,
+    // the protocol version negotiated with the server
+    version: u32,
+    // the number of requests sent by `set_nowait` whose responses haven't been read yet
+    pending: usize,
+    // the errors returned for `set_nowait` requests, that haven't been reported yet
+    pending_errors: Vec<String>,
+    // whether an IO error has left the connection unusable
+    broken: bool,
 }
 
+// the maximum number of `set_nowait` requests that can be waiting for a response. Once there
+// are this many, the responses are read, so that the server doesn't block writing responses
+// that the client isn't reading
+const MAX_PENDING_RESPONSES: usize = 1024;
+
+// the delay before the first retry of a failed connection, see `KvsClientBuilder::retries`
+const CONNECT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The address a [`KvsClientBuilder`] connects to by default, which is also the address a
+/// kvs-server listens on by default
+pub const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:4000";
+
 impl KvsClient {
 
     /// tries to create a KvsClient and establish a socket connection to a KvsServer running at
-    /// the given `addr`
+    /// the given `addr`.
+    ///
+    /// Once connected, the client and server negotiate the protocol version to use.
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if the client and server have no protocol version in common
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+        Self::with_stream(Stream::Tcp(TcpStream::connect(addr)?), Protocol::Json)
+    }
+
+    /// like [`connect`](KvsClient::connect), but encodes the requests and responses with the
+    /// given [`Protocol`] rather than JSON. A bincode client performs a handshake with the
+    /// server before negotiating the protocol version.
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if the server doesn't accept the protocol, or the client and
+    /// server have no protocol version in common
+    pub fn connect_with_protocol<A: ToSocketAddrs>(addr: A, protocol: Protocol) -> Result<Self> {
+        Self::with_stream(Stream::Tcp(TcpStream::connect(addr)?), protocol)
+    }
+
+    /// like [`connect`](KvsClient::connect), but waits at most `timeout` for a connection to each
+    /// of the addresses `addr` resolves to, and for each read or write of the connection, so that
+    /// a server that has gone away can't hang the client. A read or write that times out fails
+    /// with a [`KvsError::Io`], and leaves the connection unusable.
+    /// # Errors
+    /// `Err<KvsError::Io>` if the client could not connect in time, or the server didn't respond
+    /// to the version negotiation in time
+    /// `Err<KvsError::StringErr>` if the client and server have no protocol version in common
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<Self> {
+        let tcp = connect_tcp(addr, Some(timeout))?;
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))?;
+        Self::with_stream(Stream::Tcp(tcp), Protocol::Json)
+    }
+
+    /// like [`connect`](KvsClient::connect), but makes up to `attempts` (at least one) attempts to connect,
+    /// e.g. while the server is still binding its address. The delay before each retry grows
+    /// linearly: `backoff` before the second attempt, twice `backoff` before the third, and so on.
+    /// If `timeout` is given, each attempt waits at most `timeout` for a connection, and the
+    /// connection's reads and writes time out after it too, see
+    /// [`connect_timeout`](KvsClient::connect_timeout).
+    ///
+    /// Only failures to connect are retried. Once connected, a failed version negotiation is
+    /// returned immediately. See [`KvsClientBuilder::retries`] for a configurable client with
+    /// exponential backoff.
+    /// # Errors
+    /// `Err<KvsError::Io>` of the last attempt, if every attempt failed to connect, or if the
+    /// version negotiation failed
+    /// `Err<KvsError::StringErr>` if the client and server have no protocol version in common
+    pub fn connect_with_retries<A: ToSocketAddrs>(
+        addr: A,
+        attempts: usize,
+        backoff: Duration,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let mut attempt = 1;
+        let tcp = loop {
+            match connect_tcp(&addr, timeout) {
+                Err(e) if attempt < attempts => {
+                    debug!("could not connect (attempt {} of {}): {}", attempt, attempts, e);
+                    thread::sleep(backoff * attempt as u32);
+                    attempt += 1;
+                }
+                result => break result?,
+            }
+        };
+        tcp.set_read_timeout(timeout)?;
+        tcp.set_write_timeout(timeout)?;
+        Self::with_stream(Stream::Tcp(tcp), Protocol::Json)
+    }
+
+    /// tries to create a KvsClient and establish a TLS connection to a KvsServer running at the
+    /// given `addr`, that was started with [`KvsServer::tls`](crate::KvsServer::tls).
+    ///
+    /// The server's certificate must be valid for `server_name`, and be signed by one of the
+    /// `root_certs` (see [`tls::root_certs`](crate::tls::root_certs)). Requests and responses
+    /// are sent the same way as on a plain connection. Requires the `tls` feature.
+    /// # Errors
+    /// `Err<KvsError::Tls>` if `server_name` isn't a valid DNS name or IP address
+    /// `Err<KvsError::Io>` if the connection or the TLS handshake failed, e.g. because the
+    /// server's certificate isn't trusted
+    /// `Err<KvsError::StringErr>` if the client and server have no protocol version in common
+    #[cfg(feature = "tls")]
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, server_name: &str, root_certs: rustls::RootCertStore) -> Result<Self> {
+        Self::with_stream(tls_stream(TcpStream::connect(addr)?, server_name, root_certs)?, Protocol::Json)
+    }
+
+    /// returns a [`KvsClientBuilder`] that can be used to configure the options of a client,
+    /// e.g. its timeouts or auth token, before connecting it
+    pub fn builder() -> KvsClientBuilder {
+        KvsClientBuilder::default()
+    }
+
+    /// creates a client that sends its requests over the given `stream`, encoded with the
+    /// given `protocol`, and negotiates the protocol version with the server
+    fn with_stream(stream: Stream, protocol: Protocol) -> Result<Self> {
+        let mut client = KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            protocol,
+            version: MIN_PROTOCOL_VERSION,
+            pending: 0,
+            pending_errors: vec![],
+            broken: false,
+        };
+        if protocol == Protocol::Bincode {
+            client.bincode_handshake()?;
+        }
+        client.negotiate_version()?;
+        Ok(client)
+    }
+
+    /// announces the bincode protocol to the server, and waits for the server to acknowledge it
+    fn bincode_handshake(&mut self) -> Result<()> {
+        self.writer.write_all(&[BINCODE_HELLO, b'\n'])?;
+        self.writer.flush()?;
+        let mut reply = [0];
+        match self.reader.read_exact(&mut reply) {
+            Ok(()) if reply[0] == BINCODE_HELLO => Ok(()),
+            // the server refused the handshake, or closed the connection without understanding it
+            Ok(()) => Err(KvsError::StringErr("the server doesn't accept the bincode protocol".to_string())),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(KvsError::StringErr("the server doesn't accept the bincode protocol".to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// creates a [`KvsClientPool`] of at most `size` connections to the KvsServer running at
+    /// the given `addr`, for sharing connections between threads. Connections are opened as
+    /// they are needed. See [`KvsClientBuilder::pool`] for a pool of connections with options,
+    /// e.g. an auth token.
+    /// # Errors
+    /// `Err<KvsError::Io>` if `addr` could not be resolved
+    pub fn connect_with_pool<A: ToSocketAddrs>(addr: A, size: usize) -> Result<KvsClientPool> {
+        KvsClientPool::new(addr, size)
+    }
+
+    /// enables TCP keep-alive on the connection to the server, so that a server that has gone
+    /// away is detected, or disables it if `idle` is `None`. Keep-alive is disabled by default.
+    /// Probes are sent the same way as the server's, see
+    /// [`KvsServer::keepalive`](crate::KvsServer::keepalive).
+    /// # Errors
+    /// `Err<KvsError::Io>` if keep-alive could not be configured on the socket
+    pub fn keepalive(&self, idle: Option<Duration>) -> Result<()> {
+        let stream = self.writer.get_ref().tcp();
+        match idle {
+            Some(idle) => set_keepalive(stream, idle)?,
+            None => SockRef::from(stream).set_keepalive(false)?,
+        }
+        Ok(())
+    }
+
+    /// returns the protocol version negotiated with the server
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// returns the [`Protocol`] the client's requests and responses are encoded with
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// advertises the latest protocol version supported by this client to the server, and
+    /// stores the version the server replies with
+    fn negotiate_version(&mut self) -> Result<()> {
+        match self.send(Request::Hello { version: PROTOCOL_VERSION })? {
+            Response::Hello { version } if version >= MIN_PROTOCOL_VERSION => {
+                self.version = version;
+                Ok(())
+            }
+            Response::Hello { version } => Err(KvsError::StringErr(format!(
+                "unsupported protocol version {}, the client supports versions {} to {}",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ))),
+            resp => Err(unexpected(resp)),
+        }
+    }
 
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
-        })
+    /// authenticates this connection with the server using the given shared secret `token`.
+    /// This must be done before any other request if the server was started with an auth token.
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if the server rejected the token
+    pub fn auth(&mut self, token: String) -> Result<()> {
+        self.send(Request::Auth { token }).map(|_resp| ())
     }
 
     /// gets the value of the specified `key` from the server
@@ -63,45 +271,769 @@ impl KvsClient {
     /// `Ok<None>` if there is no value associated with the key
     /// `Err<KvsError::Command>` if an error occurred when retrieving the key
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let req = Request::Get { key };
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
+        match self.send(Request::Get { key })? {
+            Response::Ok(value) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
 
-        match Response::deserialize(&mut self.reader)? {
+    /// gets at most `len` bytes of the value of the specified `key` from the server, starting at
+    /// the byte offset `start`, e.g. a preview of a large value, see
+    /// [`KvsEngine::get_range`](crate::KvsEngine::get_range)
+    /// # Returns
+    /// the bytes of the range, which is cut short if it extends past the end of the value, or
+    /// `None` if the key does not exist
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while getting the value, e.g. if the
+    /// range starts or ends within a multi-byte UTF-8 character
+    pub fn get_range(&mut self, key: String, start: u64, len: u64) -> Result<Option<String>> {
+        match self.send(Request::GetRange { key, start, len })? {
             Response::Ok(value) => Ok(value),
-            Response::Err(msg) => Err(KvsError::StringErr(msg)), // re-throwing error here
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets the value of the specified `key` from the server, writing it to `out` as it is
+    /// received. Unlike [`get`](KvsClient::get), neither the client nor the server hold the
+    /// entire value in memory, so this should be used for very large values.
+    ///
+    /// If the server only supports protocol version 1, the value is read into memory and then
+    /// written to `out`.
+    /// # Returns
+    /// `Ok<true>` if the value was found and written to `out`
+    /// `Ok<false>` if there is no value associated with the key
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred when retrieving the key
+    /// `Err<KvsError::Io>` if the value could not be written to `out`. The connection can't
+    /// be used after this, as the rest of the value was not read
+    pub fn get_into<W: Write>(&mut self, key: String, out: &mut W) -> Result<bool> {
+        if self.version < 2 {
+            return match self.get(key)? {
+                Some(value) => {
+                    out.write_all(value.as_bytes())?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            };
+        }
+        match self.send(Request::GetStream { key })? {
+            Response::Value { len } => {
+                let copied = io::copy(&mut (&mut self.reader).take(len), out);
+                let copied = self.track(copied.map_err(KvsError::from))?;
+                if copied < len {
+                    let msg = format!("the server closed the connection after {} of {} bytes", copied, len);
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg).into());
+                }
+                Ok(true)
+            }
+            Response::Ok(None) => Ok(false),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets the value of the specified `key` from the server as raw bytes, which don't need to be
+    /// valid UTF-8, e.g. a value set with [`KvStore::set_raw`](crate::KvStore::set_raw).
+    ///
+    /// The value is framed by the exact length of its bytes, see
+    /// [`get_into`](KvsClient::get_into). If the server only supports protocol version 1, the
+    /// value must be valid UTF-8.
+    /// # Returns
+    /// `Ok<Some<Vec<u8>>>` if the value was found for the key.
+    /// `Ok<None>` if there is no value associated with the key
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred when retrieving the key
+    pub fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        let mut value = Vec::new();
+        if self.get_into(key, &mut value)? {
+            Ok(Some(value))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// gets the value of the specified `key` from the server, or the given `default` if the key
+    /// does not exist. The `default` is not written to the server
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred when retrieving the key
+    pub fn get_or(&mut self, key: String, default: String) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// checks whether the specified `key` exists on the server, without getting its value, see
+    /// [`KvsEngine::contains_key`](crate::KvsEngine::contains_key)
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while checking for the key
+    pub fn contains_key(&mut self, key: String) -> Result<bool> {
+        match self.send(Request::Contains { key })? {
+            Response::Contains(contains) => Ok(contains),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets the value of the specified `key` from the server, along with the time the value
+    /// was last written
+    /// # Returns
+    /// `Ok<Some<(String, SystemTime)>>` if the value was found for the key.
+    /// `Ok<None>` if there is no value associated with the key
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred when retrieving the key, or if the
+    /// server's engine does not track write times
+    pub fn get_with_meta(&mut self, key: String) -> Result<Option<(String, SystemTime)>> {
+        match self.send(Request::GetWithMeta { key })? {
+            Response::Meta { value, written_at } => {
+                Ok(Some((value, UNIX_EPOCH + Duration::from_millis(written_at))))
+            }
+            Response::Ok(None) => Ok(None),
+            resp => Err(unexpected(resp)),
         }
     }
 
     /// sends a set key/value request to the server
     /// # Returns
+    /// `Ok<Some<u64>>` containing the write sequence number, if the key/value pair was
+    /// successfully set and the server's engine tracks write sequence numbers
     /// `Ok<None>` if the the key/value pair was successfully set
     /// # Errors
     /// `Err<KvsError::StringErr>` if an error occurred while setting the key/value
-    pub fn set(&mut self, key: String, value: String) -> Result<Option<String>> {
-        let req = Request::Set { key, value };
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
-
-        match Response::deserialize(&mut self.reader)? {
+    pub fn set(&mut self, key: String, value: String) -> Result<Option<u64>> {
+        match self.send(Request::Set { key, value })? {
+            Response::Seq(seq) => Ok(Some(seq)),
             Response::Ok(_value) => Ok(None),
-            Response::Err(msg) => Err(KvsError::StringErr(msg)),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// sends a set key/value request to the server, along with a `tag` byte describing the
+    /// value. The tag is returned by [`get_tagged`](KvsClient::get_tagged).
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while setting the key/value, or if the
+    /// server's engine can't store tags
+    pub fn set_tagged(&mut self, key: String, value: String, tag: u8) -> Result<()> {
+        match self.send(Request::SetTagged { key, value, tag })? {
+            Response::Ok(None) => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets the value of the specified `key` from the server, along with its tag. Values set
+    /// without a tag have a tag of 0
+    /// # Returns
+    /// `Ok<Some<(String, u8)>>` if the value was found for the key.
+    /// `Ok<None>` if there is no value associated with the key
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred when retrieving the key
+    pub fn get_tagged(&mut self, key: String) -> Result<Option<(String, u8)>> {
+        match self.send(Request::GetTagged { key })? {
+            Response::Tagged { value, tag } => Ok(Some((value, tag))),
+            Response::Ok(None) => Ok(None),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets the values of several `keys` from the server, in a single request
+    /// # Returns
+    /// the value of each key, in the same order as `keys`, or `None` if a key has no value
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred when retrieving the keys, or if there
+    /// are more keys than the server allows in a batch
+    pub fn get_batch(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.send(Request::GetBatch { keys })? {
+            Response::Values(values) => Ok(values),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// sends several key/value `pairs` to the server, in a single request. The pairs are set all
+    /// together, or not at all
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while setting the pairs, or if there
+    /// are more pairs than the server allows in a batch
+    pub fn multi_set(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self.send(Request::MultiSet { pairs })? {
+            Response::Ok(None) => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// sends several key/value `pairs` to the server, in a single request. Unlike
+    /// [`multi_set`](KvsClient::multi_set) the pairs are set one at a time, so some of them may
+    /// be set even though others fail
+    /// # Returns
+    /// the result of setting each pair, in the same order as `pairs`, with the server's reason
+    /// for each pair that wasn't set
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if there are more pairs than the server allows in a batch
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<std::result::Result<(), String>>> {
+        match self.send(Request::SetMany { pairs })? {
+            Response::Results(results) => Ok(results),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// appends `suffix` to the value of `key` on the server, inserting the `separator` between
+    /// them if the key already exists. Every append rewrites the whole value, see
+    /// [`KvsEngine::append`](crate::KvsEngine::append)
+    /// # Returns
+    /// the new value of the key
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while appending, e.g. if the server's
+    /// engine doesn't support appends
+    pub fn append(&mut self, key: String, suffix: String, separator: Option<String>) -> Result<String> {
+        match self.send(Request::Append { key, suffix, separator })? {
+            Response::Ok(Some(value)) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// sets `key` to `value` on the server, and returns the value it replaced, atomically, see
+    /// [`KvsEngine::get_set`](crate::KvsEngine::get_set)
+    /// # Returns
+    /// the old value of the key, or `None` if the key didn't exist
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while setting the key, e.g. if the
+    /// server's engine doesn't support it
+    pub fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        match self.send(Request::GetSet { key, value })? {
+            Response::Ok(old) => Ok(old),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// sets `key` to the `new` value on the server, but only if its current value is `expected`.
+    /// An `expected` value of `None` only sets the key if it doesn't exist, see
+    /// [`KvsEngine::compare_and_swap`](crate::KvsEngine::compare_and_swap)
+    /// # Returns
+    /// `true` if the key was set, `false` if its current value wasn't the expected value
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while swapping, e.g. if the server's
+    /// engine doesn't support compare and swap
+    pub fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        match self.send(Request::Cas { key, expected, new })? {
+            Response::Swapped(swapped) => Ok(swapped),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// sets `key` to the `new` value on the server, but only if its current value is `expected`
+    /// # Returns
+    /// `true` if the key was set, `false` if its current value wasn't `expected`, or if the key
+    /// doesn't exist
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while swapping, e.g. if the server's
+    /// engine doesn't support compare and swap
+    pub fn set_if_equals(&mut self, key: String, expected: String, new: String) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected), new)
+    }
+
+    /// moves the value of the `from` key to the `to` key on the server, removing the `from` key
+    /// # Errors
+    /// `Err<KvsError::KeyNotFound>` if the `from` key does not exist
+    /// `Err<KvsError::StringErr>` if the server's engine can't rename keys
+    pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+        match self.send(Request::Rename { from, to })? {
+            Response::Ok(None) => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// sends a set key/value request to the server, without waiting for its response. This
+    /// avoids a round trip per request when bulk loading data.
+    ///
+    /// Requests are buffered, and only sent once the buffer is full or [`flush_responses`] is
+    /// called. Errors returned by the server are reported lazily, by the next call to
+    /// [`flush_responses`]. Other requests made with this client wait for all the outstanding
+    /// responses before they are sent, but do not report their errors.
+    /// # Errors
+    /// `Err<KvsError::Io>` if the request could not be sent
+    ///
+    /// [`flush_responses`]: KvsClient::flush_responses
+    pub fn set_nowait(&mut self, key: String, value: String) -> Result<()> {
+        self.check_usable()?;
+        if self.pending >= MAX_PENDING_RESPONSES {
+            self.read_pending()?;
         }
+        let written = self.protocol.write(&mut self.writer, &Request::Set { key, value });
+        self.track(written)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// sends any buffered [`set_nowait`](KvsClient::set_nowait) requests, and waits for all of
+    /// their responses.
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if the server returned an error for any of the requests sent
+    /// by `set_nowait` since the last flush. The error describes the number of failed requests
+    /// and the first error.
+    pub fn flush_responses(&mut self) -> Result<()> {
+        self.read_pending()?;
+        match self.pending_errors.first() {
+            None => Ok(()),
+            Some(first) => {
+                let err = KvsError::StringErr(format!(
+                    "{} request(s) failed, the first error was: {}",
+                    self.pending_errors.len(),
+                    first
+                ));
+                self.pending_errors.clear();
+                Err(err)
+            }
+        }
+    }
+
+    /// reads the responses of every outstanding `set_nowait` request, keeping their errors
+    fn read_pending(&mut self) -> Result<()> {
+        let flushed = self.writer.flush();
+        self.track(flushed.map_err(KvsError::from))?;
+        while self.pending > 0 {
+            let resp = self.read_response();
+            let resp = self.track(resp)?;
+            self.pending -= 1;
+            match resp {
+                Response::Seq(_) | Response::Ok(_) => {}
+                Response::Err(msg) => self.pending_errors.push(msg),
+                resp => self.pending_errors.push(unexpected(resp).to_string()),
+            }
+        }
+        Ok(())
     }
 
     /// removes a key and its associated value from the store
     /// # Returns
+    /// `Ok<Some<u64>>` containing the write sequence number, if the key/value was removed and
+    /// the server's engine tracks write sequence numbers
     /// `Ok<None>` if the the key/value was removed
     /// # Errors
+    /// `Err<KvsError::KeyNotFound>` if the key does not exist, like
+    /// [`KvsEngine::remove`](crate::KvsEngine::remove). Servers that only support protocol
+    /// version 2 or older report a missing key as a `KvsError::StringErr` instead
     /// `Err<KvsError::StringErr>` if an error occurred while attempting to remove the key
-    pub fn remove(&mut self, key: String) -> Result<Option<String>> {
-        let req = Request::Remove { key };
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
-
-        match Response::deserialize(&mut self.reader)? {
+    pub fn remove(&mut self, key: String) -> Result<Option<u64>> {
+        match self.send(Request::Remove { key })? {
+            Response::Seq(seq) => Ok(Some(seq)),
             Response::Ok(_value) => Ok(None),
-            Response::Err(msg) => Err(KvsError::StringErr(msg)),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets the number of keys on the server, see
+    /// [`KvsEngine::key_count`](crate::KvsEngine::key_count)
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while counting the keys
+    pub fn key_count(&mut self) -> Result<u64> {
+        match self.send(Request::Len)? {
+            Response::Ok(Some(count)) => count
+                .parse()
+                .map_err(|_| KvsError::StringErr(format!("invalid key count {} from the server", count))),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets statistics about the server and its storage engine
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while gathering the statistics
+    pub fn stats(&mut self) -> Result<Stats> {
+        match self.send(Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets statistics about the server and its storage engine, like [`stats`](KvsClient::stats),
+    /// and then resets their counters to zero, e.g. between the runs of a benchmark
+    /// # Returns
+    /// the statistics from before the reset
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while gathering the statistics
+    pub fn reset_stats(&mut self) -> Result<Stats> {
+        match self.send(Request::ResetStats)? {
+            Response::Stats(stats) => Ok(stats),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets at most `n` of the keys that the server estimates are the most accessed, hottest
+    /// first, with their estimated number of reads and writes. See
+    /// [`KvsServer::hot_keys`](crate::KvsServer::hot_keys)
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if the server doesn't track hot keys
+    pub fn hot_keys(&mut self, n: usize) -> Result<Vec<(String, u64)>> {
+        match self.send(Request::HotKeys { n })? {
+            Response::HotKeys(keys) => Ok(keys),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// checks that the server is responding
+    /// # Errors
+    /// `Err<KvsError::Io>` if the server could not be reached
+    pub fn ping(&mut self) -> Result<()> {
+        match self.send(Request::Ping)? {
+            Response::Ok(None) => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// checks that the server's storage engine is able to serve writes. Unlike [`ping`], this
+    /// is a readiness check rather than a liveness check.
+    /// # Errors
+    /// `Err<KvsError::StringErr>` containing the reason the engine can't serve writes
+    ///
+    /// [`ping`]: KvsClient::ping
+    pub fn health(&mut self) -> Result<()> {
+        match self.send(Request::Health)? {
+            Response::Ok(None) => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets a page of at most `limit` keys, in ascending order, that come after the `cursor`
+    /// key. Pass `None` to get the first page, and then the returned cursor to get the next
+    /// page, until the returned cursor is `None`.
+    ///
+    /// Keys set or removed while paging through the keys may be skipped, or returned even
+    /// though they have since been removed.
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while listing the keys, or if the
+    /// server's engine can't list its keys
+    pub fn scan(&mut self, cursor: Option<String>, limit: usize) -> Result<(Vec<String>, Option<String>)> {
+        match self.send(Request::Scan { cursor, limit })? {
+            Response::Keys { keys, next_cursor } => Ok((keys, next_cursor)),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets every key that starts with `prefix`, in ascending order, see
+    /// [`KvsEngine::scan_prefix`](crate::KvsEngine::scan_prefix)
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if the server's engine can't list its keys
+    pub fn scan_prefix(&mut self, prefix: String) -> Result<Vec<String>> {
+        match self.send(Request::ScanPrefix { prefix })? {
+            Response::Keys { keys, .. } => Ok(keys),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// gets every key that matches the glob `pattern`, in ascending order. In the pattern, `*`
+    /// matches any sequence of characters and `?` matches any single character.
+    ///
+    /// The server scans its entire keyspace to find the matching keys, so this takes O(n) time
+    /// in the number of keys. The keys are streamed from the server in chunks, but are all
+    /// collected into the returned `Vec`; use [`KvsClient::scan`] to page through the keys
+    /// instead.
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if an error occurred while listing the keys, or if the
+    /// server's engine can't list its keys
+    pub fn keys(&mut self, pattern: String) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut resp = self.send(Request::Keys { pattern })?;
+        loop {
+            match resp {
+                Response::Keys { keys: chunk, next_cursor } => {
+                    keys.extend(chunk);
+                    if next_cursor.is_none() {
+                        return Ok(keys);
+                    }
+                }
+                resp => return Err(unexpected(resp)),
+            }
+            let next = self.read_response();
+            resp = self.reply(next)?;
+        }
+    }
+
+    /// gets the size, number of live keys and reclaimable bytes of each of the log files of the
+    /// server's storage engine, see [`KvsEngine::log_info`](crate::KvsEngine::log_info)
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if the server's engine doesn't store its data in log files
+    pub fn log_info(&mut self) -> Result<Vec<LogInfo>> {
+        match self.send(Request::LogInfo)? {
+            Response::LogInfo(logs) => Ok(logs),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// compacts the files of the server's storage engine now, see
+    /// [`KvsEngine::compact`](crate::KvsEngine::compact)
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if the server's engine has nothing to compact, or the
+    /// compaction failed
+    pub fn compact(&mut self) -> Result<()> {
+        match self.send(Request::Compact)? {
+            Response::Ok(_) => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// sends the given `req`uest to the server and waits for its [`Response`]
+    /// # Errors
+    /// `Err<KvsError::StringErr>` if the server responded with a `Response::Err`, or
+    /// `Err<KvsError::KeyNotFound>` if it responded with a `Response::KeyNotFound`
+    fn send(&mut self, req: Request) -> Result<Response> {
+        self.check_usable()?;
+        // responses are read in order, so any outstanding responses must be read first
+        if self.pending > 0 {
+            self.read_pending()?;
+        }
+        let resp = self
+            .protocol
+            .write(&mut self.writer, &req)
+            .and_then(|_| Ok(self.writer.flush()?))
+            .and_then(|_| self.read_response());
+        self.reply(resp)
+    }
+
+    /// converts an error response from the server into an error
+    fn reply(&mut self, resp: Result<Response>) -> Result<Response> {
+        match self.track(resp)? {
+            Response::Err(msg) => Err(KvsError::StringErr(msg)), // re-throwing error here
+            Response::KeyNotFound => Err(KvsError::KeyNotFound),
+            resp => Ok(resp),
+        }
+    }
+
+    /// returns `true` if an IO error has left the connection unusable
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// returns an error if the connection is broken. A request that was abandoned part way,
+    /// e.g. because its response timed out, may leave its response to arrive later, and it
+    /// would then be read as the response to the next request
+    fn check_usable(&self) -> Result<()> {
+        if self.broken {
+            return Err(KvsError::from(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the connection was broken by an earlier error, reconnect to send more requests",
+            )));
+        }
+        Ok(())
+    }
+
+    /// marks the connection as broken if `result` is an IO or serialization error, as the client
+    /// and server can no longer agree on where the next request or response starts
+    fn track<T>(&mut self, result: Result<T>) -> Result<T> {
+        if matches!(result, Err(KvsError::Io { .. } | KvsError::Serialization(_) | KvsError::Bincode(_))) {
+            self.broken = true;
+        }
+        result
+    }
+
+    /// reads the next [`Response`] from the server. Nothing after the response is read, so
+    /// that a streamed value following it can be read directly from the `reader`.
+    /// A failure to read from the connection, e.g. a read timeout, is an IO error rather than a
+    /// serialization error
+    fn read_response(&mut self) -> Result<Response> {
+        match self.protocol {
+            Protocol::Json => {
+                Response::deserialize(&mut Deserializer::from_reader(&mut self.reader)).map_err(|e| match e.is_io() {
+                    true => KvsError::from(io::Error::from(e)),
+                    false => KvsError::from(e),
+                })
+            }
+            Protocol::Bincode => match Protocol::read_len(&mut self.reader)? {
+                Some(len) => Protocol::read_payload(&mut self.reader, len),
+                None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection").into()),
+            },
+        }
+    }
+}
+
+/// wraps the `tcp` stream in a TLS stream to the server called `server_name`, whose certificate
+/// must be signed by one of the `root_certs`
+#[cfg(feature = "tls")]
+fn tls_stream(tcp: TcpStream, server_name: &str, root_certs: rustls::RootCertStore) -> Result<Stream> {
+    let name = rustls::pki_types::ServerName::try_from(server_name.to_string())
+        .map_err(|e| KvsError::Tls(format!("invalid server name {}: {}", server_name, e)))?;
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(root_certs)
+        .with_no_client_auth();
+    Ok(Stream::tls_client(tcp, std::sync::Arc::new(config), name)?)
+}
+
+/// Builds a [`KvsClient`] with non-default options, created by [`KvsClient::builder`].
+///
+/// Every option has a default, so only the options that differ from it need to be set.
+/// [`KvsClient::connect`] is a shortcut for a client without any options.
+///
+/// # Example
+/// ```rust
+/// use kvs::KvsClient;
+/// use std::time::Duration;
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// // connect to a server that requires authentication, retrying a few times while it starts
+/// let mut client = KvsClient::builder()
+///     .addr("127.0.0.1:4000")
+///     .connect_timeout(Some(Duration::from_secs(1)))
+///     .retries(3)
+///     .auth_token("s3cret")
+///     .connect()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvsClientBuilder {
+    addr: String,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    retries: usize,
+    auth_token: Option<String>,
+    protocol: Protocol,
+    #[cfg(feature = "tls")]
+    tls: Option<(String, rustls::RootCertStore)>,
+}
+
+impl Default for KvsClientBuilder {
+    fn default() -> Self {
+        KvsClientBuilder {
+            addr: DEFAULT_SERVER_ADDR.to_string(),
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            retries: 0,
+            auth_token: None,
+            protocol: Protocol::Json,
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
-}
\ No newline at end of file
+}
+
+impl KvsClientBuilder {
+    /// creates a builder with the default options
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// sets the address of the server, as an `IP:PORT` or `HOSTNAME:PORT`. Defaults to
+    /// [`DEFAULT_SERVER_ADDR`].
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /// sets how long to wait for a connection to each of the addresses the server's address
+    /// resolves to. Defaults to `None`, which waits for as long as the operating system does.
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// sets how long to wait for each read of a response from the server. A read that times out
+    /// fails with a [`KvsError::Io`], and leaves the connection unusable, as the rest of the
+    /// response may still arrive. Defaults to `None`, which waits forever.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// sets how long to wait for each write of a request to the server, e.g. while the server
+    /// isn't reading. A write that times out fails with a [`KvsError::Io`], and leaves the
+    /// connection unusable, as part of the request may have been sent. Defaults to `None`,
+    /// which waits forever.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// sets the number of times a connection is retried, after a failure to connect, e.g. while
+    /// the server is starting. The delay before each retry starts at 100ms, and doubles with
+    /// every retry. Only connecting is retried, requests are never resent. Defaults to 0.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// sets the shared secret token that the client authenticates with once it's connected,
+    /// see [`KvsClient::auth`]. Defaults to `None`, the client doesn't authenticate.
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// sets the [`Protocol`] the client's requests and responses are encoded with, see
+    /// [`KvsClient::connect_with_protocol`]. Defaults to [`Protocol::Json`].
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// connects over TLS to a server whose certificate is valid for `server_name`, and is signed
+    /// by one of the `root_certs`, see [`KvsClient::connect_tls`]. Defaults to a plain
+    /// connection. Requires the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, server_name: impl Into<String>, root_certs: rustls::RootCertStore) -> Self {
+        self.tls = Some((server_name.into(), root_certs));
+        self
+    }
+
+    /// creates a [`KvsClientPool`] of at most `size` connections to the server, each opened
+    /// with the builder's options, see [`KvsClientPool::with_builder`]
+    pub fn pool(self, size: usize) -> KvsClientPool {
+        KvsClientPool::with_builder(self, size)
+    }
+
+    /// connects a [`KvsClient`] to the server, with the builder's options
+    /// # Errors
+    /// `Err<KvsError::Io>` if the client could not connect, after every retry
+    /// `Err<KvsError::StringErr>` if the server rejected the auth token, or the client and server
+    /// have no protocol version in common
+    /// `Err<KvsError::Tls>` if the TLS server name isn't a valid DNS name or IP address
+    pub fn connect(&self) -> Result<KvsClient> {
+        let mut backoff = CONNECT_RETRY_BACKOFF;
+        for retry in 1..=self.retries {
+            match self.try_connect() {
+                Err(e @ KvsError::Io { .. }) => {
+                    debug!("could not connect to {} (retry {} in {:?}): {}", self.addr, retry, backoff, e);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        self.try_connect()
+    }
+
+    /// connects to the server once, and authenticates if the builder has an auth token
+    fn try_connect(&self) -> Result<KvsClient> {
+        let tcp = connect_tcp(self.addr.as_str(), self.connect_timeout)?;
+        tcp.set_read_timeout(self.read_timeout)?;
+        tcp.set_write_timeout(self.write_timeout)?;
+        #[cfg(feature = "tls")]
+        let stream = match &self.tls {
+            Some((server_name, root_certs)) => tls_stream(tcp, server_name, root_certs.clone())?,
+            None => Stream::Tcp(tcp),
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream = Stream::Tcp(tcp);
+        let mut client = KvsClient::with_stream(stream, self.protocol)?;
+        if let Some(token) = &self.auth_token {
+            client.auth(token.clone())?;
+        }
+        Ok(client)
+    }
+}
+
+/// opens a TCP connection to the first of the server's addresses that accepts one, waiting at
+/// most `timeout` for each address, or for as long as the operating system does if it's `None`
+fn connect_tcp<A: ToSocketAddrs>(addr: A, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let Some(timeout) = timeout else {
+        return TcpStream::connect(addr);
+    };
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+    }))
+}
+
+/// builds the error returned when the server sends a response that doesn't match the request
+fn unexpected(resp: Response) -> KvsError {
+    KvsError::StringErr(format!("unexpected response from server: {:?}", resp))
+}
@@ -1,4 +1,102 @@
+use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// The latest version of the client/server protocol.
+///
+/// Version 2 added the `GetStream` request, whose response is followed by the raw bytes of the
+/// value.
+/// Version 3 added the `KeyNotFound` response, sent instead of a `Response::Err` when a request
+/// fails because a key does not exist.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// The oldest version of the client/server protocol that is still supported
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The first byte sent by a client of the [`Protocol::Bincode`] protocol, followed by a newline.
+/// The server replies with the same byte if it accepts the protocol. It can't start a JSON
+/// request, or a line of the text protocol, as it isn't an ASCII character, and the newline
+/// makes a server that only knows the text protocol reject it as a line that isn't UTF-8,
+/// rather than wait for the rest of the line.
+pub(crate) const BINCODE_HELLO: u8 = 0xBC;
+
+/// The byte a server replies to a [`BINCODE_HELLO`] with, if it doesn't accept the
+/// [`Protocol::Bincode`] protocol, before closing the connection
+pub(crate) const BINCODE_REJECTED: u8 = 0x15;
+
+/// The encoding of the [`Request`]s and [`Response`]s exchanged by a client and a server.
+///
+/// Either way, the raw bytes of a streamed value, or a key listing, follow their first
+/// response as they do with JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// every message is a JSON object, which is readable, e.g. with `nc`, but verbose
+    #[default]
+    Json,
+    /// every message is the length of its payload, as a 4 byte big endian integer, followed by
+    /// the [bincode](https://docs.rs/bincode/1) encoding of the message. This is more compact,
+    /// and faster to encode, than JSON. A client announces it by sending a single handshake
+    /// byte before its first request, which the server acknowledges, so a client and a server
+    /// that don't agree on the protocol fail on connect rather than misreading each other
+    Bincode,
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    /// parses the name of a protocol, "json" or "bincode"
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "json" => Ok(Protocol::Json),
+            "bincode" => Ok(Protocol::Bincode),
+            _ => Err(KvsError::Parsing(format!("unknown protocol {}, expected json or bincode", name))),
+        }
+    }
+}
+
+impl Protocol {
+    /// writes a single `msg` to the `writer`, without flushing it
+    pub(crate) fn write<W: Write, T: Serialize>(self, writer: &mut W, msg: &T) -> Result<()> {
+        match self {
+            Protocol::Json => serde_json::to_writer(writer, msg)?,
+            Protocol::Bincode => {
+                let payload = bincode::serialize(msg)?;
+                let len = u32::try_from(payload.len())
+                    .map_err(|_| KvsError::StringErr(format!("a message of {} bytes is too large", payload.len())))?;
+                writer.write_all(&len.to_be_bytes())?;
+                writer.write_all(&payload)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// reads the length of the next [`Protocol::Bincode`] message from the `reader`, or returns
+    /// `None` if the `reader` is at its end
+    pub(crate) fn read_len<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
+        let mut len = [0; 4];
+        match reader.read(&mut len[..1])? {
+            0 => Ok(None),
+            _ => {
+                reader.read_exact(&mut len[1..])?;
+                Ok(Some(u32::from_be_bytes(len)))
+            }
+        }
+    }
+
+    /// reads the `len` bytes of the payload of a [`Protocol::Bincode`] message from the
+    /// `reader`, and decodes it. Nothing after the message is read
+    pub(crate) fn read_payload<R: Read, T: DeserializeOwned>(reader: &mut R, len: u32) -> Result<T> {
+        let mut payload = Vec::new();
+        reader.take(u64::from(len)).read_to_end(&mut payload)?;
+        if payload.len() != len as usize {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the connection closed part way through a message").into());
+        }
+        Ok(bincode::deserialize(&payload)?)
+    }
+}
 
 /// These are the request "commands" that can be made to a key/value store
 #[derive(Debug, Serialize, Deserialize)]
@@ -8,6 +106,26 @@ pub enum Request {
         /// the key to search for
         key: String
     },
+    /// get at most `len` bytes of a value, starting at the byte offset `start`, in a
+    /// `Response::Ok`, see [`KvsEngine::get_range`]
+    ///
+    /// [`KvsEngine::get_range`]: ./trait.KvsEngine.html#method.get_range
+    GetRange {
+        /// the key to search for
+        key: String,
+        /// the byte offset of the start of the range within the value
+        start: u64,
+        /// the maximum number of bytes to get
+        len: u64,
+    },
+    /// check whether a key exists, without getting its value, in a `Response::Contains`, see
+    /// [`KvsEngine::contains_key`]
+    ///
+    /// [`KvsEngine::contains_key`]: ./trait.KvsEngine.html#method.contains_key
+    Contains {
+        /// the key to search for
+        key: String,
+    },
     /// set a key/value in the store
     Set {
         /// the key to set
@@ -20,6 +138,330 @@ pub enum Request {
         /// the key to remove
         key: String
     },
+    /// get a value from the store along with the time it was last written
+    GetWithMeta {
+        /// the key to search for
+        key: String
+    },
+    /// negotiate the protocol version to use on the connection. This should be the first
+    /// request sent on a connection. If it isn't sent, the [`MIN_PROTOCOL_VERSION`] is used
+    Hello {
+        /// the latest protocol version supported by the client
+        version: u32
+    },
+    /// authenticate the connection with the server's shared secret token
+    Auth {
+        /// the shared secret token
+        token: String
+    },
+    /// get statistics about the server and its storage engine
+    Stats,
+    /// get the number of keys in the store, as a decimal string in a `Response::Ok`, see
+    /// [`KvsEngine::key_count`]
+    ///
+    /// [`KvsEngine::key_count`]: ./trait.KvsEngine.html#method.key_count
+    Len,
+    /// check that the server is responding, i.e. a liveness check
+    Ping,
+    /// get a value from the store, streaming it rather than encoding it in the JSON response.
+    /// Requires protocol version 2
+    GetStream {
+        /// the key to search for
+        key: String
+    },
+    /// check that the server's storage engine is able to serve writes, i.e. a readiness check.
+    /// A `Response::Err` containing the reason is returned if it can't
+    Health,
+    /// move the value of a key to another key, see [`KvsEngine::rename`]
+    ///
+    /// [`KvsEngine::rename`]: ./trait.KvsEngine.html#method.rename
+    Rename {
+        /// the key to move the value from
+        from: String,
+        /// the key to move the value to
+        to: String,
+    },
+    /// get the size and live keys of each of the storage engine's log files, see
+    /// [`KvsEngine::log_info`]
+    ///
+    /// [`KvsEngine::log_info`]: ./trait.KvsEngine.html#method.log_info
+    LogInfo,
+    /// compact the storage engine's files now, see [`KvsEngine::compact`]
+    ///
+    /// [`KvsEngine::compact`]: ./trait.KvsEngine.html#method.compact
+    Compact,
+    /// get a page of keys, in ascending order, see [`KvsEngine::scan`]
+    ///
+    /// [`KvsEngine::scan`]: ./trait.KvsEngine.html#method.scan
+    Scan {
+        /// the last key of the previous page, or `None` for the first page
+        cursor: Option<String>,
+        /// the maximum number of keys in the page
+        limit: usize,
+    },
+    /// set a key/value in the store, along with a tag describing the value
+    SetTagged {
+        /// the key to set
+        key: String,
+        /// the value to set
+        value: String,
+        /// the tag of the value
+        tag: u8,
+    },
+    /// get a value from the store along with its tag
+    GetTagged {
+        /// the key to search for
+        key: String
+    },
+    /// get the values of several keys from the store. The number of keys is limited by the
+    /// server, see [`KvsServer::max_get_batch`]
+    ///
+    /// [`KvsServer::max_get_batch`]: ./struct.KvsServer.html#method.max_get_batch
+    GetBatch {
+        /// the keys to search for
+        keys: Vec<String>
+    },
+    /// set several key/values in the store, all together in a transaction. The number of pairs
+    /// is limited by the server, see [`KvsServer::max_multi_set`]
+    ///
+    /// [`KvsServer::max_multi_set`]: ./struct.KvsServer.html#method.max_multi_set
+    MultiSet {
+        /// the key/value pairs to set
+        pairs: Vec<(String, String)>
+    },
+    /// append a suffix to the value of a key, see [`KvsEngine::append`]. The new value is
+    /// returned in a `Response::Ok`
+    ///
+    /// [`KvsEngine::append`]: ./trait.KvsEngine.html#method.append
+    Append {
+        /// the key to append to
+        key: String,
+        /// the suffix to append to the value
+        suffix: String,
+        /// the separator inserted between the current value and the suffix, if the key exists
+        separator: Option<String>,
+    },
+    /// set several key/values in the store, with [`KvsEngine::set_each`], reporting whether each
+    /// of them was set in a `Response::Results`. Unlike a `MultiSet` this is **not** atomic: a pair that
+    /// fails doesn't stop the pairs after it from being set, and the pairs set before it are
+    /// kept. Other clients may see some of the pairs set before the others. The number of pairs
+    /// is limited by the server, see [`KvsServer::max_multi_set`]
+    ///
+    /// [`KvsServer::max_multi_set`]: ./struct.KvsServer.html#method.max_multi_set
+    /// [`KvsEngine::set_each`]: ./trait.KvsEngine.html#method.set_each
+    SetMany {
+        /// the key/value pairs to set
+        pairs: Vec<(String, String)>
+    },
+    /// set a key to a new value, and return the value it replaced in a `Response::Ok`, see
+    /// [`KvsEngine::get_set`]
+    ///
+    /// [`KvsEngine::get_set`]: ./trait.KvsEngine.html#method.get_set
+    GetSet {
+        /// the key to set
+        key: String,
+        /// the new value of the key
+        value: String,
+    },
+    /// get the statistics of the server, and its engine, in a `Response::Stats`, and then reset
+    /// their counters to zero: the request latencies and the engine's compactions, see
+    /// [`KvsEngine::reset_stats`]. Requests handled while the stats are reset may be counted
+    /// in either the returned stats or the next ones, but never in both
+    ///
+    /// [`KvsEngine::reset_stats`]: ./trait.KvsEngine.html#method.reset_stats
+    ResetStats,
+    /// set a key to a new value, only if its current value is the expected value, see
+    /// [`KvsEngine::compare_and_swap`]. Whether the value was set is returned in a
+    /// `Response::Swapped`
+    ///
+    /// [`KvsEngine::compare_and_swap`]: ./trait.KvsEngine.html#method.compare_and_swap
+    Cas {
+        /// the key to set
+        key: String,
+        /// the expected current value of the key, or `None` if the key must not exist
+        expected: Option<String>,
+        /// the value to set the key to
+        new: String,
+    },
+    /// list every key that matches a glob `pattern`, in ascending order. In the pattern, `*`
+    /// matches any sequence of characters and `?` matches any single character, e.g.
+    /// `user:*:active`.
+    ///
+    /// The keys are streamed in a series of `Response::Keys`, so that neither the server nor the
+    /// response has to hold every key at once. Every response but the last has a `next_cursor`,
+    /// and the client must read all of them before its next request. A `Response::Err` ends the
+    /// series early. This scans the entire keyspace of the storage engine, so it takes O(n) time
+    /// in the number of keys, however few of them match.
+    Keys {
+        /// the glob pattern that the keys must match
+        pattern: String,
+    },
+    /// get every key that starts with `prefix`, in ascending order, in a single `Response::Keys`,
+    /// see [`KvsEngine::scan_prefix`]
+    ///
+    /// [`KvsEngine::scan_prefix`]: ./trait.KvsEngine.html#method.scan_prefix
+    ScanPrefix {
+        /// the prefix that the keys must start with
+        prefix: String,
+    },
+    /// get the `n` most accessed keys, hottest first, with their estimated number of accesses,
+    /// in a `Response::HotKeys`. The accesses are sampled, see [`KvsServer::hot_keys`], which
+    /// must be enabled for the server to answer
+    ///
+    /// [`KvsServer::hot_keys`]: ./struct.KvsServer.html#method.hot_keys
+    HotKeys {
+        /// the maximum number of keys to return
+        n: usize,
+    },
+}
+
+impl Request {
+    /// Parses a single line of the text protocol into a Request.
+    ///
+    /// The text protocol is a human-typeable alternative to JSON, for debugging a server with
+    /// tools like `nc` or `telnet`. Commands are case-insensitive and their arguments are
+    /// separated by whitespace:
+    /// - `GET <key>`
+    /// - `SET <key> <value>`, where the value is the rest of the line and may contain spaces
+    /// - `RM <key>`
+    /// - `AUTH <token>`
+    /// - `PING`
+    /// - `HEALTH`
+    ///
+    /// # Examples
+    /// ```rust
+    /// use kvs::Request;
+    /// let req = Request::parse_text("SET greeting hello world").unwrap();
+    /// assert!(matches!(req, Request::Set { key, value } if key == "greeting" && value == "hello world"));
+    /// ```
+    pub fn parse_text(line: &str) -> Result<Request> {
+        let line = line.trim();
+        let (cmd, args) = split_word(line);
+        match cmd.to_ascii_uppercase().as_str() {
+            "GET" => Ok(Request::Get { key: single_arg("GET <key>", args)? }),
+            "RM" => Ok(Request::Remove { key: single_arg("RM <key>", args)? }),
+            "AUTH" => Ok(Request::Auth { token: single_arg("AUTH <token>", args)? }),
+            "SET" => match split_word(args) {
+                (key, value) if !key.is_empty() && !value.is_empty() => Ok(Request::Set {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                }),
+                _ => Err(KvsError::Parsing("usage: SET <key> <value>".to_string())),
+            },
+            "PING" => no_args("PING", args).map(|_| Request::Ping),
+            "HEALTH" => no_args("HEALTH", args).map(|_| Request::Health),
+            "" => Err(KvsError::Parsing("empty command".to_string())),
+            _ => Err(KvsError::Parsing(format!("unknown command {}", cmd))),
+        }
+    }
+
+    /// returns `true` if the request modifies the data of the storage engine
+    pub fn is_write(&self) -> bool {
+        match self {
+            Request::Set { .. }
+            | Request::Remove { .. }
+            | Request::SetTagged { .. }
+            | Request::MultiSet { .. }
+            | Request::Append { .. }
+            | Request::Rename { .. }
+            | Request::SetMany { .. }
+            | Request::Cas { .. }
+            | Request::GetSet { .. } => true,
+            Request::Get { .. }
+            | Request::GetRange { .. }
+            | Request::Contains { .. }
+            | Request::GetWithMeta { .. }
+            | Request::Hello { .. }
+            | Request::Auth { .. }
+            | Request::Stats
+            | Request::Len
+            | Request::ResetStats
+            | Request::Ping
+            | Request::GetStream { .. }
+            | Request::Health
+            | Request::LogInfo
+            | Request::Compact
+            | Request::Scan { .. }
+            | Request::Keys { .. }
+            | Request::ScanPrefix { .. }
+            | Request::HotKeys { .. }
+            | Request::GetTagged { .. }
+            | Request::GetBatch { .. } => false,
+        }
+    }
+
+    /// calls `f` with every key that the request reads or writes
+    pub(crate) fn for_each_key(&self, mut f: impl FnMut(&str)) {
+        match self {
+            Request::Get { key }
+            | Request::GetRange { key, .. }
+            | Request::Contains { key }
+            | Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::GetWithMeta { key }
+            | Request::GetStream { key }
+            | Request::SetTagged { key, .. }
+            | Request::GetTagged { key }
+            | Request::Append { key, .. }
+            | Request::GetSet { key, .. }
+            | Request::Cas { key, .. } => f(key),
+            Request::Rename { from, to } => {
+                f(from);
+                f(to);
+            }
+            Request::GetBatch { keys } => keys.iter().for_each(|key| f(key)),
+            Request::MultiSet { pairs } | Request::SetMany { pairs } => pairs.iter().for_each(|(key, _value)| f(key)),
+            Request::Hello { .. }
+            | Request::Auth { .. }
+            | Request::Stats
+            | Request::Len
+            | Request::ResetStats
+            | Request::Ping
+            | Request::Health
+            | Request::LogInfo
+            | Request::Compact
+            | Request::Scan { .. }
+            | Request::Keys { .. }
+            | Request::ScanPrefix { .. }
+            | Request::HotKeys { .. } => {}
+        }
+    }
+}
+
+impl TryFrom<&str> for Request {
+    type Error = KvsError;
+
+    /// parses a line of the text protocol, see [`Request::parse_text`]
+    fn try_from(line: &str) -> Result<Request> {
+        Request::parse_text(line)
+    }
+}
+
+/// splits the first whitespace separated word off of `s`, returning the word and the rest
+/// of `s` with its leading whitespace removed
+fn split_word(s: &str) -> (&str, &str) {
+    match s.find(char::is_whitespace) {
+        Some(end) => (&s[..end], s[end..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+/// checks that a text protocol command `cmd`, that takes no arguments, wasn't given any
+fn no_args(cmd: &str, args: &str) -> Result<()> {
+    if args.is_empty() {
+        Ok(())
+    } else {
+        Err(KvsError::Parsing(format!("usage: {}", cmd)))
+    }
+}
+
+/// returns the single argument of a text protocol command, or an error containing the
+/// command's `usage`
+fn single_arg(usage: &str, args: &str) -> Result<String> {
+    match split_word(args) {
+        (arg, "") if !arg.is_empty() => Ok(arg.to_string()),
+        _ => Err(KvsError::Parsing(format!("usage: {}", usage))),
+    }
 }
 
 /// The response Types that can be returned for any KVS Request
@@ -27,10 +469,155 @@ pub enum Request {
 pub enum Response {
     /// this variant is returned when a request was successful
     Ok(Option<String>),
+    /// this variant is returned in reply to a `Hello` request. It contains the protocol version
+    /// that will be used on the connection, i.e. the minimum of the client's and server's version
+    Hello {
+        /// the negotiated protocol version
+        version: u32
+    },
+    /// this variant is returned when a write (set or remove) request was successful. It contains
+    /// the sequence number that the engine assigned to the write
+    Seq(u64),
+    /// this variant is returned in reply to a `Contains` request. It is `true` if the key exists
+    Contains(bool),
+    /// this variant is returned when a `GetWithMeta` request found a value
+    Meta {
+        /// the value of the key
+        value: String,
+        /// the time the value was last written, as milliseconds since the unix epoch
+        written_at: u64,
+    },
+    /// this variant is returned in reply to a `Stats` request
+    Stats(Stats),
+    /// this variant is returned when a `GetStream` request found a value. The response is
+    /// immediately followed by the `len` bytes of the UTF-8 encoded value
+    Value {
+        /// the length of the value in bytes
+        len: u64,
+    },
+    /// this variant is returned when a `GetTagged` request found a value
+    Tagged {
+        /// the value of the key
+        value: String,
+        /// the tag of the value
+        tag: u8,
+    },
+    /// this variant is returned in reply to a `GetBatch` request. It contains the value of each
+    /// of the requested keys, in the order they were requested, or `None` if a key wasn't found
+    Values(Vec<Option<String>>),
+    /// this variant is returned in reply to a `Scan` or `ScanPrefix` request, and as each of the
+    /// series of responses to a `Keys` request
+    Keys {
+        /// the keys in the page
+        keys: Vec<String>,
+        /// the cursor of the next page, or `None` if this is the last page
+        next_cursor: Option<String>,
+    },
+    /// this variant is returned in reply to a `LogInfo` request
+    LogInfo(Vec<LogInfo>),
+    /// this variant is returned in reply to a `SetMany` request. It contains the result of
+    /// setting each of the pairs, in the order they were sent, with the reason a pair wasn't set
+    Results(Vec<std::result::Result<(), String>>),
+    /// this variant is returned in reply to a `Cas` request. It is `true` if the key was set,
+    /// or `false` if its current value wasn't the expected value
+    Swapped(bool),
+    /// this variant is returned in reply to a `HotKeys` request. It contains the hottest keys,
+    /// hottest first, with their estimated number of accesses
+    HotKeys(Vec<(String, u64)>),
+    /// this variant is returned when a request fails because a key does not exist, e.g. the
+    /// removal of a missing key. Requires protocol version 3, older clients receive a
+    /// `Response::Err` instead
+    KeyNotFound,
     /// this variant is returned if an Error occurs while processing the request
     Err(String),
 }
 
+/// Statistics about a server and its storage engine.
+///
+/// Storage engines that don't track a statistic leave it at 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// the number of keys in the store
+    pub key_count: u64,
+    /// the number of bytes of stale data that will be removed by the next compaction
+    pub uncompacted_bytes: u64,
+    /// the total size, in bytes, of the files used by the storage engine
+    pub disk_usage: u64,
+    /// the number of compactions since the storage engine was opened, or its stats were reset
+    pub compactions: u64,
+    /// the number of seconds since the server was started
+    pub uptime_secs: u64,
+    /// the number of currently open client connections
+    pub connections: u64,
+    /// the number of bytes of disk space a compaction would reclaim
+    #[serde(default)]
+    pub reclaimable_bytes: u64,
+    /// the time taken to handle get requests, since the server was started or its stats were
+    /// reset
+    #[serde(default)]
+    pub get_latency: LatencyStats,
+    /// the time taken to handle set requests
+    #[serde(default)]
+    pub set_latency: LatencyStats,
+    /// the time taken to handle remove requests
+    #[serde(default)]
+    pub remove_latency: LatencyStats,
+    /// the effect of the latest compaction, or `None` if there hasn't been a compaction since
+    /// the storage engine was opened, or its stats were reset
+    #[serde(default)]
+    pub last_compaction: Option<CompactionStats>,
+    /// an estimate of the memory used by the storage engine's index of keys, in bytes
+    #[serde(default)]
+    pub index_memory_bytes: u64,
+}
+
+/// The effect of a single compaction of a storage engine's files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionStats {
+    /// the time the compaction took, in microseconds
+    pub duration_micros: u64,
+    /// the total size, in bytes, of the files before the compaction
+    pub bytes_before: u64,
+    /// the total size, in bytes, of the files after the compaction
+    pub bytes_after: u64,
+    /// the number of files deleted by the compaction
+    pub files_deleted: u64,
+    /// the number of keys rewritten into the compacted file
+    pub keys_rewritten: u64,
+}
+
+/// The on-disk layout of a single log file of a storage engine, returned by
+/// [`KvsEngine::log_info`].
+///
+/// [`KvsEngine::log_info`]: ./trait.KvsEngine.html#method.log_info
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogInfo {
+    /// the generation number of the log file
+    pub gen: u64,
+    /// the size of the log file, in bytes
+    pub size: u64,
+    /// the number of keys whose latest command is in the log file
+    pub live_keys: u64,
+    /// an estimate of the number of bytes of the log file that a compaction would reclaim, i.e.
+    /// the bytes of the commands that have been overwritten or removed
+    pub dead_bytes: u64,
+}
+
+/// The distribution of the time a server took to handle one type of request.
+///
+/// Latencies are recorded in buckets, so the percentiles are accurate to within 12.5%.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyStats {
+    /// the number of requests handled
+    pub count: u64,
+    /// the median latency, in microseconds
+    pub p50_micros: u64,
+    /// the 95th percentile latency, in microseconds
+    pub p95_micros: u64,
+    /// the 99th percentile latency, in microseconds
+    pub p99_micros: u64,
+}
+
 // /// The Response type for a GET request
 // #[derive(Debug, Serialize, Deserialize)]
 // pub enum GetResponse {
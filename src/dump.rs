@@ -0,0 +1,227 @@
+//! A compact binary format for transferring every key/value pair of a store, e.g. to migrate
+//! a store to another host. A dump is written by [`KvStore::dump`](crate::KvStore::dump) or
+//! `kvs-client dump`, and loaded by [`KvStore::load_dump`](crate::KvStore::load_dump) or
+//! `kvs-client restore`.
+//!
+//! A dump is made of a header, one record per key and a trailer. Every integer is little
+//! endian:
+//!
+//! - header: the magic bytes `KVSDUMP\n`, and the [`DUMP_VERSION`] as a `u32`
+//! - entry: the byte `1`, the length of the key as a `u32`, the key, the length of the value
+//!   as a `u64`, and the value
+//! - trailer: the byte `0`, the number of entries as a `u64`, and the CRC32 of every byte
+//!   before it as a `u32`
+//!
+//! The trailer is validated when a dump is read, so a dump that was truncated or corrupted in
+//! transit is rejected with a [`KvsError::InvalidDump`].
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use crate::{KvsError, Result};
+
+/// the version of the dump format written by [`DumpWriter`], and the only one read by
+/// [`DumpReader`]
+pub const DUMP_VERSION: u32 = 1;
+
+/// the bytes that every dump starts with
+const MAGIC: &[u8; 8] = b"KVSDUMP\n";
+
+/// precedes every entry of a dump
+const ENTRY: u8 = 1;
+
+/// precedes the trailer of a dump
+const TRAILER: u8 = 0;
+
+/// Writes key/value pairs in the dump format.
+///
+/// The trailer is only written by [`finish`](DumpWriter::finish), a dump that was dropped
+/// before it was finished is rejected when it is read.
+#[derive(Debug)]
+pub struct DumpWriter<W: Write> {
+    inner: BufWriter<W>,
+    hasher: crc32fast::Hasher,
+    entries: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// creates a writer of a dump into `inner`, and writes the header of the dump
+    /// # Errors
+    /// [`KvsError::Io`] if the header could not be written
+    pub fn new(inner: W) -> Result<Self> {
+        let mut writer = DumpWriter {
+            inner: BufWriter::new(inner),
+            hasher: crc32fast::Hasher::new(),
+            entries: 0,
+        };
+        writer.write(MAGIC)?;
+        writer.write(&DUMP_VERSION.to_le_bytes())?;
+        Ok(writer)
+    }
+
+    /// writes the entry of a `key` and its `value`
+    /// # Errors
+    /// [`KvsError::Io`] if the entry could not be written, or [`KvsError::InvalidDump`] if the
+    /// key is longer than 4GiB
+    pub fn write_entry(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let key_len = u32::try_from(key.len())
+            .map_err(|_| KvsError::InvalidDump(format!("a key of {} bytes is too long for a dump", key.len())))?;
+        self.write(&[ENTRY])?;
+        self.write(&key_len.to_le_bytes())?;
+        self.write(key)?;
+        self.write(&(value.len() as u64).to_le_bytes())?;
+        self.write(value)?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// returns the number of entries written so far
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// writes the trailer of the dump and flushes it, returning the underlying writer, e.g. so
+    /// that a file can be synced
+    /// # Errors
+    /// [`KvsError::Io`] if the trailer could not be written
+    pub fn finish(mut self) -> Result<W> {
+        self.write(&[TRAILER])?;
+        self.write(&self.entries.to_le_bytes())?;
+        let crc = self.hasher.clone().finalize();
+        self.inner.write_all(&crc.to_le_bytes())?;
+        self.inner.into_inner().map_err(|e| KvsError::from(e.into_error()))
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.hasher.update(bytes);
+        self.inner.write_all(bytes)
+    }
+}
+
+/// Reads the key/value pairs of a dump, as an iterator of `(key, value)`.
+///
+/// The checksum is only validated once the trailer is reached, so the entries of a corrupt dump
+/// are returned before the error is. Use [`verify`] to validate a dump before using any of its
+/// entries.
+#[derive(Debug)]
+pub struct DumpReader<R: Read> {
+    inner: BufReader<R>,
+    hasher: crc32fast::Hasher,
+    entries: u64,
+    // set once the trailer was read, or an error was returned
+    done: bool,
+}
+
+impl<R: Read> DumpReader<R> {
+    /// creates a reader of the dump in `inner`, and reads the header of the dump
+    /// # Errors
+    /// [`KvsError::InvalidDump`] if `inner` isn't a dump, or is a dump of an unsupported version
+    pub fn new(inner: R) -> Result<Self> {
+        let mut reader = DumpReader {
+            inner: BufReader::new(inner),
+            hasher: crc32fast::Hasher::new(),
+            entries: 0,
+            done: false,
+        };
+        let mut magic = [0; 8];
+        reader.read(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(KvsError::InvalidDump("not a kvs dump".to_string()));
+        }
+        let version = u32::from_le_bytes(reader.read_array()?);
+        if version != DUMP_VERSION {
+            return Err(KvsError::InvalidDump(format!(
+                "unsupported dump version {}, expected version {}",
+                version, DUMP_VERSION
+            )));
+        }
+        Ok(reader)
+    }
+
+    /// reads the next entry, or returns `None` once the trailer has been read and validated
+    fn read_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let [tag] = self.read_array()?;
+        match tag {
+            ENTRY => {
+                let key_len = u32::from_le_bytes(self.read_array()?);
+                let key = self.read_vec(key_len as u64)?;
+                let value_len = u64::from_le_bytes(self.read_array()?);
+                let value = self.read_vec(value_len)?;
+                self.entries += 1;
+                Ok(Some((key, value)))
+            }
+            TRAILER => {
+                let entries = u64::from_le_bytes(self.read_array()?);
+                let expected_crc = self.hasher.clone().finalize();
+                let mut crc = [0; 4];
+                self.inner.read_exact(&mut crc).map_err(truncated)?;
+                if u32::from_le_bytes(crc) != expected_crc {
+                    return Err(KvsError::InvalidDump("the checksum of the dump doesn't match, it is corrupt".to_string()));
+                }
+                if entries != self.entries {
+                    return Err(KvsError::InvalidDump(format!(
+                        "the dump should have {} entries, but has {}",
+                        entries, self.entries
+                    )));
+                }
+                Ok(None)
+            }
+            tag => Err(KvsError::InvalidDump(format!("invalid record type {} in the dump", tag))),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.inner.read_exact(buf).map_err(truncated)?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.read(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// reads `len` bytes. The buffer grows as the bytes are read, so a corrupt length can't
+    /// allocate more memory than the rest of the dump
+    fn read_vec(&mut self, len: u64) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(truncated(io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.hasher.update(&buf);
+        Ok(buf)
+    }
+}
+
+impl<R: Read> Iterator for DumpReader<R> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    /// # Errors
+    /// [`KvsError::InvalidDump`] if the dump is truncated or corrupt
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.read_entry();
+        self.done = !matches!(entry, Ok(Some(_)));
+        entry.transpose()
+    }
+}
+
+/// reads the whole dump in `inner`, validating its checksum, and returns its number of entries
+/// # Errors
+/// [`KvsError::InvalidDump`] if the dump is truncated or corrupt
+pub fn verify<R: Read>(inner: R) -> Result<u64> {
+    let mut reader = DumpReader::new(inner)?;
+    for entry in &mut reader {
+        entry?;
+    }
+    Ok(reader.entries)
+}
+
+/// maps the error of a read that reached the end of a dump to a [`KvsError::InvalidDump`]
+fn truncated(e: io::Error) -> KvsError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => KvsError::InvalidDump("the dump is truncated".to_string()),
+        _ => KvsError::from(e),
+    }
+}
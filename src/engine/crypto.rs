@@ -0,0 +1,177 @@
+use super::kvs::CRYPTO_FILE;
+use super::vfs::FileSystem;
+use crate::error::{KvsError, Result};
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// the lengths of the salt and of the nonce that prefixes every encrypted value
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+// the number of PBKDF2 rounds used to derive the keys from a passphrase
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+// the value encrypted into the crypto file, that is decrypted when the store is opened to check
+// the passphrase
+const CHECK_VALUE: &[u8] = b"kvs";
+
+// the flag byte of the crypto file of a store that hashes its keys
+const HASH_KEYS_FLAG: u8 = 0x01;
+
+/// A passphrase that encryption keys are derived from. It's never printed, so that it can't
+/// leak into logs.
+#[derive(Clone)]
+pub(crate) struct Passphrase(pub(crate) String);
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+/// Encrypts the values of a [`KvStore`](super::KvStore) with AES-256-GCM, and hashes its keys
+/// with HMAC-SHA256 if the store hashes its keys.
+///
+/// Both keys are derived from a passphrase with PBKDF2-HMAC-SHA256, using a random salt that
+/// is stored in the store's working directory along with a value encrypted with the key, so a
+/// wrong passphrase is detected when the store is opened. Every value is encrypted with its
+/// own random nonce, which is written in front of the encrypted value.
+pub(crate) struct Cipher {
+    aead: Aes256Gcm,
+    // the key that keys are hashed with, if the store hashes its keys
+    key_mac: Option<Hmac<Sha256>>,
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher").field("hash_keys", &self.key_mac.is_some()).finish_non_exhaustive()
+    }
+}
+
+impl Cipher {
+    /// derives the keys of the store in `dir` from the `passphrase`. The crypto file is created
+    /// if the store doesn't have one yet, but only if it's `empty`, as its existing values
+    /// aren't encrypted.
+    ///
+    /// # Errors
+    /// [`KvsError::Encryption`] if the passphrase or `hash_keys` don't match the ones the store
+    /// was created with, or if the store holds unencrypted data
+    pub(crate) fn open(fs: &dyn FileSystem, dir: &Path, passphrase: &Passphrase, hash_keys: bool, empty: bool) -> Result<Cipher> {
+        let path = dir.join(CRYPTO_FILE);
+        let mut contents = vec![];
+        match fs.open(&path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut contents)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && empty => {
+                return Cipher::create(fs, dir, passphrase, hash_keys);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(KvsError::Encryption(
+                    "the store holds unencrypted data, so its values can't be encrypted".to_string(),
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        if contents.len() < 1 + SALT_LEN + NONCE_LEN {
+            return Err(KvsError::Encryption(format!("{} is corrupt", CRYPTO_FILE)));
+        }
+        let (flags, rest) = contents.split_at(1);
+        let (salt, check) = rest.split_at(SALT_LEN);
+        let created_with_hash_keys = flags[0] & HASH_KEYS_FLAG != 0;
+        if created_with_hash_keys != hash_keys {
+            return Err(KvsError::Encryption(format!(
+                "the store was created with hash_keys set to {}",
+                created_with_hash_keys
+            )));
+        }
+        let cipher = Cipher::derive(passphrase, salt, hash_keys);
+        match cipher.decrypt(check) {
+            Ok(value) if value == CHECK_VALUE => Ok(cipher),
+            _ => Err(KvsError::Encryption("the passphrase doesn't match the store's passphrase".to_string())),
+        }
+    }
+
+    /// creates the crypto file of the store in `dir`, with a new random salt, and derives the
+    /// keys of the store with it
+    fn create(fs: &dyn FileSystem, dir: &Path, passphrase: &Passphrase, hash_keys: bool) -> Result<Cipher> {
+        let mut salt = [0_u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = Cipher::derive(passphrase, &salt, hash_keys);
+
+        let mut contents = vec![if hash_keys { HASH_KEYS_FLAG } else { 0 }];
+        contents.extend_from_slice(&salt);
+        contents.extend_from_slice(&cipher.encrypt(CHECK_VALUE)?);
+        // the file is written atomically, so a crash can't leave the store without its salt
+        let tmp_path = dir.join(format!("{}.tmp", CRYPTO_FILE));
+        let mut file = fs.create(&tmp_path)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs.rename(&tmp_path, &dir.join(CRYPTO_FILE))?;
+        Ok(cipher)
+    }
+
+    /// derives the encryption key, and the key hashing key, from the `passphrase` and `salt`
+    fn derive(passphrase: &Passphrase, salt: &[u8], hash_keys: bool) -> Cipher {
+        let mut keys = [0_u8; 64];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.0.as_bytes(), salt, PBKDF2_ROUNDS, &mut keys);
+        let (aead_key, mac_key) = keys.split_at(32);
+        Cipher {
+            aead: Aes256Gcm::new_from_slice(aead_key).expect("the key is 32 bytes long"),
+            key_mac: hash_keys.then(|| <Hmac<Sha256> as Mac>::new_from_slice(mac_key).expect("HMAC accepts keys of any length")),
+        }
+    }
+
+    /// encrypts a `value`, returning a random nonce followed by the encrypted value and its
+    /// authentication tag
+    pub(crate) fn encrypt(&self, value: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let encrypted = self
+            .aead
+            .encrypt(&nonce, value)
+            .map_err(|_| KvsError::Encryption("a value could not be encrypted".to_string()))?;
+        let mut record = nonce.to_vec();
+        record.extend_from_slice(&encrypted);
+        Ok(record)
+    }
+
+    /// decrypts a `record` written by [`encrypt`](Cipher::encrypt)
+    ///
+    /// # Errors
+    /// [`KvsError::Encryption`] if the record was modified, or wasn't encrypted with this key
+    pub(crate) fn decrypt(&self, record: &[u8]) -> Result<Vec<u8>> {
+        if record.len() < NONCE_LEN {
+            return Err(KvsError::Encryption("an encrypted value is truncated".to_string()));
+        }
+        let (nonce, encrypted) = record.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .map_err(|_| KvsError::Encryption("a value could not be decrypted".to_string()))
+    }
+
+    /// returns `true` if the store hashes its keys
+    pub(crate) fn hashes_keys(&self) -> bool {
+        self.key_mac.is_some()
+    }
+
+    /// returns the hex encoded HMAC of `key`, if the store hashes its keys, or the `key` itself
+    pub(crate) fn hash_key(&self, key: Vec<u8>) -> Vec<u8> {
+        match &self.key_mac {
+            Some(key_mac) => {
+                let mut mac = key_mac.clone();
+                mac.update(&key);
+                let hash = mac.finalize().into_bytes();
+                hash.iter().map(|b| format!("{:02x}", b)).collect::<String>().into_bytes()
+            }
+            None => key,
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! The in-memory index of a [`KvStore`](super::KvStore), which maps every key to the position
+//! of its latest command in the logs.
+//!
+//! The index is accessed through the [`KeyIndex`] trait, so the engine doesn't depend on the
+//! map behind it. [`DashIndex`] is backed by a [`DashMap`], and is used by default.
+//! [`ShardedIndex`] is a fixed number of `RwLock<HashMap>` shards, and is used instead when the
+//! `sharded-index` feature is enabled.
+use dashmap::DashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A concurrent map from keys to values of type `V`, usually the position of a command.
+///
+/// Every method takes `&self`, so the index can be shared between the clones of a store.
+/// Values are returned by copy, so no lock on the index is held once a method returns.
+pub(crate) trait KeyIndex<V: Copy>: Debug + Default + Send + Sync {
+    /// returns the value of `key`
+    fn get(&self, key: &[u8]) -> Option<V>;
+
+    /// returns `true` if the index contains `key`
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// sets the value of `key`, returning its previous value
+    fn insert(&self, key: Vec<u8>, value: V) -> Option<V>;
+
+    /// removes `key`, returning its value
+    fn remove(&self, key: &[u8]) -> Option<V>;
+
+    /// removes `key` if `f` returns `true` for its value, returning the removed value
+    fn remove_if(&self, key: &[u8], f: impl FnOnce(&V) -> bool) -> Option<V>;
+
+    /// removes every key for which `f` returns `false`
+    fn retain(&self, f: impl FnMut(&[u8], &V) -> bool);
+
+    /// returns a copy of every key and its value, in no particular order. Keys inserted or
+    /// removed while iterating may or may not be returned
+    fn iter(&self) -> impl Iterator<Item = (Vec<u8>, V)> + '_;
+
+    /// returns every value, in no particular order, without copying the keys
+    fn values(&self) -> impl Iterator<Item = V> + '_;
+
+    /// returns the number of keys
+    fn len(&self) -> usize;
+}
+
+/// A [`KeyIndex`] backed by a [`DashMap`].
+#[cfg_attr(feature = "sharded-index", allow(dead_code))]
+#[derive(Debug)]
+pub(crate) struct DashIndex<V> {
+    map: DashMap<Vec<u8>, V>,
+}
+
+impl<V> Default for DashIndex<V> {
+    fn default() -> Self {
+        DashIndex { map: DashMap::new() }
+    }
+}
+
+impl<V: Copy + Debug + Send + Sync> KeyIndex<V> for DashIndex<V> {
+    fn get(&self, key: &[u8]) -> Option<V> {
+        self.map.get(key).map(|entry| *entry.value())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.map.contains_key(key)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: V) -> Option<V> {
+        self.map.insert(key, value)
+    }
+
+    fn remove(&self, key: &[u8]) -> Option<V> {
+        self.map.remove(key).map(|(_key, value)| value)
+    }
+
+    fn remove_if(&self, key: &[u8], f: impl FnOnce(&V) -> bool) -> Option<V> {
+        self.map.remove_if(key, |_key, value| f(value)).map(|(_key, value)| value)
+    }
+
+    fn retain(&self, mut f: impl FnMut(&[u8], &V) -> bool) {
+        self.map.retain(|key, value| f(key, value))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Vec<u8>, V)> + '_ {
+        self.map.iter().map(|entry| (entry.key().clone(), *entry.value()))
+    }
+
+    fn values(&self) -> impl Iterator<Item = V> + '_ {
+        self.map.iter().map(|entry| *entry.value())
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+}
+
+/// the number of shards of a [`ShardedIndex`]
+const SHARDS: usize = 64;
+
+/// a shard of a [`ShardedIndex`]
+type Shard<V> = RwLock<HashMap<Vec<u8>, V>>;
+
+/// A [`KeyIndex`] made of a fixed number of `RwLock<HashMap>` shards, a key's shard is chosen
+/// by its hash.
+///
+/// Compared to a [`DashIndex`], it has fewer shards, which are never resized as a whole, so it
+/// has less fixed overhead, at the cost of more contention between writers of the same shard.
+#[cfg_attr(not(feature = "sharded-index"), allow(dead_code))]
+#[derive(Debug)]
+pub(crate) struct ShardedIndex<V> {
+    shards: Box<[Shard<V>]>,
+    // picks the shard of a key, independently of the hashers of the shards
+    hasher: RandomState,
+}
+
+impl<V> Default for ShardedIndex<V> {
+    fn default() -> Self {
+        ShardedIndex {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<V> ShardedIndex<V> {
+    fn shard(&self, key: &[u8]) -> &Shard<V> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+
+    // a shard is still valid if a thread panicked while holding its lock, as every change to
+    // a shard is a single call to the HashMap
+    fn read(shard: &Shard<V>) -> RwLockReadGuard<'_, HashMap<Vec<u8>, V>> {
+        shard.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(shard: &Shard<V>) -> RwLockWriteGuard<'_, HashMap<Vec<u8>, V>> {
+        shard.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<V: Copy + Debug + Send + Sync> KeyIndex<V> for ShardedIndex<V> {
+    fn get(&self, key: &[u8]) -> Option<V> {
+        Self::read(self.shard(key)).get(key).copied()
+    }
+
+    fn insert(&self, key: Vec<u8>, value: V) -> Option<V> {
+        Self::write(self.shard(&key)).insert(key, value)
+    }
+
+    fn remove(&self, key: &[u8]) -> Option<V> {
+        Self::write(self.shard(key)).remove(key)
+    }
+
+    fn remove_if(&self, key: &[u8], f: impl FnOnce(&V) -> bool) -> Option<V> {
+        let mut shard = Self::write(self.shard(key));
+        match shard.get(key) {
+            Some(value) if f(value) => shard.remove(key),
+            _ => None,
+        }
+    }
+
+    fn retain(&self, mut f: impl FnMut(&[u8], &V) -> bool) {
+        for shard in self.shards.iter() {
+            Self::write(shard).retain(|key, value| f(key, value));
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Vec<u8>, V)> + '_ {
+        // each shard is copied while it is locked, so no lock is held between items
+        self.shards.iter().flat_map(|shard| {
+            Self::read(shard)
+                .iter()
+                .map(|(key, value)| (key.clone(), *value))
+                .collect::<Vec<_>>()
+        })
+    }
+
+    fn values(&self) -> impl Iterator<Item = V> + '_ {
+        self.shards
+            .iter()
+            .flat_map(|shard| Self::read(shard).values().copied().collect::<Vec<_>>())
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| Self::read(shard).len()).sum()
+    }
+}
@@ -1,28 +1,436 @@
-use super::KvsEngine;
+use super::{page_keys, read_range, KvsEngine, Transaction, TxOp, ValueReader};
+use super::vfs::{FileLock, FileSystem, ReadFile, StdFs, WriteFile};
+use super::index::KeyIndex;
+#[cfg(feature = "crypto")]
+use super::crypto::{Cipher, Passphrase};
 use crate::error::{KvsError, Result};
+use crate::command::{CompactionStats, LogInfo, Stats};
+use crate::dump::{self, DumpReader, DumpWriter};
 
-use std::cell::RefCell;
-use std::collections::btree_map::Entry;
-use std::collections::BTreeMap;
-use std::fs;
-use std::fs::{File, OpenOptions};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::io::{BufRead, BufReader, BufWriter, Error, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Range};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, RwLock};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::{self, JoinHandle};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use serde_json::Deserializer;
 use clap::crate_version;
-use dashmap::DashMap;
-use tracing::{debug, info, error, instrument};
-use tracing::field::debug;
+use rayon::prelude::*;
+use tracing::{debug, info, error, warn, instrument};
 
 // the size of stale data, in bytes, that will trigger a log compaction
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
+// name of the file that records the write sequence high-water mark at the time of the
+// latest compaction
+const SEQ_FILE: &str = "kvs.seq";
+
+// name of the file that records the generations of the live command logs
+const MANIFEST_FILE: &str = "MANIFEST";
+
+// name of the file holding a snapshot of the index, written when a store that snapshots its
+// index is closed, so that it can be reopened without reading every log
+const INDEX_SNAPSHOT_FILE: &str = "index.snapshot";
+
+// name of the marker file that kvs-server reads to learn which engine a data directory is
+// used by
+const ENGINE_FILE: &str = "engine";
+
+// name of the file holding the salt that the keys of an encrypted store are derived from. It's
+// checked for even without the `crypto` feature, so an encrypted store is never read as plain text
+pub(super) const CRYPTO_FILE: &str = "kvs.crypto";
+
+// a buffered reader of a command log
+type LogReader = BufReaderWithPos<Box<dyn ReadFile>>;
+
+// a reader of a single command of a log, that can't read past the end of the command
+type CommandReader = io::Take<BufReader<Box<dyn ReadFile>>>;
+
+// the memory maps of the command logs, by generation
+#[cfg(feature = "mmap")]
+type LogMaps = RefCell<BTreeMap<u64, memmap2::Mmap>>;
+
+// a buffered writer of a command log
+type LogWriter = BufWriterWithPos<Box<dyn WriteFile>>;
+
+// prefix of the name of the file written, and then deleted, to check that the working directory
+// is writable. Every probe has its own file, so that concurrent probes don't delete each other's
+const HEALTH_PROBE_FILE: &str = "kvs.health";
+
+// the number of health probes made by this process, which makes each probe's file name unique
+static HEALTH_PROBES: AtomicU64 = AtomicU64::new(0);
+
+// name of the file that is locked while a store has the working directory open
+const LOCK_FILE: &str = "kvs.lock";
+
+// the number of times the deletion of a stale log is attempted, during a single compaction
+const STALE_LOG_DELETE_ATTEMPTS: u32 = 4;
+
+// the delay before the first retry of a stale log deletion, it doubles after every retry
+const STALE_LOG_DELETE_BACKOFF: Duration = Duration::from_millis(10);
+
+// the number of times the creation of a new log is attempted, if it fails with an error that may
+// be transient
+const NEW_LOG_ATTEMPTS: u32 = 4;
+
+// the delay before the first retry of a new log creation, it doubles after every retry
+const NEW_LOG_BACKOFF: Duration = Duration::from_millis(10);
+
+// values shorter than this are always written, as a link to a shared value would be about as
+// long as the value itself
+const DEDUP_MIN_VALUE_LEN: usize = 64;
+
+/// Determines when a [`KvStore`] will compact its command logs.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CompactionTrigger {
+    /// compact once the size of stale data, in bytes, exceeds the given number of bytes
+    Bytes(u64),
+    /// compact once the ratio of stale bytes to live bytes (i.e. `uncompacted / live`) exceeds
+    /// the given ratio. This scales with the size of the store, so that a small store is
+    /// compacted proportionally as often as a large one.
+    Ratio(f64),
+}
+
+impl Default for CompactionTrigger {
+    /// the default trigger compacts once `COMPACTION_THRESHOLD` bytes of stale data exist
+    fn default() -> Self {
+        CompactionTrigger::Bytes(COMPACTION_THRESHOLD)
+    }
+}
+
+/// Determines which key is evicted when a [`KvStore`] with a maximum number of keys is full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// evict the least recently used key, i.e. the key that was least recently set or read
+    Lru,
+    /// evict the oldest key, i.e. the key that was set first. Reads and overwrites of a key do
+    /// not change its position
+    Fifo,
+}
+
+/// A builder used to configure and open a [`KvStore`].
+///
+/// # Examples
+/// ```rust
+/// use kvs::{KvStore, CompactionTrigger};
+/// use std::path::Path;
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// // compact whenever stale data is more than half the size of the live data
+/// let kvs = KvStore::builder()
+///     .compaction_trigger(CompactionTrigger::Ratio(0.5))
+///     .open(Path::new("."))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreBuilder {
+    compaction_trigger: CompactionTrigger,
+    max_log_files: Option<usize>,
+    max_keys: Option<(usize, EvictionPolicy)>,
+    file_system: Option<Arc<dyn FileSystem>>,
+    compact_on_open: bool,
+    exclusive: bool,
+    ordered_index: bool,
+    dedup_values: bool,
+    max_open_logs: Option<usize>,
+    verify_on_read: bool,
+    value_cache: Option<usize>,
+    remove_dangling_keys: bool,
+    wal_dir: Option<PathBuf>,
+    index_snapshot: bool,
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    #[cfg(feature = "crypto")]
+    passphrase: Option<Passphrase>,
+    #[cfg(feature = "crypto")]
+    hash_keys: bool,
+}
+
+impl Default for KvStoreBuilder {
+    fn default() -> Self {
+        KvStoreBuilder {
+            compaction_trigger: CompactionTrigger::default(),
+            max_log_files: None,
+            max_keys: None,
+            file_system: None,
+            compact_on_open: false,
+            exclusive: true,
+            ordered_index: false,
+            dedup_values: false,
+            max_open_logs: None,
+            verify_on_read: false,
+            value_cache: None,
+            remove_dangling_keys: false,
+            wal_dir: None,
+            index_snapshot: false,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+            #[cfg(feature = "crypto")]
+            passphrase: None,
+            #[cfg(feature = "crypto")]
+            hash_keys: false,
+        }
+    }
+}
+
+impl KvStoreBuilder {
+    /// creates a builder using the default options
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// sets the [`CompactionTrigger`] used to decide when the command logs are compacted.
+    /// Defaults to [`CompactionTrigger::Bytes`] with a threshold of 1 MB
+    pub fn compaction_trigger(mut self, trigger: CompactionTrigger) -> Self {
+        self.compaction_trigger = trigger;
+        self
+    }
+
+    /// sets the maximum number of log files that may exist in the working directory. Once
+    /// there are more log files than this, they are compacted into a single log file,
+    /// regardless of the amount of stale data they contain. This prevents many small log
+    /// files (one is created every time the store is opened) from accumulating.
+    ///
+    /// Compaction always produces two log files, so the smallest allowed value is 2.
+    /// By default, there is no maximum.
+    pub fn max_log_files(mut self, max_log_files: usize) -> Self {
+        self.max_log_files = Some(max_log_files.max(2));
+        self
+    }
+
+    /// limits the store to holding at most `max_keys` keys. Setting a new key in a full store
+    /// first removes the key chosen by the eviction `policy`, turning the store into a bounded
+    /// cache. `max_keys` must be at least 1.
+    ///
+    /// Access order is not persisted, so when the store is reopened the keys are ordered by
+    /// when they were last written. By default, the number of keys is unbounded.
+    pub fn max_keys(mut self, max_keys: usize, policy: EvictionPolicy) -> Self {
+        self.max_keys = Some((max_keys.max(1), policy));
+        self
+    }
+
+    /// sets the [`FileSystem`] used to read and write the command logs. Defaults to [`StdFs`],
+    /// i.e. the local file system
+    pub fn file_system(mut self, file_system: impl FileSystem) -> Self {
+        self.file_system = Some(Arc::new(file_system));
+        self
+    }
+
+    /// compacts the command logs as soon as the store is opened, if the stale data loaded from
+    /// them already crosses the [`CompactionTrigger`] (or there are more than
+    /// [`max_log_files`](KvStoreBuilder::max_log_files) log files). A store that was shut down
+    /// with a lot of stale data then reclaims the space when it is reopened, rather than once
+    /// enough new writes have been made.
+    ///
+    /// A failed compaction doesn't prevent the store from opening, it is reported by
+    /// [`KvsEngine::health`] instead. Defaults to `false`.
+    pub fn compact_on_open(mut self, compact_on_open: bool) -> Self {
+        self.compact_on_open = compact_on_open;
+        self
+    }
+
+    /// sets whether the store takes an exclusive lock on its working directory, so that a second
+    /// store (usually in another process) can't open the same directory and corrupt the logs.
+    /// The lock is held until the store, and all of its clones, are dropped.
+    ///
+    /// Stores opened with `exclusive(false)` neither take nor check the lock. This is only
+    /// meant for directories that are deliberately shared, see [`KvStore::reload_index`].
+    /// Defaults to `true`.
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// maintains an ordered set of the keys, alongside the index, so that
+    /// [`scan`](KvsEngine::scan) can read a page of keys without sorting every key in the store.
+    /// This costs a copy of every key in memory, and a little time on every set and remove.
+    /// Defaults to `false`.
+    pub fn ordered_index(mut self, ordered_index: bool) -> Self {
+        self.ordered_index = ordered_index;
+        self
+    }
+
+    /// stores identical values once. When a key is set to a value that is already in the logs,
+    /// a small link to the existing value is written instead of the value itself, which saves
+    /// space when many keys share the same large value. Values shorter than 64 bytes are always
+    /// written, as a link would be about as long.
+    ///
+    /// Duplicates are found through a hash of every value, kept in memory, which is built
+    /// when the store is opened and rebuilt after every compaction, both of which read every
+    /// value in the store. Compaction keeps a shared value for as long as any key links to it.
+    /// Stores opened without this option can still read the links.
+    /// Defaults to `false`.
+    pub fn dedup_values(mut self, dedup_values: bool) -> Self {
+        self.dedup_values = dedup_values;
+        self
+    }
+
+    /// limits the number of log files that each reader keeps open to `max_open_logs` (at
+    /// least 1). Once the limit is reached, the least recently read log is closed to make room
+    /// for the next one, and reopened if it's read again. Every clone of the store, and every
+    /// [`Snapshot`], has its own reader.
+    ///
+    /// By default a reader keeps every log it has read open until the log is compacted, which
+    /// may exhaust the process's file descriptors if the store has many logs.
+    pub fn max_open_logs(mut self, max_open_logs: usize) -> Self {
+        self.max_open_logs = Some(max_open_logs.max(1));
+        self
+    }
+
+    /// if `true`, every command written to the logs is prefixed with a CRC-32 of the command,
+    /// and reads recompute the checksum before returning the value. A mismatch, e.g. caused by
+    /// silent disk corruption, is returned as a [`KvsError::InvalidCommand`] containing
+    /// "checksum mismatch", rather than returning corrupted data.
+    ///
+    /// Commands written without a checksum, by a store that didn't verify reads, are read
+    /// without being verified. Values are read entirely into memory to verify them, so
+    /// [`KvsEngine::get_reader`] no longer streams values.
+    ///
+    /// Defaults to `false`, as computing the checksums slows down reads and writes.
+    pub fn verify_on_read(mut self, verify_on_read: bool) -> Self {
+        self.verify_on_read = verify_on_read;
+        self
+    }
+
+    /// caches the values of the `max_values` (at least 1) most recently read keys in memory,
+    /// so that reads of hot keys don't go to the command logs. Every clone of the store shares
+    /// the cache, which can be filled ahead of the first reads with [`KvStore::warm_cache`].
+    ///
+    /// A cached value is only returned while it is still the key's latest value, so writes
+    /// never make the cache stale. Values moved by a compaction are read from the logs again.
+    /// Only [`get`](KvsEngine::get), [`get_tagged`](KvsEngine::get_tagged) and
+    /// [`KvStore::get_raw`] read through the cache.
+    ///
+    /// By default values aren't cached, and every read goes to the logs.
+    pub fn value_cache(mut self, max_values: usize) -> Self {
+        self.value_cache = Some(max_values.max(1));
+        self
+    }
+
+    /// if `true`, a key whose value points past the end of its log file, e.g. because the log
+    /// was truncated by another process, is removed from the index when its value is read.
+    /// The read still returns a [`KvsError::InvalidCommand`], but later reads find no value,
+    /// as they would once the store is reopened. Nothing is written to the logs.
+    ///
+    /// Defaults to `false`, so that every read of the key keeps returning the error.
+    pub fn remove_dangling_keys(mut self, remove_dangling_keys: bool) -> Self {
+        self.remove_dangling_keys = remove_dangling_keys;
+        self
+    }
+
+    /// writes the current log, that new writes are appended to, into the `wal_dir` directory
+    /// rather than the working directory, e.g. to put it on a faster device. Compaction files,
+    /// which hold the bulk of the data, are still written to the working directory, along with
+    /// the manifest and the store's other files. The directory is created if it doesn't exist.
+    ///
+    /// Every log is read from the directory it was written to, so a store can be reopened with
+    /// or without a write-ahead directory, but it must always be given the directory that the
+    /// logs it last wrote are in. By default, every log is in the working directory.
+    pub fn wal_dir(mut self, wal_dir: impl Into<PathBuf>) -> Self {
+        self.wal_dir = Some(wal_dir.into());
+        self
+    }
+
+    /// if `true`, the index is written to a snapshot file in the working directory when the
+    /// store is closed, i.e. when its last clone is dropped, and is loaded from the snapshot
+    /// when the store is opened, so that the logs don't all have to be read to rebuild it.
+    /// Only the commands written after the snapshot are read: the end of the newest log it
+    /// covers, and any later logs.
+    ///
+    /// The snapshot records the size of every log it covers. It is ignored, and every log is
+    /// read, if it's corrupt or if those logs have changed since, e.g. because they were
+    /// compacted. A store that crashes keeps its last snapshot, which is still used along
+    /// with the logs written after it. The snapshot doesn't record when keys expire, so it isn't
+    /// written while the store may hold keys set with [`KvStore::set_with_ttl`].
+    ///
+    /// Defaults to `false`. Writing the snapshot makes closing the store O(number of keys).
+    pub fn index_snapshot(mut self, index_snapshot: bool) -> Self {
+        self.index_snapshot = index_snapshot;
+        self
+    }
+
+    /// if `true`, commands are read from memory maps of the command logs, rather than by
+    /// seeking and reading through a buffered file. Reads of data in the page cache then
+    /// avoid system calls, which speeds up random reads of a working set that doesn't fit in
+    /// the buffers of the readers.
+    ///
+    /// A log is mapped again when a read goes past the end of its map, as the current log
+    /// grows, and maps of logs deleted by a compaction are dropped along with their readers.
+    /// A map doesn't hold a file open, so [`max_open_logs`](KvStoreBuilder::max_open_logs)
+    /// doesn't limit the number of maps. Logs are read through buffered files if the
+    /// [`FileSystem`] can't map files.
+    ///
+    /// Defaults to `false`. Requires the `mmap` feature.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// encrypts every value with AES-256-GCM before it is written to the command logs, and
+    /// decrypts it when it is read. The key is derived from the `passphrase` with PBKDF2, using
+    /// a random salt that is stored in the working directory, and every value is encrypted with
+    /// its own random nonce.
+    ///
+    /// Encryption protects data at rest, e.g. the logs on a stolen disk or in a backup, and
+    /// detects values that were tampered with on disk. It does not protect data in the memory
+    /// of the process: the derived key, the keys of the index and the values being read or
+    /// written are all held in plain text. The keys are also written to the logs in plain
+    /// text, unless [`hash_keys`](KvStoreBuilder::hash_keys) is set. The passphrase should be
+    /// kept out of command lines and configuration files checked into version control.
+    ///
+    /// Encryption must be enabled when the store is created, and the same passphrase must be
+    /// given whenever it is opened. Values that are encrypted are never streamed by
+    /// [`KvsEngine::get_reader`], and never share storage with [`dedup_values`], as every
+    /// encrypted copy of a value is different.
+    ///
+    /// Requires the `crypto` feature.
+    ///
+    /// [`dedup_values`]: KvStoreBuilder::dedup_values
+    #[cfg(feature = "crypto")]
+    pub fn encryption_passphrase(mut self, passphrase: impl Into<String>) -> Self {
+        self.passphrase = Some(Passphrase(passphrase.into()));
+        self
+    }
+
+    /// if `true`, the keys of an encrypted store are replaced by their HMAC-SHA256, hex encoded,
+    /// both in the index and in the command logs, so the keys aren't stored in plain text. Keys
+    /// can still be looked up, but they can't be recovered: [`KvsEngine::scan`] and
+    /// [`KvStore::snapshot`] return the hashes rather than the keys.
+    ///
+    /// Only applies to stores with an
+    /// [`encryption_passphrase`](KvStoreBuilder::encryption_passphrase), and must be the same
+    /// whenever the store is opened. Defaults to `false`. Requires the `crypto` feature.
+    #[cfg(feature = "crypto")]
+    pub fn hash_keys(mut self, hash_keys: bool) -> Self {
+        self.hash_keys = hash_keys;
+        self
+    }
+
+    /// opens a [`KvStore`] in the given `working_dir` using the options of this builder.
+    /// If the `working_dir` does not exist it will be created.
+    ///
+    /// # Errors
+    /// [`KvsError::Io`] is returned if the working_dir could not be created.
+    /// [`KvsError::Locking`] is returned if the working_dir is locked by another store.
+    /// [`KvsError::Encryption`] is returned if the passphrase of an encrypted store is wrong or
+    /// missing
+    pub fn open(self, working_dir: &Path) -> Result<KvStore> {
+        KvStore::open_with(working_dir, self)
+    }
+}
+
 /// A multi-threaded, key-value storage engine implementation.
 ///
 /// Keys and values are persisted across a series of "command logs" located on the local file system.
@@ -68,8 +476,30 @@ pub struct KvStore {
     // writer of the current command log.
     writer: Arc<Mutex<KvsWriter>>,
 
+    // whether the writer is compacting the logs
+    compacting: Arc<AtomicBool>,
+
     // maps a key to the position of its value within a log file
-    index: Arc<DashMap<String, CommandPos>>,
+    index: Arc<Index>,
+
+    // the sequence number of the latest successful write
+    seq: Arc<AtomicU64>,
+
+    // whether the index may hold keys that expire, whose commands must be read to tell if they
+    // still exist
+    expiring: Arc<AtomicBool>,
+
+    // tracks the order keys are evicted in, if the store has a maximum number of keys
+    eviction: Option<Arc<Eviction>>,
+
+    // the keys in ascending order, if the store has an ordered index
+    ordered: Option<Arc<OrderedKeys>>,
+
+    // the values of the most recently read keys, if the store caches values
+    value_cache: Option<Arc<ValueCache>>,
+
+    // whether keys whose values point past the end of their log are removed from the index
+    remove_dangling_keys: bool,
 }
 
 impl KvStore {
@@ -79,362 +509,3397 @@ impl KvStore {
     ///
     /// # Errors
     /// [`KvsError::Io`] is returned if the working_dir could not be created
-    #[instrument]
     pub fn open(working_dir: &Path) -> Result<KvStore> {
+        KvStoreBuilder::default().open(working_dir)
+    }
+
+    /// creates a [`KvStore`] in the given `working_dir`, like [`open`](KvStore::open), that
+    /// compacts its command logs once `threshold` bytes of stale data exist, rather than 1 MB.
+    /// A lower threshold compacts more often, which keeps the logs small on a device with little
+    /// storage, at the cost of more time spent compacting.
+    ///
+    /// This is a shortcut for a [`KvStoreBuilder`] with a [`CompactionTrigger::Bytes`].
+    ///
+    /// # Errors
+    /// [`KvsError::Io`] is returned if the working_dir could not be created
+    pub fn open_with_threshold(working_dir: &Path, threshold: u64) -> Result<KvStore> {
+        KvStoreBuilder::default()
+            .compaction_trigger(CompactionTrigger::Bytes(threshold))
+            .open(working_dir)
+    }
+
+    /// returns a [`KvStoreBuilder`] that can be used to configure the options of a [`KvStore`]
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+
+    #[instrument]
+    fn open_with(working_dir: &Path, options: KvStoreBuilder) -> Result<KvStore> {
         info!("opening KVS engine version {}", crate_version!());
-        fs::create_dir_all(working_dir)?;
-        debug!("working_dir path= {:?}", working_dir.canonicalize().unwrap().to_str());
+        let fs = options.file_system.unwrap_or_else(|| Arc::new(StdFs));
+        fs.create_dir_all(working_dir)?;
+        debug!("working_dir path= {:?}", working_dir);
         let path = Arc::new(working_dir.to_path_buf());
+        if let Some(wal_dir) = &options.wal_dir {
+            fs.create_dir_all(wal_dir)?;
+        }
+
+        // the lock is taken before any logs are read, so the logs can't be changed by another
+        // store while they are loaded
+        let lock = if options.exclusive {
+            Some(fs.lock(&path.join(LOCK_FILE)).map_err(|e| match e.kind() {
+                io::ErrorKind::WouldBlock => KvsError::Locking(format!(
+                    "data directory {} is already in use by another process",
+                    working_dir.display()
+                )),
+                _ => e.into(),
+            })?)
+        } else {
+            None
+        };
 
-        // get all log gen numbers in the working dir
-        let log_gens = get_log_gens(&path)?.unwrap_or_default();
-        debug!(?log_gens);
+        // the logs are searched for to learn which directory each of them is in. The manifest
+        // lists the live logs, the search is only used to find them if it can't be used
+        let logs = Arc::new(LogDirs::new(path.to_path_buf(), options.wal_dir.clone()));
+        let found_gens = logs.gens(&*fs)?;
+        let (log_gens, compaction_gen) = match read_manifest(&*fs, &path, &logs)? {
+            Some(manifest) => (manifest.gens, manifest.compaction_gen),
+            None => (found_gens, 0),
+        };
+        debug!(?log_gens, ?compaction_gen);
 
         let mut readers = BTreeMap::new();
-        let index = Arc::new(DashMap::new());
+        let index = Index::default();
         let mut uncompacted = 0_u64;
+        // the write sequence high-water mark, recorded by the latest compaction
+        let mut seq = read_seq_file(&*fs, &path)?;
+        // the logs are read from their start, unless the index snapshot already covers them
+        let mut log_starts = log_gens.iter().map(|&gen| (gen, 0)).collect::<Vec<_>>();
+        if options.index_snapshot {
+            let snapshot = IndexSnapshot::read(&*fs, &path).and_then(|snapshot| {
+                let tail = snapshot.tail(&*fs, &logs, &log_gens)?;
+                Some((snapshot, tail))
+            });
+            match snapshot {
+                Some((snapshot, tail)) => {
+                    debug!(keys = snapshot.entries.len(), ?tail, "loading the index snapshot");
+                    seq = seq.max(snapshot.seq);
+                    uncompacted = snapshot.uncompacted;
+                    for (key, cmd_pos) in snapshot.entries {
+                        index.insert(key, cmd_pos);
+                    }
+                    log_starts = tail;
+                }
+                None => info!("the index snapshot is missing or stale, reading every log"),
+            }
+        }
+        let index = Arc::new(index);
 
-        // build buffered readers for all log files in the working_dir
-        for gen in &log_gens {
-            let mut reader =
-                BufReaderWithPos::new(File::open(build_log_path(&path, *gen))?)?;
-            // load data from the reader into the index
-            uncompacted += load(*gen, &mut reader, &index)?;
-            readers.insert(*gen, reader);
+        // the partial index of every log is merged into the index in generation order, so that
+        // later gens win. The index snapshot is never written while keys may expire, so only
+        // the logs read here can hold them
+        let mut expiring = false;
+        for (gen, reader, loaded) in load_logs(&*fs, &logs, &log_starts)? {
+            seq = seq.max(loaded.max_seq);
+            expiring |= loaded.expiring;
+            uncompacted += loaded.merge_into(&index);
+            readers.insert(gen, (reader, 0));
+        }
+        // the latest logs are kept open, if the number of open logs is limited
+        if let Some(max_open_logs) = options.max_open_logs {
+            while readers.len() > max_open_logs {
+                readers.pop_first();
+            }
         }
-        debug!(?uncompacted);
+        debug!(?seq);
+        let seq = Arc::new(AtomicU64::new(seq));
+        let expiring = Arc::new(AtomicBool::new(expiring));
+        // the total size of the commands that are still referenced by the index
+        let live = index.values().map(|cmd_pos| cmd_pos.len).sum::<u64>();
+        debug!(?uncompacted, ?live);
+
+        // the keys are initially ordered by the position of their latest write
+        let eviction = options.max_keys.map(|(max_keys, policy)| {
+            let mut keys = index
+                .iter()
+                .map(|(key, cmd_pos)| (cmd_pos, key))
+                .collect::<Vec<(CommandPos, Vec<u8>)>>();
+            keys.sort_by_key(|(cmd_pos, _key)| (cmd_pos.gen, cmd_pos.pos));
+            let eviction = Eviction::new(max_keys, policy);
+            for (_cmd_pos, key) in keys {
+                eviction.on_write(&key);
+            }
+            Arc::new(eviction)
+        });
+
+        let ordered = options
+            .ordered_index
+            .then(|| Arc::new(OrderedKeys::new(index.iter().map(|(key, _cmd_pos)| key))));
 
         // determine the largest generation number
         let current_log_gen = log_gens.last().unwrap_or(&0) + 1;
         debug!(?current_log_gen);
 
+        #[cfg(feature = "crypto")]
+        let cipher = match &options.passphrase {
+            Some(passphrase) => Some(Arc::new(Cipher::open(&*fs, &path, passphrase, options.hash_keys, index.len() == 0)?)),
+            None => None,
+        };
+        #[cfg(feature = "crypto")]
+        let needs_passphrase = cipher.is_none();
+        #[cfg(not(feature = "crypto"))]
+        let needs_passphrase = true;
+        if needs_passphrase && fs.metadata(&path.join(CRYPTO_FILE)).is_ok() {
+            return Err(KvsError::Encryption(
+                "the store is encrypted, so it can only be opened with its passphrase".to_string(),
+            ));
+        }
+
         // build a KvsReader for all the command log files currently in use
         let reader = KvsReader {
             path: path.clone(),
+            logs,
+            fs: fs.clone(),
             readers: RefCell::new(readers),
-            latest_compaction_gen: Arc::new(AtomicU64::new(0)),
+            reads: Cell::new(0),
+            max_open_logs: options.max_open_logs,
+            verify_on_read: options.verify_on_read,
+            #[cfg(feature = "mmap")]
+            maps: options.mmap.then(LogMaps::default),
+            #[cfg(feature = "crypto")]
+            cipher,
+            latest_compaction_gen: Arc::new(AtomicU64::new(compaction_gen)),
         };
 
         // build a new log file where new commands will be written to
-        let buf_writer = new_log_file(&path, current_log_gen)?;
-        let writer = KvsWriter {
+        let buf_writer = reader.logs.create(&*fs, current_log_gen, true)?;
+        let mut gens = log_gens.clone();
+        gens.push(current_log_gen);
+        update_manifest(&*fs, &path, &Manifest { gens, compaction_gen })?;
+        let mut writer = KvsWriter {
             reader: reader.clone(),
             writer: buf_writer,
             uncompacted,
+            live,
+            compaction_trigger: options.compaction_trigger,
+            max_log_files: options.max_log_files,
+            // the existing logs plus the new current log
+            log_files: log_gens.len() + 1,
             current_gen: current_log_gen,
             path: path.clone(),
+            fs,
             index: index.clone(),
+            seq: seq.clone(),
+            expiring: expiring.clone(),
+            eviction: eviction.clone(),
+            ordered: ordered.clone(),
+            compactions: 0,
+            last_compaction: None,
+            failed_compaction: None,
+            undeleted: BTreeSet::new(),
+            value_hashes: options.dedup_values.then(HashMap::new),
+            index_snapshot: options.index_snapshot,
+            compacting: Arc::new(AtomicBool::new(false)),
+            _lock: lock,
         };
+        writer.rebuild_value_hashes()?;
+        if options.compact_on_open && writer.needs_compaction() {
+            info!(uncompacted = writer.uncompacted, "compacting the logs loaded on open");
+            if let Err(e) = writer.compact() {
+                warn!("compaction on open failed: {}", e);
+            }
+        }
 
         Ok(KvStore {
             //working_dir: path.clone(),
             index: index.clone(),
             reader,
+            compacting: Arc::clone(&writer.compacting),
             writer: Arc::new(Mutex::new(writer)),
+            seq,
+            expiring,
+            eviction,
+            ordered,
+            value_cache: options.value_cache.map(|max_values| Arc::new(ValueCache::new(max_values))),
+            remove_dangling_keys: options.remove_dangling_keys,
         })
     }
+
+    /// rebuilds the index by re-reading every command log in the working directory.
+    ///
+    /// The index is only built when the store is opened, so it will not reflect changes made to
+    /// the logs by another process, e.g. another `KvStore` writing to the same directory. A
+    /// process that needs to pick up such changes can call this periodically.
+    ///
+    /// The writer lock is held while the index is rebuilt, so this blocks local writes. Note
+    /// that this is O(total log size), as every log must be read.
+    ///
+    /// Stores lock their working directory by default, so every store sharing the directory
+    /// must be opened with [`KvStoreBuilder::exclusive`] set to `false`.
+    ///
+    /// # Errors
+    /// returns [`KvsError`] if a log could not be read
+    pub fn reload_index(&self) -> Result<()> {
+        self.lock_writer().reload_index()
+    }
+
+    /// sets the value of a byte `key` to a byte `value`. Neither needs to be valid UTF-8.
+    ///
+    /// Keys and values set through the `String` API of [`KvsEngine`] are the UTF-8 bytes of the
+    /// strings, so the two APIs can be mixed. Commands that aren't valid UTF-8 are written to
+    /// the log in a binary format.
+    ///
+    /// # Errors
+    /// returns [`KvsError`] if the command could not be written to the log
+    pub fn set_raw(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.lock_writer().set(self.reader.index_key(key), value, 0).map(|_seq| ())
+    }
+
+    /// sets the value of a `key` that expires `ttl_secs` seconds from now. The expiry is
+    /// written to the key's command, as seconds since the unix epoch, so it survives restarts.
+    ///
+    /// Once expired, the key is treated as removed: [`get`](KvsEngine::get) returns `None`
+    /// and removes it from the index, and it isn't loaded when the store is next opened. Keys
+    /// are only expired when they are read or loaded, so an expired key that was never read
+    /// is still listed, and counted, until the store is reopened. Setting the key again, e.g.
+    /// with [`set`](KvsEngine::set), replaces its expiry.
+    ///
+    /// # Errors
+    /// returns [`KvsError`] if the command could not be written to the log
+    pub fn set_with_ttl(&self, key: String, value: String, ttl_secs: u64) -> Result<()> {
+        let expires_at = (now_millis() / 1000).saturating_add(ttl_secs);
+        self.lock_writer()
+            .set_expiring(self.reader.index_key(key.into_bytes()), value.into_bytes(), 0, Some(expires_at))
+            .map(|_seq| ())
+    }
+
+    /// gets the value of a byte `key`, or `None` if the key does not exist
+    ///
+    /// # Errors
+    /// returns [`KvsError`] if the value could not be read from the log
+    pub fn get_raw(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_set(&self.reader.index_key(key))?.map(|(value, _tag)| value))
+    }
+
+    /// reads the value, and the tag, of the latest set command of `key`, from the value cache if
+    /// it's cached there
+    fn read_set(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u8)>> {
+        self.read_set_with(key, None)
+    }
+
+    /// reads the value, and the tag, of the latest set command of `key`, like
+    /// [`read_set`](KvStore::read_set), for a caller that already holds the lock on the `writer`
+    #[instrument(skip(writer))]
+    fn read_set_with(&self, key: &[u8], writer: Option<&mut KvsWriter>) -> Result<Option<(Vec<u8>, u8)>> {
+        // check for existence of key in index
+        if let Some(cmd_pos) = self.index.get(key) {
+            if let Some(cached) = self.value_cache.as_ref().and_then(|cache| cache.get(key, cmd_pos)) {
+                if let Some(eviction) = &self.eviction {
+                    eviction.on_read(key);
+                }
+                return Ok(Some(cached));
+            }
+            // get a reader based on the command generation
+            let cmd = match self.reader.read_command(cmd_pos) {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    return Err(self.dangling_error(key, cmd_pos, writer).unwrap_or(e));
+                }
+            };
+            if let LogCommand::Set { value, tag, expires_at, .. } = cmd {
+                if let Some(expires_at) = expires_at {
+                    if is_expired(expires_at) {
+                        match writer {
+                            Some(writer) => writer.remove_expired(key, cmd_pos),
+                            None => self.lock_writer().remove_expired(key, cmd_pos),
+                        }
+                        return Ok(None);
+                    }
+                }
+                if let Some(eviction) = &self.eviction {
+                    eviction.on_read(key);
+                }
+                // the values of keys that expire aren't cached, as the cache doesn't expire them
+                if let (Some(cache), None) = (&self.value_cache, expires_at) {
+                    cache.insert(key, cmd_pos, &value, tag);
+                }
+                Ok(Some((value, tag)))
+            } else {
+                let key = String::from_utf8_lossy(key);
+                error!("could not get command for key: {} command: {:?}", &key, &cmd_pos);
+                Err(KvsError::InvalidCommand(format!("invalid command in logs for key: {}", &key)))
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// returns the error of a read of `key` that failed because its `cmd_pos` points past the
+    /// end of its log, or `None` if the command is within its log, and so failed for another
+    /// reason. The key is removed from the index if the store removes dangling keys
+    fn dangling_error(&self, key: &[u8], cmd_pos: CommandPos, writer: Option<&mut KvsWriter>) -> Option<KvsError> {
+        if !self.reader.is_dangling(cmd_pos) {
+            return None;
+        }
+        let key_str = String::from_utf8_lossy(key);
+        error!("the value of key: {} points past the end of log {}", &key_str, cmd_pos.gen);
+        if self.remove_dangling_keys {
+            match writer {
+                Some(writer) => writer.remove_dangling(key, cmd_pos),
+                None => self.lock_writer().remove_dangling(key, cmd_pos),
+            }
+        }
+        Some(KvsError::InvalidCommand(format!(
+            "the value of key: {} points past the end of log {}, which may have been truncated",
+            &key_str, cmd_pos.gen
+        )))
+    }
+
+    /// removes a byte `key`
+    ///
+    /// # Errors
+    /// [`KvsError::KeyNotFound`] if the key does not exist
+    pub fn remove_raw(&self, key: Vec<u8>) -> Result<()> {
+        self.lock_writer().remove(self.reader.index_key(key)).map(|_seq| ())
+    }
+
+    /// returns the sequence number of the latest successful write (set or remove) to the store.
+    ///
+    /// Every write is assigned a monotonically increasing sequence number, starting at 1. The
+    /// sequence number is persisted in the command logs, so it continues across restarts.
+    /// Returns 0 if nothing has ever been written to the store
+    pub fn seq(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    /// returns a point-in-time [`Snapshot`] of the store, that can be used to iterate over every
+    /// key/value pair, e.g. for a full backup or export.
+    ///
+    /// The keys (and the positions of their values) are copied out of the index up front, so
+    /// the index is only locked briefly. The values are then read from disk lazily, as the
+    /// snapshot is iterated, without holding any index locks, so writers are not stalled by a
+    /// long running export.
+    ///
+    /// Because the snapshot is point-in-time, it will not see keys that are set after it was
+    /// taken. Keys that are removed while iterating the snapshot are skipped.
+    pub fn snapshot(&self) -> Snapshot {
+        let entries = self.index.iter().collect::<Vec<_>>();
+        Snapshot {
+            entries: entries.into_iter(),
+            reader: self.reader.clone(),
+            index: Arc::clone(&self.index),
+        }
+    }
+
+    /// writes a compacted copy of the store into the new directory `dest`: every live key and
+    /// its latest value, in a single generation 1 log, along with the kvs-server engine marker.
+    /// The directory can then be opened by [`KvStore::open`] or served by kvs-server, e.g. as a
+    /// minimal backup. The store itself is left untouched.
+    ///
+    /// The index is copied while holding the writer lock, so the copy is a point-in-time
+    /// snapshot of the store, but the values are read afterwards without blocking writes. Links
+    /// between shared values are not kept, each key gets its own copy of its value. The copy of
+    /// an encrypted store is encrypted with the same keys, so it's opened with the same
+    /// passphrase.
+    ///
+    /// # Errors
+    /// [`KvsError::StringErr`] if `dest` already contains command logs, or [`KvsError`] if a
+    /// value could not be read or the copy could not be written
+    pub fn compact_to(&self, dest: &Path) -> Result<()> {
+        let fs = &*self.reader.fs;
+        fs.create_dir_all(dest)?;
+        let logs = LogDirs::new(dest.to_path_buf(), None);
+        if !logs.gens(fs)?.is_empty() {
+            return Err(KvsError::StringErr(format!("{} already contains command logs", dest.display())));
+        }
+
+        let (mut entries, seq) = {
+            let _writer = self.lock_writer();
+            let entries = self.index.iter().collect::<Vec<_>>();
+            (entries, self.seq())
+        };
+        // the keys are written in order, like a compaction file
+        entries.sort_unstable_by(|(key, _), (other_key, _)| key.cmp(other_key));
+        let mut snapshot = Snapshot {
+            entries: entries.into_iter(),
+            reader: self.reader.clone(),
+            index: Arc::clone(&self.index),
+        };
+
+        if self.reader.is_encrypted() {
+            let mut crypto_file = fs.open(&self.reader.path.join(CRYPTO_FILE))?;
+            io::copy(&mut crypto_file, &mut fs.create(&dest.join(CRYPTO_FILE))?)?;
+        }
+        let mut writer = logs.create(fs, 1, false)?;
+        while let Some((key, cmd_pos)) = snapshot.entries.next() {
+            // keys removed since the index was copied are skipped
+            if let Some(LogCommand::Set { key, value, seq, written_at, tag, expires_at }) = snapshot.read_set(&key, cmd_pos)? {
+                let value = self.reader.seal(value)?;
+                LogCommand::Set { key, value, seq, written_at, tag, expires_at }
+                    .write_record_to(&mut writer, self.reader.verify_on_read)?;
+            }
+        }
+        writer.flush()?;
+        writer.sync_all()?;
+        write_seq_file(fs, dest, seq)?;
+        update_manifest(fs, dest, &Manifest { gens: vec![1], compaction_gen: 1 })?;
+        fs.create(&dest.join(ENGINE_FILE))?.write_all(b"kvs")?;
+        info!("compacted copy written to {}", dest.display());
+        Ok(())
+    }
+
+    /// writes every key and its latest value into a new binary dump at `path`, see
+    /// [`dump`](crate::dump) for the format. The dump can be loaded into another store with
+    /// [`KvStore::load_dump`], e.g. to migrate the store to another host. Returns the number of
+    /// keys written.
+    ///
+    /// The keys are taken from a [`Snapshot`], so writers aren't blocked while the values are
+    /// read. The values of an encrypted store are written decrypted.
+    ///
+    /// # Errors
+    /// [`KvsError::Unsupported`] if the store hashes its keys, as the keys can't be recovered
+    /// from their hashes, or [`KvsError`] if a value could not be read or the dump could not be
+    /// written
+    pub fn dump(&self, path: &Path) -> Result<u64> {
+        if self.reader.hashes_keys() {
+            return Err(KvsError::Unsupported("dumping a store that hashes its keys".to_string()));
+        }
+        let fs = &*self.reader.fs;
+        let mut snapshot = self.snapshot();
+        let mut writer = DumpWriter::new(fs.create(path)?)?;
+        while let Some((key, cmd_pos)) = snapshot.entries.next() {
+            // keys removed since the snapshot was taken are skipped
+            if let Some(value) = snapshot.read_value(&key, cmd_pos)? {
+                writer.write_entry(&key, &value)?;
+            }
+        }
+        let entries = writer.entries();
+        writer.finish()?.sync_all()?;
+        info!(entries, "dump written to {}", path.display());
+        Ok(entries)
+    }
+
+    /// sets every key in the binary dump at `path` to its value in the dump, see
+    /// [`KvStore::dump`]. Keys that aren't in the dump are left untouched. Returns the number
+    /// of keys loaded.
+    ///
+    /// The whole dump is read, and its checksum validated, before any key is set, so nothing is
+    /// loaded from a truncated or corrupt dump. The writer lock is held while the keys are set.
+    ///
+    /// # Errors
+    /// [`KvsError::InvalidDump`] if the dump is truncated, corrupt, or of an unsupported
+    /// version, or [`KvsError`] if a key could not be written
+    pub fn load_dump(&self, path: &Path) -> Result<u64> {
+        let fs = &*self.reader.fs;
+        let entries = dump::verify(fs.open(path)?)?;
+        let mut writer = self.lock_writer();
+        for entry in DumpReader::new(fs.open(path)?)? {
+            let (key, value) = entry?;
+            writer.set(self.reader.index_key(key), value, 0)?;
+        }
+        info!(entries, "dump loaded from {}", path.display());
+        Ok(entries)
+    }
+
+    /// returns the number of live keys in the store, from the index. Removed keys are no longer
+    /// in the index, so they aren't counted
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// returns `true` if the store has no live keys
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// estimates the number of bytes of memory used by the index of keys: the keys themselves,
+    /// the entries of the index's hash table (including its spare capacity), and the copy of the
+    /// keys in the ordered index, if the store has one. The allocator's own overhead isn't
+    /// counted.
+    ///
+    /// This walks the whole index, so it takes time proportional to the number of keys.
+    pub fn index_memory_estimate(&self) -> usize {
+        let keys = self.index.len();
+        let key_bytes: usize = self.index.iter().map(|(key, _cmd_pos)| key.len()).sum();
+        // the buckets of a hash table are at most 7/8 full, and each has a control byte
+        let table_bytes = keys * (std::mem::size_of::<(Vec<u8>, CommandPos)>() + 1) * 8 / 7;
+        let ordered_bytes = match &self.ordered {
+            Some(_) => key_bytes + keys * std::mem::size_of::<Vec<u8>>(),
+            None => 0,
+        };
+        key_bytes + table_bytes + ordered_bytes
+    }
+
+    /// reads the values of the given `keys` into the value cache (see
+    /// [`KvStoreBuilder::value_cache`]), so that their first reads don't go to the command logs,
+    /// e.g. to load a list of known hot keys after a restart. Keys that don't exist are skipped.
+    /// Returns the number of values that were loaded.
+    ///
+    /// The cache holds a limited number of values, so if more keys are given than fit, the
+    /// values of the keys given last are the ones that stay cached.
+    ///
+    /// # Errors
+    /// [`KvsError::Unsupported`] if the store doesn't cache values, or [`KvsError`] if a value
+    /// could not be read from the logs
+    pub fn warm_cache(&self, keys: Vec<String>) -> Result<usize> {
+        if self.value_cache.is_none() {
+            return Err(KvsError::Unsupported("warm_cache".to_string()));
+        }
+        let mut loaded = 0;
+        for key in keys {
+            if self.read_set(&self.reader.index_key(key.into_bytes()))?.is_some() {
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// estimates how much disk space a compaction would reclaim, without compacting.
+    ///
+    /// This only reads the index and the sizes of the command logs, nothing is written. It
+    /// can be used to decide whether a manual compaction is worth the IO.
+    ///
+    /// # Errors
+    /// returns [`KvsError::Io`] if the sizes of the command logs could not be read
+    pub fn compaction_estimate(&self) -> Result<CompactionEstimate> {
+        self.lock_writer().compaction_estimate()
+    }
+
+    /// compacts the command logs now, whether or not the store's compaction trigger has been
+    /// crossed, e.g. during a maintenance window. The compaction holds the writer lock, so it
+    /// waits for a running compaction to finish, and blocks writes until it's done.
+    ///
+    /// A store without any stale data is left as is, rather than rewriting its logs into new
+    /// files that hold the same data.
+    ///
+    /// # Errors
+    /// returns [`KvsError`] if the compaction failed, in which case the logs are left untouched
+    pub fn force_compact(&self) -> Result<()> {
+        let mut writer = self.lock_writer();
+        if writer.uncompacted == 0 {
+            debug!("nothing to compact");
+            return Ok(());
+        }
+        writer.compact()
+    }
+
+    /// starts a background thread that wakes up every `interval` and compacts the command logs
+    /// if the store's compaction trigger has been crossed. This moves compactions off the
+    /// write path, smoothing out the latency spikes they cause.
+    ///
+    /// Writes still compact when the trigger is crossed, so the interval should be short
+    /// enough that the background thread usually gets there first. Only one compaction ever
+    /// runs at a time, as compactions hold the writer lock.
+    ///
+    /// The thread only holds a weak reference to the store, so it exits once every clone of
+    /// the store has been dropped. It can be stopped earlier with the returned
+    /// [`BackgroundCompaction`] handle. Dropping the handle also stops the thread.
+    pub fn start_background_compaction(&self, interval: Duration) -> Result<BackgroundCompaction> {
+        let writer = Arc::downgrade(&self.writer);
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("kvs-compaction".to_string())
+            .spawn(move || {
+                // runs until stopped, or the handle is dropped
+                while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                    let Some(writer) = writer.upgrade() else {
+                        break;
+                    };
+                    let mut writer = lock_writer(&writer);
+                    if writer.needs_compaction() {
+                        debug!("running a background compaction");
+                        if let Err(e) = writer.compact() {
+                            error!("background compaction failed: {}", e);
+                        }
+                    }
+                }
+            })?;
+
+        Ok(BackgroundCompaction {
+            stop_tx,
+            thread: Some(thread),
+        })
+    }
+
+    /// syncs the current log to disk, and returns (and logs) a [`ShutdownSummary`] of the
+    /// data that was flushed. It is meant to be called once the store's last writes are done,
+    /// e.g. when a server shuts down gracefully, so that the shutdown can be audited later.
+    ///
+    /// A running compaction is allowed to finish first. A compaction that is due but hasn't
+    /// started is skipped, it will run when the store is next written to. The store can still
+    /// be used afterwards, and is synced again when it is dropped.
+    ///
+    /// # Errors
+    /// returns [`KvsError::Io`] if the current log could not be synced
+    pub fn shutdown(&self) -> Result<ShutdownSummary> {
+        // the compaction holds the writer lock, so it has finished once the lock is acquired
+        let compacting = self.compacting.load(Ordering::SeqCst);
+        let mut writer = self.lock_writer();
+        let compaction = if compacting {
+            ShutdownCompaction::Finished
+        } else if writer.needs_compaction() {
+            ShutdownCompaction::Skipped
+        } else {
+            ShutdownCompaction::Idle
+        };
+        let bytes_flushed = writer.writer.unsynced_bytes();
+        writer.writer.sync_all()?;
+        let summary = ShutdownSummary {
+            bytes_flushed,
+            compaction,
+            final_gen: writer.current_gen,
+        };
+        info!(bytes_flushed, compaction = ?summary.compaction, final_gen = summary.final_gen, "store shut down");
+        Ok(summary)
+    }
+
+    /// opens the log of the set command at `cmd_pos`, and returns the length of its value and a
+    /// reader of the command that is positioned at the first byte of the value. Returns `None`
+    /// if the command wasn't written in the binary format, which prefixes the value with its
+    /// length.
+    ///
+    /// The log is opened separately from the store's reader, as the value is read after this
+    /// returns. Log files are never modified, only appended to or deleted, and an open file can
+    /// still be read after it is deleted
+    fn open_binary_value(&self, cmd_pos: CommandPos) -> Result<Option<(u64, CommandReader)>> {
+        let mut file = self.reader.fs.open(&self.reader.logs.log_path(cmd_pos.gen))?;
+        file.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let mut cmd_reader = BufReader::new(file).take(cmd_pos.len);
+        let Some(first @ (BINARY_SET | BINARY_TAGGED_SET)) = skip_whitespace(&mut cmd_reader)? else {
+            return Ok(None);
+        };
+        // skip the command type, tag, seq, written_at and key to reach the length of the value
+        cmd_reader.consume(1);
+        if first == BINARY_TAGGED_SET {
+            read_u8(&mut cmd_reader)?;
+        }
+        read_u64(&mut cmd_reader)?;
+        read_u64(&mut cmd_reader)?;
+        read_key(&mut cmd_reader)?;
+        let len = read_u64(&mut cmd_reader)?;
+        Ok(Some((len, cmd_reader)))
+    }
+
+    /// returns the key that the string `key` is stored under, see [`KvsReader::index_key`]
+    fn index_str(&self, key: String) -> String {
+        String::from_utf8(self.reader.index_key(key.into_bytes())).expect("keys and their hex hashes are UTF-8")
+    }
+
+    /// acquires the lock on the [`KvsWriter`].
+    fn lock_writer(&self) -> MutexGuard<'_, KvsWriter> {
+        lock_writer(&self.writer)
+    }
+}
+
+/// acquires the lock on the given [`KvsWriter`].
+///
+/// If a previous holder of the lock panicked, the lock will be "poisoned". Rather than
+/// propagating the panic to every subsequent operation, the poisoned lock is recovered and
+/// cleared, so that one failed write does not permanently brick the store.
+fn lock_writer(writer: &Mutex<KvsWriter>) -> MutexGuard<'_, KvsWriter> {
+    writer.lock().unwrap_or_else(|poisoned| {
+        warn!("the writer lock was poisoned by a panicked thread, recovering it");
+        writer.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// An estimate of the effect of a compaction, returned by [`KvStore::compaction_estimate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// the total size, in bytes, of the command logs
+    pub log_bytes: u64,
+    /// the size, in bytes, of the commands that would be kept by a compaction
+    pub live_bytes: u64,
+    /// the number of bytes a compaction would reclaim, i.e. `log_bytes - live_bytes`
+    pub reclaimable_bytes: u64,
+    /// the number of log files a compaction would delete. A compaction always creates two new
+    /// log files, one for the compacted commands and one for new writes
+    pub files_deleted: usize,
+}
+
+/// A summary of the shutdown of a [`KvStore`], returned by [`KvStore::shutdown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// the number of bytes written to the current log since it was last synced to disk, which
+    /// were synced by the shutdown
+    pub bytes_flushed: u64,
+    /// what happened to compaction during the shutdown
+    pub compaction: ShutdownCompaction,
+    /// the generation number of the current log, i.e. of the last log written to
+    pub final_gen: u64,
+}
+
+/// What happened to compaction during a [`KvStore::shutdown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownCompaction {
+    /// no compaction was running or due
+    Idle,
+    /// a compaction was running, and the shutdown waited for it to finish
+    Finished,
+    /// a compaction was due, but wasn't started
+    Skipped,
+}
+
+/// A handle to the background compaction thread started by
+/// [`KvStore::start_background_compaction`].
+///
+/// Dropping the handle stops the thread.
+#[derive(Debug)]
+pub struct BackgroundCompaction {
+    // wakes the thread up, telling it to stop
+    stop_tx: mpsc::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundCompaction {
+    /// stops the background compaction thread, waiting for a running compaction to finish
+    pub fn stop(mut self) {
+        self.stop_and_join();
+    }
+
+    fn stop_and_join(&mut self) {
+        // the thread may have already exited, if the store was dropped
+        let _ = self.stop_tx.send(());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("the background compaction thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for BackgroundCompaction {
+    fn drop(&mut self) {
+        self.stop_and_join();
+    }
+}
+
+impl KvsEngine for KvStore {
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_raw(key.into_bytes(), value.into_bytes())
+    }
+
+    fn set_with_seq(&self, key: String, value: String) -> Result<Option<u64>> {
+        self.lock_writer().set(self.reader.index_key(key.into_bytes()), value.into_bytes(), 0).map(Some)
+    }
+
+    /// writes every pair to the log under a single lock of the writer, and flushes the log once,
+    /// rather than once per pair. A compaction is only run once the pairs are written, if they
+    /// crossed the compaction trigger.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, value)| (self.reader.index_key(key.into_bytes()), value.into_bytes()))
+            .collect();
+        self.lock_writer().set_many(pairs).map(|_seq| ())
+    }
+
+    /// writes every pair as a single batch, like [`set_many`](KvsEngine::set_many). A batch that
+    /// fails is truncated from the log, so none of its pairs were set, and only then are the
+    /// pairs set one at a time to find the ones that fail.
+    fn set_each(&self, pairs: Vec<(String, String)>) -> Vec<Result<()>> {
+        match self.set_many(pairs.clone()) {
+            Ok(()) => pairs.iter().map(|_pair| Ok(())).collect(),
+            Err(e) => {
+                debug!("setting the pairs one at a time, as their batch failed: {}", e);
+                pairs.into_iter().map(|(key, value)| self.set(key, value)).collect()
+            }
+        }
+    }
+
+    /// sets a `key` and `value` along with a `tag`, which is stored in the value's command.
+    /// A tag of 0 isn't written to the log, so it costs nothing for untagged values.
+    fn set_tagged(&self, key: String, value: String, tag: u8) -> Result<()> {
+        self.lock_writer().set(self.reader.index_key(key.into_bytes()), value.into_bytes(), tag).map(|_seq| ())
+    }
+
+    /// writes the operations of the transaction to the log as a single batch, under the writer
+    /// lock. The log is only ever appended to, so a batch that is interrupted part way through
+    /// (e.g. by a crash) is truncated, or rejected when the log is next loaded.
+    fn commit(&self, tx: Transaction) -> Result<()> {
+        let ops = tx
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                TxOp::Set { key, value, tag } => TxOp::Set { key: self.index_str(key), value, tag },
+                TxOp::Remove { key } => TxOp::Remove { key: self.index_str(key) },
+            })
+            .collect();
+        self.lock_writer().commit(ops).map(|_seq| ())
+    }
+
+    /// # Errors
+    /// `KvsError::Utf8Error` if the value was set with [`KvStore::set_raw`] and is not valid UTF-8
+    fn get_tagged(&self, key: String) -> Result<Option<(String, u8)>> {
+        match self.read_set(&self.reader.index_key(key.into_bytes()))? {
+            Some((value, tag)) => Ok(Some((String::from_utf8(value)?, tag))),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value associated with the given `key`.
+    ///
+    /// # Errors
+    /// `KvsError::Utf8Error` if the value was set with [`KvStore::set_raw`] and is not valid UTF-8
+    #[instrument]
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_raw(key.into_bytes())?
+            .map(|value| Ok(String::from_utf8(value)?))
+            .transpose()
+    }
+
+    /// Checks the index for the key, without reading the logs, unless the store may hold keys
+    /// that expire, in which case the key's command is read to check it hasn't expired.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        let key = self.reader.index_key(key.into_bytes());
+        if !self.expiring.load(Ordering::SeqCst) {
+            return Ok(self.index.contains_key(&key));
+        }
+        Ok(self.read_set(&key)?.is_some())
+    }
+
+    /// Gets a reader that streams the value associated with the given `key` from its command log.
+    ///
+    /// Values in the JSON format are unescaped as they are read, so determining the length of
+    /// the unescaped value takes an extra pass over the command. Values in the binary format are
+    /// streamed as is.
+    fn get_reader(&self, key: String) -> Result<Option<ValueReader>> {
+        if self.reader.verify_on_read || self.reader.is_encrypted() {
+            // the checksum covers the whole command, and an encrypted value can only be
+            // authenticated as a whole, so the value is read into memory
+            return Ok(self.get(key)?.map(ValueReader::from));
+        }
+        let Some(cmd_pos) = self.index.get(key.as_bytes()) else {
+            return Ok(None);
+        };
+        let cmd_pos = self.reader.value_pos(cmd_pos)?;
+        if let Some((len, value_reader)) = self.open_binary_value(cmd_pos)? {
+            return Ok(Some(ValueReader::new(len, Box::new(value_reader))));
+        }
+
+        // the command was written as JSON, so the length of the value isn't known until it
+        // has been read once
+        let mut file = self.reader.fs.open(&self.reader.logs.log_path(cmd_pos.gen))?;
+        file.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let len = match JsonStrReader::set_value(BufReader::new(&mut file).take(cmd_pos.len))? {
+            Some(mut value_reader) => io::copy(&mut value_reader, &mut io::sink())?,
+            None => {
+                // the command wasn't written in the expected layout, e.g. the key expires, so
+                // read the whole value
+                return Ok(self.get(key)?.map(ValueReader::from));
+            }
+        };
+
+        file.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let value_reader = JsonStrReader::set_value(BufReader::new(file).take(cmd_pos.len))?
+            .ok_or_else(|| KvsError::InvalidCommand(format!("invalid command in logs for key: {}", &key)))?;
+        Ok(Some(ValueReader::new(len, Box::new(value_reader))))
+    }
+
+    /// seeks to the start of the range within values written in the binary format, which are
+    /// prefixed with their length, so only the bytes of the range are read. Other values are
+    /// read from their start up to the end of the range
+    fn get_range(&self, key: String, start: u64, len: u64) -> Result<Option<String>> {
+        if !self.reader.verify_on_read && !self.reader.is_encrypted() {
+            let Some(cmd_pos) = self.index.get(key.as_bytes()) else {
+                return Ok(None);
+            };
+            let cmd_pos = self.reader.value_pos(cmd_pos)?;
+            if let Some((value_len, mut value_reader)) = self.open_binary_value(cmd_pos)? {
+                let skip = start.min(value_len);
+                value_reader.get_mut().seek_relative(skip as i64)?;
+                value_reader.set_limit(value_len - skip);
+                return read_range(value_reader, 0, len).map(Some);
+            }
+        }
+        self.get_reader(key)?.map(|value| read_range(value, start, len)).transpose()
+    }
+
+    /// reads the current value while holding the writer lock, so no other write can land between
+    /// reading the value and writing the new one. The value keeps its tag.
+    ///
+    /// # Errors
+    /// `KvsError::Utf8Error` if the value was set with [`KvStore::set_raw`] and is not valid UTF-8
+    fn append(&self, key: String, suffix: String, separator: Option<String>) -> Result<String> {
+        let key = self.reader.index_key(key.into_bytes());
+        let mut writer = self.lock_writer();
+        let (value, tag) = match self.read_set_with(&key, Some(&mut writer))? {
+            Some((value, tag)) => {
+                let mut value = String::from_utf8(value)?;
+                if let Some(separator) = separator {
+                    value.push_str(&separator);
+                }
+                value.push_str(&suffix);
+                (value, tag)
+            }
+            None => (suffix, 0),
+        };
+        writer.set(key, value.clone().into_bytes(), tag)?;
+        Ok(value)
+    }
+
+    /// reads the value of `from` while holding the writer lock, and then writes the set of `to`
+    /// and the remove of `from` as a single batch, so the value is never lost or duplicated by
+    /// a crash part way through the rename.
+    ///
+    /// # Errors
+    /// `KvsError::Utf8Error` if the value was set with [`KvStore::set_raw`] and is not valid UTF-8
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        let (from, to) = (self.index_str(from), self.index_str(to));
+        let mut writer = self.lock_writer();
+        let Some((value, tag)) = self.read_set_with(from.as_bytes(), Some(&mut writer))? else {
+            return Err(KvsError::KeyNotFound);
+        };
+        if from == to {
+            return Ok(());
+        }
+        let ops = vec![
+            TxOp::Set { key: to, value: String::from_utf8(value)?, tag },
+            TxOp::Remove { key: from },
+        ];
+        writer.commit(ops).map(|_seq| ())
+    }
+
+    /// reads the current value while holding the writer lock, so no other write can land between
+    /// reading the old value and writing the new one.
+    ///
+    /// # Errors
+    /// `KvsError::Utf8Error` if the old value was set with [`KvStore::set_raw`] and is not valid
+    /// UTF-8, in which case the new value isn't set
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        let key = self.reader.index_key(key.into_bytes());
+        let mut writer = self.lock_writer();
+        let old = match self.read_set_with(&key, Some(&mut writer))? {
+            Some((old, _tag)) => Some(String::from_utf8(old)?),
+            None => None,
+        };
+        writer.set(key, value.into_bytes(), 0)?;
+        Ok(old)
+    }
+
+    /// compares the current value while holding the writer lock, so no other write can land
+    /// between the comparison and the set. A swapped value keeps its tag.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        let key = self.reader.index_key(key.into_bytes());
+        let mut writer = self.lock_writer();
+        let tag = match (self.read_set_with(&key, Some(&mut writer))?, expected) {
+            (Some((value, tag)), Some(expected)) if value == expected.as_bytes() => tag,
+            (None, None) => 0,
+            _ => return Ok(false),
+        };
+        writer.set(key, new.into_bytes(), tag)?;
+        Ok(true)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_raw(key.into_bytes())
+    }
+
+    /// Gets the value associated with the given `key`, along with the time it was last written.
+    ///
+    /// Commands written before write times were recorded will use the modification time of
+    /// the log file they are stored in. Note that a compaction rewrites log files, so this will
+    /// be the time of the latest compaction.
+    fn get_with_meta(&self, key: String) -> Result<Option<(String, SystemTime)>> {
+        if let Some(cmd_pos) = self.index.get(&self.reader.index_key(key.clone().into_bytes())) {
+            if let LogCommand::Set { value, written_at, expires_at, .. } = self.reader.read_command(cmd_pos)? {
+                if expires_at.is_some_and(is_expired) {
+                    return Ok(None);
+                }
+                let written_at = match written_at {
+                    Some(millis) => UNIX_EPOCH + Duration::from_millis(millis),
+                    None => self.reader.fs.metadata(&self.reader.logs.log_path(cmd_pos.gen))?.modified,
+                };
+                Ok(Some((String::from_utf8(value)?, written_at)))
+            } else {
+                error!("could not get command for key: {} command: {:?}", &key, &cmd_pos);
+                Err(KvsError::InvalidCommand(format!("invalid command in logs for key: {}", &key)))
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn remove_with_seq(&self, key: String) -> Result<Option<u64>> {
+        self.lock_writer().remove(self.reader.index_key(key.into_bytes())).map(Some)
+    }
+
+    /// Returns statistics about the store. The `disk_usage` is the total size of the command
+    /// logs, the sequence number file and the manifest.
+    fn stats(&self) -> Result<Stats> {
+        let (uncompacted_bytes, compactions, last_compaction, estimate) = {
+            let writer = self.lock_writer();
+            (writer.uncompacted, writer.compactions, writer.last_compaction, writer.compaction_estimate()?)
+        };
+        let file_bytes = |name: &str| match self.reader.fs.metadata(&self.reader.path.join(name)) {
+            Ok(metadata) => Ok(metadata.len),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        };
+
+        Ok(Stats {
+            key_count: self.index.len() as u64,
+            uncompacted_bytes,
+            disk_usage: estimate.log_bytes + file_bytes(SEQ_FILE)? + file_bytes(MANIFEST_FILE)?,
+            compactions,
+            reclaimable_bytes: estimate.reclaimable_bytes,
+            last_compaction,
+            index_memory_bytes: self.index_memory_estimate() as u64,
+            ..Stats::default()
+        })
+    }
+
+    /// Counts the keys in the index, without taking the writer lock.
+    fn key_count(&self) -> Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn reset_stats(&self) -> Result<()> {
+        let mut writer = self.lock_writer();
+        writer.compactions = 0;
+        writer.last_compaction = None;
+        Ok(())
+    }
+
+    /// Returns the layout of every command log. The dead bytes of a log are the bytes that
+    /// aren't part of a key's latest command, so a value shared by links (see
+    /// [`KvStoreBuilder::dedup_values`]) is counted as dead once the key that set it is
+    /// overwritten, even though a compaction would keep it.
+    fn log_info(&self) -> Result<Vec<LogInfo>> {
+        self.lock_writer().log_info()
+    }
+
+    fn shutdown(&self) -> Result<ShutdownSummary> {
+        KvStore::shutdown(self)
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.force_compact()
+    }
+
+    /// Returns a page of keys. Keys set with [`KvStore::set_raw`] that aren't valid UTF-8 are
+    /// skipped.
+    ///
+    /// Pages are read from the ordered index, if the store has one (see
+    /// [`KvStoreBuilder::ordered_index`]). Otherwise, every page sorts the keys after the
+    /// `cursor`.
+    fn scan(&self, cursor: Option<String>, limit: usize) -> Result<(Vec<String>, Option<String>)> {
+        let after = cursor.map(String::into_bytes);
+        if let Some(ordered) = &self.ordered {
+            return Ok(ordered.page(after, limit));
+        }
+        let mut keys: Vec<Vec<u8>> = self
+            .index
+            .iter()
+            .map(|(key, _cmd_pos)| key)
+            .filter(|key| after.as_ref().is_none_or(|after| key > after))
+            .collect();
+        keys.sort_unstable();
+        Ok(page_keys(keys.into_iter().filter_map(|key| String::from_utf8(key).ok()), limit))
+    }
+
+    /// Returns the keys that start with `prefix`. Keys set with [`KvStore::set_raw`] that aren't
+    /// valid UTF-8 are skipped.
+    ///
+    /// The keys are read from the ordered index, starting at the prefix, if the store has one.
+    /// Otherwise, the whole index is walked, and the matching keys are sorted.
+    fn scan_prefix(&self, prefix: String) -> Result<Vec<String>> {
+        let prefix = prefix.into_bytes();
+        let keys = match &self.ordered {
+            Some(ordered) => ordered.with_prefix(&prefix),
+            None => {
+                let mut keys: Vec<Vec<u8>> = self
+                    .index
+                    .iter()
+                    .map(|(key, _cmd_pos)| key)
+                    .filter(|key| key.starts_with(&prefix))
+                    .collect();
+                keys.sort_unstable();
+                keys
+            }
+        };
+        Ok(keys.into_iter().filter_map(|key| String::from_utf8(key).ok()).collect())
+    }
+
+    /// Checks that the last compaction didn't fail, and that the working directory is writable
+    /// by writing, syncing and deleting a small probe file.
+    fn health(&self) -> Result<()> {
+        if let Some(reason) = &self.lock_writer().failed_compaction {
+            return Err(KvsError::StringErr(format!("the last compaction failed: {}", reason)));
+        }
+        let fs = &self.reader.fs;
+        let probe_id = HEALTH_PROBES.fetch_add(1, Ordering::Relaxed);
+        let probe_path = self.reader.path.join(format!("{}.{}.{}", HEALTH_PROBE_FILE, std::process::id(), probe_id));
+        let probe = fs
+            .create(&probe_path)
+            .and_then(|mut file| {
+                file.write_all(b"ok")?;
+                file.sync_all()
+            })
+            .and_then(|_| fs.remove_file(&probe_path));
+        match probe {
+            Ok(()) => Ok(()),
+            Err(e) if matches!(e.kind(), io::ErrorKind::StorageFull | io::ErrorKind::WriteZero) => {
+                Err(KvsError::DiskFull(e))
+            }
+            Err(e) => Err(KvsError::StringErr(format!("the working directory is not writable: {}", e))),
+        }
+    }
+}
+
+/// A point-in-time snapshot of the key/value pairs in a [`KvStore`].
+///
+/// It is an iterator that consumes the snapshot, reading each value from disk as it is reached.
+/// See [`KvStore::snapshot`] for more information.
+#[derive(Debug)]
+pub struct Snapshot {
+    // the keys, and the position of their values, at the time the snapshot was taken
+    entries: std::vec::IntoIter<(Vec<u8>, CommandPos)>,
+    // every snapshot gets its own reader
+    reader: KvsReader,
+    // a handle to the index, used to find values that were moved by a compaction
+    index: Arc<Index>,
+}
+
+impl Snapshot {
+    /// reads the value of `key` at the given `cmd_pos`.
+    /// If the log file containing the value was removed by a compaction, the value is
+    /// looked up again in the index. Returns `None` if the key was removed since the snapshot
+    fn read_value(&self, key: &[u8], cmd_pos: CommandPos) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_set(key, cmd_pos)?.map(|cmd| match cmd {
+            LogCommand::Set { value, .. } => value,
+            _ => unreachable!("read_set only returns set commands"),
+        }))
+    }
+
+    /// reads the set command of `key` at the given `cmd_pos`, with its value decrypted, like
+    /// [`read_value`](Snapshot::read_value). Returns `None` if the key has expired
+    fn read_set(&self, key: &[u8], cmd_pos: CommandPos) -> Result<Option<LogCommand>> {
+        match self.reader.read_command(cmd_pos) {
+            Ok(LogCommand::Set { expires_at: Some(expires_at), .. }) if is_expired(expires_at) => Ok(None),
+            Ok(cmd @ LogCommand::Set { .. }) => Ok(Some(cmd)),
+            Ok(_) => Err(KvsError::InvalidCommand(format!(
+                "invalid command in logs for key: {}",
+                String::from_utf8_lossy(key)
+            ))),
+            Err(e) => match self.index.get(key) {
+                // the value was moved (or the key removed) since the snapshot was taken
+                Some(new_pos) if new_pos != cmd_pos => self.read_set(key, new_pos),
+                None => Ok(None),
+                Some(_) => Err(e),
+            },
+        }
+    }
+}
+
+impl Iterator for Snapshot {
+    type Item = Result<(String, String)>;
+
+    /// # Errors
+    /// `KvsError::Utf8Error` for a key or value that was set with [`KvStore::set_raw`] and is not
+    /// valid UTF-8
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((key, cmd_pos)) = self.entries.next() {
+            match self.read_value(&key, cmd_pos) {
+                Ok(Some(value)) => {
+                    let pair = String::from_utf8(key).and_then(|key| Ok((key, String::from_utf8(value)?)));
+                    return Some(pair.map_err(KvsError::from));
+                }
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.entries.len()))
+    }
+}
+
+/// `KvsReader` maintains a map of readers to all command logs currently in use.
+///
+/// Every `KvStore` instance has its own `KvsReader` and every `KvsReader`
+/// opens the same files separately; so a `KvsReader` can read concurrently through
+/// multiple `KvStore`s in different threads.
+#[derive(Debug)]
+struct KvsReader {
+    path: Arc<PathBuf>,
+
+    // the directories of the command logs
+    logs: Arc<LogDirs>,
+
+    // the file system containing the command logs
+    fs: Arc<dyn FileSystem>,
+
+    // maps a log generation to its reader, and the number of the latest read from it
+    readers: RefCell<BTreeMap<u64, (LogReader, u64)>>,
+
+    // the number of reads made through this reader, used to find the least recently read log
+    reads: Cell<u64>,
+
+    // the maximum number of logs that are kept open
+    max_open_logs: Option<usize>,
+
+    // whether commands are written with a checksum, that is verified when they are read
+    verify_on_read: bool,
+
+    // the memory maps of the logs, if the logs are read through memory maps
+    #[cfg(feature = "mmap")]
+    maps: Option<LogMaps>,
+
+    // encrypts the values written to the logs, and decrypts them when they are read, if the
+    // store is encrypted
+    #[cfg(feature = "crypto")]
+    cipher: Option<Arc<Cipher>>,
+
+    // generation of the latest compaction file
+    latest_compaction_gen: Arc<AtomicU64>,
+}
+
+impl KvsReader {
+
+    /// Removes handles to files that are no longer needed.
+    ///
+    /// Files are no longer needed when their generation number is less than the
+    /// `latest_compaction_gen`. Files will become "stale" after a compaction
+    /// finishes, so there is no point keeping them around, the latest compaction file
+    /// will have the sum of all generational files before it
+    fn remove_stale_handles(&self) {
+        let mut readers = self.readers.borrow_mut();
+        while !readers.is_empty() {
+            let first_gen = *readers.keys().next().unwrap();
+            if self.latest_compaction_gen.load(Ordering::SeqCst) <= first_gen {
+                break;
+            }
+            readers.remove(&first_gen);
+        }
+        #[cfg(feature = "mmap")]
+        if let Some(maps) = &self.maps {
+            let latest_compaction_gen = self.latest_compaction_gen.load(Ordering::SeqCst);
+            maps.borrow_mut().retain(|gen, _map| *gen >= latest_compaction_gen);
+        }
+    }
+
+    /// Read the log file at the given `CommandPos`.
+    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+        where
+            F: FnOnce(io::Take<&mut dyn BufRead>) -> Result<R>,
+    {
+        self.remove_stale_handles();
+
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = self.mapped(cmd_pos)? {
+            let mut mapped: &[u8] = &mapped;
+            return f((&mut mapped as &mut dyn BufRead).take(cmd_pos.len));
+        }
+
+        let mut readers = self.readers.borrow_mut();
+
+        // Open the file if we haven't opened it in this `KvStoreReader`.
+        if !readers.contains_key(&cmd_pos.gen) {
+            // close the least recently read log to make room for this one
+            if self.max_open_logs.is_some_and(|max| readers.len() >= max) {
+                let lru_gen = readers.iter().min_by_key(|(_gen, (_reader, read))| *read).map(|(gen, _)| *gen);
+                if let Some(lru_gen) = lru_gen {
+                    readers.remove(&lru_gen);
+                }
+            }
+            let reader = BufReaderWithPos::new(self.fs.open(&self.logs.log_path(cmd_pos.gen))?)?;
+            readers.insert(cmd_pos.gen, (reader, 0));
+        }
+
+        let read = self.reads.get() + 1;
+        self.reads.set(read);
+        let (reader, last_read) = readers.get_mut(&cmd_pos.gen).unwrap();
+        *last_read = read;
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let cmd_reader = (reader as &mut dyn BufRead).take(cmd_pos.len);
+        f(cmd_reader)
+    }
+
+    /// returns the mapped bytes of the log at the given `CommandPos`, from the start of the
+    /// command to the end of the map, or `None` if the logs aren't read through memory maps
+    #[cfg(feature = "mmap")]
+    fn mapped(&self, cmd_pos: CommandPos) -> Result<Option<std::cell::Ref<'_, [u8]>>> {
+        let Some(maps) = &self.maps else {
+            return Ok(None);
+        };
+        {
+            let mut maps = maps.borrow_mut();
+            let end = cmd_pos.pos + cmd_pos.len;
+            // the log is mapped again if it has grown past the end of its map
+            if maps.get(&cmd_pos.gen).is_none_or(|map| (map.len() as u64) < end) {
+                match self.fs.map(&self.logs.log_path(cmd_pos.gen))? {
+                    Some(map) => maps.insert(cmd_pos.gen, map),
+                    None => return Ok(None),
+                };
+            }
+        }
+        Ok(Some(std::cell::Ref::map(maps.borrow(), |maps| {
+            maps[&cmd_pos.gen].get(cmd_pos.pos as usize..).unwrap_or_default()
+        })))
+    }
+
+    /// Read the log file starting at the given `CommandPos` and deserialize it into a `LogCommand`.
+    /// A link is resolved into a set command, holding the linked value, and the value of a set
+    /// command is decrypted if the store is encrypted
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<LogCommand> {
+        match self.read_log_command(cmd_pos)? {
+            LogCommand::Set { key, value, seq, written_at, tag, expires_at } => {
+                Ok(LogCommand::Set { key, value: self.unseal(value)?, seq, written_at, tag, expires_at })
+            }
+            LogCommand::Link { key, seq, written_at, tag, target } => match self.read_log_command(target)? {
+                LogCommand::Set { value, .. } => {
+                    Ok(LogCommand::Set { key, value: self.unseal(value)?, seq, written_at, tag, expires_at: None })
+                }
+                _ => Err(KvsError::InvalidCommand(format!(
+                    "the link for key: {} does not point at a set command",
+                    String::from_utf8_lossy(&key)
+                ))),
+            },
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// Read the log file starting at the given `CommandPos` and deserialize it into a
+    /// `LogCommand`, exactly as it was written. The checksum of the command is verified if
+    /// the reader verifies reads
+    fn read_log_command(&self, cmd_pos: CommandPos) -> Result<LogCommand> {
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            if self.verify_on_read && skip_whitespace(&mut cmd_reader)? == Some(BINARY_CHECKSUMMED) {
+                let mut record = vec![];
+                cmd_reader.read_to_end(&mut record)?;
+                return LogCommand::read_verified(&record).map_err(|e| {
+                    error!("could not verify command in log {} at {}: {}", cmd_pos.gen, cmd_pos.pos, e);
+                    e
+                });
+            }
+            LogCommand::read_from(&mut cmd_reader)?
+                .ok_or_else(|| KvsError::InvalidCommand("a command position is past the end of its log".to_string()))
+        })
+    }
+
+    /// returns `true` if the command at `cmd_pos` ends past the end of its log, or its log no
+    /// longer exists
+    fn is_dangling(&self, cmd_pos: CommandPos) -> bool {
+        match self.fs.metadata(&self.logs.log_path(cmd_pos.gen)) {
+            Ok(metadata) => metadata.len < cmd_pos.pos + cmd_pos.len,
+            Err(e) => e.kind() == io::ErrorKind::NotFound,
+        }
+    }
+
+    /// returns `true` if the values in the logs are encrypted
+    fn is_encrypted(&self) -> bool {
+        #[cfg(feature = "crypto")]
+        return self.cipher.is_some();
+        #[cfg(not(feature = "crypto"))]
+        false
+    }
+
+    /// encrypts a `value` before it is written to the logs, if the store is encrypted
+    fn seal(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        #[cfg(feature = "crypto")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.encrypt(&value);
+        }
+        Ok(value)
+    }
+
+    /// decrypts a `value` read from the logs, if the store is encrypted
+    fn unseal(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        #[cfg(feature = "crypto")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.decrypt(&value);
+        }
+        Ok(value)
+    }
+
+    /// returns `true` if the keys are stored as their hashes, see [`KvStoreBuilder::hash_keys`]
+    fn hashes_keys(&self) -> bool {
+        #[cfg(feature = "crypto")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.hashes_keys();
+        }
+        false
+    }
+
+    /// returns the key that `key` is stored under in the index and the logs, i.e. its hash if
+    /// the store hashes its keys, or `key` itself
+    fn index_key(&self, key: Vec<u8>) -> Vec<u8> {
+        #[cfg(feature = "crypto")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.hash_key(key);
+        }
+        key
+    }
+
+    /// returns the position of the set command holding the value of the command at `cmd_pos`,
+    /// i.e. the target of a link, or `cmd_pos` itself. The position excludes the checksum
+    /// header of the command, if it has one
+    fn value_pos(&self, cmd_pos: CommandPos) -> Result<CommandPos> {
+        let cmd_pos = self.command_pos(cmd_pos)?;
+        let target = self.read_and(cmd_pos, |mut cmd_reader| match skip_whitespace(&mut cmd_reader)? {
+            Some(BINARY_LINK) => match LogCommand::read_from(&mut cmd_reader)? {
+                Some(LogCommand::Link { target, .. }) => Ok(Some(target)),
+                _ => Err(KvsError::InvalidCommand("a link could not be read".to_string())),
+            },
+            _ => Ok(None),
+        })?;
+        match target {
+            Some(target) => self.command_pos(target),
+            None => Ok(cmd_pos),
+        }
+    }
+
+    /// returns the position of the command at `cmd_pos` without its checksum header, or
+    /// `cmd_pos` itself if the command has no checksum
+    fn command_pos(&self, cmd_pos: CommandPos) -> Result<CommandPos> {
+        self.read_and(cmd_pos, |mut cmd_reader| match skip_whitespace(&mut cmd_reader)? {
+            Some(BINARY_CHECKSUMMED) => Ok(CommandPos::new(
+                cmd_pos.gen,
+                cmd_pos.pos + CHECKSUM_HEADER_LEN,
+                cmd_pos.len - CHECKSUM_HEADER_LEN,
+            )),
+            _ => Ok(cmd_pos),
+        })
+    }
+}
+
+impl Clone for KvsReader {
+    fn clone(&self) -> KvsReader {
+        KvsReader {
+            path: Arc::clone(&self.path),
+            logs: Arc::clone(&self.logs),
+            fs: Arc::clone(&self.fs),
+            latest_compaction_gen: Arc::clone(&self.latest_compaction_gen),
+            // every KvsReader will have their own map of readers
+            readers: RefCell::new(BTreeMap::new()),
+            reads: Cell::new(0),
+            max_open_logs: self.max_open_logs,
+            verify_on_read: self.verify_on_read,
+            #[cfg(feature = "mmap")]
+            maps: self.maps.as_ref().map(|_| LogMaps::default()),
+            #[cfg(feature = "crypto")]
+            cipher: self.cipher.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct KvsWriter {
+    reader: KvsReader,
+    writer: LogWriter,
+
+    // the current log generation number
+    current_gen: u64,
+
+    // the number of bytes representing "stale" commands that could be
+    // deleted during a compaction
+    uncompacted: u64,
+
+    // the number of bytes representing "live" commands, i.e. commands referenced by the index
+    live: u64,
+
+    // determines when a compaction should run
+    compaction_trigger: CompactionTrigger,
+
+    // a compaction will run when there are more than this many log files
+    max_log_files: Option<usize>,
+
+    // the number of log files in the working directory
+    log_files: usize,
+
+    // the path to the directory containing the kvs logs files
+    path: Arc<PathBuf>,
+
+    // the file system containing the command logs
+    fs: Arc<dyn FileSystem>,
+
+    // a handle to the in-memory index
+    index: Arc<Index>,
+
+    // the sequence number of the latest successful write
+    seq: Arc<AtomicU64>,
+
+    // whether the index may hold keys that expire
+    expiring: Arc<AtomicBool>,
+
+    // tracks the order keys are evicted in, if the store has a maximum number of keys
+    eviction: Option<Arc<Eviction>>,
+
+    // the keys in ascending order, if the store has an ordered index
+    ordered: Option<Arc<OrderedKeys>>,
+
+    // the number of successful compactions since the store was opened
+    compactions: u64,
+
+    // the effect of the latest successful compaction
+    last_compaction: Option<CompactionStats>,
+
+    // the reason the latest compaction failed, or `None` if it succeeded
+    failed_compaction: Option<String>,
+
+    // stale log files that could not be deleted by a compaction. Their deletion is retried
+    // at the start of the next compaction
+    undeleted: BTreeSet<PathBuf>,
+
+    // maps a hash of a value to the position of a set command holding the value, if the store
+    // dedups values
+    value_hashes: Option<HashMap<u64, CommandPos>>,
+
+    // whether the index is written to the index snapshot file when the store is closed
+    index_snapshot: bool,
+
+    // whether a compaction is running, so that it can be seen without taking the writer lock
+    compacting: Arc<AtomicBool>,
+
+    // the lock on the working directory, if the store was opened exclusively. The writer is
+    // shared by every clone of the store, so it's released once they are all dropped
+    _lock: Option<FileLock>,
+}
+
+impl KvsWriter {
+
+    /// sets the given `key` and `value` into the `index` and also writes them into
+    /// the log file.
+    /// Returns the sequence number assigned to the write
+    #[instrument]
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, tag: u8) -> Result<u64> {
+        self.set_expiring(key, value, tag, None)
+    }
+
+    /// sets the given `key` and `value`, like [`set`](KvsWriter::set), along with the time the
+    /// key expires, as seconds since the unix epoch. The value of a key that expires is never
+    /// linked to another value.
+    /// Returns the sequence number assigned to the write
+    #[instrument]
+    fn set_expiring(&mut self, key: Vec<u8>, value: Vec<u8>, tag: u8, expires_at: Option<u64>) -> Result<u64> {
+        let value = self.reader.seal(value)?;
+        if expires_at.is_some() {
+            self.expiring.store(true, Ordering::SeqCst);
+        }
+        // make room for a new key if the store is full
+        if let Some(eviction) = self.eviction.clone() {
+            if !self.index.contains_key(&key) {
+                self.evict(&eviction, 1)?;
+            }
+        }
+        // the sequence number of this write. It's only published once the write succeeds
+        let seq = self.seq.load(Ordering::SeqCst) + 1;
+        let written_at = Some(now_millis());
+        // a value that is already in the logs is linked to, rather than written again
+        let hash = (self.value_hashes.is_some() && expires_at.is_none() && value.len() >= DEDUP_MIN_VALUE_LEN)
+            .then(|| hash_value(&value));
+        let target = match hash {
+            Some(hash) => self.find_value(hash, &value)?,
+            None => None,
+        };
+        // create a Set command variant
+        let cmd = match target {
+            Some(target) => LogCommand::Link { key, seq, written_at, tag, target },
+            None => LogCommand::Set { key, value, seq, written_at, tag, expires_at },
+        };
+        // set pos to the current position of the writer which is usually at the end of the log
+        let pos = self.writer.pos;
+        // serialize the command into the log using serde and flush the writer
+        self.append(&cmd)?;
+        if let (Some(hash), None, Some(value_hashes)) = (hash, target, &mut self.value_hashes) {
+            value_hashes.insert(hash, (self.current_gen, pos..self.writer.pos).into());
+        }
+        self.apply(cmd, pos..self.writer.pos);
+        self.seq.store(seq, Ordering::SeqCst);
+
+        self.compact_if_needed();
+
+        Ok(seq)
+    }
+
+    /// sets every key/value pair into the `index`, like [`set`](KvsWriter::set), but writes all
+    /// of their commands to the log before flushing it. Unlike a [`commit`](KvsWriter::commit),
+    /// the commands aren't a batch, so a crash part way through keeps the pairs that were
+    /// written. A value is only linked to a value that was in the logs before the pairs were
+    /// written, if the store dedups values.
+    /// Returns the sequence number assigned to the last write
+    #[instrument(skip(pairs))]
+    fn set_many(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<u64> {
+        // make room for the keys that are added, if the store is full
+        if let Some(eviction) = self.eviction.clone() {
+            let new_keys: HashSet<&[u8]> = pairs
+                .iter()
+                .map(|(key, _value)| key.as_slice())
+                .filter(|key| !self.index.contains_key(key))
+                .collect();
+            self.evict(&eviction, new_keys.len())?;
+        }
+
+        let mut seq = self.seq.load(Ordering::SeqCst);
+        let written_at = Some(now_millis());
+        let mut cmds = Vec::with_capacity(pairs.len());
+        // the hashes of the values that are written rather than linked, if the store dedups values
+        let mut hashes = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            seq += 1;
+            let value = self.reader.seal(value)?;
+            let hash = (self.value_hashes.is_some() && value.len() >= DEDUP_MIN_VALUE_LEN).then(|| hash_value(&value));
+            let target = match hash {
+                Some(hash) => self.find_value(hash, &value)?,
+                None => None,
+            };
+            cmds.push(match target {
+                Some(target) => LogCommand::Link { key, seq, written_at, tag: 0, target },
+                None => LogCommand::Set { key, value, seq, written_at, tag: 0, expires_at: None },
+            });
+            hashes.push(hash.filter(|_hash| target.is_none()));
+        }
+        let checksum = self.reader.verify_on_read;
+        let ranges = self.write_and_flush(|writer| {
+            let mut ranges = Vec::with_capacity(cmds.len());
+            for cmd in &cmds {
+                let pos = writer.pos;
+                cmd.write_record_to(writer, checksum)?;
+                ranges.push(pos..writer.pos);
+            }
+            Ok(ranges)
+        })?;
+
+        for ((cmd, range), hash) in cmds.into_iter().zip(ranges).zip(hashes) {
+            if let (Some(hash), Some(value_hashes)) = (hash, &mut self.value_hashes) {
+                value_hashes.insert(hash, (self.current_gen, range.clone()).into());
+            }
+            self.apply(cmd, range);
+        }
+        self.seq.store(seq, Ordering::SeqCst);
+
+        self.compact_if_needed();
+        Ok(seq)
+    }
+
+    /// remove the given `key` from the index.
+    /// Returns the sequence number assigned to the write
+    #[instrument]
+    fn remove(&mut self, key: Vec<u8>) -> Result<u64> {
+        if self.contains_live_key(&key)? {
+            let seq = self.seq.load(Ordering::SeqCst) + 1;
+            let cmd = LogCommand::Remove { key, seq };
+            let pos = self.writer.pos;
+            // serialze the remove command into the log and flush
+            self.append(&cmd)?;
+            self.apply(cmd, pos..self.writer.pos);
+            self.seq.store(seq, Ordering::SeqCst);
+
+            self.compact_if_needed();
+            Ok(seq)
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// writes the operations of a transaction to the log as a single batch, and then applies
+    /// them to the index. Nothing is written if any of the operations would fail.
+    /// Returns the sequence number assigned to the last write
+    #[instrument(skip(ops))]
+    fn commit(&mut self, ops: Vec<TxOp>) -> Result<u64> {
+        if ops.is_empty() {
+            return Ok(self.seq.load(Ordering::SeqCst));
+        }
+        let count = u32::try_from(ops.len())
+            .map_err(|_| KvsError::StringErr(format!("transactions are limited to {} operations", u32::MAX)))?;
+
+        // every key that is removed must exist, either in the index or earlier in the transaction
+        let mut exists: HashMap<&str, bool> = HashMap::new();
+        for op in &ops {
+            match op {
+                TxOp::Set { key, .. } => {
+                    exists.insert(key, true);
+                }
+                TxOp::Remove { key } => {
+                    let present = match exists.get(key.as_str()) {
+                        Some(&present) => present,
+                        None => self.contains_live_key(key.as_bytes())?,
+                    };
+                    if !present {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    exists.insert(key, false);
+                }
+            }
+        }
+        // make room for the keys the transaction adds, if the store is full
+        if let Some(eviction) = self.eviction.clone() {
+            let new_keys = exists
+                .iter()
+                .filter(|(key, exists)| **exists && !self.index.contains_key(key.as_bytes()))
+                .count();
+            self.evict(&eviction, new_keys)?;
+        }
+
+        let mut seq = self.seq.load(Ordering::SeqCst);
+        let written_at = Some(now_millis());
+        let cmds = ops
+            .into_iter()
+            .map(|op| {
+                seq += 1;
+                Ok(match op {
+                    TxOp::Set { key, value, tag } => LogCommand::Set {
+                        key: key.into_bytes(),
+                        value: self.reader.seal(value.into_bytes())?,
+                        seq,
+                        written_at,
+                        tag,
+                        expires_at: None,
+                    },
+                    TxOp::Remove { key } => LogCommand::Remove { key: key.into_bytes(), seq },
+                })
+            })
+            .collect::<Result<Vec<LogCommand>>>()?;
+        let checksum = self.reader.verify_on_read;
+        let ranges = self.write_and_flush(|writer| {
+            writer.write_all(&[BINARY_BATCH])?;
+            writer.write_all(&count.to_le_bytes())?;
+            let mut ranges = Vec::with_capacity(cmds.len());
+            for cmd in &cmds {
+                let pos = writer.pos;
+                cmd.write_record_to(writer, checksum)?;
+                ranges.push(pos..writer.pos);
+            }
+            Ok(ranges)
+        })?;
+
+        // the batch header can be deleted in the next compaction
+        self.uncompacted += BATCH_HEADER_LEN;
+        for (cmd, range) in cmds.into_iter().zip(ranges) {
+            self.apply(cmd, range);
+        }
+        self.seq.store(seq, Ordering::SeqCst);
+
+        self.compact_if_needed();
+        Ok(seq)
+    }
+
+    /// returns the position of a set command holding the given `value`, whose hash is `hash`,
+    /// or `None` if the value isn't known to be in the logs
+    fn find_value(&self, hash: u64, value: &[u8]) -> Result<Option<CommandPos>> {
+        let Some(&cmd_pos) = self.value_hashes.as_ref().and_then(|hashes| hashes.get(&hash)) else {
+            return Ok(None);
+        };
+        // values with the same hash aren't necessarily the same
+        match self.reader.read_log_command(cmd_pos)? {
+            LogCommand::Set { value: existing, expires_at: None, .. } if existing == value => Ok(Some(cmd_pos)),
+            _ => Ok(None),
+        }
+    }
+
+    /// rebuilds the hashes of the values in the store by reading every value, if the store
+    /// dedups values. The hashes only point at set commands that are referenced by the index
+    fn rebuild_value_hashes(&mut self) -> Result<()> {
+        let Some(value_hashes) = &mut self.value_hashes else {
+            return Ok(());
+        };
+        value_hashes.clear();
+        for cmd_pos in self.index.values() {
+            if let LogCommand::Set { value, expires_at: None, .. } = self.reader.read_log_command(cmd_pos)? {
+                if value.len() >= DEDUP_MIN_VALUE_LEN {
+                    value_hashes.insert(hash_value(&value), cmd_pos);
+                }
+            }
+        }
+        debug!(values = value_hashes.len(), "value hashes rebuilt");
+        Ok(())
+    }
+
+    /// updates the index, and the amount of stale data, with a `cmd` that was written to the
+    /// given `range` of the current log
+    fn apply(&mut self, cmd: LogCommand, range: Range<u64>) {
+        let len = range.end - range.start;
+        match cmd {
+            LogCommand::Set { key, .. } | LogCommand::Link { key, .. } => {
+                // check if the key currently exists in the index, if so, increment
+                // uncompacted with the old.len, as that data is now stale and will be overriden with new key
+                if let Some(old_cmd) = self.index.get(&key) {
+                    self.uncompacted += old_cmd.len;
+                    self.live -= old_cmd.len;
+                }
+                // insert the key along with its CommandPos data
+                self.live += len;
+                if let Some(eviction) = &self.eviction {
+                    eviction.on_write(&key);
+                }
+                if let Some(ordered) = &self.ordered {
+                    ordered.insert(&key);
+                }
+                self.index.insert(key, (self.current_gen, range).into());
+            }
+            LogCommand::Remove { key, .. } => {
+                // update uncompacted with the removed length
+                if let Some(old_cmd) = self.index.remove(&key) {
+                    self.uncompacted += old_cmd.len;
+                    self.live -= old_cmd.len;
+                }
+                if let Some(eviction) = &self.eviction {
+                    eviction.on_remove(&key);
+                }
+                if let Some(ordered) = &self.ordered {
+                    ordered.remove(&key);
+                }
+                // the "remove" command itself can be deleted in the next compaction
+                // so we add its length to `uncompacted`
+                self.uncompacted += len;
+            }
+        }
+    }
+
+    /// removes a `key` whose command at `cmd_pos` points past the end of its log from the
+    /// index, unless the key has since been written again. The command is no longer on disk,
+    /// so it isn't counted as stale data
+    fn remove_dangling(&mut self, key: &[u8], cmd_pos: CommandPos) {
+        if self.index.remove_if(key, |pos| *pos == cmd_pos).is_none() {
+            return;
+        }
+        self.live -= cmd_pos.len;
+        if let Some(eviction) = &self.eviction {
+            eviction.on_remove(key);
+        }
+        if let Some(ordered) = &self.ordered {
+            ordered.remove(key);
+        }
+    }
+
+    /// returns `true` if the index holds `key`, and it hasn't expired. An expired key is removed
+    /// from the index
+    fn contains_live_key(&mut self, key: &[u8]) -> Result<bool> {
+        let Some(cmd_pos) = self.index.get(key) else {
+            return Ok(false);
+        };
+        if self.expiring.load(Ordering::SeqCst) {
+            if let LogCommand::Set { expires_at: Some(expires_at), .. } = self.reader.read_log_command(cmd_pos)? {
+                if is_expired(expires_at) {
+                    self.remove_expired(key, cmd_pos);
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// removes a `key` whose set command at `cmd_pos` has expired from the index, unless the key
+    /// has since been written again. The command is left in the logs as stale data
+    fn remove_expired(&mut self, key: &[u8], cmd_pos: CommandPos) {
+        if self.index.remove_if(key, |pos| *pos == cmd_pos).is_none() {
+            return;
+        }
+        self.uncompacted += cmd_pos.len;
+        self.live -= cmd_pos.len;
+        if let Some(eviction) = &self.eviction {
+            eviction.on_remove(key);
+        }
+        if let Some(ordered) = &self.ordered {
+            ordered.remove(key);
+        }
+    }
+
+    /// estimates the effect of compacting the current log files
+    fn compaction_estimate(&self) -> Result<CompactionEstimate> {
+        let (log_bytes, log_files) = self.log_bytes()?;
+        Ok(CompactionEstimate {
+            log_bytes,
+            live_bytes: self.live,
+            reclaimable_bytes: log_bytes.saturating_sub(self.live),
+            files_deleted: log_files,
+        })
+    }
+
+    /// returns the size and the live keys of every log file, in generation order
+    fn log_info(&self) -> Result<Vec<LogInfo>> {
+        // the number of live keys, and the bytes of their commands, in each generation
+        let mut live: HashMap<u64, (u64, u64)> = HashMap::new();
+        for cmd_pos in self.index.values() {
+            let (keys, bytes) = live.entry(cmd_pos.gen).or_default();
+            *keys += 1;
+            *bytes += cmd_pos.len;
+        }
+        self.reader
+            .logs
+            .gens(&*self.fs)?
+            .into_iter()
+            .map(|gen| {
+                let size = self.fs.metadata(&self.reader.logs.log_path(gen))?.len;
+                let (live_keys, live_bytes) = live.get(&gen).copied().unwrap_or_default();
+                Ok(LogInfo {
+                    gen,
+                    size,
+                    live_keys,
+                    dead_bytes: size.saturating_sub(live_bytes),
+                })
+            })
+            .collect()
+    }
+
+    /// returns the total size, in bytes, of the log files in the working directory, and the
+    /// number of log files
+    fn log_bytes(&self) -> Result<(u64, usize)> {
+        let log_gens = self.reader.logs.gens(&*self.fs)?;
+        let mut log_bytes = 0;
+        for gen in &log_gens {
+            log_bytes += self.fs.metadata(&self.reader.logs.log_path(*gen))?.len;
+        }
+        Ok((log_bytes, log_gens.len()))
+    }
+
+    /// rebuilds the index from every log file in the working directory
+    #[instrument]
+    fn reload_index(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let log_gens = self.reader.logs.gens(&*self.fs)?;
+
+        let fresh = Index::default();
+        let mut uncompacted = 0_u64;
+        let mut seq = read_seq_file(&*self.fs, &self.path)?;
+        let log_starts = log_gens.iter().map(|&gen| (gen, 0)).collect::<Vec<_>>();
+        let mut expiring = false;
+        for (_gen, _reader, loaded) in load_logs(&*self.fs, &self.reader.logs, &log_starts)? {
+            seq = seq.max(loaded.max_seq);
+            expiring |= loaded.expiring;
+            uncompacted += loaded.merge_into(&fresh);
+        }
+        self.expiring.store(expiring, Ordering::SeqCst);
+
+        // the fresh entries are copied into the existing index before the keys that no longer
+        // exist are removed, so that readers never see a key that exists go missing
+        for (key, cmd_pos) in fresh.iter() {
+            let is_new = self.index.insert(key.clone(), cmd_pos).is_none();
+            if let (true, Some(eviction)) = (is_new, &self.eviction) {
+                eviction.on_write(&key);
+            }
+            if let (true, Some(ordered)) = (is_new, &self.ordered) {
+                ordered.insert(&key);
+            }
+        }
+        self.index.retain(|key, _cmd_pos| {
+            let keep = fresh.contains_key(key);
+            if let (false, Some(eviction)) = (keep, &self.eviction) {
+                eviction.on_remove(key);
+            }
+            if let (false, Some(ordered)) = (keep, &self.ordered) {
+                ordered.remove(key);
+            }
+            keep
+        });
+
+        self.uncompacted = uncompacted;
+        self.live = fresh.values().map(|cmd_pos| cmd_pos.len).sum();
+        self.log_files = log_gens.len();
+        self.seq.fetch_max(seq, Ordering::SeqCst);
+        debug!(uncompacted = self.uncompacted, live = self.live, keys = self.index.len(), "index reloaded");
+        self.rebuild_value_hashes()
+    }
+
+    /// removes keys, in the order chosen by the `eviction` policy, until there is room in the
+    /// store for `room` more keys
+    fn evict(&mut self, eviction: &Eviction, room: usize) -> Result<()> {
+        while self.index.len() + room > eviction.max_keys {
+            let Some(victim) = eviction.victim() else {
+                break;
+            };
+            debug!("evicting key {}", String::from_utf8_lossy(&victim));
+            match self.remove(victim.clone()) {
+                // the victim was already removed from the index, so just stop tracking it
+                Err(KvsError::KeyNotFound) => eviction.on_remove(&victim),
+                result => {
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// serializes the given `cmd` to the end of the current log and flushes it.
+    ///
+    /// If the write fails, the log is truncated back to where the command started, so that
+    /// a partially written command doesn't corrupt the log.
+    /// # Errors
+    /// `KvsError::DiskFull` if the storage device is out of space
+    fn append(&mut self, cmd: &LogCommand) -> Result<()> {
+        let checksum = self.reader.verify_on_read;
+        self.write_and_flush(|writer| cmd.write_record_to(writer, checksum))
+    }
+
+    /// runs `write` against the end of the current log and flushes it, truncating the log if
+    /// either fails. See [`append`](KvsWriter::append)
+    fn write_and_flush<T>(&mut self, write: impl FnOnce(&mut LogWriter) -> Result<T>) -> Result<T> {
+        let pos = self.writer.pos;
+        let written = write(&mut self.writer).and_then(|t| {
+            self.writer.flush()?;
+            Ok(t)
+        });
+
+        written.map_err(|e| {
+            if let Err(truncate_err) = self.writer.truncate(pos) {
+                error!("failed to truncate log {} after a failed write: {}", self.current_gen, truncate_err);
+            }
+            let is_full = |kind| matches!(kind, io::ErrorKind::StorageFull | io::ErrorKind::WriteZero);
+            match e {
+                KvsError::Io { source } if is_full(source.kind()) => KvsError::DiskFull(source),
+                KvsError::Serialization(e) if e.io_error_kind().is_some_and(is_full) => {
+                    KvsError::DiskFull(e.into())
+                }
+                e => e,
+            }
+        })
+    }
+
+    /// returns `true` if the amount of stale data has crossed the configured
+    /// [`CompactionTrigger`], or if there are more than `max_log_files` log files
+    fn needs_compaction(&self) -> bool {
+        if self.max_log_files.is_some_and(|max| self.log_files > max) {
+            return true;
+        }
+        match self.compaction_trigger {
+            CompactionTrigger::Bytes(threshold) => self.uncompacted > threshold,
+            CompactionTrigger::Ratio(ratio) => {
+                self.uncompacted > 0 && self.uncompacted as f64 > ratio * self.live as f64
+            }
+        }
+    }
+
+    /// Clears stale entries in the log, see [`compact_logs`](KvsWriter::compact_logs).
+    fn compact(&mut self) -> Result<()> {
+        self.compacting.store(true, Ordering::SeqCst);
+        let compacted = self.compact_logs();
+        self.compacting.store(false, Ordering::SeqCst);
+        if let Err(e) = &compacted {
+            self.failed_compaction = Some(e.to_string());
+        }
+        compacted
+    }
+
+    /// runs a compaction after a write, if one is needed. The write has already been made, so
+    /// a failed compaction isn't returned to the writer, it's logged and reported by
+    /// [`KvStore::health`] until a compaction succeeds
+    fn compact_if_needed(&mut self) {
+        if self.needs_compaction() {
+            if let Err(e) = self.compact() {
+                warn!("compaction after a write failed: {}", e);
+            }
+        }
+    }
+
+    /// Clears stale entries in the log.
+    ///
+    /// Compaction is transactional: the live commands are copied into a new compaction file,
+    /// which is fully written and synced to disk before the index is updated to point at it and
+    /// before any stale log files are deleted. The manifest is updated to list the compaction
+    /// file and the new current log before the stale logs are deleted. If compaction fails, the
+    /// partial compaction file is deleted and the index (and the existing log files) are left
+    /// untouched.
+    #[instrument]
+    fn compact_logs(&mut self) -> Result<()> {
+        // current_gen + 1 is for the compaction file, current_gen + 2 will be the new current log
+        let compaction_gen = self.current_gen + 1;
+        debug!("compaction started, compaction_gen={}, current_gen={}", &compaction_gen, self.current_gen + 2);
+        let started = Instant::now();
+        let (bytes_before, _log_files) = self.log_bytes()?;
+
+        // the readers have had a chance to close their handles to the logs left behind by the
+        // previous compaction
+        let mut files_deleted = 0;
+        let undeleted = std::mem::take(&mut self.undeleted);
+        for file_path in undeleted {
+            if self.remove_stale_log(&file_path) {
+                files_deleted += 1;
+            } else {
+                self.undeleted.insert(file_path);
+            }
+        }
+
+        let new_positions = match self.write_compaction_file(compaction_gen) {
+            Ok(new_positions) => new_positions,
+            Err(e) => {
+                error!("compaction failed, rolling back: {}", e);
+                let file_path = self.reader.logs.log_path(compaction_gen);
+                if let Err(e) = self.fs.remove_file(&file_path) {
+                    error!("{:?} cannot be deleted: {}", file_path, e);
+                }
+                return Err(e);
+            }
+        };
+
+        // the compaction file is durable, so it is now safe to swap the index pointers to it
+        self.current_gen += 2;
+        let manifest = Manifest {
+            gens: vec![compaction_gen, self.current_gen],
+            compaction_gen,
+        };
+        update_manifest(&*self.fs, &self.path, &manifest)?;
+        let keys_rewritten = new_positions.len() as u64;
+        let mut new_pos = 0;
+        for (key, cmd_pos) in new_positions {
+            new_pos += cmd_pos.len;
+            self.index.insert(key, cmd_pos);
+        }
+
+        self.reader
+            .latest_compaction_gen
+            .store(compaction_gen, Ordering::SeqCst);
+        self.reader.remove_stale_handles();
+
+        // remove stale log files
+        // Note that actually these files are not deleted immediately because `KvStoreReader`s
+        // still keep open file handles. When `KvStoreReader` is used next time, it will clear
+        // its stale file handles. On Unix, the files will be deleted after all the handles
+        // are closed. On Windows, the deletions below may fail, in which case they are
+        // retried with a backoff, and then again at the start of the next compaction.
+
+        let stale_gens = self.reader.logs.gens(&*self.fs)?;
+        for stale_gen in stale_gens.into_iter().filter(|&gen| gen < compaction_gen) {
+            let file_path = self.reader.logs.log_path(stale_gen);
+            debug!("{:?} marked as stale", &file_path);
+            if self.undeleted.contains(&file_path) {
+                continue;
+            }
+            if self.remove_stale_log(&file_path) {
+                files_deleted += 1;
+            } else {
+                self.undeleted.insert(file_path);
+            }
+        }
+        self.uncompacted = 0;
+        self.live = new_pos;
+        let (bytes_after, log_files) = self.log_bytes()?;
+        self.log_files = log_files;
+        self.compactions += 1;
+        self.failed_compaction = None;
+        let duration = started.elapsed();
+        info!(
+            duration_ms = duration.as_millis() as u64,
+            bytes_before,
+            bytes_after,
+            files_deleted,
+            keys_rewritten,
+            "compaction finished"
+        );
+        self.last_compaction = Some(CompactionStats {
+            duration_micros: duration.as_micros() as u64,
+            bytes_before,
+            bytes_after,
+            files_deleted,
+            keys_rewritten,
+        });
+        // the values moved to the compaction file
+        self.rebuild_value_hashes()
+    }
+
+    /// deletes the stale log at `file_path`, retrying with a backoff if the deletion fails.
+    /// Returns `false` if the log could not be deleted after `STALE_LOG_DELETE_ATTEMPTS`
+    fn remove_stale_log(&self, file_path: &Path) -> bool {
+        let mut backoff = STALE_LOG_DELETE_BACKOFF;
+        for attempt in 1..=STALE_LOG_DELETE_ATTEMPTS {
+            match self.fs.remove_file(file_path) {
+                Ok(()) => return true,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return true,
+                Err(e) if attempt < STALE_LOG_DELETE_ATTEMPTS => {
+                    warn!("{:?} cannot be deleted, retrying in {:?} (attempt {}): {}", file_path, backoff, attempt, e);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    error!("{:?} cannot be deleted, it will be retried by the next compaction: {}", file_path, e);
+                }
+            }
+        }
+        false
+    }
+
+    /// copies all live commands into a new log file with the given `compaction_gen`, syncs it
+    /// to disk, and then switches this writer to a new current log file with a generation
+    /// after the compaction file.
+    /// Returns the new positions of every key within the compaction file. The index is not
+    /// modified.
+    ///
+    /// The commands are written in key order, so compacting the same data always writes a
+    /// byte-identical compaction file.
+    ///
+    /// Set commands are copied as is, but a shared value must be kept for as long as any key
+    /// links to it, even if the key that set it has since been overwritten or removed. So the
+    /// first key found that links to a value that hasn't been copied is rewritten as a set
+    /// command holding the value, and every other key that shares the value links to that.
+    fn write_compaction_file(&mut self, compaction_gen: u64) -> Result<Vec<(Vec<u8>, CommandPos)>> {
+        let mut compaction_writer = self.reader.logs.create(&*self.fs, compaction_gen, false)?;
+        let checksum = self.reader.verify_on_read;
+
+        // the index's iteration order is random, so the keys are sorted
+        let mut entries: Vec<(Vec<u8>, CommandPos)> = self.index.iter().collect();
+        entries.sort_unstable_by(|(key, _), (other_key, _)| key.cmp(other_key));
+
+        let mut new_positions = Vec::with_capacity(entries.len());
+        // maps the position of a set command in the old logs, to the position of the set command
+        // in the compaction file that now holds its value
+        let mut moved: HashMap<(u64, u64), CommandPos> = HashMap::new();
+        for (key, cmd_pos) in entries {
+            let pos = compaction_writer.pos;
+            let copied = match moved.get(&(cmd_pos.gen, cmd_pos.pos)) {
+                // the value of this set command was already written by a key that links to it
+                Some(&target) => match self.reader.read_log_command(cmd_pos)? {
+                    LogCommand::Set { key, seq, written_at, tag, .. } => {
+                        LogCommand::Link { key, seq, written_at, tag, target }
+                            .write_record_to(&mut compaction_writer, checksum)?;
+                        true
+                    }
+                    _ => return Err(KvsError::InvalidCommand(format!(
+                        "invalid command in logs for key: {}",
+                        String::from_utf8_lossy(&key)
+                    ))),
+                },
+                None => self.reader.read_and(cmd_pos, |mut entry_reader| {
+                    match skip_whitespace(&mut entry_reader)? {
+                        Some(BINARY_LINK) => return Ok(false),
+                        Some(BINARY_CHECKSUMMED) => {
+                            // the command is copied along with its checksum, unless it's a link
+                            let mut record = vec![];
+                            entry_reader.read_to_end(&mut record)?;
+                            if record.get(CHECKSUM_HEADER_LEN as usize) == Some(&BINARY_LINK) {
+                                return Ok(false);
+                            }
+                            compaction_writer.write_all(&record)?;
+                        }
+                        _ => {
+                            io::copy(&mut entry_reader, &mut compaction_writer)?;
+                        }
+                    }
+                    Ok(true)
+                })?,
+            };
+            if copied {
+                moved.entry((cmd_pos.gen, cmd_pos.pos)).or_insert((compaction_gen, pos..compaction_writer.pos).into());
+            } else if let LogCommand::Link { key, seq, written_at, tag, target } = self.reader.read_log_command(cmd_pos)? {
+                match moved.get(&(target.gen, target.pos)) {
+                    Some(&new_target) => {
+                        LogCommand::Link { key, seq, written_at, tag, target: new_target }
+                            .write_record_to(&mut compaction_writer, checksum)?
+                    }
+                    None => {
+                        let LogCommand::Set { value, .. } = self.reader.read_log_command(target)? else {
+                            return Err(KvsError::InvalidCommand(format!(
+                                "the link for key: {} does not point at a set command",
+                                String::from_utf8_lossy(&key)
+                            )));
+                        };
+                        LogCommand::Set { key, value, seq, written_at, tag, expires_at: None }
+                            .write_record_to(&mut compaction_writer, checksum)?;
+                        moved.insert((target.gen, target.pos), (compaction_gen, pos..compaction_writer.pos).into());
+                    }
+                }
+            }
+            new_positions.push((key, (compaction_gen, pos..compaction_writer.pos).into()));
+        }
+        compaction_writer.flush()?;
+        compaction_writer.sync_all()?;
+        // the commands holding the latest sequence numbers may not survive the compaction,
+        // so the high-water mark is recorded separately
+        write_seq_file(&*self.fs, &self.path, self.seq.load(Ordering::SeqCst))?;
+
+        // new writes go to a generation after the compaction file, so they take precedence
+        // over it when the logs are loaded
+        self.writer = self.reader.logs.create(&*self.fs, compaction_gen + 1, true)?;
+        Ok(new_positions)
+    }
+
+    /// writes the index to the [`INDEX_SNAPSHOT_FILE`], along with the size of every live log.
+    /// The current log must have been flushed
+    fn write_index_snapshot(&self) -> Result<()> {
+        let started = Instant::now();
+        let log_gens = match read_manifest(&*self.fs, &self.path, &self.reader.logs)? {
+            Some(manifest) => manifest.gens,
+            None => self.reader.logs.gens(&*self.fs)?,
+        };
+        let mut logs = Vec::with_capacity(log_gens.len());
+        for gen in log_gens {
+            logs.push((gen, self.fs.metadata(&self.reader.logs.log_path(gen))?.len));
+        }
+        IndexSnapshot::write(
+            &*self.fs,
+            &self.path,
+            self.seq.load(Ordering::SeqCst),
+            self.uncompacted,
+            &logs,
+            &self.index,
+        )?;
+        debug!(keys = self.index.len(), duration_ms = started.elapsed().as_millis() as u64, "index snapshot written");
+        Ok(())
+    }
+}
+
+impl Drop for KvsWriter {
+    /// The writer is shared by every clone of a [`KvStore`], so this only runs when the last
+    /// clone is dropped. Any buffered writes are flushed and the current log is synced to disk,
+    /// and then the index snapshot is written, if the store snapshots its index.
+    fn drop(&mut self) {
+        match self.writer.sync_all() {
+            Err(e) => error!("failed to sync log {} on drop: {}", self.current_gen, e),
+            Ok(()) if self.index_snapshot && self.expiring.load(Ordering::SeqCst) => {
+                debug!("the index snapshot isn't written, as the store may hold keys that expire");
+            }
+            Ok(()) if self.index_snapshot => {
+                if let Err(e) = self.write_index_snapshot() {
+                    error!("failed to write the index snapshot on drop: {}", e);
+                }
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+/// Tracks the order in which the keys of a [`KvStore`] with a maximum number of keys will be
+/// evicted, according to its [`EvictionPolicy`].
+#[derive(Debug)]
+struct Eviction {
+    max_keys: usize,
+    policy: EvictionPolicy,
+    order: Mutex<KeyOrder>,
+}
+
+impl Eviction {
+    fn new(max_keys: usize, policy: EvictionPolicy) -> Self {
+        Eviction {
+            max_keys,
+            policy,
+            order: Mutex::new(KeyOrder::default()),
+        }
+    }
+
+    /// records that `key` was read
+    fn on_read(&self, key: &[u8]) {
+        if self.policy == EvictionPolicy::Lru {
+            self.lock_order().touch(key);
+        }
+    }
+
+    /// records that `key` was set
+    fn on_write(&self, key: &[u8]) {
+        let mut order = self.lock_order();
+        match self.policy {
+            EvictionPolicy::Lru => order.touch(key),
+            EvictionPolicy::Fifo => order.insert(key),
+        }
+    }
+
+    /// records that `key` was removed
+    fn on_remove(&self, key: &[u8]) {
+        self.lock_order().remove(key);
+    }
+
+    /// returns the key that should be evicted next
+    fn victim(&self) -> Option<Vec<u8>> {
+        self.lock_order().first()
+    }
+
+    /// the key order is only used to pick eviction victims, so a panic while it was locked
+    /// can't leave it in a state that's worth propagating
+    fn lock_order(&self) -> MutexGuard<'_, KeyOrder> {
+        self.order.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// An ordered set of keys, that can cheaply move a key to the back of the order.
+#[derive(Debug, Default)]
+struct KeyOrder {
+    // the tick that will be assigned to the next key moved to the back of the order
+    next_tick: u64,
+    // maps a key to its current tick
+    ticks: HashMap<Vec<u8>, u64>,
+    // the keys, ordered by their tick
+    keys: BTreeMap<u64, Vec<u8>>,
+}
+
+impl KeyOrder {
+    /// moves `key` to the back of the order, adding it if it isn't in the order
+    fn touch(&mut self, key: &[u8]) {
+        self.remove(key);
+        self.insert(key);
+    }
+
+    /// adds `key` to the back of the order, if it isn't already in the order
+    fn insert(&mut self, key: &[u8]) {
+        if !self.ticks.contains_key(key) {
+            self.ticks.insert(key.to_owned(), self.next_tick);
+            self.keys.insert(self.next_tick, key.to_owned());
+            self.next_tick += 1;
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.keys.remove(&tick);
+        }
+    }
+
+    /// returns the key at the front of the order
+    fn first(&self) -> Option<Vec<u8>> {
+        self.keys.values().next().cloned()
+    }
+}
+
+/// The keys of a [`KvStore`] in ascending (byte) order, used to page through the keys.
+#[derive(Debug)]
+struct OrderedKeys {
+    keys: RwLock<BTreeSet<Vec<u8>>>,
+}
+
+impl OrderedKeys {
+    fn new(keys: impl Iterator<Item = Vec<u8>>) -> Self {
+        OrderedKeys {
+            keys: RwLock::new(keys.collect()),
+        }
+    }
+
+    fn insert(&self, key: &[u8]) {
+        let mut keys = self.keys.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !keys.contains(key) {
+            keys.insert(key.to_vec());
+        }
+    }
+
+    fn remove(&self, key: &[u8]) {
+        self.keys.write().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(key);
+    }
+
+    /// returns the keys that start with `prefix`, in ascending order
+    fn with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        let keys = self.keys.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        keys.range(prefix.to_vec()..)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// returns a page of at most `limit` keys that come `after` the given key, skipping keys
+    /// that aren't valid UTF-8
+    fn page(&self, after: Option<Vec<u8>>, limit: usize) -> (Vec<String>, Option<String>) {
+        let keys = self.keys.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let page = keys
+            .range((start, Bound::Unbounded))
+            .filter_map(|key| String::from_utf8(key.clone()).ok());
+        page_keys(page, limit)
+    }
+}
+
+/// A cache of the values of the most recently read keys, see [`KvStoreBuilder::value_cache`].
+///
+/// Every cached value records the position of the command it was read from, and is only
+/// returned while the index still points at that command. So a value that was overwritten,
+/// removed, or moved by a compaction is never returned, and is replaced by the key's next read.
+#[derive(Debug)]
+struct ValueCache {
+    max_values: usize,
+    entries: Mutex<CachedValues>,
+}
+
+/// The values held by a [`ValueCache`], and the order they were read in.
+#[derive(Debug, Default)]
+struct CachedValues {
+    // maps a key to the position of the command its value and tag were read from
+    values: HashMap<Vec<u8>, (CommandPos, Vec<u8>, u8)>,
+    // the keys in the order they were last read
+    order: KeyOrder,
+}
+
+impl ValueCache {
+    fn new(max_values: usize) -> Self {
+        ValueCache {
+            max_values,
+            entries: Mutex::new(CachedValues::default()),
+        }
+    }
+
+    /// returns the cached value and tag of `key`, if they were read from the command at `cmd_pos`
+    fn get(&self, key: &[u8], cmd_pos: CommandPos) -> Option<(Vec<u8>, u8)> {
+        let mut entries = self.lock_entries();
+        let entries = &mut *entries;
+        match entries.values.get(key) {
+            Some((cached_pos, value, tag)) if *cached_pos == cmd_pos => {
+                entries.order.touch(key);
+                Some((value.clone(), *tag))
+            }
+            _ => None,
+        }
+    }
+
+    /// caches the `value` and `tag` of `key` read from the command at `cmd_pos`, evicting the
+    /// least recently read values if the cache is full
+    fn insert(&self, key: &[u8], cmd_pos: CommandPos, value: &[u8], tag: u8) {
+        let mut entries = self.lock_entries();
+        entries.values.insert(key.to_vec(), (cmd_pos, value.to_vec(), tag));
+        entries.order.touch(key);
+        while entries.values.len() > self.max_values {
+            let Some(victim) = entries.order.first() else {
+                break;
+            };
+            entries.order.remove(&victim);
+            entries.values.remove(&victim);
+        }
+    }
+
+    /// every cached value is checked against the index before it's returned, so a panic while
+    /// the cache was locked can't make it return a stale value
+    fn lock_entries(&self) -> MutexGuard<'_, CachedValues> {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The commands loaded from a single log file.
+///
+/// Log files are loaded independently of each other, so each `LoadedLog` is a partial index
+/// that must be merged into the store's index in generation order.
+#[derive(Debug)]
+struct LoadedLog {
+    // maps a key to the position of its latest set command in the log, or `None` if the
+    // latest command for the key was a remove
+    commands: HashMap<Vec<u8>, Option<CommandPos>>,
+    // the number of bytes within the log that could be compacted
+    uncompacted: u64,
+    // the largest write sequence number found in the log
+    max_seq: u64,
+    // whether any of the log's sets expire, and hadn't expired when it was loaded
+    expiring: bool,
+}
+
+impl LoadedLog {
+    /// merges the commands of this log into the store's `index`, replacing the commands from
+    /// any earlier generations.
+    /// Returns the total amount of bytes in this log, and the earlier logs, that could be compacted
+    fn merge_into(self, index: &Index) -> u64 {
+        let mut uncompacted = self.uncompacted;
+        for (key, cmd_pos) in self.commands {
+            let old_command = match cmd_pos {
+                Some(cmd_pos) => index.insert(key, cmd_pos),
+                None => index.remove(&key),
+            };
+            if let Some(old_command) = old_command {
+                uncompacted += old_command.len;
+            }
+        }
+        uncompacted
+    }
+}
+
+/// opens and loads the log files in the given `logs` directories of the file system `fs`.
+/// `log_starts` holds the generation of each log, and the position in the log to load it
+/// from. The logs are loaded in parallel, each producing a [`LoadedLog`] that must be merged
+/// into the index in generation order
+fn load_logs(
+    fs: &dyn FileSystem,
+    logs: &LogDirs,
+    log_starts: &[(u64, u64)],
+) -> Result<Vec<(u64, LogReader, LoadedLog)>> {
+    log_starts
+        .par_iter()
+        .map(|&(gen, start)| {
+            let mut reader = BufReaderWithPos::new(fs.open(&logs.log_path(gen))?)?;
+            let loaded = load(gen, &mut reader, start)?;
+            Ok((gen, reader, loaded))
+        })
+        .collect()
+}
+
+/// loads the commands from the given reader, starting at the position `start`, into a
+/// [`LoadedLog`]. `gen` is the generation number of the log file being read by `reader`
+///
+/// # Errors
+/// IO Errors will be returned if any log file could not be opened/read
+fn load(gen: u64, reader: &mut LogReader, start: u64) -> Result<LoadedLog> {
+    reader.seek(SeekFrom::Start(start))?;
+    let mut commands: HashMap<Vec<u8>, Option<CommandPos>> = HashMap::new();
+    let mut uncompacted = 0_u64;
+    let mut max_seq = 0_u64;
+    let mut expiring = false;
+
+    // commands may be separated by whitespace, which isn't part of either command
+    while let Some(first) = skip_whitespace(reader)? {
+        // the commands of a batch follow its header, the header itself can be compacted
+        let batch_len = if first == BINARY_BATCH {
+            reader.consume(1);
+            uncompacted += BATCH_HEADER_LEN;
+            read_u32(reader)?
+        } else {
+            1
+        };
+        for _ in 0..batch_len {
+            let pos = reader.pos;
+            let command = LogCommand::read_from(reader)?.ok_or_else(|| {
+                KvsError::InvalidCommand(format!("log {} ends with an incomplete batch", gen))
+            })?;
+            let length = reader.pos - pos; // length of the command
+            match command {
+                // an expired key is loaded as if it was removed
+                LogCommand::Set { key, seq, expires_at: Some(expires_at), .. } if is_expired(expires_at) => {
+                    if let Some(Some(old_command)) = commands.insert(key, None) {
+                        uncompacted += old_command.len;
+                    }
+                    uncompacted += length;
+                    max_seq = max_seq.max(seq);
+                }
+                LogCommand::Set { key, seq, expires_at, .. } => {
+                    expiring |= expires_at.is_some();
+                    if let Some(Some(old_command)) =
+                    commands.insert(key, Some(CommandPos::new(gen, pos, length)))
+                    {
+                        uncompacted += old_command.len;
+                    }
+                    max_seq = max_seq.max(seq);
+                }
+                LogCommand::Link { key, seq, .. } => {
+                    if let Some(Some(old_command)) =
+                    commands.insert(key, Some(CommandPos::new(gen, pos, length)))
+                    {
+                        uncompacted += old_command.len;
+                    }
+                    max_seq = max_seq.max(seq);
+                }
+                LogCommand::Remove { key, seq } => {
+                    if let Some(Some(old_command)) = commands.insert(key, None) {
+                        uncompacted += old_command.len;
+                    }
+                    // this "remove" command itself can be deleted in the next compaction
+                    uncompacted += length;
+                    max_seq = max_seq.max(seq);
+                }
+            }
+        }
+    }
+
+    Ok(LoadedLog { commands, uncompacted, max_seq, expiring })
+}
+
+/// returns the hash of a `value`, used to find values that are already in the logs. The hash
+/// is never persisted, so it doesn't need to be stable across versions of Rust
+fn hash_value(value: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// returns the current time as the number of milliseconds since the unix epoch
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// returns `true` if a key that expires at `expires_at`, in seconds since the unix epoch, has
+/// expired
+fn is_expired(expires_at: u64) -> bool {
+    now_millis() / 1000 >= expires_at
+}
+
+/// reads the write sequence high-water mark from the [`SEQ_FILE`] in the given `dir`.
+/// Returns 0 if the file does not exist
+///
+/// # Errors
+/// returns an IO Error if the file could not be read, or [`KvsError::Parsing`] if the
+/// file contents are not a valid integer
+fn read_seq_file(fs: &dyn FileSystem, dir: &Path) -> Result<u64> {
+    let read = fs.open(&dir.join(SEQ_FILE)).and_then(|mut file| {
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(contents)
+    });
+    match read {
+        Ok(contents) => contents.trim().parse::<u64>().map_err(|_| {
+            KvsError::Parsing(format!("could not parse the sequence file contents: {} into a u64", &contents))
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// atomically writes the given write sequence high-water mark into the [`SEQ_FILE`] of the
+/// given `dir`, by writing a temporary file and then renaming it. The temporary file is synced
+/// before the rename, as a compaction deletes the logs holding the sequence numbers once this
+/// returns
+fn write_seq_file(fs: &dyn FileSystem, dir: &Path, seq: u64) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", SEQ_FILE));
+    let mut file = fs.create(&tmp_path)?;
+    file.write_all(seq.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs.rename(&tmp_path, &dir.join(SEQ_FILE))?;
+    Ok(())
+}
+
+/// The generations of the live command logs, recorded in the [`MANIFEST_FILE`] of a working
+/// directory whenever the set of logs changes, so that the logs don't need to be searched for
+/// when a store is opened.
+///
+/// The file holds the CRC32 of the manifest's JSON, as 8 hex digits, followed by a space and
+/// the JSON itself.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Manifest {
+    /// the generations of the live logs, in ascending order. The last one is the current log
+    gens: Vec<u64>,
+    /// the generation of the latest compaction file, or 0 if the logs haven't been compacted
+    /// since the manifest was created
+    compaction_gen: u64,
+}
+
+/// reads the [`Manifest`] in the given `dir`, checking that the logs it lists exist in the
+/// `logs` directories.
+/// Returns `None` if there is no manifest, or if it can't be trusted because it is corrupt or
+/// lists a log that doesn't exist, in which case the logs must be searched for instead
+///
+/// # Errors
+/// returns an IO Error if the manifest exists but could not be read
+fn read_manifest(fs: &dyn FileSystem, dir: &Path, logs: &LogDirs) -> Result<Option<Manifest>> {
+    let mut contents = String::new();
+    match fs.open(&dir.join(MANIFEST_FILE)) {
+        Ok(mut file) => file.read_to_string(&mut contents)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let manifest = contents.trim_end().split_once(' ').and_then(|(crc, json)| {
+        let expected = u32::from_str_radix(crc, 16).ok()?;
+        if crc32fast::hash(json.as_bytes()) != expected {
+            return None;
+        }
+        serde_json::from_str::<Manifest>(json).ok()
+    });
+    let Some(manifest) = manifest else {
+        warn!("the manifest is corrupt, searching for the logs instead");
+        return Ok(None);
+    };
+    for gen in &manifest.gens {
+        if let Err(e) = fs.metadata(&logs.log_path(*gen)) {
+            warn!("log {} in the manifest can't be read, searching for the logs instead: {}", gen, e);
+            return Ok(None);
+        }
+    }
+    Ok(Some(manifest))
+}
+
+/// atomically writes the given `manifest` into the [`MANIFEST_FILE`] of the given `dir`, by
+/// writing and syncing a temporary file and then renaming it.
+///
+/// A manifest that is out of date would hide the logs it doesn't list, so if the new manifest
+/// can't be written the old one is removed, and the logs are searched for when the store is
+/// next opened.
+///
+/// # Errors
+/// returns an IO Error if the manifest could neither be written nor removed
+fn update_manifest(fs: &dyn FileSystem, dir: &Path, manifest: &Manifest) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    let written = serde_json::to_string(manifest).map_err(KvsError::from).and_then(|json| {
+        let mut file = fs.create(&tmp_path)?;
+        write!(file, "{:08x} {}", crc32fast::hash(json.as_bytes()), json)?;
+        file.sync_all()?;
+        fs.rename(&tmp_path, &dir.join(MANIFEST_FILE))?;
+        Ok(())
+    });
+    if let Err(e) = written {
+        warn!("could not write the manifest, removing it: {}", e);
+        match fs.remove_file(&dir.join(MANIFEST_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A snapshot of the index of a store, kept in the [`INDEX_SNAPSHOT_FILE`] of its working
+/// directory (see [`KvStoreBuilder::index_snapshot`]).
+///
+/// The file holds, in little endian: the write sequence number, the number of bytes that could
+/// be compacted, the number of logs covered followed by the generation and size of each, the
+/// number of keys followed by each length prefixed key and the generation, position and length
+/// of its command, and finally the CRC32 of everything before it.
+#[derive(Debug)]
+struct IndexSnapshot {
+    // the sequence number of the latest write
+    seq: u64,
+    // the number of bytes within the logs that could be compacted
+    uncompacted: u64,
+    // the generation and size of every live log when the snapshot was written
+    logs: Vec<(u64, u64)>,
+    // every key of the index, and the position of its command
+    entries: Vec<(Vec<u8>, CommandPos)>,
+}
+
+impl IndexSnapshot {
+    /// reads the snapshot in the given `dir`. Returns `None` if there is no snapshot, or if it
+    /// can't be read or is corrupt, in which case the logs must be read instead
+    fn read(fs: &dyn FileSystem, dir: &Path) -> Option<IndexSnapshot> {
+        let mut contents = vec![];
+        match fs.open(&dir.join(INDEX_SNAPSHOT_FILE)).and_then(|mut file| file.read_to_end(&mut contents)) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("the index snapshot can't be read: {}", e);
+                return None;
+            }
+        }
+        let snapshot = contents.len().checked_sub(4).and_then(|len| {
+            let (mut body, mut crc) = contents.split_at(len);
+            if read_u32(&mut crc).ok()? != crc32fast::hash(body) {
+                return None;
+            }
+            IndexSnapshot::read_body(&mut body).ok()
+        });
+        if snapshot.is_none() {
+            warn!("the index snapshot is corrupt");
+        }
+        snapshot
+    }
+
+    fn read_body(body: &mut &[u8]) -> io::Result<IndexSnapshot> {
+        let seq = read_u64(body)?;
+        let uncompacted = read_u64(body)?;
+        let logs = (0..read_u32(body)?)
+            .map(|_| Ok((read_u64(body)?, read_u64(body)?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let entries = (0..read_u64(body)?)
+            .map(|_| {
+                let key = read_key(body)?;
+                let cmd_pos = CommandPos::new(read_u64(body)?, read_u64(body)?, read_u64(body)?);
+                Ok((key, cmd_pos))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(IndexSnapshot { seq, uncompacted, logs, entries })
+    }
+
+    /// returns the generation of each of the live `log_gens` that have commands the snapshot
+    /// doesn't cover, and the position to load each of them from. Returns `None` if the
+    /// snapshot is stale, because the logs it covers are no longer the oldest live logs, or
+    /// because one of them other than the newest has changed size
+    fn tail(&self, fs: &dyn FileSystem, logs: &LogDirs, log_gens: &[u64]) -> Option<Vec<(u64, u64)>> {
+        let ((newest_gen, newest_len), covered) = self.logs.split_last()?;
+        if log_gens.len() < self.logs.len() || self.logs.iter().zip(log_gens).any(|((gen, _len), live)| gen != live) {
+            return None;
+        }
+        let log_len = |gen: u64| fs.metadata(&logs.log_path(gen)).ok().map(|metadata| metadata.len);
+        if covered.iter().any(|&(gen, len)| log_len(gen) != Some(len)) || log_len(*newest_gen)? < *newest_len {
+            return None;
+        }
+        let later_logs = log_gens[self.logs.len()..].iter().map(|&gen| (gen, 0));
+        Some(std::iter::once((*newest_gen, *newest_len)).chain(later_logs).collect())
+    }
+
+    /// atomically writes a snapshot of the `index` into the given `dir`, by writing and syncing
+    /// a temporary file and then renaming it
+    fn write(
+        fs: &dyn FileSystem,
+        dir: &Path,
+        seq: u64,
+        uncompacted: u64,
+        logs: &[(u64, u64)],
+        index: &Index,
+    ) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", INDEX_SNAPSHOT_FILE));
+        let mut writer = CrcWriter { inner: BufWriter::new(fs.create(&tmp_path)?), hasher: crc32fast::Hasher::new() };
+        writer.write_all(&seq.to_le_bytes())?;
+        writer.write_all(&uncompacted.to_le_bytes())?;
+        writer.write_all(&(logs.len() as u32).to_le_bytes())?;
+        for (gen, len) in logs {
+            writer.write_all(&gen.to_le_bytes())?;
+            writer.write_all(&len.to_le_bytes())?;
+        }
+        // the store is closing, so the index can't change while it's written
+        writer.write_all(&(index.len() as u64).to_le_bytes())?;
+        for (key, cmd_pos) in index.iter() {
+            writer.write_all(&(key.len() as u32).to_le_bytes())?;
+            writer.write_all(&key)?;
+            writer.write_all(&cmd_pos.gen.to_le_bytes())?;
+            writer.write_all(&cmd_pos.pos.to_le_bytes())?;
+            writer.write_all(&cmd_pos.len.to_le_bytes())?;
+        }
+        let crc = writer.hasher.finalize();
+        let mut file = writer.inner.into_inner().map_err(|e| e.into_error())?;
+        file.write_all(&crc.to_le_bytes())?;
+        file.sync_all()?;
+        fs.rename(&tmp_path, &dir.join(INDEX_SNAPSHOT_FILE))?;
+        Ok(())
+    }
 }
 
-impl KvsEngine for KvStore {
+/// A writer that computes the CRC32 of everything written through it
+struct CrcWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
     }
 
-    #[instrument]
-    fn get(&self, key: String) -> Result<Option<String>> {
-        // check for existence of key in index
-        if let Some(command) = self.index.get(&key) {
-            // get a reader based on the command generation
-            if let Command::Set { value, .. } = self.reader.read_command(*command.value())? {
-                Ok(Some(value))
-            } else {
-                error!("could not get command for key: {} command: {:?}", &key, &command.value());
-                Err(KvsError::InvalidCommand(format!("invalid command in logs for key: {}", &key)))
-            }
-        } else {
-            Ok(None)
-        }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
+}
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
-    }
+/// Constructs a log file path using the `gen` number as the file stem and the appending the
+/// suffix **.log** to it. The log file name is then joined to the given `dir` path
+fn build_log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
 }
 
-/// `KvsReader` maintains a map of readers to all command logs currently in use.
-///
-/// Every `KvStore` instance has its own `KvsReader` and every `KvsReader`
-/// opens the same files separately; so a `KvsReader` can read concurrently through
-/// multiple `KvStore`s in different threads.
+/// The directories that the command logs of a store are kept in: the working directory, and
+/// the write-ahead directory that current logs are written to, if the store has one (see
+/// [`KvStoreBuilder::wal_dir`]). The directory of every log is tracked by its generation.
 #[derive(Debug)]
-struct KvsReader {
-    path: Arc<PathBuf>,
-
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
-
-    // generation of the latest compaction file
-    latest_compaction_gen: Arc<AtomicU64>,
+struct LogDirs {
+    // the working directory, that compaction files are written to
+    data: PathBuf,
+    // the directory that current logs are written to, if it isn't the working directory
+    wal: Option<PathBuf>,
+    // the generations of the logs in the `wal` directory, every other log is in `data`
+    wal_gens: Mutex<BTreeSet<u64>>,
 }
 
-impl KvsReader {
+impl LogDirs {
+    fn new(data: PathBuf, wal: Option<PathBuf>) -> Self {
+        LogDirs { data, wal, wal_gens: Mutex::new(BTreeSet::new()) }
+    }
 
-    /// Removes handles to files that are no longer needed.
-    ///
-    /// Files are no longer needed when their generation number is less than the
-    /// `latest_compaction_gen`. Files will become "stale" after a compaction
-    /// finishes, so there is no point keeping them around, the latest compaction file
-    /// will have the sum of all generational files before it
-    fn remove_stale_handles(&self) {
-        let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let first_gen = *readers.keys().next().unwrap();
-            if self.latest_compaction_gen.load(Ordering::SeqCst) <= first_gen {
-                break;
-            }
-            readers.remove(&first_gen);
+    /// returns the path of the log with the given `gen`
+    fn log_path(&self, gen: u64) -> PathBuf {
+        match &self.wal {
+            Some(wal) if self.wal_gens().contains(&gen) => build_log_path(wal, gen),
+            _ => build_log_path(&self.data, gen),
         }
     }
 
-    /// Read the log file at the given `CommandPos`.
-    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
-        where
-            F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
-    {
-        self.remove_stale_handles();
-
-        let mut readers = self.readers.borrow_mut();
-
-        // Open the file if we haven't opened it in this `KvStoreReader`.
-        // We don't use entry API here because we want the errors to be propagated.
-        if let Entry::Vacant(e) = readers.entry(cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(build_log_path(&self.path, cmd_pos.gen))?)?;
-            e.insert(reader);
+    /// searches both directories for logs, recording the directory each of them is in.
+    /// Returns the generations of the logs in ascending order
+    fn gens(&self, fs: &dyn FileSystem) -> Result<Vec<u64>> {
+        let mut gens = get_log_gens(fs, &self.data)?.unwrap_or_default();
+        if let Some(wal) = &self.wal {
+            let found = get_log_gens(fs, wal)?.unwrap_or_default();
+            gens.extend(&found);
+            gens.sort_unstable();
+            gens.dedup();
+            *self.wal_gens() = found.into_iter().collect();
         }
+        Ok(gens)
+    }
 
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
-        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-        let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
+    /// creates a new log file with the given `gen`, in the write-ahead directory if `wal` is
+    /// true and the store has one, or else in the working directory. A log with the same
+    /// generation can only have been left behind by a compaction that didn't finish updating
+    /// the manifest, so it isn't live and is discarded rather than appended to
+    fn create(&self, fs: &dyn FileSystem, gen: u64, wal: bool) -> Result<LogWriter> {
+        let dirs = std::iter::once(&self.data).chain(&self.wal);
+        for path in dirs.map(|dir| build_log_path(dir, gen)) {
+            if fs.metadata(&path).is_ok() {
+                warn!("discarding {:?}, which was left behind by an unfinished compaction", path);
+                fs.remove_file(&path)?;
+            }
+        }
+        match &self.wal {
+            Some(wal_dir) if wal => {
+                self.wal_gens().insert(gen);
+                new_log_file(fs, wal_dir, gen)
+            }
+            _ => {
+                self.wal_gens().remove(&gen);
+                new_log_file(fs, &self.data, gen)
+            }
+        }
     }
 
-    /// Read the log file starting at the given `CommandPos` and deserialize it into `Command`.
-    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_and(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
-        })
+    fn wal_gens(&self) -> MutexGuard<'_, BTreeSet<u64>> {
+        self.wal_gens.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
     }
 }
 
-impl Clone for KvsReader {
-    fn clone(&self) -> KvsReader {
-        KvsReader {
-            path: Arc::clone(&self.path),
-            latest_compaction_gen: Arc::clone(&self.latest_compaction_gen),
-            // every KvsReader will have their own map of readers
-            readers: RefCell::new(BTreeMap::new()),
+/// Creates and joins a new log file with the given `gen` number to the given `path`, within
+/// the file system `fs`.
+/// Returns a new [`BufWriterWithPos`] to the newly created log file.
+fn new_log_file(fs: &dyn FileSystem, path: &Path, gen: u64) -> Result<LogWriter> {
+    let path = build_log_path(path, gen);
+    let mut backoff = NEW_LOG_BACKOFF;
+    let mut attempt = 1;
+    // a transient error, e.g. a network file system hiccup, is retried a few times before it
+    // fails the open or compaction that needs the log
+    let file = loop {
+        match fs.append(&path) {
+            Ok(file) => break file,
+            Err(e) if attempt < NEW_LOG_ATTEMPTS && is_transient(&e) => {
+                warn!("{:?} cannot be created, retrying in {:?} (attempt {}): {}", path, backoff, attempt, e);
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
         }
-    }
+    };
+    let writer = BufWriterWithPos::new(file)?;
+    Ok(writer)
 }
 
-#[derive(Debug)]
-struct KvsWriter {
-    reader: KvsReader,
-    writer: BufWriterWithPos<File>,
+/// returns `true` if the file system error `e` may be caused by a momentary condition, so the
+/// operation that failed may succeed if it's retried
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::AlreadyExists
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::StaleNetworkFileHandle
+    )
+}
 
-    // the current log generation number
-    current_gen: u64,
+/// These are the command types that will be recorded in the command log(s)
+/// NOTE that "GET" commands are not stored in the logs
+///
+/// Every command records the write sequence number that was assigned to it. Logs written
+/// before sequence numbers existed will default the sequence number to 0.
+/// Set commands also record the time they were written, as milliseconds since the unix epoch,
+/// the tag of the value if it isn't 0, and the time the key expires, as seconds since the unix
+/// epoch, if it was set with a TTL (see [`KvStore::set_with_ttl`]).
+///
+/// This is the JSON format of a command, it can only hold UTF-8 keys and values. Commands with
+/// other keys or values are written in the binary format, see [`LogCommand`].
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Command<'a> {
+    Set {
+        // written before the key, so that readers streaming the value of a command, which
+        // expect it to start with the key, read the whole command of an expiring key instead
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        key: Cow<'a, str>,
+        value: Cow<'a, str>,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        written_at: Option<u64>,
+        #[serde(default, skip_serializing_if = "is_untagged")]
+        tag: u8,
+    },
+    Remove {
+        key: Cow<'a, str>,
+        #[serde(default)]
+        seq: u64,
+    },
+}
 
-    // the number of bytes representing "stale" commands that could be
-    // deleted during a compaction
-    uncompacted: u64,
+// the first byte of a set command in the binary format. JSON commands always start with a `{`
+// (or whitespace)
+const BINARY_SET: u8 = 0x01;
 
-    // the path to the directory containing the kvs logs files
-    path: Arc<PathBuf>,
+// the first byte of a remove command in the binary format
+const BINARY_REMOVE: u8 = 0x02;
 
-    // a handle to the in-memory index
-    index: Arc<DashMap<String, CommandPos>>,
-}
+// the first byte of a set command, with a non-zero tag, in the binary format
+const BINARY_TAGGED_SET: u8 = 0x03;
 
-impl KvsWriter {
+// the first byte of the header of a batch of commands, that were written by a transaction
+const BINARY_BATCH: u8 = 0x04;
 
-    /// sets the given `key` and `value` into the `index` and also writes them into
-    /// the log file
-    #[instrument]
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        // create a Set command variant
-        let cmd = Command::Set { key, value };
-        // set pos to the current position of the writer which is usually at the end of the log
-        let pos = self.writer.pos;
-        // serialize the command into the log using serde and flush the writer
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+// the length of a batch header, i.e. the `BINARY_BATCH` byte and the number of commands
+const BATCH_HEADER_LEN: u64 = 5;
 
-        if let Command::Set { key, .. } = cmd {
-            // check if the key currently exists in the index, if so, increment
-            // uncompacted with the old.len, as that data is now stale and will be overriden with new key
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
-            }
-            // insert the key along with its CommandPos data
-            self.index.insert(key, (self.current_gen, pos..self.writer.pos).into());
-        }
+// the first byte of a link, from a key to the value of another set command
+const BINARY_LINK: u8 = 0x05;
 
-        // run a log compaction if needed
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
+// the first byte of the header of a command that is prefixed with its checksum
+const BINARY_CHECKSUMMED: u8 = 0x06;
 
-        Ok(())
-    }
+// the length of a checksum header, i.e. the `BINARY_CHECKSUMMED` byte and the CRC-32
+const CHECKSUM_HEADER_LEN: u64 = 5;
 
-    /// remove the given `key` from the index
-    #[instrument]
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::Remove { key };
-            let pos = self.writer.pos;
-            // serialze the remove command into the log and flush
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+// the first byte of a set command of a key that expires, in the binary format
+const BINARY_EXPIRING_SET: u8 = 0x07;
 
-            if let Command::Remove { key } = cmd {
-                let (_key, old_cmd) = self.index.remove(&key).expect("key not found");
-                // update uncompacted with the removed length
-                self.uncompacted += old_cmd.len;
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                self.uncompacted += self.writer.pos - pos;
-            }
+fn is_untagged(tag: &u8) -> bool {
+    *tag == 0
+}
+
+/// A command read from, or written to, a command log.
+///
+/// Commands whose key and value are valid UTF-8 are written as a JSON [`Command`], so that the
+/// logs remain readable. Other commands are written in a binary format, where every integer is
+/// little endian and keys and values are prefixed with their length in bytes:
+/// ```text
+/// set:        0x01 | seq: u64 | written_at: u64 | key_len: u32 | key | value_len: u64 | value
+/// remove:     0x02 | seq: u64 | key_len: u32 | key
+/// tagged set: 0x03 | tag: u8 | <the fields of a set, after the 0x01>
+/// link:       0x05 | seq: u64 | written_at: u64 | tag: u8 | key_len: u32 | key | gen: u64 | pos: u64 | len: u64
+/// expiring:   0x07 | expires_at: u64 | <a set or a tagged set>
+/// ```
+///
+/// The set command of a key that expires records when it expires, as seconds since the unix
+/// epoch. Values of such keys are never linked to, or from, as a link doesn't hold an expiry.
+///
+/// A link sets a key to the value of the set command at the given position, it's written by
+/// stores that dedup values (see [`KvStoreBuilder::dedup_values`]). Links are always written
+/// in the binary format.
+///
+/// The commands of a transaction are written as a batch: a `0x04 | count: u32` header followed
+/// by `count` commands, in either format. A log that ends part way through a batch can't be
+/// loaded, so a transaction is never partially applied.
+///
+/// Stores that verify reads (see [`KvStoreBuilder::verify_on_read`]) prefix every command
+/// with a `0x06 | crc: u32` header, holding the CRC-32 of the command that follows it.
+#[derive(Debug, PartialEq, Eq)]
+enum LogCommand {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        seq: u64,
+        written_at: Option<u64>,
+        tag: u8,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+        seq: u64,
+    },
+    Link {
+        key: Vec<u8>,
+        seq: u64,
+        written_at: Option<u64>,
+        tag: u8,
+        // the set command holding the value
+        target: CommandPos,
+    },
+}
 
-            // run a compaction if needed
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
+impl LogCommand {
+    /// writes this command to the `writer`, in the JSON format if possible
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            LogCommand::Set { key, value, seq, written_at, tag, expires_at } => {
+                if let (Ok(key), Ok(value)) = (std::str::from_utf8(key), std::str::from_utf8(value)) {
+                    let cmd = Command::Set {
+                        expires_at: *expires_at,
+                        key: key.into(),
+                        value: value.into(),
+                        seq: *seq,
+                        written_at: *written_at,
+                        tag: *tag,
+                    };
+                    return Ok(serde_json::to_writer(writer, &cmd)?);
+                }
+                if let Some(expires_at) = expires_at {
+                    writer.write_all(&[BINARY_EXPIRING_SET])?;
+                    writer.write_all(&expires_at.to_le_bytes())?;
+                }
+                if *tag == 0 {
+                    writer.write_all(&[BINARY_SET])?;
+                } else {
+                    writer.write_all(&[BINARY_TAGGED_SET, *tag])?;
+                }
+                writer.write_all(&seq.to_le_bytes())?;
+                writer.write_all(&written_at.unwrap_or(0).to_le_bytes())?;
+                write_key(writer, key)?;
+                writer.write_all(&(value.len() as u64).to_le_bytes())?;
+                writer.write_all(value)?;
+            }
+            LogCommand::Remove { key, seq } => {
+                if let Ok(key) = std::str::from_utf8(key) {
+                    return Ok(serde_json::to_writer(writer, &Command::Remove { key: key.into(), seq: *seq })?);
+                }
+                writer.write_all(&[BINARY_REMOVE])?;
+                writer.write_all(&seq.to_le_bytes())?;
+                write_key(writer, key)?;
+            }
+            LogCommand::Link { key, seq, written_at, tag, target } => {
+                writer.write_all(&[BINARY_LINK])?;
+                writer.write_all(&seq.to_le_bytes())?;
+                writer.write_all(&written_at.unwrap_or(0).to_le_bytes())?;
+                writer.write_all(&[*tag])?;
+                write_key(writer, key)?;
+                for n in [target.gen, target.pos, target.len] {
+                    writer.write_all(&n.to_le_bytes())?;
+                }
             }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
         }
+        Ok(())
     }
 
-    /// Clears stale entries in the log.
-    #[instrument]
-    fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
-        debug!("compaction started, compaction_gen={}, current_gen={}", &compaction_gen, &self.current_gen);
-
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
-
-        let mut new_pos = 0; // pos in the new log file
-        for mut entry in self.index.iter_mut() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            *entry.value_mut() = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
+    /// writes this command to the `writer`, like [`write_to`](LogCommand::write_to), prefixed
+    /// with a checksum header if `checksum` is `true`
+    fn write_record_to<W: Write>(&self, writer: &mut W, checksum: bool) -> Result<()> {
+        if !checksum {
+            return self.write_to(writer);
         }
-        compaction_writer.flush()?;
-
-        self.reader
-            .latest_compaction_gen
-            .store(compaction_gen, Ordering::SeqCst);
-        self.reader.remove_stale_handles();
+        let mut cmd = vec![];
+        self.write_to(&mut cmd)?;
+        writer.write_all(&[BINARY_CHECKSUMMED])?;
+        writer.write_all(&crc32fast::hash(&cmd).to_le_bytes())?;
+        writer.write_all(&cmd)?;
+        Ok(())
+    }
 
-        // remove stale log files
-        // Note that actually these files are not deleted immediately because `KvStoreReader`s
-        // still keep open file handles. When `KvStoreReader` is used next time, it will clear
-        // its stale file handles. On Unix, the files will be deleted after all the handles
-        // are closed. On Windows, the deletions below will fail and stale files are expected
-        // to be deleted in the next compaction.
+    /// reads a single command from a `record` that starts with a checksum header, after
+    /// verifying the checksum of the command
+    fn read_verified(record: &[u8]) -> Result<LogCommand> {
+        let header_len = CHECKSUM_HEADER_LEN as usize;
+        if record.len() < header_len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let (header, mut cmd) = record.split_at(header_len);
+        let expected = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+        let actual = crc32fast::hash(cmd);
+        if actual != expected {
+            return Err(KvsError::InvalidCommand(format!(
+                "checksum mismatch: expected {:#010x} but computed {:#010x}",
+                expected, actual
+            )));
+        }
+        LogCommand::read_from(&mut cmd)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
 
-        let stale_gens = get_log_gens(&self.path)?.unwrap_or_default();
-        stale_gens
-            .iter()
-            .filter(|&&gen| gen < compaction_gen)
-            .for_each(|stale_gen| {
-                let file_path = build_log_path(&self.path, *stale_gen);
-                debug!("{:?} marked as stale", &file_path);
-                if let Err(e) = fs::remove_file(&file_path) {
-                    error!("{:?} cannot be deleted: {}", file_path, e);
+    /// reads the next command, in either format, from the `reader`.
+    /// Returns `None` at the end of the log
+    fn read_from<R: BufRead>(reader: &mut R) -> Result<Option<LogCommand>> {
+        match skip_whitespace(reader)? {
+            None => Ok(None),
+            Some(BINARY_CHECKSUMMED) => {
+                // the checksum is skipped, it's only verified by reads of a single command
+                reader.consume(1);
+                read_u32(reader)?;
+                match LogCommand::read_from(reader)? {
+                    Some(cmd) => Ok(Some(cmd)),
+                    None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
                 }
-            });
-        self.uncompacted = 0;
-        debug("compaction finished");
-        Ok(())
+            }
+            Some(first @ (BINARY_SET | BINARY_TAGGED_SET)) => {
+                reader.consume(1);
+                let tag = if first == BINARY_TAGGED_SET { read_u8(reader)? } else { 0 };
+                let seq = read_u64(reader)?;
+                let written_at = read_u64(reader)?;
+                let key = read_key(reader)?;
+                let value_len = read_u64(reader)?;
+                let mut value = vec![];
+                reader.take(value_len).read_to_end(&mut value)?;
+                if value.len() as u64 != value_len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                let written_at = if written_at == 0 { None } else { Some(written_at) };
+                Ok(Some(LogCommand::Set { key, value, seq, written_at, tag, expires_at: None }))
+            }
+            Some(BINARY_EXPIRING_SET) => {
+                reader.consume(1);
+                let expiry = read_u64(reader)?;
+                match LogCommand::read_from(reader)? {
+                    Some(LogCommand::Set { key, value, seq, written_at, tag, .. }) => {
+                        Ok(Some(LogCommand::Set { key, value, seq, written_at, tag, expires_at: Some(expiry) }))
+                    }
+                    Some(_) => Err(KvsError::InvalidCommand("an expiry must be followed by a set command".to_string())),
+                    None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                }
+            }
+            Some(BINARY_REMOVE) => {
+                reader.consume(1);
+                let seq = read_u64(reader)?;
+                let key = read_key(reader)?;
+                Ok(Some(LogCommand::Remove { key, seq }))
+            }
+            Some(BINARY_LINK) => {
+                reader.consume(1);
+                let seq = read_u64(reader)?;
+                let written_at = read_u64(reader)?;
+                let tag = read_u8(reader)?;
+                let key = read_key(reader)?;
+                let target = CommandPos::new(read_u64(reader)?, read_u64(reader)?, read_u64(reader)?);
+                let written_at = if written_at == 0 { None } else { Some(written_at) };
+                Ok(Some(LogCommand::Link { key, seq, written_at, tag, target }))
+            }
+            Some(_) => {
+                let cmd = Command::deserialize(&mut Deserializer::from_reader(reader))?;
+                Ok(Some(cmd.into()))
+            }
+        }
     }
 }
 
-/// loads the commands from the given reader into the store's `index`.
-/// Returns the amount of bytes that could be compacted.
-/// `gen` is the generation number of the log file being read by `reader`
-///
-/// # Errors
-/// IO Errors will be returned if any log file could not be opened/read
-fn load(
-    gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &DashMap<String, CommandPos>,
-) -> Result<u64> {
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut uncompacted = 0_u64;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+impl From<Command<'_>> for LogCommand {
+    fn from(cmd: Command<'_>) -> Self {
+        match cmd {
+            Command::Set { expires_at, key, value, seq, written_at, tag } => LogCommand::Set {
+                key: key.into_owned().into_bytes(),
+                value: value.into_owned().into_bytes(),
+                seq,
+                written_at,
+                tag,
+                expires_at,
+            },
+            Command::Remove { key, seq } => LogCommand::Remove {
+                key: key.into_owned().into_bytes(),
+                seq,
+            },
+        }
+    }
+}
 
-    while let Some(command) = stream.next() {
-        let length = stream.byte_offset() as u64 - pos; // length of the command
-        match command? {
-            Command::Set { key, .. } => {
-                if let Some(old_command) =
-                index.insert(key, CommandPos::new(gen, pos, length))
-                {
-                    uncompacted += old_command.len;
-                }
+/// consumes any whitespace at the start of `reader`, returning the first byte after it without
+/// consuming it, or `None` at the end of the `reader`
+fn skip_whitespace<R: BufRead>(reader: &mut R) -> io::Result<Option<u8>> {
+    loop {
+        let buf = reader.fill_buf()?;
+        match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(start) => {
+                let first = buf[start];
+                reader.consume(start);
+                return Ok(Some(first));
             }
-            Command::Remove { key } => {
-                if let Some((_key, old_command)) = index.remove(&key) {
-                    uncompacted += old_command.len;
-                }
-                // this "remove" command itself can be deleted in the next compaction
-                uncompacted += length;
+            None if buf.is_empty() => return Ok(None),
+            None => {
+                let len = buf.len();
+                reader.consume(len);
             }
         }
-        pos = stream.byte_offset() as u64;
     }
+}
 
-    Ok(uncompacted)
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
 }
 
-/// Constructs a log file path using the `gen` number as the file stem and the appending the
-/// suffix **.log** to it. The log file name is then joined to the given `dir` path
-fn build_log_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(format!("{}.log", gen))
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
 }
 
-/// Creates and joins a new log file with the given `gen` number to the given `path`.
-/// Returns a new [`BufWriterWithPos`] to the newly created log file.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
-    let path = build_log_path(path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)?,
-    )?;
-    Ok(writer)
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
 }
 
-/// These are the command types that will be recorded in the command log(s)
-/// NOTE that "GET" commands are not stored in the logs
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+/// reads a length prefixed key of the binary format
+fn read_key(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut key = vec![0; read_u32(reader)? as usize];
+    reader.read_exact(&mut key)?;
+    Ok(key)
+}
+
+/// writes a length prefixed key of the binary format
+fn write_key(writer: &mut impl Write, key: &[u8]) -> Result<()> {
+    let len = u32::try_from(key.len())
+        .map_err(|_| KvsError::StringErr(format!("keys are limited to {} bytes", u32::MAX)))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(key)?;
+    Ok(())
 }
 
+/// The index of a store, which maps a key to the position of its latest command. The
+/// `sharded-index` feature replaces the default `DashIndex` with a `ShardedIndex`
+#[cfg(not(feature = "sharded-index"))]
+type Index = super::index::DashIndex<CommandPos>;
+#[cfg(feature = "sharded-index")]
+type Index = super::index::ShardedIndex<CommandPos>;
+
 /// Position data for commands that will be written to a log
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 struct CommandPos {
     // the log generation number that the command is stored in
     gen: u64,
@@ -462,7 +3927,7 @@ impl From<(u64, Range<u64>)> for CommandPos {
     }
 }
 
-/// Searches for kvs ".log" files within the given `dir`.
+/// Searches for kvs ".log" files within the given `dir` of the file system `fs`.
 /// Returns the generation numbers of all ".log" files that were found, sorted in ascending order.
 ///
 /// This function expects the log files will end with a `.log` suffix and that the
@@ -472,28 +3937,21 @@ impl From<(u64, Range<u64>)> for CommandPos {
 /// returns an IO Error if the given `dir` and/or log files in that dir could not be read,
 /// or if a file stem could not be found for a .log file
 /// or if a file stem could not be converted to an integer
-fn get_log_gens(dir: &Path) -> Result<Option<Vec<u64>>> {
+fn get_log_gens(fs: &dyn FileSystem, dir: &Path) -> Result<Option<Vec<u64>>> {
     let mut logs: Vec<u64> = vec![];
 
-    for entry in (fs::read_dir(dir)?).flatten() {
-        if entry.file_type()?.is_file()
-            && entry
-            .path()
-            .extension()
-            .map_or(false, |ext| ext.to_str() == Some("log"))
-        {
+    for path in fs.read_dir(dir)? {
+        if path.extension().is_some_and(|ext| ext.to_str() == Some("log")) {
             // get the file stem, convert it into a &str, then try to parse that &str to an integer
-            let stem = entry
-                .path()
+            let stem = path
                 .file_stem()
-                .ok_or_else(|| Error::new(
-                    ErrorKind::Other,
-                    format!("could not find log file stem for {:?}", &entry.path()),
+                .ok_or_else(|| Error::other(
+                    format!("could not find log file stem for {:?}", &path),
                 ))?
                 .to_os_string();
             let gen_str = stem.to_str()
                 .map(String::from)
-                .ok_or_else(|| Error::new(ErrorKind::Other, format!("could not convert the file stem: {:?} into a str", &stem), ))?;
+                .ok_or_else(|| Error::other(format!("could not convert the file stem: {:?} into a str", &stem)))?;
             let gen = gen_str.parse::<u64>().map_err(|_| {
                 KvsError::Parsing(format!(
                     "could not parse the file stem: {} into a u64",
@@ -511,6 +3969,152 @@ fn get_log_gens(dir: &Path) -> Result<Option<Vec<u64>>> {
     }
 }
 
+/// Reads the unescaped bytes of a JSON string from a reader positioned just after the opening
+/// quote of the string. The reader stops at the closing quote.
+#[derive(Debug)]
+struct JsonStrReader<R: BufRead> {
+    reader: R,
+    // the bytes of a decoded escape sequence that haven't been read yet
+    escaped: [u8; 4],
+    escaped_pos: usize,
+    escaped_len: usize,
+    // whether the closing quote has been reached
+    done: bool,
+}
+
+impl<R: BufRead> JsonStrReader<R> {
+    fn new(reader: R) -> Self {
+        JsonStrReader {
+            reader,
+            escaped: [0; 4],
+            escaped_pos: 0,
+            escaped_len: 0,
+            done: false,
+        }
+    }
+
+    /// returns a reader of the value of the serialized `Command::Set` read by `reader`, or `None`
+    /// if the command doesn't start with the layout written by serde_json, i.e. the key followed
+    /// by the value
+    fn set_value(mut reader: R) -> io::Result<Option<Self>> {
+        if !expect_bytes(&mut reader, br#"{"Set":{"key":""#)? {
+            return Ok(None);
+        }
+        let mut key_reader = JsonStrReader::new(reader);
+        io::copy(&mut key_reader, &mut io::sink())?;
+        let mut reader = key_reader.reader;
+        if !expect_bytes(&mut reader, br#","value":""#)? {
+            return Ok(None);
+        }
+        Ok(Some(JsonStrReader::new(reader)))
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.reader.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// reads the 4 hex digits of a `\u` escape
+    fn read_hex(&mut self) -> io::Result<u32> {
+        let mut digits = [0; 4];
+        self.reader.read_exact(&mut digits)?;
+        std::str::from_utf8(&digits)
+            .ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| invalid_json("invalid \\u escape"))
+    }
+
+    /// decodes the escape sequence following a backslash into `escaped`
+    fn decode_escape(&mut self) -> io::Result<()> {
+        let ch = match self.read_byte()? {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let mut code = self.read_hex()?;
+                // characters outside the basic multilingual plane are escaped as a surrogate pair
+                if (0xD800..0xDC00).contains(&code) {
+                    if self.read_byte()? != b'\\' || self.read_byte()? != b'u' {
+                        return Err(invalid_json("unpaired surrogate"));
+                    }
+                    let low = self.read_hex()?;
+                    if !(0xDC00..0xE000).contains(&low) {
+                        return Err(invalid_json("unpaired surrogate"));
+                    }
+                    code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                }
+                char::from_u32(code).ok_or_else(|| invalid_json("invalid \\u escape"))?
+            }
+            _ => return Err(invalid_json("invalid escape")),
+        };
+        self.escaped_len = ch.encode_utf8(&mut self.escaped).len();
+        self.escaped_pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for JsonStrReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.escaped_pos == self.escaped_len {
+            if self.done {
+                return Ok(0);
+            }
+            let available = self.reader.fill_buf()?;
+            match available.first() {
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "unterminated string")),
+                Some(b'"') => {
+                    self.reader.consume(1);
+                    self.done = true;
+                    return Ok(0);
+                }
+                Some(b'\\') => {
+                    self.reader.consume(1);
+                    self.decode_escape()?;
+                }
+                Some(_) => {
+                    // copy the run of bytes up to the next quote or escape
+                    let max = available.len().min(buf.len());
+                    let len = available[..max]
+                        .iter()
+                        .position(|&b| b == b'"' || b == b'\\')
+                        .unwrap_or(max);
+                    buf[..len].copy_from_slice(&available[..len]);
+                    self.reader.consume(len);
+                    return Ok(len);
+                }
+            }
+        }
+        let escaped = &self.escaped[self.escaped_pos..self.escaped_len];
+        let len = escaped.len().min(buf.len());
+        buf[..len].copy_from_slice(&escaped[..len]);
+        self.escaped_pos += len;
+        Ok(len)
+    }
+}
+
+/// reads `expected.len()` bytes from the `reader`, returning `true` if they match `expected`
+fn expect_bytes(reader: &mut impl Read, expected: &[u8]) -> io::Result<bool> {
+    let mut actual = vec![0; expected.len()];
+    match reader.read_exact(&mut actual) {
+        Ok(()) => Ok(actual == expected),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn invalid_json(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
 /// A struct that wraps a [`BufReader`] along with its current seek `pos`ition
 #[derive(Debug)]
 struct BufReaderWithPos<R: Read + Seek> {
@@ -536,6 +4140,17 @@ impl<R: Read + Seek> Read for BufReaderWithPos<R> {
     }
 }
 
+impl<R: Read + Seek> BufRead for BufReaderWithPos<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.pos += amt as u64;
+    }
+}
+
 impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         self.pos = self.reader.seek(pos)?;
@@ -547,6 +4162,8 @@ impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
 struct BufWriterWithPos<W: Write + Seek> {
     writer: BufWriter<W>,
     pos: u64,
+    // the position up to which the underlying file is known to be synced to disk
+    synced_pos: u64,
 }
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
@@ -555,10 +4172,37 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
+            synced_pos: pos,
         })
     }
 }
 
+impl BufWriterWithPos<Box<dyn WriteFile>> {
+    /// flushes the buffer and syncs all data and metadata of the underlying file to disk
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        self.synced_pos = self.pos;
+        Ok(())
+    }
+
+    /// returns the number of bytes written since the file was last synced
+    fn unsynced_bytes(&self) -> u64 {
+        self.pos.saturating_sub(self.synced_pos)
+    }
+
+    /// discards any buffered data and truncates the underlying file to `pos` bytes
+    fn truncate(&mut self, pos: u64) -> io::Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        // the buffer must be dropped without being flushed
+        let (file, _buffer) = std::mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+        file.set_len(pos)?;
+        self.pos = pos;
+        self.synced_pos = self.synced_pos.min(pos);
+        Ok(())
+    }
+}
+
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
@@ -0,0 +1,186 @@
+use super::{page_keys, KvsEngine, Transaction, TxOp};
+use std::collections::HashMap;
+use crate::error::{KvsError, Result};
+use crate::command::Stats;
+
+use std::sync::Arc;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+
+/// A key-value storage engine that keeps all of its data in memory.
+///
+/// Nothing is persisted to disk, so all data is lost once the last clone of the engine is
+/// dropped. This is useful for throwaway caches and for tests.
+///
+/// # Examples
+/// ```rust
+/// use kvs::{KvsEngine, MemoryKvsEngine};
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// let engine = MemoryKvsEngine::new();
+/// engine.set("myKey".to_string(), "myValue".to_string())?;
+/// assert_eq!(engine.get("myKey".to_string())?, Some("myValue".to_string()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryKvsEngine {
+    // maps a key to its value and tag, every clone of the engine shares the same map
+    map: Arc<DashMap<String, (String, u8)>>,
+}
+
+impl MemoryKvsEngine {
+    /// creates a new, empty, in-memory engine
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_tagged(key, value, 0)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_tagged(key)?.map(|(value, _tag)| value))
+    }
+
+    fn set_tagged(&self, key: String, value: String, tag: u8) -> Result<()> {
+        self.map.insert(key, (value, tag));
+        Ok(())
+    }
+
+    fn get_tagged(&self, key: String) -> Result<Option<(String, u8)>> {
+        Ok(self.map.get(&key).map(|entry| entry.value().clone()))
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.map.contains_key(&key))
+    }
+
+    /// Appends to a value in place, while holding the lock on the value's shard of the map
+    fn append(&self, key: String, suffix: String, separator: Option<String>) -> Result<String> {
+        match self.map.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let (value, _tag) = entry.get_mut();
+                if let Some(separator) = separator {
+                    value.push_str(&separator);
+                }
+                value.push_str(&suffix);
+                Ok(value.clone())
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((suffix.clone(), 0));
+                Ok(suffix)
+            }
+        }
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        Ok(self.map.insert(key, (value, 0)).map(|(old, _tag)| old))
+    }
+
+    /// Compares and sets the value while holding the lock on the value's shard of the map
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        match (self.map.entry(key), expected) {
+            (Entry::Occupied(mut entry), Some(expected)) if entry.get().0 == expected => {
+                entry.get_mut().0 = new;
+                Ok(true)
+            }
+            (Entry::Vacant(entry), None) => {
+                entry.insert((new, 0));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Moves the value out of the `from` key and into the `to` key. The engine has no writer
+    /// lock, so readers on other threads may briefly see neither key.
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        if from == to {
+            return if self.map.contains_key(&from) { Ok(()) } else { Err(KvsError::KeyNotFound) };
+        }
+        let (_key, value) = self.map.remove(&from).ok_or(KvsError::KeyNotFound)?;
+        self.map.insert(to, value);
+        Ok(())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.map
+            .remove(&key)
+            .map(|_entry| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    /// Returns a page of keys. The keys aren't ordered in memory, so every page sorts the keys
+    /// after the `cursor`.
+    fn scan(&self, cursor: Option<String>, limit: usize) -> Result<(Vec<String>, Option<String>)> {
+        let mut keys: Vec<String> = self
+            .map
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|key| cursor.as_ref().is_none_or(|cursor| key > cursor))
+            .collect();
+        keys.sort_unstable();
+        Ok(page_keys(keys.into_iter(), limit))
+    }
+
+    /// Collects the matching keys in a single pass over the map, rather than a pass per page
+    fn scan_prefix(&self, prefix: String) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .map
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|key| key.starts_with(&prefix))
+            .collect();
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Applies the operations of a transaction. Every remove is checked before anything is
+    /// applied, but the engine has no writer lock, so writes made concurrently by other threads
+    /// may be interleaved with the operations.
+    fn commit(&self, tx: Transaction) -> Result<()> {
+        let ops = tx.into_ops();
+        // every key that is removed must exist, either in the map or earlier in the transaction
+        let mut exists: HashMap<&str, bool> = HashMap::new();
+        for op in &ops {
+            match op {
+                TxOp::Set { key, .. } => {
+                    exists.insert(key, true);
+                }
+                TxOp::Remove { key } => {
+                    let present = exists.get(key.as_str()).copied();
+                    if !present.unwrap_or_else(|| self.map.contains_key(key)) {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    exists.insert(key, false);
+                }
+            }
+        }
+        for op in ops {
+            match op {
+                TxOp::Set { key, value, tag } => {
+                    self.map.insert(key, (value, tag));
+                }
+                TxOp::Remove { key } => {
+                    self.map.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns statistics about the engine. Only the `key_count` is tracked.
+    fn stats(&self) -> Result<Stats> {
+        Ok(Stats {
+            key_count: self.map.len() as u64,
+            ..Stats::default()
+        })
+    }
+
+    fn key_count(&self) -> Result<u64> {
+        Ok(self.map.len() as u64)
+    }
+}
@@ -1,9 +1,18 @@
 //! This module provides various key/value storage engine implementations.
-//! Currently, only the [`KvStore`] engine is implemented. In the future, a wrapper around the
-//! [`sled`] database engine will be added.
+//! Currently, the persistent [`KvStore`] engine, the [`SledKvsEngine`] wrapper around the
+//! [`sled`] database and the in-memory [`MemoryKvsEngine`] are implemented, along with a
+//! [`TeeEngine`] that writes to two engines while migrating data between them. The file
+//! operations of a [`KvStore`] go through the [`FileSystem`] trait, so they can be replaced,
+//! e.g. to inject IO errors in tests.
 //!
 //! [`sled`]: https://docs.rs/sled/latest/sled/
-use crate::Result;
+use crate::{KvsError, LogInfo, Result, Stats};
+use std::fmt;
+use std::io::{self, Cursor, Read};
+use std::time::SystemTime;
+
+/// The number of keys read at a time by the default [`KvsEngine::scan_prefix`]
+const SCAN_PREFIX_PAGE: usize = 1000;
 
 /// A trait for the basic functionality of a key/value storage engine
 pub trait KvsEngine: Clone + Send + 'static {
@@ -17,18 +26,443 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// Returns `None` if the given `key` does not exist.
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Gets the value associated with the given `key`, or the given `default` if the `key`
+    /// does not exist.
+    ///
+    /// This is a read-only operation, the `default` is **not** written to the store.
+    fn get_or_default(&self, key: String, default: String) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// Gets a [`ValueReader`] that reads the value associated with the given `key`, so that large
+    /// values can be streamed without holding the entire value in memory.
+    ///
+    /// Returns `None` if the given `key` does not exist.
+    /// Engines that can't stream values read the entire value into memory.
+    fn get_reader(&self, key: String) -> Result<Option<ValueReader>> {
+        Ok(self.get(key)?.map(ValueReader::from))
+    }
+
+    /// Gets at most `len` bytes of the value associated with the given `key`, starting at the
+    /// byte offset `start`, e.g. a preview of a large value. A range that extends past the end
+    /// of the value is cut short, so a `start` at or past the end returns an empty string.
+    ///
+    /// Returns `None` if the given `key` does not exist. Engines that can't seek within a value
+    /// read it from its start, through [`get_reader`](KvsEngine::get_reader).
+    ///
+    /// # Errors
+    ///
+    /// The range is in bytes rather than characters, so it may split a multi-byte UTF-8
+    /// character, in which case `KvsError::Utf8Error` is returned. Values that are known to be
+    /// ASCII can be sliced anywhere.
+    fn get_range(&self, key: String, start: u64, len: u64) -> Result<Option<String>> {
+        self.get_reader(key)?.map(|value| read_range(value, start, len)).transpose()
+    }
+
+    /// Returns `true` if the given `key` exists, without returning its value, e.g. to check for
+    /// a large value without reading it.
+    ///
+    /// Engines that can't check for a key without reading its value get the value.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.get_reader(key)?.is_some())
+    }
+
     /// Removes the given `key` (and associated value) from the store
     ///
     /// # Errors
     ///
     /// Returns `KvsError::KeyNotFound` if the given `key` is not found.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// sets a `key` and `value`, like [`set`](KvsEngine::set), but also returns the write sequence
+    /// number that was assigned to the write.
+    ///
+    /// Engines that do not track write sequence numbers return `None`.
+    fn set_with_seq(&self, key: String, value: String) -> Result<Option<u64>> {
+        self.set(key, value).map(|_| None)
+    }
+
+    /// removes the given `key`, like [`remove`](KvsEngine::remove), but also returns the write
+    /// sequence number that was assigned to the write.
+    ///
+    /// Engines that do not track write sequence numbers return `None`.
+    fn remove_with_seq(&self, key: String) -> Result<Option<u64>> {
+        self.remove(key).map(|_| None)
+    }
+
+    /// sets every key/value pair, in order, e.g. to bulk load data. If a key appears more than
+    /// once, its last value is kept.
+    ///
+    /// Unlike a [`transaction`](KvsEngine::transaction) this is **not** atomic: if an error is
+    /// returned, some of the pairs may have been set. Engines that can write the pairs together
+    /// override this to do so, by default the pairs are [`set`](KvsEngine::set) one at a time.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        pairs.into_iter().try_for_each(|(key, value)| self.set(key, value))
+    }
+
+    /// sets every key/value pair, in order, like [`set_many`](KvsEngine::set_many), but a pair
+    /// that fails doesn't stop the pairs after it from being set. Returns the result of setting
+    /// each pair, in the same order as `pairs`, so that the pairs that were set are known.
+    ///
+    /// By default the pairs are [`set`](KvsEngine::set) one at a time.
+    fn set_each(&self, pairs: Vec<(String, String)>) -> Vec<Result<()>> {
+        pairs.into_iter().map(|(key, value)| self.set(key, value)).collect()
+    }
+
+    /// sets a `key` and `value`, like [`set`](KvsEngine::set), along with a `tag` byte that is
+    /// returned by [`get_tagged`](KvsEngine::get_tagged). Applications can use the tag as a
+    /// discriminator of the value's type (e.g. JSON, plain text) without encoding it into the
+    /// value. Values set without a tag have a tag of 0.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine can't store tags, and `tag` isn't 0.
+    fn set_tagged(&self, key: String, value: String, tag: u8) -> Result<()> {
+        if tag == 0 {
+            self.set(key, value)
+        } else {
+            Err(KvsError::Unsupported("set_tagged".to_string()))
+        }
+    }
+
+    /// Gets the value associated with the given `key`, along with its tag.
+    ///
+    /// Returns `None` if the given `key` does not exist.
+    /// Engines that can't store tags return a tag of 0.
+    fn get_tagged(&self, key: String) -> Result<Option<(String, u8)>> {
+        Ok(self.get(key)?.map(|value| (value, 0)))
+    }
+
+    /// Gets the value associated with the given `key`, along with the time it was last written.
+    ///
+    /// Returns `None` if the given `key` does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine does not track write times.
+    fn get_with_meta(&self, _key: String) -> Result<Option<(String, SystemTime)>> {
+        Err(KvsError::Unsupported("get_with_meta".to_string()))
+    }
+
+    /// Returns statistics about the storage engine, i.e. the `key_count`, `uncompacted_bytes`,
+    /// `disk_usage`, `compactions`, `reclaimable_bytes`, `last_compaction` and
+    /// `index_memory_bytes` fields of [`Stats`].
+    /// The remaining fields describe the server and are left at 0.
+    ///
+    /// Engines that do not track statistics return `Stats::default()`.
+    fn stats(&self) -> Result<Stats> {
+        Ok(Stats::default())
+    }
+
+    /// Resets the counters reported by [`stats`](KvsEngine::stats), i.e. `compactions` and
+    /// `last_compaction`, e.g. between the runs of a benchmark. The other fields describe the
+    /// current state of the engine, and aren't affected.
+    ///
+    /// Engines that do not track statistics do nothing.
+    fn reset_stats(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the number of keys in the engine.
+    ///
+    /// Engines that can't count their keys on their own return the `key_count` of their
+    /// [`stats`](KvsEngine::stats).
+    fn key_count(&self) -> Result<u64> {
+        Ok(self.stats()?.key_count)
+    }
+
+    /// Returns the size, number of live keys and an estimate of the reclaimable bytes of each
+    /// of the engine's log files, in generation order. This shows how the data is laid out on
+    /// disk, e.g. to check how much a compaction would reclaim from each file.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine doesn't store its data in log files.
+    fn log_info(&self) -> Result<Vec<LogInfo>> {
+        Err(KvsError::Unsupported("log_info".to_string()))
+    }
+
+    /// Checks whether the engine is able to serve writes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error describing why the engine can't serve writes, e.g. a
+    /// `KvsError::DiskFull` if its storage device is out of space.
+    /// Engines that have no failure states always return `Ok`.
+    fn health(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Syncs the engine's data to disk before it is closed, and returns a summary of what was
+    /// flushed, see [`KvStore::shutdown`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine has nothing to flush on shutdown.
+    fn shutdown(&self) -> Result<ShutdownSummary> {
+        Err(KvsError::Unsupported("shutdown".to_string()))
+    }
+
+    /// Compacts the engine's files now, rather than waiting for the engine to compact them on
+    /// its own, see [`KvStore::force_compact`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine has nothing to compact.
+    fn compact(&self) -> Result<()> {
+        Err(KvsError::Unsupported("compact".to_string()))
+    }
+
+    /// Returns a page of at most `limit` keys, in ascending order, that come after the `cursor`
+    /// key (or from the first key, if `cursor` is `None`). Also returns the cursor of the next
+    /// page, or `None` if this is the last page. `limit` must be at least 1.
+    ///
+    /// A cursor is the last key of the previous page, so paging is stable across calls, but
+    /// the pages are not a snapshot of the store: keys set or removed while paging through
+    /// the store may be skipped, or returned even though they have since been removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine can't list its keys.
+    fn scan(&self, _cursor: Option<String>, _limit: usize) -> Result<(Vec<String>, Option<String>)> {
+        Err(KvsError::Unsupported("scan".to_string()))
+    }
+
+    /// Returns every key that starts with `prefix`, in ascending order, e.g. every key of a
+    /// namespace like `user:`. An empty prefix returns every key.
+    ///
+    /// The matching keys are collected at once, so this isn't suited to prefixes that match a
+    /// large part of a large store; use [`scan`](KvsEngine::scan) to page through them instead.
+    /// By default, the keys are paged through with [`scan`](KvsEngine::scan) until the keys are
+    /// past the prefix.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine can't list its keys.
+    fn scan_prefix(&self, prefix: String) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut cursor = None;
+        loop {
+            let (page, next_cursor) = self.scan(cursor, SCAN_PREFIX_PAGE)?;
+            // the keys are in ascending order, so once a key is past the prefix, so are the rest
+            let past_prefix = page.last().is_some_and(|last| *last > prefix && !last.starts_with(&prefix));
+            keys.extend(page.into_iter().filter(|key| key.starts_with(&prefix)));
+            match next_cursor {
+                Some(next_cursor) if !past_prefix => cursor = Some(next_cursor),
+                _ => return Ok(keys),
+            }
+        }
+    }
+
+    /// Appends `suffix` to the value of the given `key`, and returns the new value. If the key
+    /// exists, the `separator` (if any) is inserted between its value and the suffix, otherwise
+    /// the key is set to the suffix.
+    ///
+    /// The value is read and written back atomically with respect to other writes, so
+    /// concurrent appends are never lost. Note that the entire value is rewritten, so every
+    /// append costs O(value size), and appending to a value n times costs O(n²) overall.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine can't append atomically.
+    fn append(&self, _key: String, _suffix: String, _separator: Option<String>) -> Result<String> {
+        Err(KvsError::Unsupported("append".to_string()))
+    }
+
+    /// Moves the value (and tag) of the `from` key to the `to` key, replacing any value of `to`,
+    /// and removes the `from` key. Renaming a key to itself does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if the `from` key does not exist.
+    /// Returns `KvsError::Unsupported` if the engine can't rename keys atomically.
+    fn rename(&self, _from: String, _to: String) -> Result<()> {
+        Err(KvsError::Unsupported("rename".to_string()))
+    }
+
+    /// Sets the given `key` to the given `value`, like [`set`](KvsEngine::set), and returns the
+    /// value it replaced, or `None` if the key didn't exist. The old value is read and the new
+    /// one written atomically with respect to other writes, e.g. to reset a counter and read its
+    /// total. The new value has a tag of 0.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine can't get and set atomically.
+    fn get_set(&self, _key: String, _value: String) -> Result<Option<String>> {
+        Err(KvsError::Unsupported("get_set".to_string()))
+    }
+
+    /// Sets the given `key` to the `new` value, but only if its current value is `expected`,
+    /// and returns whether the value was set. An `expected` value of `None` only sets the key
+    /// if it doesn't exist. The value is compared and set atomically with respect to other
+    /// writes.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine can't compare and swap atomically.
+    fn compare_and_swap(&self, _key: String, _expected: Option<String>, _new: String) -> Result<bool> {
+        Err(KvsError::Unsupported("compare_and_swap".to_string()))
+    }
+
+    /// Applies all of the set and remove operations staged in a [`Transaction`] by `f`, or none
+    /// of them.
+    ///
+    /// `f` stages operations in the transaction rather than applying them, so reads made inside
+    /// the closure see the state of the store from before the transaction. If `f` returns an
+    /// error, nothing is written and the error is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound`, and applies none of the operations, if the transaction
+    /// removes a key that doesn't exist.
+    /// Returns `KvsError::Unsupported` if the engine doesn't support transactions.
+    fn transaction<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Transaction) -> Result<()>,
+    {
+        let mut tx = Transaction::default();
+        f(&mut tx)?;
+        self.commit(tx)
+    }
+
+    /// Applies all of the operations of the given transaction, or none of them, see
+    /// [`transaction`](KvsEngine::transaction).
+    ///
+    /// The operations are applied together with respect to other writes, but readers on other
+    /// threads may briefly see some of them applied before the others.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Unsupported` if the engine doesn't support transactions.
+    fn commit(&self, _tx: Transaction) -> Result<()> {
+        Err(KvsError::Unsupported("transactions".to_string()))
+    }
+}
+
+/// A staging buffer of set and remove operations, that are applied all together by
+/// [`KvsEngine::transaction`].
+///
+/// Operations are applied in the order they were staged.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    ops: Vec<TxOp>,
+}
+
+/// an operation staged in a [`Transaction`]
+#[derive(Debug)]
+pub(crate) enum TxOp {
+    Set { key: String, value: String, tag: u8 },
+    Remove { key: String },
 }
 
+impl Transaction {
+    /// stages setting a `key` to `value`
+    pub fn set(&mut self, key: String, value: String) {
+        self.ops.push(TxOp::Set { key, value, tag: 0 });
+    }
+
+    /// stages removing a `key`. The transaction fails if the key doesn't exist when it is
+    /// applied, and wasn't set earlier in the transaction
+    pub fn remove(&mut self, key: String) {
+        self.ops.push(TxOp::Remove { key });
+    }
+
+    /// returns the number of staged operations
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// returns `true` if no operations have been staged
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// returns the staged operations, in the order they were staged
+    pub(crate) fn into_ops(self) -> Vec<TxOp> {
+        self.ops
+    }
+}
+
+/// collects a page of at most `limit` keys from `keys`, which must be in ascending order and
+/// after the cursor of the page. Returns the page and the cursor of the next page
+pub(crate) fn page_keys(keys: impl Iterator<Item = String>, limit: usize) -> (Vec<String>, Option<String>) {
+    let limit = limit.max(1);
+    // one extra key is taken to learn whether there is a next page
+    let mut page: Vec<String> = keys.take(limit + 1).collect();
+    if page.len() > limit {
+        page.truncate(limit);
+        let next_cursor = page.last().cloned();
+        (page, next_cursor)
+    } else {
+        (page, None)
+    }
+}
 
 
+/// reads at most `len` bytes from the `reader` of a value, after skipping its first `skip` bytes,
+/// see [`KvsEngine::get_range`]
+pub(crate) fn read_range(mut reader: impl Read, skip: u64, len: u64) -> Result<String> {
+    io::copy(&mut (&mut reader).take(skip), &mut io::sink())?;
+    let mut range = vec![];
+    reader.take(len).read_to_end(&mut range)?;
+    Ok(String::from_utf8(range)?)
+}
+
+/// A reader of a single value, returned by [`KvsEngine::get_reader`].
+///
+/// It reads the UTF-8 bytes of the value, and knows the total length of the value up front.
+pub struct ValueReader {
+    len: u64,
+    reader: Box<dyn Read + Send>,
+}
+
+impl ValueReader {
+    /// creates a reader of a value that is `len` bytes long, whose bytes are read from `reader`
+    pub fn new(len: u64, reader: Box<dyn Read + Send>) -> Self {
+        ValueReader { len, reader }
+    }
+
+    /// returns the length of the value in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// returns `true` if the value is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl From<String> for ValueReader {
+    /// creates a reader of a value that is already in memory
+    fn from(value: String) -> Self {
+        ValueReader::new(value.len() as u64, Box::new(Cursor::new(value.into_bytes())))
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl fmt::Debug for ValueReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueReader").field("len", &self.len).finish()
+    }
+}
+
+#[cfg(feature = "crypto")]
+mod crypto;
+mod index;
 mod kvs;
-//mod sled;
+mod memory;
+mod sled;
+mod tee;
+mod vfs;
 
-pub use self::kvs::KvStore;
-//pub use self::sled::SledKvsEngine;
\ No newline at end of file
+pub use self::kvs::{KvStore, KvStoreBuilder, CompactionTrigger, EvictionPolicy, Snapshot, BackgroundCompaction, CompactionEstimate, ShutdownCompaction, ShutdownSummary};
+pub use self::memory::MemoryKvsEngine;
+pub use self::sled::SledKvsEngine;
+pub use self::tee::TeeEngine;
+pub use self::vfs::{FileLock, FileSystem, FileMetadata, ReadFile, StdFs, WriteFile};
\ No newline at end of file
@@ -0,0 +1,62 @@
+use super::KvsEngine;
+use crate::error::{KvsError, Result};
+use sled::Db;
+
+/// A key-value storage engine backed by a [`sled`](https://docs.rs/sled/latest/sled/) database.
+///
+/// Every write is flushed to disk before it returns, so that a write that succeeded survives a
+/// crash, like a write to a [`KvStore`](crate::KvStore).
+///
+/// # Examples
+/// ```rust
+/// use kvs::{KvsEngine, SledKvsEngine};
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// let temp_dir = tempfile::TempDir::new()?;
+/// let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+/// engine.set("myKey".to_string(), "myValue".to_string())?;
+/// assert_eq!(engine.get("myKey".to_string())?, Some("myValue".to_string()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SledKvsEngine {
+    // every clone of the engine shares the same database
+    db: Db,
+}
+
+impl SledKvsEngine {
+    /// creates an engine that stores its data in the sled database `db`
+    pub fn new(db: Db) -> Self {
+        SledKvsEngine { db }
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.db.insert(key, value.into_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// # Errors
+    /// `KvsError::Utf8Error` if the stored value isn't valid UTF-8, e.g. it was written to the
+    /// database by another program
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.db
+            .get(key)?
+            .map(|value| Ok(String::from_utf8(value.to_vec())?))
+            .transpose()
+    }
+
+    /// Checks for the key without copying its value out of the database.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.db.contains_key(key)?)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
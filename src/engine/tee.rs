@@ -0,0 +1,166 @@
+use super::{KvsEngine, ShutdownSummary, ValueReader};
+use crate::error::{KvsError, Result};
+use crate::command::Stats;
+
+use std::time::SystemTime;
+use tracing::error;
+
+/// A storage engine that writes to two engines, and reads from the first (primary) engine.
+///
+/// It's used to migrate data between engines without downtime: while the data of the primary
+/// engine is copied into the secondary engine, every new write goes to both of them. Once the
+/// copy is finished, the secondary engine can replace the primary.
+///
+/// Every `set` and `remove` is applied to the primary engine first, and then to the secondary.
+/// If the secondary fails, the write to the primary is rolled back, by restoring the key's
+/// previous value, and the secondary's error is returned. Writes made concurrently to the same
+/// key by other threads may be interleaved with the rollback.
+///
+/// A key removed from the primary that the secondary doesn't have yet (i.e. it hasn't been
+/// copied) is not an error.
+///
+/// # Examples
+/// ```rust
+/// use kvs::{KvsEngine, MemoryKvsEngine, TeeEngine};
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// let (old, new) = (MemoryKvsEngine::new(), MemoryKvsEngine::new());
+/// let engine = TeeEngine::new(old, new.clone());
+/// engine.set("key1".to_owned(), "value1".to_owned())?;
+/// assert_eq!(new.get("key1".to_owned())?, Some("value1".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct TeeEngine<A: KvsEngine, B: KvsEngine> {
+    primary: A,
+    secondary: B,
+}
+
+impl<A: KvsEngine, B: KvsEngine> TeeEngine<A, B> {
+    /// creates an engine that reads from the `primary` engine, and writes to both the `primary`
+    /// and `secondary` engines
+    pub fn new(primary: A, secondary: B) -> Self {
+        TeeEngine { primary, secondary }
+    }
+
+    /// returns the primary engine, that is read from
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    /// returns the secondary engine, that is only written to
+    pub fn secondary(&self) -> &B {
+        &self.secondary
+    }
+
+    /// restores the `previous` value and tag of a `key` in the primary engine, or removes the key
+    /// if it didn't exist, after the secondary engine failed with the error `e`. Returns `e`
+    fn roll_back(&self, key: String, previous: Option<(String, u8)>, e: KvsError) -> KvsError {
+        let rolled_back = match previous {
+            Some((value, tag)) => self.primary.set_tagged(key.clone(), value, tag),
+            None => match self.primary.remove(key.clone()) {
+                Err(KvsError::KeyNotFound) => Ok(()),
+                removed => removed,
+            },
+        };
+        if let Err(rollback_err) = rolled_back {
+            error!("could not roll back the primary engine's write of key {}: {}", key, rollback_err);
+        }
+        e
+    }
+}
+
+impl<A: KvsEngine, B: KvsEngine> KvsEngine for TeeEngine<A, B> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_tagged(key, value, 0)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.primary.get(key)
+    }
+
+    fn get_reader(&self, key: String) -> Result<Option<ValueReader>> {
+        self.primary.get_reader(key)
+    }
+
+    fn get_range(&self, key: String, start: u64, len: u64) -> Result<Option<String>> {
+        self.primary.get_range(key, start, len)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let previous = self.primary.get_tagged(key.clone())?;
+        self.primary.remove(key.clone())?;
+        match self.secondary.remove(key.clone()) {
+            Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+            Err(e) => Err(self.roll_back(key, previous, e)),
+        }
+    }
+
+    fn set_tagged(&self, key: String, value: String, tag: u8) -> Result<()> {
+        let previous = self.primary.get_tagged(key.clone())?;
+        self.primary.set_tagged(key.clone(), value.clone(), tag)?;
+        self.secondary
+            .set_tagged(key.clone(), value, tag)
+            .map_err(|e| self.roll_back(key, previous, e))
+    }
+
+    fn get_tagged(&self, key: String) -> Result<Option<(String, u8)>> {
+        self.primary.get_tagged(key)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        self.primary.contains_key(key)
+    }
+
+    fn get_with_meta(&self, key: String) -> Result<Option<(String, SystemTime)>> {
+        self.primary.get_with_meta(key)
+    }
+
+    /// Returns the statistics of the primary engine
+    fn stats(&self) -> Result<Stats> {
+        self.primary.stats()
+    }
+
+    fn reset_stats(&self) -> Result<()> {
+        self.primary.reset_stats()
+    }
+
+    fn key_count(&self) -> Result<u64> {
+        self.primary.key_count()
+    }
+
+    /// Checks that both engines are able to serve writes
+    fn health(&self) -> Result<()> {
+        self.primary.health()?;
+        self.secondary.health()
+    }
+
+    fn scan(&self, cursor: Option<String>, limit: usize) -> Result<(Vec<String>, Option<String>)> {
+        self.primary.scan(cursor, limit)
+    }
+
+    fn scan_prefix(&self, prefix: String) -> Result<Vec<String>> {
+        self.primary.scan_prefix(prefix)
+    }
+
+    /// Compacts both engines, either of which may have nothing to compact
+    fn compact(&self) -> Result<()> {
+        for compacted in [self.primary.compact(), self.secondary.compact()] {
+            match compacted {
+                Ok(()) | Err(KvsError::Unsupported(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Shuts down both engines, and returns the summary of the primary engine
+    fn shutdown(&self) -> Result<ShutdownSummary> {
+        let summary = self.primary.shutdown()?;
+        match self.secondary.shutdown() {
+            Ok(_) | Err(KvsError::Unsupported(_)) => Ok(summary),
+            Err(e) => Err(e),
+        }
+    }
+}
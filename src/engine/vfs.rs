@@ -0,0 +1,168 @@
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The file operations used by a [`KvStore`] to manage its command logs.
+///
+/// [`StdFs`], the default, uses the local file system. Alternative implementations can store
+/// the logs elsewhere, or wrap [`StdFs`] to inject IO errors at precise points (e.g. while a
+/// compaction is writing) to test error recovery.
+///
+/// [`KvStore`]: ./struct.KvStore.html
+pub trait FileSystem: Debug + Send + Sync + 'static {
+    /// opens an existing file for reading
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadFile>>;
+
+    /// creates a file for writing, truncating it if it already exists
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>>;
+
+    /// opens a file for appending, creating it if it doesn't exist
+    fn append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>>;
+
+    /// returns the paths of the files (not the sub-directories) within the directory at `path`
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// removes the file at `path`
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// renames the file at `from` to `to`, replacing `to` if it already exists
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// returns the [`FileMetadata`] of the file at `path`
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata>;
+
+    /// creates the directory at `path`, and any missing parent directories
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// acquires an exclusive, advisory lock on the file at `path`, creating the file if it
+    /// doesn't exist. The lock is held until the returned [`FileLock`] is dropped.
+    ///
+    /// Returns an error of kind [`io::ErrorKind::WouldBlock`] if the lock is already held. The
+    /// default implementation doesn't lock anything, which suits file systems that can't be
+    /// shared with another process.
+    fn lock(&self, _path: &Path) -> io::Result<FileLock> {
+        Ok(Box::new(()))
+    }
+
+    /// maps the file at `path` into memory, for reading. The map covers the file as it was when
+    /// it was mapped, it doesn't grow with the file.
+    ///
+    /// Returns `None` if the file system can't map files, in which case the file is read
+    /// through [`open`](FileSystem::open). The default implementation doesn't map files.
+    #[cfg(feature = "mmap")]
+    fn map(&self, _path: &Path) -> io::Result<Option<Mmap>> {
+        Ok(None)
+    }
+}
+
+/// A lock acquired by [`FileSystem::lock`], which is released when it is dropped
+pub type FileLock = Box<dyn Debug + Send + Sync>;
+
+/// A file opened for reading by a [`FileSystem`]
+pub trait ReadFile: Read + Seek + Send + Debug {}
+
+impl<T: Read + Seek + Send + Debug> ReadFile for T {}
+
+/// A file opened for writing by a [`FileSystem`]
+pub trait WriteFile: Write + Seek + Send + Debug {
+    /// syncs all data and metadata of the file to its storage device
+    fn sync_all(&self) -> io::Result<()>;
+
+    /// truncates, or extends, the file to `size` bytes
+    fn set_len(&self, size: u64) -> io::Result<()>;
+
+    /// returns a new handle to the same file
+    fn try_clone(&self) -> io::Result<Box<dyn WriteFile>>;
+}
+
+impl WriteFile for File {
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(File::try_clone(self)?))
+    }
+}
+
+/// The metadata of a file, returned by [`FileSystem::metadata`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// the size of the file in bytes
+    pub len: u64,
+    /// the time the file was last modified
+    pub modified: SystemTime,
+}
+
+/// The default [`FileSystem`], which uses the local file system via [`std::fs`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdFs;
+
+impl FileSystem for StdFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(OpenOptions::new().create(true).append(true).open(path)?))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for entry in (fs::read_dir(path)?).flatten() {
+            if entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileMetadata {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    /// locks the file with [`File::try_lock`], i.e. `flock` on unix and `LockFileEx` on windows.
+    /// The lock is released when the file is closed, including when the process exits
+    fn lock(&self, path: &Path) -> io::Result<FileLock> {
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+        file.try_lock()?;
+        Ok(Box::new(file))
+    }
+
+    #[cfg(feature = "mmap")]
+    fn map(&self, path: &Path) -> io::Result<Option<Mmap>> {
+        let file = File::open(path)?;
+        // SAFETY: command logs are only ever appended to, and never modified in place, so the
+        // mapped bytes don't change while they are mapped
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Some(map))
+    }
+}
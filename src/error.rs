@@ -16,6 +16,10 @@ pub enum KvsError {
         source: io::Error,
     },
 
+    /// variant for write errors caused by the storage device being out of space
+    #[error("storage full")]
+    DiskFull(#[source] io::Error),
+
     /// variant for errors that occur when a key was not found in the KV Store
     #[error("Key not found")]
     KeyNotFound,
@@ -24,6 +28,11 @@ pub enum KvsError {
     #[error("serialization/deserialization error")]
     Serialization(#[from] serde_json::Error),
 
+    /// variant for errors encoding or decoding the messages of the
+    /// [`Protocol::Bincode`](crate::Protocol::Bincode) protocol
+    #[error("bincode serialization/deserialization error")]
+    Bincode(#[from] bincode::Error),
+
     /// variant for errors when parsing strings to some other type
     #[error("{}", .0)]
     Parsing(String),
@@ -40,13 +49,30 @@ pub enum KvsError {
     #[error("sled error")]
     Sled(#[from] sled::Error),
 
+    /// variant for operations that are not supported by a storage engine
+    #[error("{} is not supported by this engine", .0)]
+    Unsupported(String),
+
     /// a Key or value is an invalid UTF-8 sequence
     #[error("{}", .0)]
     Utf8Error(#[from] FromUtf8Error),
 
-    // variant for resource locking related errors
-    //#[error("{}", .0)]
-    //Locking(String),
+    /// variant for resource locking related errors, e.g. a data directory that is already in
+    /// use by another process
+    #[error("{}", .0)]
+    Locking(String),
+
+    /// variant for errors encrypting or decrypting values, e.g. a wrong passphrase
+    #[error("{}", .0)]
+    Encryption(String),
+
+    /// variant for errors configuring TLS, e.g. an invalid certificate or private key
+    #[error("{}", .0)]
+    Tls(String),
+
+    /// variant for dumps that can't be loaded, e.g. because they are truncated or corrupt
+    #[error("invalid dump: {}", .0)]
+    InvalidDump(String),
 }
 
 /// a custom Debug implementation that will write the entire error chain
@@ -0,0 +1,36 @@
+/// returns `true` if `text` matches the glob `pattern`, in which `*` matches any sequence of
+/// characters (including an empty one) and `?` matches any single character. Every other
+/// character only matches itself, and the whole of `text` must be matched.
+///
+/// The pattern is matched without backtracking into earlier stars, so this takes
+/// O(pattern length * text length) time in the worst case.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // the position of the latest `*` in the pattern, and of the first character of the text
+    // that it hasn't matched yet
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            // let the latest `*` match one more character, and retry the rest of the pattern
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,108 @@
+use crate::command::LatencyStats;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// latencies below this many microseconds each get their own bucket
+const LINEAR_BUCKETS: usize = 16;
+
+// every power of two above `LINEAR_BUCKETS` is split into this many buckets, so a recorded
+// latency is within 12.5% of its true value
+const SUB_BUCKETS: usize = 8;
+
+// the bucket of the largest latency that can be recorded, larger latencies are clamped to it.
+// 2^36 microseconds is almost 20 hours
+const MAX_EXPONENT: usize = 36;
+
+const BUCKETS: usize = LINEAR_BUCKETS + (MAX_EXPONENT - 3) * SUB_BUCKETS;
+
+/// A histogram of latencies, with log-linear microsecond buckets.
+///
+/// Recording a latency is a single atomic increment, so histograms can be shared by every
+/// connection without adding lock contention to the request path.
+#[derive(Debug)]
+pub(crate) struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// records a single latency
+    pub(crate) fn record(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[bucket_index(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// returns the number of latencies recorded, and their percentiles
+    pub(crate) fn stats(&self) -> LatencyStats {
+        let counts: Vec<u64> = self.buckets.iter().map(|count| count.load(Ordering::Relaxed)).collect();
+        stats_of(&counts)
+    }
+
+    /// returns the number of latencies recorded, and their percentiles, and resets the
+    /// histogram. Every bucket is swapped with 0, so a latency recorded concurrently is either
+    /// included in the returned stats or kept in the histogram, but never lost
+    pub(crate) fn take(&self) -> LatencyStats {
+        let counts: Vec<u64> = self.buckets.iter().map(|count| count.swap(0, Ordering::Relaxed)).collect();
+        stats_of(&counts)
+    }
+}
+
+/// returns the number of latencies counted by the buckets' `counts`, and their percentiles
+fn stats_of(counts: &[u64]) -> LatencyStats {
+    let total = counts.iter().sum();
+    LatencyStats {
+        count: total,
+        p50_micros: percentile(counts, total, 0.50),
+        p95_micros: percentile(counts, total, 0.95),
+        p99_micros: percentile(counts, total, 0.99),
+    }
+}
+
+/// returns the index of the bucket that holds the latency of `micros`
+fn bucket_index(micros: u64) -> usize {
+    if micros < LINEAR_BUCKETS as u64 {
+        return micros as usize;
+    }
+    let exponent = (63 - micros.leading_zeros()) as usize;
+    if exponent > MAX_EXPONENT {
+        return BUCKETS - 1;
+    }
+    // the 3 bits below the most significant bit select the sub-bucket
+    let sub_bucket = ((micros >> (exponent - 3)) & (SUB_BUCKETS as u64 - 1)) as usize;
+    LINEAR_BUCKETS + (exponent - 4) * SUB_BUCKETS + sub_bucket
+}
+
+/// returns the largest latency, in microseconds, that falls into the bucket at `index`
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < LINEAR_BUCKETS {
+        return index as u64;
+    }
+    let exponent = (index - LINEAR_BUCKETS) / SUB_BUCKETS + 4;
+    let sub_bucket = ((index - LINEAR_BUCKETS) % SUB_BUCKETS) as u64;
+    let width = 1_u64 << (exponent - 3);
+    (1_u64 << exponent) + (sub_bucket + 1) * width - 1
+}
+
+/// returns the upper bound of the bucket containing the given `percentile` of the `total`
+/// latencies counted by `counts`, or 0 if there are none
+fn percentile(counts: &[u64], total: u64, percentile: f64) -> u64 {
+    if total == 0 {
+        return 0;
+    }
+    let rank = ((total as f64 * percentile).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (index, count) in counts.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return bucket_upper_bound(index);
+        }
+    }
+    bucket_upper_bound(counts.len() - 1)
+}
@@ -0,0 +1,114 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+// the number of rows of the count-min sketch, each with its own hash function
+const DEPTH: usize = 4;
+
+// the number of counters in each row of the count-min sketch
+const WIDTH: usize = 2048;
+
+/// the number of keys whose counts are kept, i.e. the largest number of hot keys that can be
+/// reported
+pub(crate) const TRACKED_KEYS: usize = 100;
+
+// every counter is halved after this many sampled accesses, so that keys that are no longer
+// accessed fade out
+const WINDOW: u64 = 100_000;
+
+/// Estimates the most accessed keys, from a sample of the accesses.
+///
+/// Only 1 in `sample_rate` accesses is counted, the others cost a single atomic increment. The
+/// sampled accesses are counted in a count-min sketch, whose estimates are never below the
+/// true (sampled) counts, and the [`TRACKED_KEYS`] keys with the highest estimates are kept,
+/// so memory is bounded however many keys are accessed.
+#[derive(Debug)]
+pub(crate) struct HotKeys {
+    sample_rate: u64,
+    // the number of accesses, sampled or not
+    accesses: AtomicU64,
+    // the number of sampled accesses
+    sampled: AtomicU64,
+    // DEPTH rows of WIDTH counters
+    sketch: Box<[AtomicU32]>,
+    hashers: [RandomState; DEPTH],
+    // the estimated counts of the hottest keys
+    top: Mutex<HashMap<String, u64>>,
+}
+
+impl HotKeys {
+    /// creates an estimator that counts 1 in `sample_rate` accesses
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        HotKeys {
+            sample_rate: u64::from(sample_rate.max(1)),
+            accesses: AtomicU64::new(0),
+            sampled: AtomicU64::new(0),
+            sketch: (0..DEPTH * WIDTH).map(|_| AtomicU32::new(0)).collect(),
+            hashers: std::array::from_fn(|_| RandomState::new()),
+            top: Mutex::new(HashMap::with_capacity(TRACKED_KEYS + 1)),
+        }
+    }
+
+    /// records an access to `key`, if it is sampled
+    pub(crate) fn record(&self, key: &str) {
+        if !self.accesses.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample_rate) {
+            return;
+        }
+        if (self.sampled.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(WINDOW) {
+            self.decay();
+        }
+
+        let estimate = (0..DEPTH)
+            .map(|row| {
+                let counter = &self.sketch[row * WIDTH + self.hashers[row].hash_one(key) as usize % WIDTH];
+                counter.fetch_add(1, Ordering::Relaxed).saturating_add(1)
+            })
+            .min()
+            .map_or(0, u64::from);
+
+        let mut top = self.lock_top();
+        if let Some(count) = top.get_mut(key) {
+            *count = estimate;
+            return;
+        }
+        top.insert(key.to_string(), estimate);
+        // the coldest key makes room for the new one, which may be the new key itself
+        if top.len() > TRACKED_KEYS {
+            if let Some(coldest) = top.iter().min_by_key(|(_key, count)| **count).map(|(key, _count)| key.clone()) {
+                top.remove(&coldest);
+            }
+        }
+    }
+
+    /// returns at most `n` of the hottest keys, hottest first, with their estimated number of
+    /// accesses, i.e. their sampled counts scaled up by the sample rate
+    pub(crate) fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut keys: Vec<(String, u64)> = self
+            .lock_top()
+            .iter()
+            .map(|(key, count)| (key.clone(), count * self.sample_rate))
+            .collect();
+        keys.sort_unstable_by(|(key, count), (other_key, other_count)| {
+            other_count.cmp(count).then_with(|| key.cmp(other_key))
+        });
+        keys.truncate(n);
+        keys
+    }
+
+    /// halves every counter. Accesses recorded concurrently may be halved or not
+    fn decay(&self) {
+        for counter in self.sketch.iter() {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| Some(count / 2));
+        }
+        let mut top = self.lock_top();
+        top.values_mut().for_each(|count| *count /= 2);
+        top.retain(|_key, count| *count > 0);
+    }
+
+    fn lock_top(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+        // the counts are still valid estimates if a thread panicked while holding the lock
+        self.top.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -25,9 +25,16 @@
 //! - persisting the kv data into "command-log" files
 //! - loading kv data from the command-log files at start-up
 //! - periodically performing a command-log clean-up (a.k.a a compaction) once the size of stale
-//! data hits a certain byte size
-//!     - This compaction operation will run once the size of stale data hits the
-//!     COMPACTION_THRESHOLD limit (currently set to 2 KB).
+//!   data hits a certain byte size
+//!     - By default, this compaction operation will run once the size of stale data hits the
+//!       COMPACTION_THRESHOLD limit (currently set to 1 MB), which can be changed with
+//!       [`KvStore::open_with_threshold`]. Alternatively, a [`KvStoreBuilder`] can be used to compact once the ratio of stale data to live data
+//!       exceeds a given [`CompactionTrigger::Ratio`].
+//! - expiring keys that were set with a TTL, see [`KvStore::set_with_ttl`]
+//!
+//! ## MemoryKvsEngine
+//! [`MemoryKvsEngine`] is an alternative [`KvsEngine`] that keeps all of its data in memory,
+//! without any command logs. It is useful for throwaway caches and tests.
 //!
 //! ## Client / Server
 //! Client and server logic is contained in the [`client`] and [`server`] structs. They are
@@ -42,6 +49,17 @@
 //! be returned, containing the result of the request. If an error occurred, an [`Err`] response
 //! is returned, containing a description of the error.
 //!
+//! Clients can instead encode their requests more compactly with bincode, each prefixed with its
+//! length, see [`Protocol`] and [`KvsClient::connect_with_protocol`]. A server accepts either
+//! encoding, unless it's restricted to one with [`KvsServer::protocol`].
+//!
+//! For debugging with tools like `nc`, the server also speaks a line based text protocol
+//! (`GET key`, `SET key value`, `RM key`), see [`Request::parse_text`]. The server detects which
+//! protocol a client is using from the first byte it sends.
+//!
+//! With the `tls` feature, the server can encrypt its connections with TLS, see
+//! [`KvsServer::tls`] and [`KvsClient::connect_tls`]. Every protocol works the same way over TLS.
+//!
 //! ## Command Log Files
 //! KV data is persisted into a series of "command log" files, that are created every time the
 //! KvStore is started. By default, these files are created in the same directory that you started
@@ -67,20 +85,31 @@
 //! [`KvsEngine`]: ./engine/trait.KvsEngine.html
 //! [`Request`]: ./enum.Request.html
 //! [`Response`]: ./enum.Response.html
+//! [`Request::parse_text`]: ./enum.Request.html#method.parse_text
 //! [`kvs-server`]: ./kvs-server.rs
 //! [`kvs-client`]: /kvs-client.rs
 
 
 pub use error::{Result, KvsError};
-pub use engine::{KvsEngine, KvStore};
-pub use server::KvsServer;
-pub use client::KvsClient;
+pub use engine::{KvsEngine, Transaction, ValueReader, KvStore, KvStoreBuilder, CompactionTrigger, EvictionPolicy, Snapshot, BackgroundCompaction, CompactionEstimate, ShutdownCompaction, ShutdownSummary, MemoryKvsEngine, SledKvsEngine, TeeEngine, FileLock, FileSystem, FileMetadata, ReadFile, StdFs, WriteFile};
+pub use server::{BoundServer, KvsServer, ServerEvent, DEFAULT_KEEPALIVE, DEFAULT_MAX_BATCH, DEFAULT_MAX_LINE_LEN, DEFAULT_MAX_VALUE_SIZE, DEFAULT_READ_BUFFER};
+pub use client::{KvsClient, KvsClientBuilder, DEFAULT_SERVER_ADDR};
+pub use pool::{KvsClientPool, PooledClient};
 pub use thread_pool::{ThreadPool, NaiveThreadPool, SharedQueueThreadPool, RayonThreadPool};
-pub use command::{Response, Request};
+pub use command::{Protocol, Response, Request, Stats, LatencyStats, CompactionStats, LogInfo, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
 
+mod audit;
 mod client;
 mod command;
+pub mod dump;
 mod engine;
 mod error;
+mod glob;
+mod histogram;
+mod hot_keys;
+mod pool;
 mod server;
-pub mod thread_pool;
\ No newline at end of file
+mod stream;
+pub mod thread_pool;
+#[cfg(feature = "tls")]
+pub mod tls;
\ No newline at end of file
@@ -0,0 +1,193 @@
+use crate::{KvsClient, KvsClientBuilder, KvsError, Result};
+use std::net::ToSocketAddrs;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use tracing::debug;
+
+/// A pool of reusable [`KvsClient`] connections to a [`KvsServer`], that can be shared by
+/// multiple threads.
+///
+/// Connections are opened lazily, up to the maximum `size` of the pool, and are checked out
+/// with [`get`]. Every connection is opened with the options of the pool's
+/// [`KvsClientBuilder`], see [`KvsClientBuilder::pool`]. The returned [`PooledClient`] derefs to a [`KvsClient`] and returns the
+/// connection to the pool when it is dropped. Connections that failed with an IO error are
+/// closed rather than returned, so the next checkout opens a new connection.
+///
+/// Cloning the pool is cheap, every clone shares the same connections.
+///
+/// # Example
+/// ```rust,no_run
+/// use kvs::KvsClient;
+/// use std::thread;
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// let pool = KvsClient::builder()
+///     .addr("127.0.0.1:4000")
+///     .auth_token("s3cret")
+///     .pool(4);
+///
+/// let handles: Vec<_> = (0..8).map(|i| {
+///     let pool = pool.clone();
+///     thread::spawn(move || {
+///         let mut client = pool.get().unwrap();
+///         client.set(format!("key{}", i), "value".to_string()).unwrap();
+///     })
+/// }).collect();
+/// for handle in handles {
+///     handle.join().unwrap();
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`KvsServer`]: ./struct.KvsServer.html
+/// [`get`]: KvsClientPool::get
+#[derive(Debug, Clone)]
+pub struct KvsClientPool {
+    inner: Arc<PoolInner>,
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    builder: KvsClientBuilder,
+    size: usize,
+    state: Mutex<PoolState>,
+    // signalled whenever a connection is returned, or closed
+    available: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    // connections that aren't checked out
+    idle: Vec<KvsClient>,
+    // the number of open connections, both idle and checked out
+    open: usize,
+}
+
+impl KvsClientPool {
+    /// creates a pool of at most `size` connections to the server at `addr`, without any
+    /// client options. Connections are made to the first address `addr` resolves to. No
+    /// connections are opened until they are needed. `size` must be at least 1.
+    /// # Errors
+    /// `Err<KvsError::Io>` if `addr` could not be resolved
+    pub fn new<A: ToSocketAddrs>(addr: A, size: usize) -> Result<Self> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            KvsError::StringErr("the address did not resolve to any socket addresses".to_string())
+        })?;
+        Ok(Self::with_builder(KvsClient::builder().addr(addr.to_string()), size))
+    }
+
+    /// creates a pool of at most `size` connections, each opened with the options of the
+    /// `builder`. No connections are opened until they are needed. `size` must be at least 1.
+    pub fn with_builder(builder: KvsClientBuilder, size: usize) -> Self {
+        KvsClientPool {
+            inner: Arc::new(PoolInner {
+                builder,
+                size: size.max(1),
+                state: Mutex::new(PoolState::default()),
+                available: Condvar::new(),
+            }),
+        }
+    }
+
+    /// checks out a connection from the pool, opening a new connection if there are no idle
+    /// ones and the pool isn't full. If the pool is full, this blocks until a connection is
+    /// returned.
+    /// # Errors
+    /// `Err<KvsError>` if a new connection could not be opened
+    pub fn get(&self) -> Result<PooledClient> {
+        let mut state = self.lock_state();
+        loop {
+            if let Some(client) = state.idle.pop() {
+                return Ok(self.pooled(client));
+            }
+            if state.open < self.inner.size {
+                state.open += 1;
+                // the lock isn't held while connecting, so other threads can return connections
+                drop(state);
+                return match self.inner.builder.connect() {
+                    Ok(client) => {
+                        debug!("opened a new pooled connection");
+                        Ok(self.pooled(client))
+                    }
+                    Err(e) => {
+                        self.close_one();
+                        Err(e)
+                    }
+                };
+            }
+            state = self.inner.available.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /// returns the maximum number of connections in the pool
+    pub fn size(&self) -> usize {
+        self.inner.size
+    }
+
+    /// returns the number of idle connections in the pool
+    pub fn idle(&self) -> usize {
+        self.lock_state().idle.len()
+    }
+
+    fn pooled(&self, client: KvsClient) -> PooledClient {
+        PooledClient {
+            pool: self.clone(),
+            client: Some(client),
+        }
+    }
+
+    /// records that a checked out connection was closed, making room for a new one
+    fn close_one(&self) {
+        self.lock_state().open -= 1;
+        self.inner.available.notify_one();
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, PoolState> {
+        self.inner.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A [`KvsClient`] checked out of a [`KvsClientPool`]. The connection is returned to the pool
+/// when this is dropped.
+///
+/// Any responses to [`set_nowait`] requests that weren't flushed are read, and their errors
+/// discarded, before the connection is returned.
+///
+/// [`set_nowait`]: KvsClient::set_nowait
+#[derive(Debug)]
+pub struct PooledClient {
+    pool: KvsClientPool,
+    // only `None` once dropped
+    client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().expect("client is only taken on drop")
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().expect("client is only taken on drop")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let Some(mut client) = self.client.take() else {
+            return;
+        };
+        let _ = client.flush_responses();
+        if client.is_broken() {
+            debug!("closing a broken pooled connection");
+            self.pool.close_one();
+        } else {
+            self.pool.lock_state().idle.push(client);
+            self.pool.inner.available.notify_one();
+        }
+    }
+}
@@ -1,10 +1,73 @@
-use crate::{KvsEngine, Result};
-use crate::command::{Request, Response};
+use crate::{KvsEngine, KvsError, Result, ValueReader};
+use crate::command::{Protocol, Request, Response, Stats, BINCODE_HELLO, BINCODE_REJECTED, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use tracing::{debug, error};
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc::Sender, Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
 use crate::thread_pool::{ThreadPool};
+use socket2::{SockRef, TcpKeepalive};
+use std::path::PathBuf;
+use crate::audit::AuditLog;
+use crate::histogram::LatencyHistogram;
+use crate::stream::Stream;
+use crate::glob::glob_match;
+use crate::hot_keys::HotKeys;
+
+/// The default idle time before keep-alive probes are sent on a connection
+pub const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// The default maximum number of keys in a `GetBatch` request, and of pairs in a `MultiSet`
+/// request
+pub const DEFAULT_MAX_BATCH: usize = 1000;
+
+/// The default capacity, in bytes, of the buffer that requests are read into on each connection
+pub const DEFAULT_READ_BUFFER: usize = 8 * 1024;
+
+/// The default maximum length, in bytes, of a line of the text protocol
+pub const DEFAULT_MAX_LINE_LEN: usize = 64 * 1024;
+
+/// The default maximum size, in bytes, of the values sent in a request
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 64 * 1024 * 1024;
+
+// the number of bytes a JSON request may have on top of the maximum value size, for its key and
+// framing
+const REQUEST_OVERHEAD: usize = 64 * 1024;
+
+// the longest interval between keep-alive probes, once probing has started
+const MAX_KEEPALIVE_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+// how long the accept loop sleeps, when there are no pending connections, before checking
+// for a shutdown again
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// how often the idle watchdog checks whether the server has been idle for long enough to exit
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of keys read from the engine at a time, while looking for the keys that match
+/// the pattern of a `Request::Keys`
+const KEYS_SCAN_PAGE: usize = 1000;
+
+/// The number of matching keys after which a `Response::Keys` is sent to the client, while
+/// streaming the keys that match the pattern of a `Request::Keys`
+const KEYS_CHUNK: usize = 1000;
+
+/// An event in the life of a [`KvsServer`]'s connections, sent to the channel given to
+/// [`KvsServer::events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// a connection from the given client address was accepted
+    Accepted(SocketAddr),
+    /// the connection from the given client address was closed, by either side
+    Closed(SocketAddr),
+    /// the server failed to bind or accept a connection, or a connection failed, e.g. because
+    /// the client sent a malformed request or the connection was reset
+    Error(String),
+}
 
 /// A TCP socket server implementation over a key value storage engine.
 /// It listens for incoming [`Request`]s on a [`SocketAddr`](https://doc.rust-lang.org/std/net/enum.SocketAddr.html),
@@ -41,6 +104,92 @@ pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     /// a pool of threads that will perform work using a handle to the engine
     pool: P,
+    /// options that control how connections are serviced
+    config: ServerConfig,
+}
+
+/// Options that control how a [`KvsServer`] services its connections.
+/// A copy of these options is shared with every connection.
+#[derive(Debug, Clone)]
+struct ServerConfig {
+    /// the token clients must send in a `Request::Auth` before any other request is accepted
+    auth_token: Option<String>,
+    /// the number of requests a connection may send before it must reconnect
+    max_requests: Option<usize>,
+    /// how long a connection may be idle before keep-alive probes are sent
+    keepalive: Option<Duration>,
+    /// the file that successful writes are recorded in
+    audit_log: Option<PathBuf>,
+    /// the maximum number of keys in a `GetBatch` request
+    max_get_batch: usize,
+    /// the maximum number of pairs in a `MultiSet` request
+    max_multi_set: usize,
+    /// the maximum number of connections serviced at once, or `None` to use the number of
+    /// threads in the pool
+    max_connections: Option<usize>,
+    /// whether requests that modify the engine's data are rejected
+    read_only: bool,
+    /// the capacity of the buffer that each connection's requests are read into
+    read_buffer: usize,
+    /// the maximum length of a line of the text protocol, excluding its line ending
+    max_line_len: usize,
+    /// the maximum size of the values in a JSON or bincode request
+    max_value_size: usize,
+    /// the only protocol, other than the text protocol, that clients may use, or `None` if
+    /// they may use either
+    protocol: Option<Protocol>,
+    /// the channel that connection events are sent to
+    events: Option<Sender<ServerEvent>>,
+    /// 1 in this many key accesses are counted to find the hot keys, or `None` if they aren't
+    hot_keys: Option<u32>,
+    /// how long the server may go without any connection before it shuts down
+    idle_exit: Option<Duration>,
+    /// the TLS configuration that every connection is encrypted with, if any
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            auth_token: None,
+            max_requests: None,
+            keepalive: Some(DEFAULT_KEEPALIVE),
+            audit_log: None,
+            max_get_batch: DEFAULT_MAX_BATCH,
+            max_multi_set: DEFAULT_MAX_BATCH,
+            max_connections: None,
+            read_only: false,
+            read_buffer: DEFAULT_READ_BUFFER,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            protocol: None,
+            events: None,
+            hot_keys: None,
+            idle_exit: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+/// State of a running [`KvsServer`], that is shared with every connection.
+#[derive(Debug)]
+struct ServerState {
+    /// the time the server was started
+    started: Instant,
+    /// the number of currently open connections
+    connections: AtomicU64,
+    /// the number of milliseconds after `started` that a connection was last accepted or closed
+    last_activity: AtomicU64,
+    /// records the successful writes made by clients
+    audit_log: Option<Arc<AuditLog>>,
+    /// the time taken by the engine to handle get, set and remove requests
+    get_latency: LatencyHistogram,
+    set_latency: LatencyHistogram,
+    remove_latency: LatencyHistogram,
+    /// estimates the most accessed keys, if enabled
+    hot_keys: Option<HotKeys>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
@@ -49,74 +198,1147 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
        KvsServer {
             engine,
             pool,
+            config: ServerConfig::default(),
         }
     }
 
+    /// Requires clients to authenticate with the given shared secret `token`, by sending a
+    /// `Request::Auth`, before any of their other requests are accepted.
+    /// Unauthenticated requests receive a `Response::Err`.
+    ///
+    /// Note that this is **not** strong security. The token is sent in plain text over the
+    /// connection, so it only deters casual access to the server.
+    pub fn auth_token(mut self, token: String) -> Self {
+        self.config.auth_token = Some(token);
+        self
+    }
+
+    /// Limits each connection to `max` requests, so that a single long-lived connection can't
+    /// monopolize a worker thread of a blocking [`ThreadPool`]. Once a connection has sent
+    /// `max` requests, the next request receives a `Response::Err` asking the client to
+    /// reconnect, and the connection is closed.
+    ///
+    /// Connections are not limited by default.
+    pub fn max_requests_per_connection(mut self, max: usize) -> Self {
+        self.config.max_requests = Some(max);
+        self
+    }
+
+    /// Sets how long an accepted connection may be idle before TCP keep-alive probes are sent
+    /// to the client, or disables keep-alive if `idle` is `None`. Keep-alive detects clients that
+    /// have silently gone away (e.g. dropped by a NAT), so the worker servicing the connection is
+    /// freed rather than blocking on a read forever.
+    ///
+    /// Once a connection has been idle for `idle`, probes are sent every `idle` (or every 10
+    /// seconds, if that is shorter). If the client doesn't answer, the operating system closes
+    /// the connection after its default number of probes (9 on Linux). Setting the probe
+    /// interval isn't supported on every platform, those platforms use their default interval.
+    ///
+    /// Defaults to [`DEFAULT_KEEPALIVE`].
+    pub fn keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.config.keepalive = idle;
+        self
+    }
+
+    /// Records every successful write (set or remove) in an append-only audit log at `path`,
+    /// separate from the storage engine's data. Each record is a single line containing the
+    /// time, the address of the client, the operation and the key, values are not recorded:
+    /// ```text
+    /// ts_ms=1634567890123 peer=127.0.0.1:50422 op=SET key="mykey"
+    /// ```
+    /// Records are buffered and flushed to the file every second.
+    ///
+    /// The file is created, or appended to, when the server is started.
+    pub fn audit_log<T: Into<PathBuf>>(mut self, path: T) -> Self {
+        self.config.audit_log = Some(path.into());
+        self
+    }
+
+    /// Limits the number of keys in a `Request::GetBatch` to `max`, so that a single request
+    /// can't exhaust the server's memory. Larger batches receive a `Response::Err`, starting with
+    /// "batch too large", without any of their keys being read.
+    ///
+    /// Defaults to [`DEFAULT_MAX_BATCH`].
+    pub fn max_get_batch(mut self, max: usize) -> Self {
+        self.config.max_get_batch = max;
+        self
+    }
+
+    /// Limits the number of key/value pairs in a `Request::MultiSet` or `Request::SetMany` to
+    /// `max`, so that a single request can't exhaust the server's memory. Larger batches receive a `Response::Err`,
+    /// starting with "batch too large", without any of their pairs being set.
+    ///
+    /// Defaults to [`DEFAULT_MAX_BATCH`].
+    pub fn max_multi_set(mut self, max: usize) -> Self {
+        self.config.max_multi_set = max;
+        self
+    }
+
+    /// Limits the number of connections that are serviced at once to `max` (at least 1). Once
+    /// the limit is reached, the server stops accepting connections until one of them closes,
+    /// so further connections wait in the operating system's listen backlog rather than piling
+    /// up in the [`ThreadPool`]'s queue.
+    ///
+    /// Defaults to the number of threads in the pool (see [`ThreadPool::threads`]), or no limit
+    /// if the pool doesn't have a fixed number of threads.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = Some(max.max(1));
+        self
+    }
+
+    /// Rejects every request that modifies data (see [`Request::is_write`]) with a
+    /// `Response::Err` of "server is read-only", without passing it to the engine, while reads
+    /// are served normally. This works with any engine, even one that can be written to, e.g. to
+    /// keep serving reads during maintenance.
+    ///
+    /// Servers accept writes by default.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Tracks the most accessed keys, so that they can be listed with a `Request::HotKeys`, e.g.
+    /// to find the keys worth caching or sharding. Every key read or written by a request is an
+    /// access, but only 1 in `sample_rate` accesses is counted, so tracking costs a single
+    /// atomic increment for most requests. The counts are estimated in a fixed amount of memory,
+    /// however many keys there are, and are halved every 100,000 counted accesses so that keys
+    /// that have cooled down drop out. At most 100 hot keys are reported.
+    ///
+    /// Hot keys aren't tracked by default, and a `Request::HotKeys` receives a `Response::Err`.
+    pub fn hot_keys(mut self, sample_rate: u32) -> Self {
+        self.config.hot_keys = Some(sample_rate.max(1));
+        self
+    }
+
+    /// Shuts the server down once it has had no open connection for `idle`, e.g. so that a server
+    /// started on demand frees its resources when it's no longer used. The server is idle from
+    /// the moment it starts serving, and from the moment its last open connection closes; a
+    /// connection that stays open keeps the server running, even if it sends no requests.
+    ///
+    /// The shutdown is graceful: the `shutdown` flag given to
+    /// [`run_until`](KvsServer::run_until) is set, so the server returns `Ok(())` as if the flag
+    /// had been set by the caller. The server never shuts down when idle by default.
+    pub fn idle_exit(mut self, idle: Duration) -> Self {
+        self.config.idle_exit = Some(idle);
+        self
+    }
+
+    /// Sets the capacity, in bytes, of the buffer that the requests of each connection are read
+    /// into (at least 1). A larger buffer reads more pipelined requests with each system call,
+    /// at the cost of memory per connection.
+    ///
+    /// Defaults to [`DEFAULT_READ_BUFFER`].
+    pub fn read_buffer(mut self, capacity: usize) -> Self {
+        self.config.read_buffer = capacity.max(1);
+        self
+    }
+
+    /// Sets the maximum length, in bytes, of a request line of the text protocol (at least 1),
+    /// excluding its line ending. A client that sends a longer line, e.g. one that never sends
+    /// a newline, receives `ERR line too long` and its connection is closed, so that it can't make
+    /// the server buffer an unbounded amount of data. Requests of the JSON protocol are limited
+    /// by [`max_value_size`](KvsServer::max_value_size) instead.
+    ///
+    /// Defaults to [`DEFAULT_MAX_LINE_LEN`].
+    pub fn max_line_len(mut self, max_line_len: usize) -> Self {
+        self.config.max_line_len = max_line_len.max(1);
+        self
+    }
+
+    /// Sets the maximum size, in bytes, of the values in a JSON or bincode request. A request may be at
+    /// most this size, plus 64 KiB for its key and framing. The server stops reading a request
+    /// as soon as it's longer than that, so a client can't exhaust the server's memory by
+    /// sending an enormous value; the client receives an error starting with "request too
+    /// large" and its connection is closed. The limit applies to whole requests, so it also
+    /// limits the total size of the values of a batch set.
+    ///
+    /// This is independent of any limit of the engine. Defaults to [`DEFAULT_MAX_VALUE_SIZE`].
+    pub fn max_value_size(mut self, max_value_size: usize) -> Self {
+        self.config.max_value_size = max_value_size;
+        self
+    }
+
+    /// Only accepts clients that use the given [`Protocol`]. By default clients may use either
+    /// protocol, each connection's protocol is detected from the first byte its client sends.
+    ///
+    /// A bincode client of a JSON only server is refused during its handshake, and a JSON
+    /// client of a bincode only server is sent a `Response::Err`, so either fails cleanly when
+    /// it connects. The line based text protocol is always accepted.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.config.protocol = Some(protocol);
+        self
+    }
+
+    /// Sends a [`ServerEvent`] to `events` whenever a connection is accepted or closed, and
+    /// whenever binding, accepting or servicing a connection fails, so that they can be observed
+    /// (e.g. asserted on by tests) rather than only being logged. Errors that are returned to a
+    /// client in a `Response::Err`, e.g. a key that doesn't exist, aren't events.
+    ///
+    /// Events are sent without blocking, and are dropped once the receiver has been dropped.
+    /// No events are sent by default.
+    pub fn events(mut self, events: Sender<ServerEvent>) -> Self {
+        self.config.events = Some(events);
+        self
+    }
+
+    /// Encrypts every connection with TLS, using the given `config`, which holds the server's
+    /// certificate and private key (see [`tls::server_config`](crate::tls::server_config)).
+    /// Clients must then connect with [`KvsClient::connect_tls`](crate::KvsClient::connect_tls),
+    /// plain connections fail the handshake and are closed. Connections aren't encrypted by
+    /// default. Requires the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.config.tls = Some(config);
+        self
+    }
+
     /// starts a server listening on the given address.
     /// Each request that comes in gets serviced on its own thread from the ThreadPool
     ///
+    /// The server runs forever, see [`run_until`](KvsServer::run_until) for a server that can
+    /// be shut down.
+    ///
     /// # Errors
     /// returns [`KvsError`] if the server could not be started
     ///
     /// [`KvsError`]: ./enum.KvsError.html
     pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let eng = self.engine.clone();
-                    self.pool.spawn(move || {
-                        if let Err(e) = serve(eng, stream) {
+        self.bind(addr)?.serve()
+    }
+
+    /// starts a server listening on the given address, like [`run`](KvsServer::run), that
+    /// stops accepting connections and returns `Ok(())` once the `shutdown` flag is set.
+    ///
+    /// The listener is polled, so the server notices the flag within a short interval even if
+    /// no connections are arriving. Connections that are already being serviced are not
+    /// interrupted.
+    ///
+    /// # Errors
+    /// returns [`KvsError`] if the server could not be started
+    ///
+    /// [`KvsError`]: ./enum.KvsError.html
+    pub fn run_until<A: ToSocketAddrs>(self, addr: A, shutdown: Arc<AtomicBool>) -> Result<()> {
+        self.bind(addr)?.serve_until(shutdown)
+    }
+
+    /// binds the server to the given address, without accepting any connections yet.
+    ///
+    /// The returned [`BoundServer`] knows the address it is bound to, so a server can be bound
+    /// to port 0 and the port assigned by the operating system read with
+    /// [`local_addr`](BoundServer::local_addr) before connections are served.
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{KvsServer, MemoryKvsEngine};
+    /// use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(4)?)
+    ///     .bind("127.0.0.1:0")?;
+    /// let addr = server.local_addr()?;
+    /// std::thread::spawn(move || server.serve());
+    /// // connect a client to `addr`
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    /// returns [`KvsError`] if the address could not be bound, or the audit log could not be
+    /// opened
+    ///
+    /// [`KvsError`]: ./enum.KvsError.html
+    pub fn bind<A: ToSocketAddrs>(self, addr: A) -> Result<BoundServer<E, P>> {
+        let listener = TcpListener::bind(addr)
+            .inspect_err(|e| self.config.emit(|| ServerEvent::Error(format!("bind failed: {}", e))))?;
+        listener.set_nonblocking(true)?;
+        let audit_log = match &self.config.audit_log {
+            Some(path) => Some(AuditLog::open(path)?),
+            None => None,
+        };
+        let state = Arc::new(ServerState {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            last_activity: AtomicU64::new(0),
+            audit_log,
+            get_latency: LatencyHistogram::default(),
+            set_latency: LatencyHistogram::default(),
+            remove_latency: LatencyHistogram::default(),
+            hot_keys: self.config.hot_keys.map(HotKeys::new),
+        });
+        Ok(BoundServer {
+            server: self,
+            listener,
+            state,
+        })
+    }
+}
+
+/// A [`KvsServer`] that is bound to an address, but isn't accepting connections yet.
+/// Created by [`KvsServer::bind`].
+pub struct BoundServer<E: KvsEngine, P: ThreadPool> {
+    server: KvsServer<E, P>,
+    listener: TcpListener,
+    state: Arc<ServerState>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> BoundServer<E, P> {
+    /// returns the address the server is bound to
+    ///
+    /// # Errors
+    /// returns [`KvsError::Io`](crate::KvsError::Io) if the address could not be read from the
+    /// listener
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// accepts connections forever.
+    /// Each request that comes in gets serviced on its own thread from the ThreadPool
+    ///
+    /// # Errors
+    /// returns [`KvsError`] if the server could not be started
+    ///
+    /// [`KvsError`]: ./enum.KvsError.html
+    pub fn serve(self) -> Result<()> {
+        self.serve_until(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// accepts connections until the `shutdown` flag is set, see [`KvsServer::run_until`]
+    ///
+    /// # Errors
+    /// returns [`KvsError`] if the server could not be started
+    ///
+    /// [`KvsError`]: ./enum.KvsError.html
+    pub fn serve_until(self, shutdown: Arc<AtomicBool>) -> Result<()> {
+        let BoundServer { server, listener, state } = self;
+        let config = Arc::new(server.config);
+        let max_connections = config
+            .max_connections
+            .or_else(|| server.pool.threads().map(|threads| threads.max(1) as usize));
+        let semaphore = max_connections.map(|max| Arc::new(Semaphore::new(max)));
+        state.touch();
+        let watchdog = config.idle_exit.map(|idle| {
+            let state = Arc::clone(&state);
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || watch_idle(&state, idle, &shutdown))
+        });
+        while !shutdown.load(Ordering::SeqCst) {
+            // a connection is only accepted once there's a permit to service it
+            let permit = match &semaphore {
+                Some(semaphore) => match semaphore.acquire_timeout(ACCEPT_POLL_INTERVAL) {
+                    Some(permit) => Some(permit),
+                    None => continue,
+                },
+                None => None,
+            };
+            match listener.accept() {
+                Ok((stream, peer_addr)) => {
+                    // the accepted stream inherits the listener's non-blocking mode
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        error!("Connection failed: {}", e);
+                        config.emit(|| ServerEvent::Error(format!("connection from {} failed: {}", peer_addr, e)));
+                        continue;
+                    }
+                    state.touch();
+                    config.emit(|| ServerEvent::Accepted(peer_addr));
+                    // every response is flushed once it's complete, so Nagle's algorithm would
+                    // only hold back the responses to pipelined requests until the client
+                    // acknowledges the previous one
+                    if let Err(e) = stream.set_nodelay(true) {
+                        warn!("could not disable Nagle's algorithm on a connection: {}", e);
+                    }
+                    if let Some(idle) = config.keepalive {
+                        if let Err(e) = set_keepalive(&stream, idle) {
+                            warn!("could not enable keep-alive on a connection: {}", e);
+                        }
+                    }
+                    let eng = server.engine.clone();
+                    let config = Arc::clone(&config);
+                    let state = Arc::clone(&state);
+                    server.pool.spawn(move || {
+                        // the permit is released once the connection is closed
+                        let _permit = permit;
+                        state.connections.fetch_add(1, Ordering::SeqCst);
+                        if let Err(e) = serve(eng, stream, &config, &state) {
                             error!("Error on serving client: {}", e);
+                            config.emit(|| ServerEvent::Error(format!("error serving {}: {}", peer_addr, e)));
                         }
+                        state.touch();
+                        state.connections.fetch_sub(1, Ordering::SeqCst);
+                        config.emit(|| ServerEvent::Closed(peer_addr));
                     });
 
                 }
-                Err(e) => error!("Connection failed: {}", e),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    config.emit(|| ServerEvent::Error(format!("accept failed: {}", e)));
+                }
             }
         }
+        debug!("shutting down, no longer accepting connections");
+        if let Some(watchdog) = watchdog {
+            // the watchdog stops once it sees the shutdown flag
+            let _ = watchdog.join();
+        }
         Ok(())
     }
 }
 
+impl ServerState {
+    /// records that a connection was just accepted or closed
+    fn touch(&self) {
+        self.last_activity.store(self.started.elapsed().as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// returns how long the server has had no open connection, or `None` if a connection is open
+    fn idle_for(&self) -> Option<Duration> {
+        if self.connections.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        let last_activity = Duration::from_millis(self.last_activity.load(Ordering::SeqCst));
+        Some(self.started.elapsed().saturating_sub(last_activity))
+    }
+}
+
+/// sets the `shutdown` flag once the server has been idle for `idle`, or returns once the flag
+/// is set by someone else
+fn watch_idle(state: &ServerState, idle: Duration, shutdown: &AtomicBool) {
+    while !shutdown.load(Ordering::SeqCst) {
+        if state.idle_for().is_some_and(|idle_for| idle_for >= idle) {
+            info!("no connections for {:?}, shutting down", idle);
+            shutdown.store(true, Ordering::SeqCst);
+            return;
+        }
+        thread::sleep(IDLE_CHECK_INTERVAL.min(idle));
+    }
+}
+
+impl ServerConfig {
+    /// sends the event made by `event` to the server's events channel, if it has one. The event
+    /// is only made if it will be sent
+    fn emit(&self, event: impl FnOnce() -> ServerEvent) {
+        if let Some(events) = &self.events {
+            // the receiver may have been dropped, in which case no one is interested in events
+            let _ = events.send(event());
+        }
+    }
+}
+
+/// A counting semaphore, that limits the number of connections serviced at once
+#[derive(Debug)]
+struct Semaphore {
+    /// the number of permits that are available
+    permits: Mutex<usize>,
+    /// notified when a permit is released
+    released: Condvar,
+}
+
+/// A permit acquired from a [`Semaphore`], which is released when it is dropped
+#[derive(Debug)]
+struct Permit(Arc<Semaphore>);
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// acquires a permit, waiting up to `timeout` for one to be released.
+    /// Returns `None` if no permit was available in time
+    fn acquire_timeout(self: &Arc<Self>, timeout: Duration) -> Option<Permit> {
+        let permits = self.permits.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (mut permits, _timeout) = self
+            .released
+            .wait_timeout_while(permits, timeout, |permits| *permits == 0)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if *permits == 0 {
+            return None;
+        }
+        *permits -= 1;
+        Some(Permit(Arc::clone(self)))
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.0.permits.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) += 1;
+        self.0.released.notify_one();
+    }
+}
+
 /// Listens for and processes kvs [`Request`]s coming over the given `tcp` stream
 /// This function will: deserialize the request, execute the request in the KvsEngine,
-/// and finally return a [`Response`] to the client on the `tcp` stream
+/// and finally return a [`Response`] to the client on the `tcp` stream.
+/// If the server has a TLS configuration, the stream is encrypted with TLS first.
+///
+/// Clients can speak the JSON protocol, the bincode protocol or the line based text protocol
+/// (see [`Protocol`] and [`Request::parse_text`]). The protocol is detected from the first byte
+/// sent by the client, JSON requests always start with a `{` and bincode clients start with a
+/// handshake byte.
 ///
 /// [`Request`]: ./enum.Request.html
 /// [`Response`]: ./enum.Response.html
+/// [`Request::parse_text`]: ./enum.Request.html#method.parse_text
 ///
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+fn serve<E: KvsEngine>(engine: E, tcp: TcpStream, config: &ServerConfig, state: &ServerState) -> Result<()> {
     let peer_addr = tcp.peer_addr()?;
-    let stream_reader = BufReader::new(&tcp);
-    let mut stream_writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(stream_reader).into_iter::<Request>();
-
-    let mut send_resp = move |resp: Response| -> Result<()> {
-        serde_json::to_writer(&mut stream_writer, &resp)?;
-        stream_writer.flush()?;
-        debug!("Response sent to {}: {:?}", peer_addr, resp);
-        Ok(())
+    #[cfg(feature = "tls")]
+    let stream = match &config.tls {
+        Some(tls) => Stream::tls_server(tcp, Arc::clone(tls))?,
+        None => Stream::Tcp(tcp),
     };
+    #[cfg(not(feature = "tls"))]
+    let stream = Stream::Tcp(tcp);
+    let mut reader = BufReader::with_capacity(config.read_buffer, stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let session = Session {
+        engine,
+        config,
+        state,
+        peer_addr,
+        // connections only need to authenticate if the server has an auth token
+        authenticated: config.auth_token.is_none(),
+        // clients that don't negotiate a version use the oldest supported version
+        version: MIN_PROTOCOL_VERSION,
+        processed: 0,
+        body: None,
+        keys: None,
+    };
+
+    match detect_protocol(&mut reader)? {
+        Some(Some(protocol)) if config.protocol.is_some_and(|accepted| accepted != protocol) => {
+            warn!("refusing connection from {}, it is using the {:?} protocol", peer_addr, protocol);
+            refuse(reader, writer, protocol)
+        }
+        Some(Some(Protocol::Json)) => serve_json(reader, writer, session),
+        Some(Some(Protocol::Bincode)) => {
+            debug!("{} is using the bincode protocol", peer_addr);
+            // acknowledge the client's handshake
+            reader.consume(1);
+            let mut newline = [0];
+            reader.read_exact(&mut newline)?;
+            if newline != *b"\n" {
+                return Err(KvsError::StringErr(format!("malformed bincode handshake from {}", peer_addr)));
+            }
+            writer.write_all(&[BINCODE_HELLO])?;
+            writer.flush()?;
+            serve_bincode(reader, writer, session)
+        }
+        Some(None) => {
+            debug!("{} is using the text protocol", peer_addr);
+            serve_text(reader, writer, session)
+        }
+        // the client disconnected without sending anything
+        None => Ok(()),
+    }
+}
+
+/// tells a client that its `protocol` isn't accepted by the server, in a way the client can
+/// understand, before its connection is closed
+fn refuse(mut reader: BufReader<Stream>, mut writer: BufWriter<Stream>, protocol: Protocol) -> Result<()> {
+    match protocol {
+        Protocol::Json => {
+            let msg = "the server only accepts the bincode protocol".to_string();
+            serde_json::to_writer(&mut writer, &Response::Err(msg))?;
+        }
+        Protocol::Bincode => {
+            reader.consume(1);
+            writer.write_all(&[BINCODE_REJECTED])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
 
+/// services a client speaking the JSON protocol
+fn serve_json<E: KvsEngine>(reader: BufReader<Stream>, mut writer: BufWriter<Stream>, mut session: Session<E>) -> Result<()> {
+    let max_value_size = session.config.max_value_size;
+    let limit = RequestLimit::new(max_value_size.saturating_add(REQUEST_OVERHEAD));
+    // a single deserializer reads every request of the connection. It stops reading at the end
+    // of each request, so the bytes of pipelined requests that follow it stay buffered for the
+    // next iteration
+    let req_reader = Deserializer::from_reader(LimitedReader { inner: reader, limit: &limit }).into_iter::<Request>();
     for req in req_reader {
-        let req = req?;
-        debug!("Receive request from {}: {:?}", peer_addr, req);
+        let req = match req {
+            Err(_) if limit.exceeded.get() => {
+                // the rest of the request is never read, so the connection can't be used again
+                warn!("closing connection from {}, its request is too large", session.peer_addr);
+                let msg = format!("request too large, values are limited to {} bytes", max_value_size);
+                serde_json::to_writer(&mut writer, &Response::Err(msg))?;
+                writer.flush()?;
+                break;
+            }
+            req => req?,
+        };
+        limit.reset();
+        let (resp, close) = session.handle(req);
+        write_response(Protocol::Json, &mut writer, &mut session, resp)?;
+        if close {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// services a client speaking the bincode protocol, whose handshake has been acknowledged
+fn serve_bincode<E: KvsEngine>(mut reader: BufReader<Stream>, mut writer: BufWriter<Stream>, mut session: Session<E>) -> Result<()> {
+    let max_value_size = session.config.max_value_size;
+    let max_len = max_value_size.saturating_add(REQUEST_OVERHEAD);
+    while let Some(len) = Protocol::read_len(&mut reader)? {
+        if len as usize > max_len {
+            // the request is never read, so the connection can't be used again
+            warn!("closing connection from {}, its request is too large", session.peer_addr);
+            let msg = format!("request too large, values are limited to {} bytes", max_value_size);
+            Protocol::Bincode.write(&mut writer, &Response::Err(msg))?;
+            writer.flush()?;
+            break;
+        }
+        let req = Protocol::read_payload(&mut reader, len)?;
+        let (resp, close) = session.handle(req);
+        write_response(Protocol::Bincode, &mut writer, &mut session, resp)?;
+        if close {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// writes the response to a request, followed by anything streamed after it, and flushes the
+/// `writer`
+fn write_response<E: KvsEngine>(protocol: Protocol, writer: &mut BufWriter<Stream>, session: &mut Session<E>, resp: Response) -> Result<()> {
+    protocol.write(writer, &resp)?;
+    // a streamed value is written straight after its response, without buffering all of it
+    if let Some(mut body) = session.body.take() {
+        let len = body.len();
+        if io::copy(&mut body, writer)? != len {
+            // the client would wait forever for the rest of the value, so close the connection
+            return Err(KvsError::StringErr(format!("value of {} bytes was cut short", len)));
+        }
+    }
+    // the rest of a key listing is streamed straight after its first response
+    while let Some(resp) = session.next_keys() {
+        protocol.write(writer, &resp)?;
+    }
+    writer.flush()?;
+    debug!("Response sent to {}: {:?}", session.peer_addr, resp);
+    Ok(())
+}
+
+/// The number of bytes left to read of the current JSON request of a connection
+struct RequestLimit {
+    max: usize,
+    remaining: Cell<usize>,
+    // whether a read failed because the request is longer than the maximum
+    exceeded: Cell<bool>,
+}
+
+impl RequestLimit {
+    fn new(max: usize) -> Self {
+        RequestLimit {
+            max,
+            remaining: Cell::new(max),
+            exceeded: Cell::new(false),
+        }
+    }
+
+    /// allows the next request to be read
+    fn reset(&self) {
+        self.remaining.set(self.max);
+    }
+}
+
+/// A reader that fails once the current request has used up its [`RequestLimit`]
+struct LimitedReader<'a, R> {
+    inner: R,
+    limit: &'a RequestLimit,
+}
+
+impl<R: Read> Read for LimitedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.limit.remaining.get();
+        if remaining == 0 && !buf.is_empty() {
+            self.limit.exceeded.set(true);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+        }
+        let len = buf.len().min(remaining);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.limit.remaining.set(remaining - read);
+        Ok(read)
+    }
+}
 
-        match req {
-            Request::Get { key } => match engine.get(key) {
-                Ok(value) => send_resp(Response::Ok(value))?,
-                Err(e) => send_resp(Response::Err(format!("{}", e)))?,
+/// services a client speaking the text protocol. Every request is a line of text, and every
+/// response is a single line: `OK`, `VALUE <value>`, `NOT_FOUND` or `ERR <message>`
+fn serve_text<E: KvsEngine>(mut reader: BufReader<Stream>, mut writer: BufWriter<Stream>, mut session: Session<E>) -> Result<()> {
+    let max_line_len = session.config.max_line_len;
+    while let Some(line) = read_line(&mut reader, max_line_len)? {
+        let Some(line) = line else {
+            warn!("closing connection from {}, its line is longer than {} bytes", session.peer_addr, max_line_len);
+            writeln!(writer, "ERR line too long")?;
+            writer.flush()?;
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (resp, close) = match Request::parse_text(&line) {
+            Ok(req) => {
+                let is_get = matches!(req, Request::Get { .. });
+                let (resp, close) = session.handle(req);
+                let text = match resp {
+                    Response::Ok(Some(value)) => format!("VALUE {}", value),
+                    Response::Ok(None) if is_get => "NOT_FOUND".to_string(),
+                    Response::Ok(None) | Response::Seq(_) => "OK".to_string(),
+                    Response::Err(msg) => format!("ERR {}", msg),
+                    resp => format!("ERR unexpected response {:?}", resp),
+                };
+                (text, close)
+            }
+            Err(e) => (format!("ERR {}", e), false),
+        };
+        writeln!(writer, "{}", resp)?;
+        writer.flush()?;
+        debug!("Response sent to {}: {}", session.peer_addr, resp);
+        if close {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// reads a line of at most `max_len` bytes, excluding its line ending, from the `reader`.
+/// Returns `None` at the end of the stream, or `Some(None)` if the line is too long, in which
+/// case only the first `max_len` bytes of it have been read
+fn read_line(reader: &mut BufReader<Stream>, max_len: usize) -> io::Result<Option<Option<String>>> {
+    let mut line = vec![];
+    // a line of `max_len` bytes may be followed by a "\r\n"
+    reader.by_ref().take(max_len as u64 + 2).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+    if line.len() > max_len {
+        return Ok(Some(None));
+    }
+    String::from_utf8(line)
+        .map(|line| Some(Some(line)))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))
+}
+
+/// peeks at the first non-whitespace byte sent by the client, to determine which protocol
+/// it is speaking. JSON requests always start with a `{`, and bincode clients send a
+/// [`BINCODE_HELLO`], anything else is the text protocol. Returns `None` if the client
+/// disconnected without sending anything, or `Some(None)` for the text protocol
+fn detect_protocol(reader: &mut BufReader<Stream>) -> io::Result<Option<Option<Protocol>>> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(start) => {
+                let protocol = match buf[start] {
+                    b'{' => Some(Protocol::Json),
+                    BINCODE_HELLO => Some(Protocol::Bincode),
+                    _ => None,
+                };
+                reader.consume(start);
+                return Ok(Some(protocol));
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+/// The state of a single client connection
+struct Session<'a, E: KvsEngine> {
+    engine: E,
+    config: &'a ServerConfig,
+    state: &'a ServerState,
+    peer_addr: SocketAddr,
+    // whether the client has sent the server's auth token
+    authenticated: bool,
+    // the protocol version used on this connection
+    version: u32,
+    // the number of requests processed on this connection
+    processed: usize,
+    // the value to stream to the client after the current response
+    body: Option<ValueReader>,
+    // the pattern of a key listing that is being streamed to the client, and the last key
+    // that has been scanned for it
+    keys: Option<(String, String)>,
+}
+
+impl<E: KvsEngine> Session<'_, E> {
+    /// executes the given `req`uest, returning the [`Response`] for the client and whether the
+    /// connection should be closed after the response is sent.
+    ///
+    /// The first request on a connection may be a `Request::Hello`, used to negotiate the
+    /// protocol version. If the client's version is older than the [`MIN_PROTOCOL_VERSION`], the
+    /// client is sent an error and the connection is closed.
+    ///
+    /// If the server has an auth token, every request other than a `Request::Hello` or
+    /// `Request::Auth` is rejected until the client has authenticated.
+    ///
+    /// If the server limits the number of requests per connection, the connection is closed
+    /// once the limit is reached.
+    ///
+    /// Batch requests that are larger than the server's limits, and writes to a read-only
+    /// server, are rejected before the engine is used.
+    fn handle(&mut self, req: Request) -> (Response, bool) {
+        let peer_addr = self.peer_addr;
+        debug!("Receive request from {} (protocol v{}): {:?}", peer_addr, self.version, req);
+
+        if let Request::Hello { version: client_version } = req {
+            if client_version < MIN_PROTOCOL_VERSION {
+                warn!("closing connection from {}, unsupported protocol version {}", peer_addr, client_version);
+                let msg = format!(
+                    "unsupported protocol version {}, the server supports versions {} to {}",
+                    client_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                );
+                return (Response::Err(msg), true);
+            }
+            self.version = client_version.min(PROTOCOL_VERSION);
+            debug!("negotiated protocol version {} with {}", self.version, peer_addr);
+            return (Response::Hello { version: self.version }, false);
+        }
+
+        if self.config.max_requests.is_some_and(|max| self.processed >= max) {
+            debug!("closing connection from {} after {} requests", peer_addr, self.processed);
+            let msg = format!("connection request limit of {} reached, please reconnect", self.processed);
+            return (Response::Err(msg), true);
+        }
+        self.processed += 1;
+
+        // liveness and readiness checks don't require authentication, so that load balancers
+        // can use them
+        let exempt = matches!(req, Request::Auth { .. } | Request::Ping | Request::Health);
+        if !self.authenticated && !exempt {
+            warn!("rejected unauthenticated request from {}", peer_addr);
+            return (Response::Err("authentication required".to_string()), false);
+        }
+
+        if let Some(msg) = self.check_batch_size(&req) {
+            warn!("rejected request from {}: {}", peer_addr, msg);
+            return (Response::Err(msg), false);
+        }
+
+        if self.config.read_only && req.is_write() {
+            debug!("rejected write from {}, the server is read-only", peer_addr);
+            return (Response::Err("server is read-only".to_string()), false);
+        }
+
+        let state = self.state;
+        if let Some(hot_keys) = &state.hot_keys {
+            req.for_each_key(|key| hot_keys.record(key));
+        }
+        let resp = match req {
+            Request::Get { key } => match timed(&state.get_latency, || self.engine.get(key)) {
+                Ok(value) => Response::Ok(value),
+                Err(e) => self.error_response(e),
             },
-            Request::Set { key, value } => match engine.set(key, value) {
-                Ok(_) => send_resp(Response::Ok(None))?,
-                Err(e) => send_resp(Response::Err(format!("{}", e)))?,
+            Request::GetRange { key, start, len } => {
+                match timed(&state.get_latency, || self.engine.get_range(key, start, len)) {
+                    Ok(value) => Response::Ok(value),
+                    Err(e) => self.error_response(e),
+                }
+            }
+            Request::Contains { key } => match timed(&state.get_latency, || self.engine.contains_key(key)) {
+                Ok(contains) => Response::Contains(contains),
+                Err(e) => self.error_response(e),
             },
-            Request::Remove { key } => match engine.remove(key) {
-                Ok(_) => send_resp(Response::Ok(None))?,
-                Err(e) => send_resp(Response::Err(format!("{}", e)))?,
+            Request::GetWithMeta { key } => match timed(&state.get_latency, || self.engine.get_with_meta(key)) {
+                Ok(Some((value, written_at))) => {
+                    let written_at = written_at
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_millis() as u64);
+                    Response::Meta { value, written_at }
+                }
+                Ok(None) => Response::Ok(None),
+                Err(e) => self.error_response(e),
+            },
+            Request::Set { key, value } => match timed(&state.set_latency, || self.engine.set_with_seq(key.clone(), value)) {
+                Ok(seq) => {
+                    self.audit("SET", &key);
+                    seq.map_or(Response::Ok(None), Response::Seq)
+                }
+                Err(e) => self.error_response(e),
+            },
+            Request::Remove { key } => match timed(&state.remove_latency, || self.engine.remove_with_seq(key.clone())) {
+                Ok(seq) => {
+                    self.audit("REMOVE", &key);
+                    seq.map_or(Response::Ok(None), Response::Seq)
+                }
+                Err(e) => self.error_response(e),
+            },
+            Request::Len => match self.engine.key_count() {
+                Ok(count) => Response::Ok(Some(count.to_string())),
+                Err(e) => self.error_response(e),
+            },
+            Request::Stats => match self.engine.stats() {
+                Ok(stats) => Response::Stats(Stats {
+                    uptime_secs: self.state.started.elapsed().as_secs(),
+                    connections: self.state.connections.load(Ordering::SeqCst),
+                    get_latency: state.get_latency.stats(),
+                    set_latency: state.set_latency.stats(),
+                    remove_latency: state.remove_latency.stats(),
+                    ..stats
+                }),
+                Err(e) => self.error_response(e),
+            },
+            Request::ResetStats => match self.engine.stats().and_then(|stats| self.engine.reset_stats().map(|()| stats)) {
+                Ok(stats) => {
+                    debug!("stats reset by {}", peer_addr);
+                    Response::Stats(Stats {
+                        uptime_secs: self.state.started.elapsed().as_secs(),
+                        connections: self.state.connections.load(Ordering::SeqCst),
+                        get_latency: state.get_latency.take(),
+                        set_latency: state.set_latency.take(),
+                        remove_latency: state.remove_latency.take(),
+                        ..stats
+                    })
+                }
+                Err(e) => self.error_response(e),
+            },
+            Request::GetStream { key } => match timed(&state.get_latency, || self.engine.get_reader(key)) {
+                Ok(Some(reader)) => {
+                    let len = reader.len();
+                    self.body = Some(reader);
+                    Response::Value { len }
+                }
+                Ok(None) => Response::Ok(None),
+                Err(e) => self.error_response(e),
+            },
+            Request::SetTagged { key, value, tag } => {
+                match timed(&state.set_latency, || self.engine.set_tagged(key.clone(), value, tag)) {
+                    Ok(()) => {
+                        self.audit("SET", &key);
+                        Response::Ok(None)
+                    }
+                    Err(e) => self.error_response(e),
+                }
+            }
+            Request::GetTagged { key } => match timed(&state.get_latency, || self.engine.get_tagged(key)) {
+                Ok(Some((value, tag))) => Response::Tagged { value, tag },
+                Ok(None) => Response::Ok(None),
+                Err(e) => self.error_response(e),
+            },
+            Request::GetBatch { keys } => {
+                let values = timed(&state.get_latency, || {
+                    keys.into_iter().map(|key| self.engine.get(key)).collect::<Result<Vec<_>>>()
+                });
+                match values {
+                    Ok(values) => Response::Values(values),
+                    Err(e) => self.error_response(e),
+                }
+            }
+            Request::MultiSet { pairs } => {
+                let keys: Vec<String> = pairs.iter().map(|(key, _value)| key.clone()).collect();
+                let committed = timed(&state.set_latency, || {
+                    self.engine.transaction(|tx| {
+                        for (key, value) in pairs {
+                            tx.set(key, value);
+                        }
+                        Ok(())
+                    })
+                });
+                match committed {
+                    Ok(()) => {
+                        for key in &keys {
+                            self.audit("SET", key);
+                        }
+                        Response::Ok(None)
+                    }
+                    Err(e) => self.error_response(e),
+                }
+            }
+            Request::SetMany { pairs } => {
+                let keys: Vec<String> = pairs.iter().map(|(key, _value)| key.clone()).collect();
+                let results = timed(&state.set_latency, || self.engine.set_each(pairs));
+                let results = keys
+                    .iter()
+                    .zip(results)
+                    .map(|(key, result)| result.map(|()| self.audit("SET", key)).map_err(|e| format!("{}", e)))
+                    .collect();
+                Response::Results(results)
+            }
+            Request::Append { key, suffix, separator } => {
+                match timed(&state.set_latency, || self.engine.append(key.clone(), suffix, separator)) {
+                    Ok(value) => {
+                        self.audit("APPEND", &key);
+                        Response::Ok(Some(value))
+                    }
+                    Err(e) => self.error_response(e),
+                }
+            }
+            Request::GetSet { key, value } => {
+                match timed(&state.set_latency, || self.engine.get_set(key.clone(), value)) {
+                    Ok(old) => {
+                        self.audit("SET", &key);
+                        Response::Ok(old)
+                    }
+                    Err(e) => self.error_response(e),
+                }
+            }
+            Request::Cas { key, expected, new } => {
+                match timed(&state.set_latency, || self.engine.compare_and_swap(key.clone(), expected, new)) {
+                    Ok(swapped) => {
+                        if swapped {
+                            self.audit("CAS", &key);
+                        }
+                        Response::Swapped(swapped)
+                    }
+                    Err(e) => self.error_response(e),
+                }
+            }
+            Request::Rename { from, to } => {
+                match timed(&state.set_latency, || self.engine.rename(from.clone(), to.clone())) {
+                    Ok(()) => {
+                        self.audit("SET", &to);
+                        self.audit("REMOVE", &from);
+                        Response::Ok(None)
+                    }
+                    Err(e) => self.error_response(e),
+                }
+            }
+            Request::LogInfo => match self.engine.log_info() {
+                Ok(logs) => Response::LogInfo(logs),
+                Err(e) => self.error_response(e),
+            },
+            Request::Compact => match self.engine.compact() {
+                Ok(()) => {
+                    info!("compaction requested by {}", peer_addr);
+                    Response::Ok(None)
+                }
+                Err(e) => self.error_response(e),
+            },
+            Request::Scan { cursor, limit } => match self.engine.scan(cursor, limit) {
+                Ok((keys, next_cursor)) => Response::Keys { keys, next_cursor },
+                Err(e) => self.error_response(e),
+            },
+            Request::Keys { pattern } => self.keys_response(pattern, None),
+            Request::ScanPrefix { prefix } => match self.engine.scan_prefix(prefix) {
+                Ok(keys) => Response::Keys { keys, next_cursor: None },
+                Err(e) => self.error_response(e),
+            },
+            Request::HotKeys { n } => match &state.hot_keys {
+                Some(hot_keys) => Response::HotKeys(hot_keys.top(n)),
+                None => Response::Err("hot keys are not tracked by this server".to_string()),
+            },
+            Request::Ping => Response::Ok(None),
+            Request::Health => match self.engine.health() {
+                Ok(()) => Response::Ok(None),
+                Err(e) => {
+                    warn!("health check failed: {}", e);
+                    self.error_response(e)
+                }
+            },
+            // handled before the requirement to authenticate
+            Request::Hello { .. } => unreachable!("hello requests are handled above"),
+            Request::Auth { token } => match &self.config.auth_token {
+                Some(expected) if *expected != token => {
+                    warn!("invalid auth token received from {}", peer_addr);
+                    Response::Err("invalid authentication token".to_string())
+                }
+                _ => {
+                    self.authenticated = true;
+                    Response::Ok(None)
+                }
             },
         };
+        (resp, false)
     }
-    Ok(())
-}
\ No newline at end of file
+
+    /// scans the engine's keys after the `cursor` for the next chunk of keys that match the
+    /// glob `pattern`. If the scan stops before the end of the keyspace, the response has a
+    /// `next_cursor` and the rest of the listing is left pending for [`Session::next_keys`]
+    fn keys_response(&mut self, pattern: String, mut cursor: Option<String>) -> Response {
+        let mut keys = vec![];
+        loop {
+            let (page, next_cursor) = match self.engine.scan(cursor, KEYS_SCAN_PAGE) {
+                Ok(page) => page,
+                Err(e) => return self.error_response(e),
+            };
+            keys.extend(page.into_iter().filter(|key| glob_match(&pattern, key)));
+            match next_cursor {
+                Some(next) if keys.len() < KEYS_CHUNK => cursor = Some(next),
+                next_cursor => {
+                    if let Some(next) = &next_cursor {
+                        self.keys = Some((pattern, next.clone()));
+                    }
+                    return Response::Keys { keys, next_cursor };
+                }
+            }
+        }
+    }
+
+    /// returns the next response of a key listing that is being streamed to the client, or
+    /// `None` once the listing is complete
+    fn next_keys(&mut self) -> Option<Response> {
+        let (pattern, cursor) = self.keys.take()?;
+        Some(self.keys_response(pattern, Some(cursor)))
+    }
+
+    /// returns the error message for a batch request that is larger than the server allows, or
+    /// `None` if the request isn't too large
+    fn check_batch_size(&self, req: &Request) -> Option<String> {
+        let (kind, len, max) = match req {
+            Request::GetBatch { keys } => ("keys per GetBatch", keys.len(), self.config.max_get_batch),
+            Request::MultiSet { pairs } => ("pairs per MultiSet", pairs.len(), self.config.max_multi_set),
+            Request::SetMany { pairs } => ("pairs per SetMany", pairs.len(), self.config.max_multi_set),
+            _ => return None,
+        };
+        (len > max).then(|| format!("batch too large: {} {}, the server allows at most {}", len, kind, max))
+    }
+
+    /// builds the response to a request that failed with the error `e`. A missing key is
+    /// reported with a `Response::KeyNotFound` to clients that negotiated protocol version 3 or
+    /// later, so that they can tell it apart from other errors
+    fn error_response(&self, e: KvsError) -> Response {
+        match e {
+            KvsError::KeyNotFound if self.version >= 3 => Response::KeyNotFound,
+            e => Response::Err(format!("{}", e)),
+        }
+    }
+
+    /// records a successful write in the audit log, if there is one
+    fn audit(&self, op: &str, key: &str) {
+        if let Some(audit_log) = &self.state.audit_log {
+            audit_log.record(self.peer_addr, op, key);
+        }
+    }
+}
+
+/// runs `f`, recording how long it took in the given `histogram`
+fn timed<T>(histogram: &LatencyHistogram, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    histogram.record(start.elapsed());
+    result
+}
+
+/// Enables TCP keep-alive on the given `stream`, sending probes once the connection has been
+/// idle for `idle`. See [`KvsServer::keepalive`] for the details
+pub(crate) fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "windows",
+    ))]
+    let keepalive = keepalive.with_interval(idle.min(MAX_KEEPALIVE_PROBE_INTERVAL));
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
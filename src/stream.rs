@@ -0,0 +1,118 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(feature = "tls")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "tls")]
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
+
+/// A connection between a client and a server, which is either a plain TCP stream or, with the
+/// `tls` feature, a TLS stream over TCP. The framing of requests and responses is the same on
+/// both.
+///
+/// A clone of a stream shares its connection, so that one clone can be buffered for reading and
+/// another for writing.
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    TlsServer(TcpStream, Arc<Mutex<StreamOwned<ServerConnection, TcpStream>>>),
+    #[cfg(feature = "tls")]
+    TlsClient(TcpStream, Arc<Mutex<StreamOwned<ClientConnection, TcpStream>>>),
+}
+
+impl Stream {
+    /// wraps the `tcp` stream of an accepted connection in a TLS stream using the server's
+    /// `config`. The handshake is done by the first read or write
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls_server(tcp: TcpStream, config: Arc<rustls::ServerConfig>) -> io::Result<Stream> {
+        let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        let socket = tcp.try_clone()?;
+        Ok(Stream::TlsServer(socket, Arc::new(Mutex::new(StreamOwned::new(conn, tcp)))))
+    }
+
+    /// wraps the `tcp` stream of a connection to the server called `server_name` in a TLS
+    /// stream using the client's `config`. The handshake is done by the first read or write
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls_client(
+        tcp: TcpStream,
+        config: Arc<rustls::ClientConfig>,
+        server_name: rustls::pki_types::ServerName<'static>,
+    ) -> io::Result<Stream> {
+        let conn = ClientConnection::new(config, server_name).map_err(io::Error::other)?;
+        let socket = tcp.try_clone()?;
+        Ok(Stream::TlsClient(socket, Arc::new(Mutex::new(StreamOwned::new(conn, tcp)))))
+    }
+
+    /// returns the underlying TCP stream, e.g. to configure socket options. Reading from or
+    /// writing to it directly would corrupt a TLS stream
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Tcp(tcp) => tcp,
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(tcp, _) | Stream::TlsClient(tcp, _) => tcp,
+        }
+    }
+
+    /// creates a new handle to the same connection
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        Ok(match self {
+            Stream::Tcp(tcp) => Stream::Tcp(tcp.try_clone()?),
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(tcp, tls) => Stream::TlsServer(tcp.try_clone()?, Arc::clone(tls)),
+            #[cfg(feature = "tls")]
+            Stream::TlsClient(tcp, tls) => Stream::TlsClient(tcp.try_clone()?, Arc::clone(tls)),
+        })
+    }
+}
+
+/// maps a peer closing the TCP connection without a TLS close_notify to the end of the stream.
+/// Clients usually just drop their connection, and a request or response that is cut short is
+/// still detected by its framing
+#[cfg(feature = "tls")]
+fn tls_eof(result: io::Result<usize>) -> io::Result<usize> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+        result => result,
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(_, tls) => tls_eof(lock(tls).read(buf)),
+            #[cfg(feature = "tls")]
+            Stream::TlsClient(_, tls) => tls_eof(lock(tls).read(buf)),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(_, tls) => lock(tls).write(buf),
+            #[cfg(feature = "tls")]
+            Stream::TlsClient(_, tls) => lock(tls).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.flush(),
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(_, tls) => lock(tls).flush(),
+            #[cfg(feature = "tls")]
+            Stream::TlsClient(_, tls) => lock(tls).flush(),
+        }
+    }
+}
+
+// a connection is only used by one thread at a time, so the lock is never contended, and a
+// panic while holding it leaves nothing for the other handles to recover from
+#[cfg(feature = "tls")]
+fn lock<T>(tls: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    tls.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -19,6 +19,12 @@ pub trait ThreadPool {
     /// the thread pool destroyed, corrupted or invalidated.
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static;
 
+    /// Returns the number of threads that run spawned functions, or `None` if the pool doesn't
+    /// have a fixed number of threads.
+    fn threads(&self) -> Option<u32> {
+        None
+    }
+
 }
 
 mod naive;
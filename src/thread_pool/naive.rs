@@ -1,26 +1,58 @@
-use std::thread;
+use std::sync::{Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
 use crate::Result;
 use super::ThreadPool;
 
 /// a simple thread-pool that is not actually a pool. It starts a new thread on every spawn
-/// request
+/// request.
+///
+/// The handles of the threads are kept, so that [`join_all`](NaiveThreadPool::join_all) can
+/// wait for every spawned job to finish, e.g. before a test checks the effects of the jobs.
 #[allow(dead_code)]
 pub struct NaiveThreadPool {
     threads: u32,
+    /// the handles of the spawned threads that haven't been joined yet
+    handles: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl NaiveThreadPool {
+    /// waits for every spawned job to finish, including the jobs spawned while waiting, e.g.
+    /// by other jobs. Jobs that panicked are ignored, as they are by [`ThreadPool::spawn`]
+    pub fn join_all(&self) {
+        loop {
+            let handles = std::mem::take(&mut *self.lock_handles());
+            if handles.is_empty() {
+                break;
+            }
+            for handle in handles {
+                let _ = handle.join();
+            }
+        }
+    }
+
+    fn lock_handles(&self) -> MutexGuard<'_, Vec<JoinHandle<()>>> {
+        // the handles are still valid if a thread panicked while holding the lock
+        self.handles.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 }
 
 impl ThreadPool for NaiveThreadPool {
 
     fn new(threads: u32) -> Result<Self> {
         Ok(NaiveThreadPool {
-            threads
+            threads,
+            handles: Mutex::new(Vec::new()),
         })
     }
 
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
-        // let hamdle = thread::Builder::new()
-        //     .name("thread1".into_string())
-        //     .spawn(job);
-        thread::spawn(job);
+        let handle = thread::spawn(job);
+        let mut handles = self.lock_handles();
+        // the handles of finished threads are dropped before the vec would grow, so that it
+        // only grows with the number of threads that are still running
+        if handles.len() == handles.capacity() {
+            handles.retain(|handle| !handle.is_finished());
+        }
+        handles.push(handle);
     }
 }
@@ -1,5 +1,5 @@
 use crate::{ThreadPool, Result, KvsError};
-use tracing::{debug};
+use tracing::{debug, error};
 use rayon;
 
 /// A thread pool that uses a work stealing strategy as implemented by the [`Rayon`] library.
@@ -14,6 +14,8 @@ impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> where Self: Sized {
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
+            // rayon aborts the process when a spawned job panics, unless there's a handler
+            .panic_handler(|_panic| error!("a job panicked in the thread pool"))
             .build()
             .map_err(|e|
                 KvsError::StringErr(format!("could not build thread pool: {:?}", &e)))?;
@@ -24,7 +26,12 @@ impl ThreadPool for RayonThreadPool {
         )
     }
 
+    /// runs the `job` on one of the pool's threads, without waiting for it to finish
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
-        self.pool.install(job);
+        self.pool.spawn(job);
+    }
+
+    fn threads(&self) -> Option<u32> {
+        Some(self.pool.current_num_threads() as u32)
     }
 }
\ No newline at end of file
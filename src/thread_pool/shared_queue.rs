@@ -1,3 +1,5 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use crossbeam::channel;
 use crossbeam::channel::{Sender, Receiver};
@@ -15,10 +17,26 @@ use tracing::{error, debug, instrument};
 /// is captured after the thread pool is created. So, the thread number in the pool
 /// can decrease to zero, then spawning a task to the thread pool will panic.
 ///
+/// Worker threads are named `kvs-worker-<index>`, and a replacement thread keeps the name of
+/// the thread it replaces. The number of tasks that panicked is available via
+/// [`panic_count`](SharedQueueThreadPool::panic_count).
+///
 /// [`channel`]: https://docs.rs/crossbeam/0.8.1/crossbeam/channel/index.html
 pub struct SharedQueueThreadPool {
     /// the sending part of the channel
     tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    /// the number of tasks that panicked
+    panics: Arc<AtomicUsize>,
+    /// the number of threads in the pool
+    threads: u32,
+}
+
+impl SharedQueueThreadPool {
+    /// returns the number of tasks that have panicked (and caused their worker thread to be
+    /// replaced) since this pool was created
+    pub fn panic_count(&self) -> usize {
+        self.panics.load(Ordering::SeqCst)
+    }
 }
 
 impl ThreadPool for SharedQueueThreadPool {
@@ -27,12 +45,13 @@ impl ThreadPool for SharedQueueThreadPool {
     /// Every thread created will have a handle to the receiving end of the channel
     fn new(threads: u32) -> Result<Self> {
         let (tx, rx) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
-        for _ in 0..threads {
-            let task_rx = TaskReceiver(rx.clone());
-            thread::Builder::new().spawn(move || run_tasks(task_rx))?;
+        let panics = Arc::new(AtomicUsize::new(0));
+        for id in 0..threads as usize {
+            let task_rx = TaskReceiver { rx: rx.clone(), id, panics: Arc::clone(&panics) };
+            task_rx.spawn_thread()?;
         }
         debug!("created shared queue pool with {} threads", &threads);
-        Ok(SharedQueueThreadPool { tx })
+        Ok(SharedQueueThreadPool { tx, panics, threads })
     }
 
     /// Spawns a function into the thread pool.
@@ -48,21 +67,43 @@ impl ThreadPool for SharedQueueThreadPool {
             .send(Box::new(job))
             .expect("There are no threads in the pool");
     }
+
+    /// returns the number of threads the pool was created with. A thread that is replaced after
+    /// a panic is still counted
+    fn threads(&self) -> Option<u32> {
+        Some(self.threads)
+    }
 }
 
 /// A type that can receive tasks (i.e. closures) from a channel and run them.
 /// Additionally, this type is responsible for restarting any threads that panicked
 #[derive(Clone, Debug)]
-struct TaskReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+struct TaskReceiver {
+    /// the receiving part of the channel
+    rx: Receiver<Box<dyn FnOnce() + Send + 'static>>,
+    /// the index of the worker thread, used to name the thread
+    id: usize,
+    /// the number of tasks, across the whole pool, that panicked
+    panics: Arc<AtomicUsize>,
+}
+
+impl TaskReceiver {
+    /// spawns a new, named, worker thread that runs the tasks received by this receiver
+    fn spawn_thread(self) -> std::io::Result<thread::JoinHandle<()>> {
+        thread::Builder::new()
+            .name(format!("kvs-worker-{}", self.id))
+            .spawn(move || run_tasks(self))
+    }
+}
 
 impl Drop for TaskReceiver {
     #[instrument]
     fn drop(&mut self) {
         debug!("dropping thread");
         if thread::panicking() {
+            self.panics.fetch_add(1, Ordering::SeqCst);
             debug!("thread panicked, starting a new thread");
-            let task_rx = self.clone();
-            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(task_rx)) {
+            if let Err(e) = self.clone().spawn_thread() {
                 error!("Failed to spawn a thread: {}", e);
             }
         }
@@ -74,12 +115,15 @@ impl Drop for TaskReceiver {
 #[instrument]
 fn run_tasks(rx: TaskReceiver) {
     loop {
-        match rx.0.recv() {
+        match rx.rx.recv() {
             Ok(task) => {
                 debug!("received a new task");
                 task();
             }
-            Err(_) => debug!("Thread exited because the thread pool was destroyed."),
+            Err(_) => {
+                debug!("Thread exited because the thread pool was destroyed.");
+                break;
+            }
         }
     }
 }
\ No newline at end of file
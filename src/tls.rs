@@ -0,0 +1,56 @@
+//! Helpers for configuring TLS connections between a [`KvsServer`](crate::KvsServer) and a
+//! [`KvsClient`](crate::KvsClient), from certificates and keys in PEM files.
+//!
+//! Requires the `tls` feature. The `rustls` crate is re-exported, so that its configuration
+//! types can be built directly when these helpers aren't enough.
+use std::path::Path;
+use std::sync::Arc;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{RootCertStore, ServerConfig};
+use crate::{KvsError, Result};
+
+pub use rustls;
+
+/// builds the TLS configuration of a server, from the certificate chain in the PEM file at
+/// `cert` and the private key in the PEM file at `key`. The configuration can be passed to
+/// [`KvsServer::tls`](crate::KvsServer::tls).
+/// # Errors
+/// `Err<KvsError::Tls>` if either file can't be read, or doesn't contain a valid certificate
+/// chain or private key
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| KvsError::Tls(format!("could not load a private key from {}: {}", key.display(), e)))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| KvsError::Tls(format!("invalid certificate or private key: {}", e)))?;
+    Ok(Arc::new(config))
+}
+
+/// builds a store of the root certificates that a client trusts, from the certificates in the
+/// PEM file at `path`. The store can be passed to
+/// [`KvsClient::connect_tls`](crate::KvsClient::connect_tls).
+/// # Errors
+/// `Err<KvsError::Tls>` if the file can't be read, or contains an invalid certificate
+pub fn root_certs(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| KvsError::Tls(format!("invalid root certificate in {}: {}", path.display(), e)))?;
+    }
+    Ok(roots)
+}
+
+/// loads every certificate in the PEM file at `path`, there must be at least one
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| KvsError::Tls(format!("could not load certificates from {}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!("no certificates found in {}", path.display())));
+    }
+    Ok(certs)
+}
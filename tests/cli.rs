@@ -1,8 +1,17 @@
 use assert_cmd::prelude::*;
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsError, KvsServer, MemoryKvsEngine, Protocol, Request, Response,
+    ServerEvent, SharedQueueThreadPool, ThreadPool, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
+use serde::Deserialize;
 use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
 use std::process::Command;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
@@ -20,28 +29,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +61,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +100,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +132,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +143,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +154,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +166,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +188,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +208,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +229,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +250,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +258,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +266,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +274,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +282,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +290,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +298,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +311,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -332,7 +346,1891 @@ fn cli_access_server_kvs_engine() {
 }
 
 #[test]
-#[ignore] // sled engine not implemented
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_batch() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key1 value one\nget key1\n\nrm key1\nget key1\n")
+        .assert()
+        .success()
+        .stdout("OK\nvalue one\nOK\nKey not found\n");
+
+    // parse errors abort the batch by default
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key2 value2\nbogus key2\nget key2\n")
+        .assert()
+        .failure()
+        .stdout("OK\n")
+        .stderr(contains("line 2"));
+
+    // and are skipped with --keep-going
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", "--keep-going", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("get key2\nrm missing\nget\nget key2\n")
+        .assert()
+        .failure()
+        .stdout("value2\nvalue2\n")
+        .stderr(contains("line 2").and(contains("line 3")));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_auth_token() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("authentication required"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--auth-token", "wrong"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid authentication token"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_get_default() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--default", "fallback", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("fallback\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--default", "fallback", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_protocol_version_negotiation() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    drop(client);
+
+    // a client with no version in common with the server is rejected
+    let mut stream = TcpStream::connect(addr).unwrap();
+    serde_json::to_writer(&mut stream, &Request::Hello { version: MIN_PROTOCOL_VERSION - 1 }).unwrap();
+    stream.flush().unwrap();
+    let mut reader = serde_json::Deserializer::from_reader(BufReader::new(stream));
+    match Response::deserialize(&mut reader).unwrap() {
+        Response::Err(msg) => assert!(msg.contains("unsupported protocol version")),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_max_requests_per_connection() {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--max-requests", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    let err = client.get("key1".to_owned()).unwrap_err();
+    assert!(err.to_string().contains("request limit"), "unexpected error {}", err);
+    drop(client);
+
+    // the client can keep going after reconnecting
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    drop(client);
+
+    // each kvs-client invocation is a new connection, so it isn't affected by the limit
+    for _ in 0..3 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_server_stats() {
+    let addr = "127.0.0.1:4011";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.key_count, 2);
+    assert!(stats.disk_usage > 0);
+    assert_eq!(stats.connections, 1);
+    // request latencies are recorded per operation
+    assert_eq!(stats.set_latency.count, 2);
+    assert!(stats.set_latency.p50_micros > 0);
+    assert!(stats.set_latency.p50_micros <= stats.set_latency.p99_micros);
+    assert_eq!(stats.get_latency.count, 0);
+    client.get("key1".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.get_latency.count, 1);
+    assert_eq!(stats.remove_latency.count, 1);
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// The server should write a pid file, and remove it after a graceful shutdown on SIGTERM
+#[cfg(unix)]
+#[test]
+fn cli_pid_file_and_sigterm() {
+    let addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().unwrap();
+    let pid_file = temp_dir.path().join("kvs-server.pid");
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--pid-file"])
+        .arg(&pid_file)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let pid = fs::read_to_string(&pid_file).expect("unable to read pid file");
+    assert_eq!(pid, child.id().to_string());
+
+    Command::new("kill")
+        .args(["-TERM", &pid])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+    assert!(!pid_file.exists());
+}
+
+#[test]
+fn cli_memory_engine() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    // the memory engine doesn't persist anything, so it can be used in a directory that
+    // already belongs to another engine
+    fs::write(temp_dir.path().join("engine"), "kvs").unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert_eq!(fs::read_to_string(temp_dir.path().join("engine")).unwrap(), "kvs");
+    assert!(!temp_dir.path().join("1.log").exists());
+}
+
+#[test]
+fn cli_keepalive() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--keepalive", "soon", "--addr", "127.0.0.1:4014"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--keepalive", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.keepalive(Some(Duration::from_secs(1))).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    // an idle connection that answers keep-alive probes stays open
+    thread::sleep(Duration::from_secs(3));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    client.keepalive(None).unwrap();
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_set_nowait() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // errors are reported when the responses are flushed
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..3 {
+        client.set_nowait(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    let err = client.flush_responses().unwrap_err().to_string();
+    assert!(err.contains("3 request(s) failed"), "unexpected error {}", err);
+    assert!(err.contains("authentication required"), "unexpected error {}", err);
+    // the errors were reported, so they are not reported again
+    client.flush_responses().unwrap();
+
+    // more requests than can be outstanding at once
+    client.auth("secret".to_owned()).unwrap();
+    for i in 0..2000 {
+        client.set_nowait(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    // a synchronous request waits for the outstanding responses
+    assert_eq!(client.get("key1999".to_owned()).unwrap(), Some("value1999".to_owned()));
+    client.set_nowait("key0".to_owned(), "value0b".to_owned()).unwrap();
+    client.flush_responses().unwrap();
+    assert_eq!(client.get("key0".to_owned()).unwrap(), Some("value0b".to_owned()));
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_audit_log() {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let audit_log = temp_dir.path().join("audit.txt");
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--audit-log"])
+        .arg(&audit_log)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "secret value".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    // a failed write is not audited
+    client.remove("key1".to_owned()).unwrap_err();
+    drop(client);
+    // wait for the audit log to be flushed
+    thread::sleep(Duration::from_secs(2));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let audit = fs::read_to_string(&audit_log).unwrap();
+    let lines: Vec<&str> = audit.lines().collect();
+    assert_eq!(lines.len(), 2, "unexpected audit log {}", audit);
+    assert!(lines[0].contains("peer=127.0.0.1:"));
+    assert!(lines[0].contains(r#"op=SET key="key1""#));
+    assert!(lines[1].contains(r#"op=REMOVE key="key1""#));
+    assert!(!audit.contains("secret value"));
+}
+
+// the server should detect, and speak, the text protocol
+#[test]
+fn cli_text_protocol() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4017";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut send = |line: &str| {
+        writeln!(stream, "{}", line).unwrap();
+        let mut resp = String::new();
+        reader.read_line(&mut resp).unwrap();
+        resp.trim_end().to_string()
+    };
+
+    assert_eq!(send("SET key1 hello world"), "OK");
+    assert_eq!(send("get key1"), "VALUE hello world");
+    assert_eq!(send("RM key1"), "OK");
+    assert_eq!(send("GET key1"), "NOT_FOUND");
+    assert_eq!(send("RM key1"), "ERR Key not found");
+    assert_eq!(send("FROB key1"), "ERR unknown command FROB");
+    assert_eq!(send("GET"), "ERR usage: GET <key>");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// a text protocol line longer than the server's maximum should be rejected, and its connection
+// closed, even if it never ends
+#[test]
+fn cli_text_protocol_max_line_len() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .max_line_len(16)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut resp = String::new();
+    // a line of exactly the maximum length is accepted
+    stream.write_all(b"SET key1 1234567\r\n").unwrap();
+    reader.read_line(&mut resp).unwrap();
+    assert_eq!(resp, "OK\n");
+
+    // the line is never ended, the server stops reading it once it's longer than the maximum
+    stream.write_all(b"SET key2 123456789").unwrap();
+    resp.clear();
+    reader.read_line(&mut resp).unwrap();
+    assert_eq!(resp, "ERR line too long\n");
+    resp.clear();
+    assert_eq!(reader.read_line(&mut resp).unwrap(), 0, "the connection should be closed");
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("1234567".to_owned()));
+    assert_eq!(client.get("key2".to_owned()).unwrap(), None);
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// ping and health checks should succeed without authenticating
+#[test]
+fn cli_ping_and_health() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4018";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--auth-token", "s3cret"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.ping().unwrap();
+    client.health().unwrap();
+    // other requests still require authentication
+    client.get("key1".to_owned()).unwrap_err();
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// large values should be streamed to the client
+#[test]
+fn cli_get_stream() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4019";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "a \"large\" value\n".repeat(100_000);
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), value.clone()).unwrap();
+
+    let mut out = vec![];
+    assert!(client.get_into("key1".to_owned(), &mut out).unwrap());
+    assert_eq!(String::from_utf8(out).unwrap(), value);
+    let mut out = vec![];
+    assert!(!client.get_into("key2".to_owned(), &mut out).unwrap());
+    assert!(out.is_empty());
+    // the connection is still usable after a streamed value
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some(value));
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// a client pool should share its connections between threads
+#[test]
+fn cli_client_pool() {
+    // the server is bound to an ephemeral port, so it is ready to accept connections as soon
+    // as its address is known
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(4).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let pool = KvsClient::connect_with_pool(addr, 2).unwrap();
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for i in 0..25 {
+                    let mut client = pool.get().unwrap();
+                    client.set(format!("key{}-{}", t, i), format!("value{}", i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    // connections are reused, rather than opened per checkout
+    assert!(pool.idle() >= 1 && pool.idle() <= pool.size());
+    let mut client = pool.get().unwrap();
+    assert_eq!(client.get("key3-24".to_owned()).unwrap(), Some("value24".to_owned()));
+    assert_eq!(client.stats().unwrap().key_count, 100);
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// a pooled connection that fails should be replaced by a new connection
+#[test]
+fn cli_client_pool_reconnects() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4021";
+    let start_server = || {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+
+    let mut child = start_server();
+    let pool = KvsClient::connect_with_pool(addr, 1).unwrap();
+    pool.get().unwrap().set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(pool.idle(), 1);
+
+    // the idle connection breaks when the server restarts
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    let mut child = start_server();
+    pool.get().unwrap().get("key1".to_owned()).unwrap_err();
+    assert_eq!(pool.idle(), 0);
+
+    // the next checkout opens a new connection
+    assert_eq!(pool.get().unwrap().get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(pool.idle(), 1);
+    drop(pool);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// a client should be able to page through the keys of the server
+#[test]
+fn cli_client_scan() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for key in ["c", "a", "e", "b", "d"] {
+        client.set(key.to_owned(), "value".to_owned()).unwrap();
+    }
+    let (keys, cursor) = client.scan(None, 2).unwrap();
+    assert_eq!(keys, ["a", "b"]);
+    let (keys, cursor) = client.scan(cursor, 2).unwrap();
+    assert_eq!(keys, ["c", "d"]);
+    let (keys, cursor) = client.scan(cursor, 2).unwrap();
+    assert_eq!(keys, ["e"]);
+    assert_eq!(cursor, None);
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// tags should round trip through the server
+#[test]
+fn cli_client_tagged_values() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set_tagged("key1".to_owned(), "value1".to_owned(), 42).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(client.get_tagged("key1".to_owned()).unwrap(), Some(("value1".to_owned(), 42)));
+    assert_eq!(client.get_tagged("key2".to_owned()).unwrap(), Some(("value2".to_owned(), 0)));
+    assert_eq!(client.get_tagged("key3".to_owned()).unwrap(), None);
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// batch requests should be applied by the server, and rejected if they are larger than the
+// server's limits
+#[test]
+fn cli_client_batches() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .max_get_batch(3)
+        .max_multi_set(2)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .multi_set(vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ])
+        .unwrap();
+    let values = client
+        .get_batch(vec!["key2".to_owned(), "key3".to_owned(), "key1".to_owned()])
+        .unwrap();
+    assert_eq!(values, [Some("value2".to_owned()), None, Some("value1".to_owned())]);
+
+    let err = client
+        .multi_set((3..6).map(|i| (format!("key{}", i), format!("value{}", i))).collect())
+        .unwrap_err();
+    assert!(err.to_string().starts_with("batch too large"), "{}", err);
+    let err = client
+        .get_batch((1..5).map(|i| format!("key{}", i)).collect())
+        .unwrap_err();
+    assert!(err.to_string().starts_with("batch too large"), "{}", err);
+    // none of the oversized batch was set, and the connection is still usable
+    assert_eq!(client.get("key3".to_owned()).unwrap(), None);
+
+    // appends return the new value
+    let sep = Some(",".to_owned());
+    assert_eq!(client.append("key1".to_owned(), "a".to_owned(), sep.clone()).unwrap(), "value1,a");
+    assert_eq!(client.append("list".to_owned(), "a".to_owned(), sep).unwrap(), "a");
+
+    // renames move the value, and fail if the key doesn't exist
+    client.rename("list".to_owned(), "moved".to_owned()).unwrap();
+    assert_eq!(client.get("moved".to_owned()).unwrap(), Some("a".to_owned()));
+    assert!(client.rename("list".to_owned(), "moved".to_owned()).is_err());
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// `kvs-server init` should prepare a data directory without starting the server, and refuse
+// a directory that belongs to another engine
+#[test]
+fn server_cli_init() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    for _ in 0..2 {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["init", "--data-dir"])
+            .arg(&data_dir)
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "kvs");
+        assert!(data_dir.join("1.log").exists());
+    }
+
+    fs::write(data_dir.join("engine"), "sled").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["init", "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("sled"));
+}
+
+// `kvs-client get --binary` should write the exact bytes of a value, even if they aren't UTF-8,
+// while `get_range` can only get the parts of the value that are UTF-8
+#[test]
+fn cli_get_binary() {
+    let addr = "127.0.0.1:4022";
+    let temp_dir = TempDir::new().unwrap();
+    let value = vec![0xff, 0x00, b'\n', 0xfe, b'x'];
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set_raw(b"binary".to_vec(), value.clone()).unwrap();
+    drop(store);
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "binary", "--binary", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicate::eq(value.as_slice()));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "missing", "--binary", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get_bytes("binary".to_owned()).unwrap(), Some(value));
+    assert_eq!(client.get_bytes("missing".to_owned()).unwrap(), None);
+    // the string API can't return the value
+    assert!(client.get("binary".to_owned()).is_err());
+    assert_eq!(client.get_range("binary".to_owned(), 2, 1).unwrap(), Some("\n".to_owned()));
+    assert_eq!(client.get_range("binary".to_owned(), 4, 10).unwrap(), Some("x".to_owned()));
+    assert_eq!(client.get_range("missing".to_owned(), 0, 10).unwrap(), None);
+    assert!(client.get_range("binary".to_owned(), 0, 2).is_err());
+    // checking for a key doesn't need its value to be UTF-8
+    assert!(client.contains_key("binary".to_owned()).unwrap());
+    assert!(!client.contains_key("missing".to_owned()).unwrap());
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client loginfo` should print the layout of the server's log files
+#[test]
+fn cli_loginfo() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value1".to_owned()).unwrap();
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let logs = client.log_info().unwrap();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].live_keys, 2);
+    assert!(logs[0].dead_bytes > 0 && logs[0].dead_bytes < logs[0].size, "{:?}", logs);
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["loginfo", "--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("LIVE KEYS").and(contains(format!(
+            "{:>10} {:>12} {:>10} {:>12}",
+            logs[0].gen, logs[0].size, 2, logs[0].dead_bytes
+        ))));
+
+    // a forced compaction leaves no dead bytes behind
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compact", "--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    let mut client = KvsClient::connect(addr).unwrap();
+    let logs = client.log_info().unwrap();
+    assert!(logs.iter().all(|log| log.dead_bytes == 0), "{:?}", logs);
+    assert_eq!(logs.iter().map(|log| log.live_keys).sum::<u64>(), 2);
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value2".to_owned()));
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+
+    // the memory engine has no log files
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["loginfo", "--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compact", "--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// a server should stop accepting connections while it's servicing its maximum number of them
+#[test]
+fn cli_max_connections() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(4).unwrap())
+        .max_connections(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut first = KvsClient::connect(addr).unwrap();
+    first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    // the second connection waits until the first one is closed
+    let (tx, rx) = mpsc::channel();
+    let second = thread::spawn(move || {
+        let mut second = KvsClient::connect(addr).unwrap();
+        tx.send(second.get("key1".to_owned()).unwrap()).unwrap();
+    });
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+    drop(first);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), Some("value1".to_owned()));
+    second.join().unwrap();
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// A read-only server should reject writes without touching the engine, and serve reads
+#[test]
+fn cli_read_only() {
+    let engine = MemoryKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let server = KvsServer::new(engine.clone(), SharedQueueThreadPool::new(2).unwrap())
+        .read_only(true)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let err = client.set("key1".to_owned(), "value2".to_owned()).unwrap_err();
+    assert!(err.to_string().contains("server is read-only"), "{}", err);
+    assert!(client.remove("key1".to_owned()).is_err());
+    assert!(client.append("key1".to_owned(), "suffix".to_owned(), None).is_err());
+    assert!(client.rename("key1".to_owned(), "key2".to_owned()).is_err());
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(engine.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned()).unwrap(), None);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// A memory engine that refuses to set empty values
+#[derive(Clone)]
+struct NonEmptyEngine(MemoryKvsEngine);
+
+impl KvsEngine for NonEmptyEngine {
+    fn set(&self, key: String, value: String) -> kvs::Result<()> {
+        if value.is_empty() {
+            return Err(KvsError::StringErr(format!("empty value for key {}", key)));
+        }
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> kvs::Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> kvs::Result<()> {
+        self.0.remove(key)
+    }
+}
+
+// a batch set that isn't atomic should report which of its pairs were set
+#[test]
+fn cli_client_set_many() {
+    let engine = MemoryKvsEngine::new();
+    let server = KvsServer::new(NonEmptyEngine(engine.clone()), SharedQueueThreadPool::new(2).unwrap())
+        .max_multi_set(3)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let results = client
+        .set_many(vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ])
+        .unwrap();
+    assert_eq!(results, [Ok(()), Err("empty value for key key2".to_owned()), Ok(())]);
+    // the pairs around the failed pair were still set
+    assert_eq!(engine.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned()).unwrap(), None);
+    assert_eq!(engine.get("key3".to_owned()).unwrap(), Some("value3".to_owned()));
+
+    let err = client
+        .set_many((4..8).map(|i| (format!("key{}", i), format!("value{}", i))).collect())
+        .unwrap_err();
+    assert!(err.to_string().starts_with("batch too large"), "{}", err);
+    assert_eq!(engine.get("key4".to_owned()).unwrap(), None);
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// pipelined requests should all be served, even when they straddle the server's read buffer
+#[test]
+fn cli_pipelined_requests() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .read_buffer(7)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..100 {
+        client.set_nowait(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    client.flush_responses().unwrap();
+    assert_eq!(client.get("key99".to_owned()).unwrap(), Some("value99".to_owned()));
+    drop(client);
+
+    // several requests sent in a single write are answered in order
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut requests = vec![];
+    for key in ["key1", "missing", "key2"] {
+        serde_json::to_writer(&mut requests, &Request::Get { key: key.to_owned() }).unwrap();
+    }
+    stream.write_all(&requests).unwrap();
+    let responses: Vec<Response> = serde_json::Deserializer::from_reader(&stream)
+        .into_iter()
+        .take(3)
+        .map(|resp| resp.unwrap())
+        .collect();
+    assert!(matches!(&responses[0], Response::Ok(Some(value)) if value == "value1"));
+    assert!(matches!(&responses[1], Response::Ok(None)));
+    assert!(matches!(&responses[2], Response::Ok(Some(value)) if value == "value2"));
+    drop(stream);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// removing a missing key through a client should fail with the same error as a local removal
+#[test]
+fn cli_remove_missing_key() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(matches!(client.remove("missing".to_owned()), Err(KvsError::KeyNotFound)));
+    assert!(matches!(client.rename("missing".to_owned(), "key".to_owned()), Err(KvsError::KeyNotFound)));
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "missing", "--addr", &addr.to_string()])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    // clients that don't negotiate version 3 receive an error message instead
+    let mut stream = TcpStream::connect(addr).unwrap();
+    serde_json::to_writer(&mut stream, &Request::Hello { version: 2 }).unwrap();
+    serde_json::to_writer(&mut stream, &Request::Remove { key: "missing".to_owned() }).unwrap();
+    let mut responses = serde_json::Deserializer::from_reader(&stream).into_iter::<Response>();
+    assert!(matches!(responses.next().unwrap().unwrap(), Response::Hello { version: 2 }));
+    assert!(matches!(responses.next().unwrap().unwrap(), Response::Err(msg) if msg == "Key not found"));
+    drop(stream);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// `kvs-client --json` should print a JSON object for every result, including errors, and keep
+// the exit codes of the plain text output
+#[test]
+fn cli_json_output() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--json", "--addr", &addr]);
+        cmd
+    };
+    client(&["set", "key1", "value \"one\""]).assert().success().stdout("{\"ok\":true}\n");
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("{\"ok\":true,\"value\":\"value \\\"one\\\"\"}\n");
+    client(&["get", "missing"]).assert().success().stdout("{\"ok\":true,\"value\":null}\n");
+    client(&["get", "missing", "--default", "none"])
+        .assert()
+        .success()
+        .stdout("{\"ok\":true,\"value\":\"none\"}\n");
+    client(&["rm", "missing"])
+        .assert()
+        .failure()
+        .stdout("{\"error\":\"Key not found\",\"ok\":false}\n");
+    client(&["rm", "key1"]).assert().success().stdout("{\"ok\":true}\n");
+    client(&["get", "key1", "--binary"]).assert().failure().stdout(is_empty());
+
+    client(&["batch", "--keep-going"])
+        .with_stdin()
+        .buffer("set key2 value2\nbogus\nget key2\n")
+        .assert()
+        .failure()
+        .stdout(
+            "{\"ok\":true}\n\
+             {\"error\":\"unknown command: 'bogus'\",\"line\":2,\"ok\":false}\n\
+             {\"ok\":true,\"value\":\"value2\"}\n",
+        )
+        .stderr(is_empty());
+    client(&["len"]).assert().success().stdout("{\"count\":1,\"ok\":true}\n");
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// `set_if_equals` and `kvs-client cas` should only set a key whose current value is the expected
+// value, and shouldn't treat a missing key as an error. `get_set` should return the old value
+#[test]
+fn cli_client_cas() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    assert!(!client.set_if_equals("key1".to_owned(), "value1".to_owned(), "value2".to_owned()).unwrap());
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert!(client.compare_and_swap("key1".to_owned(), None, "value1".to_owned()).unwrap());
+    assert!(!client.compare_and_swap("key1".to_owned(), None, "value2".to_owned()).unwrap());
+    assert!(!client.set_if_equals("key1".to_owned(), "value2".to_owned(), "value3".to_owned()).unwrap());
+    assert!(client.set_if_equals("key1".to_owned(), "value1".to_owned(), "value2".to_owned()).unwrap());
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value2".to_owned()));
+    assert_eq!(client.get_set("key2".to_owned(), "value1".to_owned()).unwrap(), None);
+    assert_eq!(client.get_set("key2".to_owned(), "value2".to_owned()).unwrap(), Some("value1".to_owned()));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "value2", "value3", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("OK\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "value2", "value4", "--addr", &addr])
+        .assert()
+        .code(2)
+        .stdout("Not swapped\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "missing", "value1", "value2", "--json", "--addr", &addr])
+        .assert()
+        .code(2)
+        .stdout("{\"ok\":true,\"swapped\":false}\n");
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value3".to_owned()));
+    assert_eq!(client.get("missing".to_owned()).unwrap(), None);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// resetting the stats should return the counters from before the reset, without losing the
+// requests that are handled concurrently
+#[test]
+fn cli_reset_stats() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(4).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    for i in 0..3 {
+        client.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    client.get("key1".to_owned()).unwrap();
+    let stats = client.reset_stats().unwrap();
+    assert_eq!((stats.set_latency.count, stats.get_latency.count), (3, 1));
+    let stats = client.stats().unwrap();
+    assert_eq!((stats.set_latency.count, stats.get_latency.count), (0, 0));
+    assert_eq!(stats.key_count, 3);
+
+    let writer = {
+        let addr = addr.clone();
+        thread::spawn(move || {
+            let mut client = KvsClient::connect(&addr).unwrap();
+            for i in 0..200 {
+                client.set(format!("key{}", i), "value".to_owned()).unwrap();
+            }
+        })
+    };
+    let mut sets = 0;
+    for _ in 0..10 {
+        sets += client.reset_stats().unwrap().set_latency.count;
+    }
+    writer.join().unwrap();
+    sets += client.reset_stats().unwrap().set_latency.count;
+    assert_eq!(sets, 200);
+
+    client.remove("key0".to_owned()).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--reset", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(contains("keys: 199\n").and(contains("remove requests: 1 ")));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(contains("remove requests: 0 ").and(contains("uptime: ")));
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// a server with an events channel should report every accepted and closed connection, and the
+// connections that fail
+#[test]
+fn cli_server_events() {
+    let (events, received) = mpsc::channel();
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .events(events.clone())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+    let next_event = || received.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    serde_json::to_writer(&mut stream, &Request::Set { key: "key1".to_owned(), value: "value1".to_owned() }).unwrap();
+    // a missing key is reported to the client, it isn't a failure of the connection
+    serde_json::to_writer(&mut stream, &Request::Remove { key: "missing".to_owned() }).unwrap();
+    let mut responses = serde_json::Deserializer::from_reader(&stream).into_iter::<Response>();
+    assert!(matches!(responses.next().unwrap().unwrap(), Response::Ok(None)));
+    assert!(matches!(responses.next().unwrap().unwrap(), Response::Err(_)));
+    drop(stream);
+    assert_eq!(next_event(), ServerEvent::Accepted(client_addr));
+    assert_eq!(next_event(), ServerEvent::Closed(client_addr));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    stream.write_all(b"{\"Get\": not json}").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    assert_eq!(next_event(), ServerEvent::Accepted(client_addr));
+    assert!(matches!(next_event(), ServerEvent::Error(msg) if msg.contains(&client_addr.to_string())));
+    assert_eq!(next_event(), ServerEvent::Closed(client_addr));
+
+    // binding to an address that's in use is an error
+    let bound = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(1).unwrap())
+        .events(events)
+        .bind(addr);
+    assert!(bound.is_err());
+    assert!(matches!(next_event(), ServerEvent::Error(msg) if msg.starts_with("bind failed")));
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// `--tls` requires both a certificate and a private key, which are only accepted with `--tls`
+#[test]
+fn server_cli_tls_requires_cert_and_key() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--tls", "--cert", "server.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--cert", "server.pem", "--key", "server.key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// a client that trusts the server's certificate can use a TLS connection like a plain one
+#[cfg(feature = "tls")]
+#[test]
+fn cli_client_tls() {
+    let certs = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+    let config = kvs::tls::server_config(&certs.join("server.pem"), &certs.join("server.key")).unwrap();
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .tls(config)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let roots = || kvs::tls::root_certs(&certs.join("ca.pem")).unwrap();
+    let mut client = KvsClient::connect_tls(addr, "localhost", roots()).unwrap();
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert!(matches!(client.remove("key2".to_owned()), Err(KvsError::KeyNotFound)));
+    drop(client);
+
+    // the certificate is only valid for localhost, and plain connections fail the handshake
+    assert!(KvsClient::connect_tls(addr, "example.com", roots()).is_err());
+    assert!(KvsClient::connect(addr).is_err());
+
+    let mut client = KvsClient::connect_tls(addr, "localhost", roots()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// a request whose value is larger than the server's maximum should be rejected before it has
+// been read in full, and its connection closed
+#[test]
+fn cli_max_value_size() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .max_value_size(16)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    // values within the limit are accepted, the limit applies to each request separately
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..10 {
+        client.set(format!("key{}", i), "a".repeat(16)).unwrap();
+    }
+    drop(client);
+
+    // the value is never closed, the server stops reading once the request is over the limit
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(br#"{"Set":{"key":"big","value":""#).unwrap();
+    stream.write_all("a".repeat(64 * 1024 + 100).as_bytes()).unwrap();
+    let mut responses = serde_json::Deserializer::from_reader(&stream).into_iter::<Response>();
+    assert!(matches!(responses.next().unwrap().unwrap(), Response::Err(msg) if msg.starts_with("request too large")));
+    assert!(responses.next().is_none(), "the connection should be closed");
+    drop(stream);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key9".to_owned()).unwrap(), Some("a".repeat(16)));
+    assert_eq!(client.get("big".to_owned()).unwrap(), None);
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// a client built with an auth token should authenticate once connected, and the builder's
+// timeouts and retries should bound how long a connection is waited for
+#[test]
+fn cli_client_builder() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .auth_token("secret".to_owned())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let builder = KvsClient::builder().addr(addr.to_string()).connect_timeout(Some(Duration::from_secs(1)));
+    let mut client = builder.clone().auth_token("secret").connect().unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(client);
+    let mut client = builder.clone().connect().unwrap();
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::StringErr(msg)) if msg.contains("authentication required")));
+    assert!(matches!(builder.clone().auth_token("wrong").retries(3).connect(), Err(KvsError::StringErr(_))));
+    drop(client);
+
+    // every connection of a pool built from the builder authenticates
+    let pool = builder.clone().auth_token("secret").pool(2);
+    let (mut first, mut second) = (pool.get().unwrap(), pool.get().unwrap());
+    first.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(second.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+    drop((first, second));
+
+    // a server that never responds times out the version negotiation
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let silent_addr = listener.local_addr().unwrap();
+    let result = KvsClient::builder()
+        .addr(silent_addr.to_string())
+        .read_timeout(Some(Duration::from_millis(100)))
+        .connect();
+    assert!(matches!(result, Err(KvsError::Io { .. })));
+
+    // every retry waits longer than the last, before the error is returned
+    drop(listener);
+    let started = std::time::Instant::now();
+    let result = KvsClient::builder().addr(silent_addr.to_string()).retries(2).connect();
+    assert!(matches!(result, Err(KvsError::Io { .. })));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// connect_timeout should bound how long a silent server is waited for, and connect_with_retries
+// should wait for a server that is still binding its address, but not retry a silent one
+#[test]
+fn cli_client_connect_timeout_and_retries() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let started = std::time::Instant::now();
+    let result = KvsClient::connect_timeout(addr, Duration::from_millis(100));
+    assert!(matches!(result, Err(KvsError::Io { .. })));
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // the connection succeeds but the version negotiation times out, which isn't retried
+    let started = std::time::Instant::now();
+    let result = KvsClient::connect_with_retries(addr, 3, Duration::from_secs(1), Some(Duration::from_millis(100)));
+    assert!(matches!(result, Err(KvsError::Io { .. })));
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // every retry waits longer than the last, before the last error is returned
+    drop(listener);
+    let started = std::time::Instant::now();
+    let result = KvsClient::connect_with_retries(addr, 3, Duration::from_millis(100), Some(Duration::from_millis(100)));
+    assert!(matches!(result, Err(KvsError::Io { .. })));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    // the server binds its address while the client is retrying
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap()).run_until(addr, shutdown)
+        })
+    };
+    let mut client = KvsClient::connect_with_retries(addr, 10, Duration::from_millis(50), None).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(client);
+    let mut client = KvsClient::connect_timeout(addr, Duration::from_secs(1)).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap().unwrap();
+}
+
+// the keys matching a glob pattern should be streamed in chunks, and the connection should
+// still be usable afterwards. The keys can also be counted, or listed by prefix
+#[test]
+fn cli_keys_pattern() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    for key in ["user:1:active", "user:2:inactive", "user:10:active", "group:1:active"] {
+        client.set(key.to_owned(), "value".to_owned()).unwrap();
+    }
+    // enough matching keys to be streamed in several responses
+    for i in 0..2500 {
+        client.set(format!("item:{:04}", i), "value".to_owned()).unwrap();
+    }
+
+    assert_eq!(client.keys("user:*:active".to_owned()).unwrap(), vec!["user:10:active", "user:1:active"]);
+    assert_eq!(client.keys("user:?:*".to_owned()).unwrap(), vec!["user:1:active", "user:2:inactive"]);
+    assert_eq!(client.keys("*:1:*".to_owned()).unwrap(), vec!["group:1:active", "user:1:active"]);
+    assert!(client.keys("missing*".to_owned()).unwrap().is_empty());
+    let items = client.keys("item:*".to_owned()).unwrap();
+    assert_eq!(items, (0..2500).map(|i| format!("item:{:04}", i)).collect::<Vec<_>>());
+    assert_eq!(client.get("user:1:active".to_owned()).unwrap(), Some("value".to_owned()));
+    assert_eq!(client.key_count().unwrap(), 2504);
+    assert_eq!(client.scan_prefix("user:1".to_owned()).unwrap(), vec!["user:10:active", "user:1:active"]);
+    assert_eq!(client.scan_prefix("item:".to_owned()).unwrap().len(), 2500);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["len", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("2504\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "user:", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("user:10:active\nuser:1:active\nuser:2:inactive\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["keys", "user:*", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("user:10:active\nuser:1:active\nuser:2:inactive\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["keys", "*:2:*", "--json", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("{\"keys\":[\"user:2:inactive\"],\"ok\":true}\n");
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// `kvs-client dump` and `restore` should copy every key and value through a dump file
+#[test]
+fn cli_dump_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    // enough keys to be dumped and restored in several batches
+    for i in 0..2500 {
+        client.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    let dump_path = temp_dir.path().join("store.dump");
+    let dump_path = dump_path.to_str().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dump", dump_path, "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("2500 entries\n");
+
+    client.remove("key0".to_owned()).unwrap();
+    client.set("key1".to_owned(), "changed".to_owned()).unwrap();
+    client.set("extra".to_owned(), "value".to_owned()).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["restore", dump_path, "--json", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("{\"entries\":2500,\"ok\":true}\n");
+    assert_eq!(client.get("key0".to_owned()).unwrap(), Some("value0".to_owned()));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(client.get("key2499".to_owned()).unwrap(), Some("value2499".to_owned()));
+    assert_eq!(client.get("extra".to_owned()).unwrap(), Some("value".to_owned()));
+
+    // a truncated dump is rejected, and nothing is restored from it
+    let dump = fs::read(dump_path).unwrap();
+    fs::write(dump_path, &dump[..dump.len() / 2]).unwrap();
+    client.set("key1".to_owned(), "changed".to_owned()).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["restore", dump_path, "--addr", &addr])
+        .assert()
+        .failure()
+        .stderr(contains("invalid dump: the dump is truncated"));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("changed".to_owned()));
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// a get whose response times out should leave its connection unusable, rather than let the late
+// response be read as the response to the next get, while a new connection still works
+#[test]
+fn cli_abandoned_request() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    // a server that is slow to answer the gets of its first connection
+    let server = thread::spawn(move || {
+        for (conn, stream) in listener.incoming().take(2).enumerate() {
+            let mut stream = stream.unwrap();
+            let requests = serde_json::Deserializer::from_reader(stream.try_clone().unwrap()).into_iter::<Request>();
+            // the client resets the connection if it closes it with the late response unread
+            for req in requests.map_while(|req| req.ok()) {
+                let resp = match req {
+                    Request::Hello { .. } => Response::Hello { version: PROTOCOL_VERSION },
+                    Request::Get { key } => {
+                        if conn == 0 {
+                            thread::sleep(Duration::from_millis(300));
+                        }
+                        Response::Ok(Some(format!("value of {}", key)))
+                    }
+                    req => Response::Err(format!("unexpected request {:?}", req)),
+                };
+                serde_json::to_writer(&mut stream, &resp).unwrap();
+            }
+        }
+    });
+
+    let builder = KvsClient::builder().addr(addr.to_string()).read_timeout(Some(Duration::from_millis(100)));
+    let mut client = builder.connect().unwrap();
+    assert!(matches!(client.get("key1".to_owned()), Err(KvsError::Io { .. })));
+    // wait for the late response to arrive
+    thread::sleep(Duration::from_millis(400));
+    assert!(matches!(client.get("key2".to_owned()), Err(KvsError::Io { .. })));
+    assert!(matches!(client.set_nowait("key2".to_owned(), "value2".to_owned()), Err(KvsError::Io { .. })));
+    drop(client);
+
+    let mut client = builder.connect().unwrap();
+    assert_eq!(client.get("key2".to_owned()).unwrap(), Some("value of key2".to_owned()));
+    drop(client);
+    server.join().unwrap();
+}
+
+// the most accessed keys should be listed first by a server that tracks hot keys, and listing
+// them should fail on a server that doesn't
+#[test]
+fn cli_hot_keys() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .hot_keys(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    client.set("hot".to_owned(), "value".to_owned()).unwrap();
+    for _ in 0..199 {
+        client.get("hot".to_owned()).unwrap();
+    }
+    // keys accessed 1 to 20 times, the warmest of which should follow the hot key
+    for i in 0..20 {
+        for _ in 0..=i {
+            client.set(format!("warm:{:02}", i), "value".to_owned()).unwrap();
+        }
+    }
+    client.get_batch(vec!["warm:00".to_owned(), "missing".to_owned()]).unwrap();
+
+    let hot_keys = client.hot_keys(3).unwrap();
+    let keys: Vec<&str> = hot_keys.iter().map(|(key, _count)| key.as_str()).collect();
+    assert_eq!(keys, vec!["hot", "warm:19", "warm:18"]);
+    // the counts of a sketch are never underestimated
+    assert!(hot_keys[0].1 >= 200);
+    assert!(hot_keys[1].1 >= 20);
+    assert_eq!(client.hot_keys(100).unwrap().len(), 22);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["hotkeys", "1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with(" hot\n"));
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+    let mut client = KvsClient::connect(&addr).unwrap();
+    assert!(matches!(client.hot_keys(10), Err(KvsError::StringErr(msg)) if msg.contains("hot keys are not tracked")));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["hotkeys", "--addr", &addr])
+        .assert()
+        .failure();
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+// a server with an idle exit should keep running while a connection is open, and shut down
+// once it has had no connection for the idle time
+#[test]
+fn cli_idle_exit() {
+    let server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap())
+        .idle_exit(Duration::from_millis(300))
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || server.serve_until(shutdown).unwrap())
+    };
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    // an open connection keeps the server running, even while it sends nothing
+    thread::sleep(Duration::from_millis(600));
+    assert!(!server.is_finished());
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    drop(client);
+
+    thread::sleep(Duration::from_millis(1000));
+    assert!(server.is_finished());
+    assert!(shutdown.load(Ordering::SeqCst));
+    server.join().unwrap();
+}
+
+// kvs-server should exit successfully once it has had no connection for --idle-exit seconds
+#[test]
+fn server_cli_idle_exit() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4023", "--idle-exit", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(3));
+    let status = child.try_wait().unwrap();
+    if status.is_none() {
+        child.kill().unwrap();
+    }
+    assert!(status.is_some_and(|status| status.success()), "the server didn't exit when idle");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--idle-exit", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("idle exit must be a positive integer"));
+}
+
+// run_until should serve connections until the shutdown flag is set, and then return promptly,
+// even though no connection arrives to wake up the accept loop
+#[test]
+fn cli_run_until() {
+    let addr = "127.0.0.1:4024";
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap()).run_until(addr, shutdown)
+        })
+    };
+    thread::sleep(Duration::from_millis(200));
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    drop(client);
+
+    let started = std::time::Instant::now();
+    shutdown.store(true, Ordering::SeqCst);
+    while !server.is_finished() {
+        assert!(started.elapsed() < Duration::from_secs(2), "run_until didn't return once shut down");
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.join().unwrap().unwrap();
+}
+
+// A bincode client can use a server that accepts either protocol, alongside JSON clients, and
+// mixed clients of a server restricted to one protocol fail when they connect
+#[test]
+fn cli_bincode_protocol() {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let spawn_server = |addr: &'static str, protocol: Option<Protocol>| {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            let mut server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2).unwrap());
+            if let Some(protocol) = protocol {
+                server = server.protocol(protocol);
+            }
+            server.run_until(addr, shutdown)
+        })
+    };
+    let servers = vec![
+        spawn_server("127.0.0.1:4025", None),
+        spawn_server("127.0.0.1:4026", Some(Protocol::Json)),
+        spawn_server("127.0.0.1:4027", Some(Protocol::Bincode)),
+    ];
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect_with_protocol("127.0.0.1:4025", Protocol::Bincode).unwrap();
+    assert_eq!(client.protocol(), Protocol::Bincode);
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(client.get("missing".to_owned()).unwrap(), None);
+    assert!(matches!(client.remove("missing".to_owned()), Err(KvsError::KeyNotFound)));
+    for i in 0..10 {
+        client.set_nowait(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    client.flush_responses().unwrap();
+    // a streamed value, and a key listing, follow their first response
+    let mut value = vec![];
+    assert!(client.get_into("key9".to_owned(), &mut value).unwrap());
+    assert_eq!(value, b"value9");
+    assert_eq!(client.keys("key*".to_owned()).unwrap().len(), 10);
+
+    let mut json_client = KvsClient::connect("127.0.0.1:4025").unwrap();
+    assert_eq!(json_client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+
+    match KvsClient::connect_with_protocol("127.0.0.1:4026", Protocol::Bincode) {
+        Err(KvsError::StringErr(msg)) => assert!(msg.contains("doesn't accept the bincode protocol"), "{}", msg),
+        result => panic!("expected the handshake to be refused, got {:?}", result),
+    }
+    match KvsClient::connect("127.0.0.1:4027") {
+        Err(KvsError::StringErr(msg)) => assert!(msg.contains("only accepts the bincode protocol"), "{}", msg),
+        result => panic!("expected the JSON client to be refused, got {:?}", result),
+    }
+    let mut client = KvsClient::builder().addr("127.0.0.1:4027").protocol(Protocol::Bincode).connect().unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--protocol", "bincode", "--addr", "127.0.0.1:4027"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--protocol", "xml", "--addr", "127.0.0.1:4027"])
+        .assert()
+        .failure();
+
+    shutdown.store(true, Ordering::SeqCst);
+    for server in servers {
+        server.join().unwrap().unwrap();
+    }
+}
@@ -1,6 +1,14 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CompactionTrigger, EvictionPolicy, FileMetadata, FileSystem, KvStore, KvsEngine, KvsError, MemoryKvsEngine,
+    ReadFile, Result, ShutdownCompaction, ShutdownSummary, SledKvsEngine, StdFs, TeeEngine, WriteFile,
+};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -127,6 +135,119 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// a store opened with a small threshold should compact more often than one with the default
+// threshold, for the same writes
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let compactions = |store: KvStore| -> Result<u64> {
+        for iter in 0..20 {
+            for key_id in 0..1000 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+        }
+        Ok(store.stats()?.compactions)
+    };
+    let default_dir = TempDir::new().expect("unable to create temporary working directory");
+    let small_dir = TempDir::new().expect("unable to create temporary working directory");
+    let default_compactions = compactions(KvStore::open(default_dir.path())?)?;
+    let small_compactions = compactions(KvStore::open_with_threshold(small_dir.path(), 64 * 1024)?)?;
+    assert!(
+        small_compactions > default_compactions,
+        "{} compactions with a 64 KB threshold, {} with the default threshold",
+        small_compactions,
+        default_compactions
+    );
+
+    let store = KvStore::open(small_dir.path())?;
+    assert_eq!(store.get("key999".to_owned())?, Some("value19".to_owned()));
+    Ok(())
+}
+
+// A forced compaction should remove every stale command, whatever the trigger, and leave a
+// store without stale data untouched
+#[test]
+fn force_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.stats()?.uncompacted_bytes > 0);
+
+    store.force_compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // nothing is stale, so no files are written
+    let files = |dir: &Path| -> Result<Vec<PathBuf>> {
+        let mut files = std::fs::read_dir(dir)?.map(|entry| Ok(entry?.path())).collect::<Result<Vec<_>>>()?;
+        files.sort();
+        Ok(files)
+    };
+    let before = files(temp_dir.path())?;
+    store.compact()?;
+    assert_eq!(files(temp_dir.path())?, before);
+    assert_eq!(store.stats()?.compactions, 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(MemoryKvsEngine::new().compact(), Err(KvsError::Unsupported(_))));
+    Ok(())
+}
+
+// A ratio based trigger should compact a small store long before the default byte threshold
+#[test]
+fn compaction_ratio_trigger() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.5))
+        .open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    // the stale data written here is far below the default byte threshold
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+
+            let new_size = dir_size();
+            if new_size > current_size {
+                current_size = new_size;
+                continue;
+            }
+            // Compaction triggered
+
+            drop(store);
+            // reopen and check content
+            let store = KvStore::open(temp_dir.path())?;
+            for check_id in 0..100 {
+                let expected = if check_id <= key_id { iter } else { iter - 1 };
+                assert_eq!(store.get(format!("key{}", check_id))?, Some(format!("{}", expected)));
+            }
+            return Ok(());
+        }
+    }
+
+    panic!("No compaction detected");
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +330,2407 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Only a swap that expects the current value should set it, and of many concurrent swaps that
+// expect the same value only one should succeed
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.compare_and_swap("key1".to_owned(), None, "value1".to_owned())?);
+    assert!(!store.compare_and_swap("key1".to_owned(), None, "value2".to_owned())?);
+    assert!(!store.compare_and_swap("key1".to_owned(), Some("other".to_owned()), "value2".to_owned())?);
+    assert!(!store.compare_and_swap("missing".to_owned(), Some("value1".to_owned()), "value2".to_owned())?);
+    assert_eq!(store.get("missing".to_owned())?, None);
+
+    let barrier = Arc::new(Barrier::new(100));
+    let handles: Vec<_> = (0..100)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store
+                    .compare_and_swap("key1".to_owned(), Some("value1".to_owned()), format!("swapped{}", thread_id))
+                    .unwrap()
+            })
+        })
+        .collect();
+    let swapped: Vec<usize> = handles
+        .into_iter()
+        .enumerate()
+        .filter_map(|(thread_id, handle)| handle.join().unwrap().then_some(thread_id))
+        .collect();
+    assert_eq!(swapped.len(), 1);
+    let winner = format!("swapped{}", swapped[0]);
+    assert_eq!(store.get("key1".to_owned())?, Some(winner.clone()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(winner));
+
+    Ok(())
+}
+
+// A tracing subscriber that panics when the writer's "set" span is created. Since that span is
+// entered while the writer lock is held, this is used to poison the writer lock.
+struct PanicOnSetSpan;
+
+impl tracing::Subscriber for PanicOnSetSpan {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        if span.metadata().name() == "set" {
+            panic!("forced panic while holding the writer lock");
+        }
+        tracing::span::Id::from_u64(1)
+    }
+    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+    fn event(&self, _event: &tracing::Event<'_>) {}
+    fn enter(&self, _span: &tracing::span::Id) {}
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
+
+// A panic while holding the writer lock should not brick the store
+#[test]
+fn recover_from_poisoned_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let poisoner = store.clone();
+    let handle = thread::spawn(move || {
+        panic_control::disable_hook_in_current_thread();
+        tracing::subscriber::with_default(PanicOnSetSpan, || {
+            poisoner.set("key2".to_owned(), "value2".to_owned())
+        })
+    });
+    assert!(handle.join().is_err());
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Every write should be assigned an increasing sequence number that persists across restarts
+#[test]
+fn write_sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.seq(), 0);
+
+    assert_eq!(store.set_with_seq("key1".to_owned(), "value1".to_owned())?, Some(1));
+    assert_eq!(store.set_with_seq("key2".to_owned(), "value2".to_owned())?, Some(2));
+    assert_eq!(store.remove_with_seq("key1".to_owned())?, Some(3));
+    // failed writes are not assigned a sequence number
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.seq(), 3);
+
+    // Open from disk again and check the sequence continues
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.seq(), 3);
+    assert_eq!(store.set_with_seq("key3".to_owned(), "value3".to_owned())?, Some(4));
+
+    Ok(())
+}
+
+// The sequence number should survive a compaction that discards the latest commands
+#[test]
+fn write_sequence_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.5))
+        .open(temp_dir.path())?;
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    // removing every key leaves no live commands, so every removal compacts the logs
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert_eq!(store.seq(), 20);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.seq(), 20);
+
+    Ok(())
+}
+
+// Should get the time a value was last written
+#[test]
+fn get_with_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_with_meta("key1".to_owned())?, None);
+
+    let before = SystemTime::now() - Duration::from_millis(1);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let after = SystemTime::now();
+
+    let (value, written_at) = store.get_with_meta("key1".to_owned())?.expect("key1 not found");
+    assert_eq!(value, "value1");
+    assert!(written_at >= before && written_at <= after);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_with_meta("key1".to_owned())?.map(|(_, t)| t), Some(written_at));
+
+    Ok(())
+}
+
+// Commands written without a write time should default to the log file's modification time
+#[test]
+fn get_with_meta_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    std::fs::write(&log_path, r#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    let modified = std::fs::metadata(&log_path)?.modified()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_meta("key1".to_owned())?,
+        Some(("value1".to_owned(), modified))
+    );
+
+    Ok(())
+}
+
+// Commands spread across many log generations should be loaded with later generations winning
+#[test]
+fn load_multiple_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for gen in 0..10 {
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", gen))?;
+        }
+        // every generation removes a different key, which is set again by the next generation
+        store.remove(format!("key{}", gen))?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let expected = if key_id == 9 { None } else { Some("value9".to_owned()) };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    assert_eq!(store.seq(), 10 * 101);
+
+    Ok(())
+}
+
+// A failed compaction should leave the store readable, writable and consistent on disk
+#[test]
+fn failed_compaction_rolls_back() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.1))
+        .open(temp_dir.path())?;
+
+    // the first compaction will write to "2.log", make that path unwritable
+    let blocker = temp_dir.path().join("2.log");
+    std::fs::create_dir(&blocker)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    // overwriting a key triggers the compaction, which fails. The write itself succeeded, so
+    // the failure is only reported by the health check
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(store.health().unwrap_err().to_string().contains("compaction failed"));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // once the problem is fixed, compaction succeeds
+    std::fs::remove_dir(&blocker)?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    store.health()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// A snapshot should iterate the key/value pairs present when it was taken, even if the
+// store is modified and compacted while iterating
+#[test]
+fn snapshot_iteration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.5))
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let snapshot = store.snapshot();
+    // keys set after the snapshot are not seen, removed keys are skipped
+    store.set("new_key".to_owned(), "new_value".to_owned())?;
+    store.remove("key0".to_owned())?;
+    // overwriting every key moves all values into new log files via compaction
+    for key_id in 1..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut pairs = snapshot.collect::<Result<Vec<_>>>()?;
+    pairs.sort();
+    let mut expected = (1..100)
+        .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+        .collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(pairs, expected);
+
+    Ok(())
+}
+
+// Should get the default for a non-existent key, without storing it
+#[test]
+fn get_or_default() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_or_default("key1".to_owned(), "default".to_owned())?, "value1");
+    assert_eq!(store.get_or_default("key2".to_owned(), "default".to_owned())?, "default");
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Reopening the store many times should not accumulate more than `max_log_files` log files
+#[test]
+fn compaction_max_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = || {
+        std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| {
+                entry.as_ref().unwrap().path().extension().and_then(|ext| ext.to_str()) == Some("log")
+            })
+            .count()
+    };
+
+    for iter in 0..20 {
+        let store = KvStore::builder().max_log_files(4).open(temp_dir.path())?;
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+        assert!(log_files() <= 4, "{} log files after {} reopens", log_files(), iter);
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..20 {
+        assert_eq!(store.get(format!("key{}", iter))?, Some(format!("value{}", iter)));
+    }
+
+    Ok(())
+}
+
+// Writes made through any clone of a store should be durable once the last clone is dropped
+#[test]
+fn drop_syncs_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    clone.set("key1".to_owned(), "value1".to_owned())?;
+    drop(clone);
+    // the store is still usable after one of its clones is dropped
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A clean shutdown should sync the writes made since the last sync, and report them
+#[test]
+fn shutdown_summary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let log_len = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert!(log_len > 0);
+
+    assert_eq!(
+        store.shutdown()?,
+        ShutdownSummary { bytes_flushed: log_len, compaction: ShutdownCompaction::Idle, final_gen: 1 }
+    );
+    // nothing was written since the last shutdown
+    assert_eq!(store.shutdown()?.bytes_flushed, 0);
+    store.remove("key1".to_owned())?;
+    assert!(store.shutdown()?.bytes_flushed > 0);
+
+    // memory engine has nothing to flush
+    assert!(matches!(MemoryKvsEngine::new().shutdown(), Err(KvsError::Unsupported(_))));
+    Ok(())
+}
+
+// A full store with an LRU policy should evict the least recently set or read key
+#[test]
+fn max_keys_lru_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .max_keys(3, EvictionPolicy::Lru)
+        .open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    // reading key1 makes key2 the least recently used
+    store.get("key1".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // overwriting an existing key never evicts
+    store.set("key3".to_owned(), "value3b".to_owned())?;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3b".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    // evictions are persisted, and keys are ordered by their latest write after reopening
+    drop(store);
+    let store = KvStore::builder()
+        .max_keys(3, EvictionPolicy::Lru)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // key4 was written before key3 was overwritten
+    store.set("key6".to_owned(), "value6".to_owned())?;
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3b".to_owned()));
+
+    Ok(())
+}
+
+// A full store with a FIFO policy should evict the oldest key, regardless of reads
+#[test]
+fn max_keys_fifo_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .max_keys(2, EvictionPolicy::Fifo)
+        .open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // a removed key frees up room without evicting
+    store.remove("key2".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should report statistics about the store
+#[test]
+fn engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.1))
+        .open(temp_dir.path())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 0);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.last_compaction, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 2);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert!(stats.disk_usage > 0);
+    assert_eq!(stats.index_memory_bytes, store.index_memory_estimate() as u64);
+
+    // overwriting a key creates stale data that crosses the ratio and triggers a compaction
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 2);
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    // the compaction rewrote both keys into a single log, and deleted the log they were in
+    let last_compaction = stats.last_compaction.expect("a compaction ran");
+    assert_eq!(last_compaction.keys_rewritten, 2);
+    assert_eq!(last_compaction.files_deleted, 1);
+    assert!(last_compaction.bytes_after < last_compaction.bytes_before);
+    // server statistics aren't tracked by the engine
+    assert_eq!(stats.uptime_secs, 0);
+    assert_eq!(stats.connections, 0);
+
+    Ok(())
+}
+
+// Should estimate the memory used by the index, growing and shrinking with the keys
+#[test]
+fn index_memory_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.index_memory_estimate(), 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let one_key = store.index_memory_estimate();
+    assert!(one_key > "key1".len());
+    store.set("a much longer key".to_owned(), "value2".to_owned())?;
+    assert!(store.index_memory_estimate() > one_key + "a much longer key".len());
+    assert_eq!(store.stats()?.index_memory_bytes, store.index_memory_estimate() as u64);
+    store.remove("a much longer key".to_owned())?;
+    assert_eq!(store.index_memory_estimate(), one_key);
+
+    Ok(())
+}
+
+// The memory engine should support the same operations as a KvStore, without persisting data
+#[test]
+fn memory_engine_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = MemoryKvsEngine::new();
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.stats()?.key_count, 1);
+
+    // clones of the engine share the same data
+    let clone = engine.clone();
+    clone.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(engine.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+
+    // nothing was written to disk
+    assert_eq!(WalkDir::new(temp_dir.path()).into_iter().count(), 1);
+
+    Ok(())
+}
+
+// The sled engine should set, get and remove values, and persist them across reopens
+#[test]
+fn sled_engine_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // sled keeps its directory locked until its background flusher stops, so the database is
+    // shared rather than reopened. Restarts are covered by `cli_access_server_sled_engine`
+    let db = sled::open(temp_dir.path())?;
+    let engine = SledKvsEngine::new(db.clone());
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("missing".to_owned())?, None);
+    assert!(engine.contains_key("key2".to_owned())?);
+
+    engine.clone().remove("key2".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert!(matches!(engine.remove("key2".to_owned()), Err(KvsError::KeyNotFound)));
+
+    drop(engine);
+    db.flush()?;
+    let engine = SledKvsEngine::new(db.clone());
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(!engine.contains_key("key2".to_owned())?);
+    Ok(())
+}
+
+// Reloading the index should pick up changes written to the logs by another store
+#[test]
+fn reload_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // stores sharing a directory must not lock it
+    let follower = KvStore::builder().exclusive(false).open(temp_dir.path())?;
+    follower.set("key1".to_owned(), "value1".to_owned())?;
+
+    let leader = KvStore::builder().exclusive(false).open(temp_dir.path())?;
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    leader.remove("key1".to_owned())?;
+    assert_eq!(follower.get("key2".to_owned())?, None);
+
+    follower.reload_index()?;
+    assert_eq!(follower.get("key1".to_owned())?, None);
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(follower.seq(), leader.seq());
+
+    // local writes continue to work after a reload
+    follower.set("key3".to_owned(), "value3".to_owned())?;
+    drop(follower);
+    drop(leader);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A background compaction should compact logs that crossed the trigger without a write
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for iter in 0..5 {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+
+    // the logs from the earlier opens exceed the max, but nothing is written to trigger a compaction
+    let store = KvStore::builder().max_log_files(2).open(temp_dir.path())?;
+    let compaction = store.start_background_compaction(Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.stats()?.compactions, 1);
+    compaction.stop();
+
+    let log_files = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "log"))
+        .count();
+    assert_eq!(log_files, 2);
+    for iter in 0..5 {
+        assert_eq!(store.get(format!("key{}", iter))?, Some(format!("value{}", iter)));
+    }
+
+    // the thread exits once the store is dropped, even if the handle is kept
+    let compaction = store.start_background_compaction(Duration::from_millis(10))?;
+    drop(store);
+    thread::sleep(Duration::from_millis(100));
+    compaction.stop();
+
+    Ok(())
+}
+
+// Should estimate how much a compaction would reclaim, without compacting
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let estimate = store.compaction_estimate()?;
+    assert_eq!(estimate.log_bytes, 0);
+    assert_eq!(estimate.reclaimable_bytes, 0);
+    assert_eq!(estimate.files_deleted, 1);
+
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    let estimate = store.compaction_estimate()?;
+    assert!(estimate.live_bytes > 0);
+    assert_eq!(estimate.reclaimable_bytes, estimate.log_bytes - estimate.live_bytes);
+    // only the latest of the 100 sets is live
+    assert!(estimate.reclaimable_bytes > 90 * estimate.live_bytes);
+    assert_eq!(store.stats()?.reclaimable_bytes, estimate.reclaimable_bytes);
+
+    // estimating doesn't change the logs
+    assert_eq!(store.compaction_estimate()?, estimate);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.compaction_estimate()?.files_deleted, 2);
+
+    Ok(())
+}
+
+// The stale and live byte counts maintained while writing should match the counts computed
+// when the logs are loaded by a fresh store
+#[test]
+fn uncompacted_accounting_survives_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // returns the (uncompacted, live) byte counts of the store
+    let accounting = |store: &KvStore| -> Result<(u64, u64)> {
+        Ok((store.stats()?.uncompacted_bytes, store.compaction_estimate()?.live_bytes))
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // overwrites and removes within the same log
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("new value{}", i))?;
+        store.remove(format!("key{}", i + 40))?;
+    }
+    // a key set and removed within the same log
+    store.set("temp".to_owned(), "temp value".to_owned())?;
+    store.remove("temp".to_owned())?;
+    let expected = accounting(&store)?;
+    assert!(expected.0 > 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(accounting(&store)?, expected);
+
+    // overwrites and removes of keys written to an earlier log
+    for i in 10..20 {
+        store.set(format!("key{}", i), format!("newer value{}", i))?;
+        store.remove(format!("key{}", i + 10))?;
+    }
+    let expected = accounting(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(accounting(&store)?, expected);
+    drop(store);
+
+    // writes following a compaction
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Bytes(1000))
+        .open(temp_dir.path())?;
+    for i in 0..30 {
+        store.set(format!("key{}", i % 5), format!("latest value{}", i))?;
+    }
+    assert!(store.stats()?.compactions > 0);
+    let expected = accounting(&store)?;
+    assert!(expected.0 > 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(accounting(&store)?, expected);
+
+    Ok(())
+}
+
+// Only the logs listed in the manifest should be loaded, unless the manifest is corrupt
+#[test]
+fn manifest_lists_live_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let stray_log = std::fs::read(temp_dir.path().join("1.log"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // a log that isn't in the manifest, e.g. one copied in by hand, is ignored
+    std::fs::write(temp_dir.path().join("10.log"), stray_log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // the logs are searched for if the manifest's checksum doesn't match
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let manifest = std::fs::read_to_string(&manifest_path)?;
+    std::fs::write(&manifest_path, manifest.replace("[1,", "[0,"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // and the manifest is rewritten, so the new log is loaded on the next open
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// The health check should fail once the working directory can't be written to
+#[test]
+fn health_check() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.health()?;
+    // the probe file is cleaned up, leaving the log, the manifest and the lock file
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 3);
+
+    // concurrent probes don't remove each other's probe files
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || (0..50).try_for_each(|_| store.health()))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 3);
+
+    std::fs::remove_dir_all(temp_dir.path())?;
+    let err = store.health().unwrap_err();
+    assert!(err.to_string().contains("not writable"), "unexpected error {}", err);
+
+    // the memory engine is always healthy
+    MemoryKvsEngine::new().health()?;
+    Ok(())
+}
+
+// A file system that fails every write to the files opened while `fail_writes` is set, the next
+// `fail_removes` file removals, and the next `fail_appends` opens for appending, with a timeout.
+// It counts the files that are open for reading in `open_reads`
+#[derive(Debug, Default)]
+struct FaultyFs {
+    fail_writes: Arc<AtomicBool>,
+    fail_removes: Arc<AtomicUsize>,
+    fail_appends: Arc<AtomicUsize>,
+    open_reads: Arc<AtomicUsize>,
+}
+
+// A file opened for reading, that is counted while it's open
+#[derive(Debug)]
+struct CountedFile {
+    file: Box<dyn ReadFile>,
+    open_reads: Arc<AtomicUsize>,
+}
+
+impl Read for CountedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for CountedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for CountedFile {
+    fn drop(&mut self) {
+        self.open_reads.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct FaultyFile {
+    file: File,
+    fail: bool,
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.fail {
+            return Err(io::Error::other("injected write failure"));
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl WriteFile for FaultyFile {
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(FaultyFile { file: self.file.try_clone()?, fail: self.fail }))
+    }
+}
+
+impl FileSystem for FaultyFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        let file = StdFs.open(path)?;
+        self.open_reads.fetch_add(1, Ordering::SeqCst);
+        Ok(Box::new(CountedFile { file, open_reads: self.open_reads.clone() }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        StdFs.create(path)
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        let fail = self
+            .fail_appends
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if fail {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "injected append failure"));
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let fail = self.fail_writes.load(Ordering::SeqCst);
+        Ok(Box::new(FaultyFile { file, fail }))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        StdFs.read_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let fail = self
+            .fail_removes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if fail {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "injected remove failure"));
+        }
+        StdFs.remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        StdFs.rename(from, to)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        StdFs.metadata(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        StdFs.create_dir_all(path)
+    }
+}
+
+// A compaction that fails part way through writing the compaction file should be rolled back,
+// leaving the store's data intact
+#[test]
+fn failed_compaction_write_is_rolled_back() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fail_writes = Arc::new(AtomicBool::new(false));
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Bytes(1000))
+        .file_system(FaultyFs { fail_writes: fail_writes.clone(), ..FaultyFs::default() })
+        .open(temp_dir.path())?;
+
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let log_files = || std::fs::read_dir(temp_dir.path()).unwrap().count();
+    let files_before = log_files();
+
+    // the current log is still writable, but writing the compaction file fails. The writes
+    // succeed regardless, the failed compaction is reported by the health check
+    fail_writes.store(true, Ordering::SeqCst);
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    assert_eq!(store.stats()?.compactions, 0);
+    assert!(store.health().unwrap_err().to_string().contains("compaction failed"));
+    // the partial compaction file was deleted
+    assert_eq!(log_files(), files_before);
+
+    // every key is still readable, and survives a restart
+    let expected: Vec<Option<String>> = (0..10).map(|i| store.get(format!("key{}", i)).unwrap()).collect();
+    assert!(expected.iter().all(Option::is_some));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for (i, value) in expected.into_iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", i))?, value);
+    }
+    store.health()?;
+
+    Ok(())
+}
+
+// Stale logs that can't be deleted by a compaction should be retried, and then deleted by the
+// next compaction, without failing either compaction
+#[test]
+fn stale_log_deletion_is_retried() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fail_removes = Arc::new(AtomicUsize::new(0));
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Bytes(0))
+        .file_system(FaultyFs { fail_removes: fail_removes.clone(), ..FaultyFs::default() })
+        .open(temp_dir.path())?;
+    let log_gens = || -> Vec<u64> {
+        let mut gens: Vec<u64> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().to_str()?.strip_suffix(".log")?.parse().ok())
+            .collect();
+        gens.sort_unstable();
+        gens
+    };
+
+    // the first removals fail, but are retried within the compaction
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    fail_removes.store(2, Ordering::SeqCst);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 1);
+    assert_eq!(fail_removes.load(Ordering::SeqCst), 0);
+    assert_eq!(log_gens(), [2, 3]);
+
+    // every attempt fails, so the stale log is left behind without failing the compaction
+    fail_removes.store(usize::MAX, Ordering::SeqCst);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(log_gens(), [2, 3, 4, 5]);
+
+    // the next compaction deletes it
+    fail_removes.store(0, Ordering::SeqCst);
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 3);
+    assert_eq!(log_gens(), [6, 7]);
+    drop(store);
+    assert_eq!(KvStore::open(temp_dir.path())?.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// a new log that can't be created because of a transient error should be retried a few times,
+// but a persistent error should still fail the open or the compaction
+#[test]
+fn new_log_creation_is_retried() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fail_appends = Arc::new(AtomicUsize::new(2));
+    let faulty_fs = || FaultyFs { fail_appends: fail_appends.clone(), ..FaultyFs::default() };
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Bytes(0))
+        .file_system(faulty_fs())
+        .open(temp_dir.path())?;
+    assert_eq!(fail_appends.load(Ordering::SeqCst), 0);
+
+    // the compaction file and the new current log are retried
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    fail_appends.store(3, Ordering::SeqCst);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 1);
+    assert_eq!(fail_appends.load(Ordering::SeqCst), 0);
+    drop(store);
+
+    // every attempt fails, so the error is returned once the attempts run out
+    fail_appends.store(10, Ordering::SeqCst);
+    let opened = KvStore::builder().file_system(faulty_fs()).open(temp_dir.path());
+    assert!(matches!(opened, Err(KvsError::Io { source }) if source.kind() == io::ErrorKind::TimedOut));
+    assert_eq!(fail_appends.load(Ordering::SeqCst), 6);
+
+    fail_appends.store(0, Ordering::SeqCst);
+    let store = KvStore::builder().file_system(faulty_fs()).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Values read by `get_reader` should be unescaped, and match the values returned by `get`
+#[test]
+fn get_reader_streams_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a log whose command doesn't use the layout written by the store
+    std::fs::write(temp_dir.path().join("1.log"), r#"{"Set":{"value":"legacy value","key":"legacy"}}"#)?;
+    let store = KvStore::open(temp_dir.path())?;
+
+    let read_value = |key: &str| -> Result<Option<String>> {
+        match store.get_reader(key.to_owned())? {
+            Some(mut reader) => {
+                let mut value = String::new();
+                reader.read_to_string(&mut value)?;
+                assert_eq!(reader.len(), value.len() as u64);
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    };
+
+    let values = [
+        ("plain", "value".to_owned()),
+        ("empty", String::new()),
+        (r#"escaped "key"\"#, "quote \" backslash \\ slash / \n\r\t\u{8}\u{c} control \u{1}\u{1f}".to_owned()),
+        ("unicode", "caf\u{e9} \u{1F600} \u{FFFF}".to_owned()),
+        ("large", "0123456789\"".repeat(100_000)),
+    ];
+    for (key, value) in &values {
+        store.set(key.to_string(), value.clone())?;
+    }
+    for (key, value) in &values {
+        assert_eq!(read_value(key)?.as_ref(), Some(value), "key {}", key);
+    }
+    assert_eq!(read_value("legacy")?, Some("legacy value".to_owned()));
+    assert_eq!(read_value("missing")?, None);
+
+    // the memory engine reads the value from memory
+    let engine = MemoryKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let mut value = String::new();
+    engine.get_reader("key1".to_owned())?.unwrap().read_to_string(&mut value)?;
+    assert_eq!(value, "value1");
+
+    Ok(())
+}
+
+// a value reader owns its file handle, so a large value can be copied out after a compaction
+// has deleted the log the value was read from
+#[test]
+fn get_reader_outlives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Bytes(64 * 1024))
+        .open(temp_dir.path())?;
+    let value = "0123456789".repeat(500_000);
+    store.set("large".to_owned(), value.clone())?;
+    let mut reader = store.get_reader("large".to_owned())?.expect("the key was set");
+
+    for iter in 0..100 {
+        store.set("small".to_owned(), format!("value{:0>1000}", iter))?;
+    }
+    store.set("large".to_owned(), "overwritten".to_owned())?;
+    assert!(store.stats()?.compactions > 0);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    assert_eq!(reader.len(), value.len() as u64);
+    assert_eq!(io::copy(&mut reader, &mut io::sink())?, value.len() as u64);
+    Ok(())
+}
+
+#[test]
+fn raw_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let binary_key = vec![0xff, 0x00, 0xfe, b'k'];
+    let binary_value = vec![0xc3, 0x28, 0x00, 0xff];
+    store.set_raw(binary_key.clone(), binary_value.clone())?;
+    store.set_raw(b"text".to_vec(), binary_value.clone())?;
+    store.set_raw(binary_key.clone(), b"overwritten".to_vec())?;
+    store.set_raw(vec![0x80], b"removed".to_vec())?;
+    store.remove_raw(vec![0x80])?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get_raw(binary_key.clone())?, Some(b"overwritten".to_vec()));
+        assert_eq!(store.get_raw(b"text".to_vec())?, Some(binary_value.clone()));
+        assert_eq!(store.get_raw(vec![0x80])?, None);
+        // the string and byte APIs share the same keys
+        assert_eq!(store.get_raw(b"key1".to_vec())?, Some(b"value1".to_vec()));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert!(matches!(store.get("text".to_owned()), Err(KvsError::Utf8Error(_))));
+        let mut value = vec![];
+        store.get_reader("text".to_owned())?.unwrap().read_to_end(&mut value)?;
+        assert_eq!(value, binary_value);
+        Ok(())
+    };
+    check(&store)?;
+    assert!(matches!(store.remove_raw(vec![0x80]), Err(KvsError::KeyNotFound)));
+
+    // binary commands are read back when the store is reopened, and survive a compaction
+    drop(store);
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Bytes(0))
+        .open(temp_dir.path())?;
+    check(&store)?;
+    store.set_raw(binary_key.clone(), b"overwritten".to_vec())?;
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// A dump should load every key and value into another store, and a damaged dump nothing
+#[test]
+fn dump_and_load_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(&temp_dir.path().join("source"))?;
+    let binary_key = vec![0xff, 0x00, 0xfe, b'k'];
+    store.set_raw(binary_key.clone(), vec![0xc3, 0x28])?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+    store.set("empty".to_owned(), String::new())?;
+    let dump_path = temp_dir.path().join("store.dump");
+    assert_eq!(store.dump(&dump_path)?, 4);
+
+    let dest = KvStore::open(&temp_dir.path().join("dest"))?;
+    dest.set("key1".to_owned(), "old".to_owned())?;
+    dest.set("other".to_owned(), "untouched".to_owned())?;
+    assert_eq!(dest.load_dump(&dump_path)?, 4);
+    assert_eq!(dest.get_raw(binary_key)?, Some(vec![0xc3, 0x28]));
+    assert_eq!(dest.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(dest.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(dest.get("empty".to_owned())?, Some(String::new()));
+    assert_eq!(dest.get("removed".to_owned())?, None);
+    assert_eq!(dest.get("other".to_owned())?, Some("untouched".to_owned()));
+
+    // truncated, corrupt and unknown dumps are rejected before any key is loaded
+    let dump = std::fs::read(&dump_path)?;
+    let mut corrupt = dump.clone();
+    corrupt[20] ^= 0xff;
+    let mut newer = dump.clone();
+    newer[8] = 2;
+    let damaged = [dump[..dump.len() - 2].to_vec(), dump[..30].to_vec(), corrupt, newer, b"key1,value1".to_vec()];
+    let empty = KvStore::open(&temp_dir.path().join("empty"))?;
+    for bytes in damaged {
+        std::fs::write(&dump_path, bytes)?;
+        assert!(matches!(empty.load_dump(&dump_path), Err(KvsError::InvalidDump(_))));
+    }
+    assert_eq!(empty.scan(None, 10)?, (vec![], None));
+
+    Ok(())
+}
+
+// a store reopened with `compact_on_open` compacts the stale data it loaded
+#[test]
+fn compact_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_bytes = |dir: &Path| -> u64 {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+
+    // the default trigger isn't crossed by these overwrites
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+    let stale_size = log_bytes(temp_dir.path());
+
+    // the stale data is left alone unless compaction on open is enabled
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.5))
+        .open(temp_dir.path())?;
+    assert_eq!(store.stats()?.compactions, 0);
+    drop(store);
+    assert_eq!(log_bytes(temp_dir.path()), stale_size);
+
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.5))
+        .compact_on_open(true)
+        .open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert!(log_bytes(temp_dir.path()) < stale_size / 10);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    // a store without stale data isn't compacted
+    drop(store);
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.5))
+        .compact_on_open(true)
+        .open(temp_dir.path())?;
+    assert_eq!(store.stats()?.compactions, 0);
+    Ok(())
+}
+
+// compacting the same data should write byte-identical logs, however the compaction is started
+#[test]
+fn compaction_is_reproducible() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().dedup_values(true).open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("{}-{:0>64}", key_id % 10, iter))?;
+        }
+    }
+    for key_id in (0..200).step_by(7) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set_tagged("tagged".to_owned(), "value".to_owned(), 3)?;
+    drop(store);
+
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        std::fs::copy(&path, copy_dir.path().join(path.file_name().unwrap()))?;
+    }
+
+    // one copy is compacted when it's opened, and the other by a background compaction
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.5))
+        .compact_on_open(true)
+        .open(temp_dir.path())?;
+    assert_eq!(store.stats()?.compactions, 1);
+    drop(store);
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.5))
+        .open(copy_dir.path())?;
+    let compaction = store.start_background_compaction(Duration::from_millis(10))?;
+    while store.stats()?.compactions == 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    compaction.stop();
+    drop(store);
+
+    let logs = |dir: &Path| -> Vec<(PathBuf, Vec<u8>)> {
+        let mut logs: Vec<(PathBuf, Vec<u8>)> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+            .map(|path| (PathBuf::from(path.file_name().unwrap()), std::fs::read(&path).unwrap()))
+            .collect();
+        logs.sort();
+        logs
+    };
+    let compacted = logs(temp_dir.path());
+    assert_eq!(compacted.len(), 2);
+    assert!(compacted == logs(copy_dir.path()), "the compacted logs differ");
+    Ok(())
+}
+
+// only one store at a time can open a directory exclusively
+#[test]
+fn working_dir_is_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let err = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(matches!(err, KvsError::Locking(_)), "unexpected error {:?}", err);
+    assert!(err.to_string().contains("already in use by another process"));
+    // a non-exclusive store ignores the lock
+    KvStore::builder().exclusive(false).open(temp_dir.path())?;
+
+    // the lock is held until every clone of the store is dropped
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locking(_))));
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// paging through the keys should return every key once, in order
+#[test]
+fn scan_pages_through_keys() -> Result<()> {
+    fn scan_all<E: KvsEngine>(engine: &E, limit: usize) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut cursor = None;
+        loop {
+            let (page, next_cursor) = engine.scan(cursor, limit)?;
+            assert!(page.len() <= limit);
+            keys.extend(page);
+            match next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return Ok(keys),
+            }
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let ordered_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ordered = KvStore::builder().ordered_index(true).open(ordered_dir.path())?;
+    let memory = MemoryKvsEngine::new();
+    for i in (0..25).rev() {
+        let key = format!("key{:02}", i);
+        store.set(key.clone(), "value".to_owned())?;
+        ordered.set(key.clone(), "value".to_owned())?;
+        memory.set(key, "value".to_owned())?;
+    }
+    store.remove("key03".to_owned())?;
+    ordered.remove("key03".to_owned())?;
+    memory.remove("key03".to_owned())?;
+    // keys that aren't valid UTF-8 are skipped
+    store.set_raw(vec![0xff], b"value".to_vec())?;
+    ordered.set_raw(vec![0xff], b"value".to_vec())?;
+
+    let expected: Vec<String> = (0..25).filter(|i| *i != 3).map(|i| format!("key{:02}", i)).collect();
+    for limit in [1, 7, 24, 100] {
+        assert_eq!(scan_all(&store, limit)?, expected);
+        assert_eq!(scan_all(&ordered, limit)?, expected);
+        assert_eq!(scan_all(&memory, limit)?, expected);
+    }
+
+    let (page, next_cursor) = ordered.scan(Some("key20".to_owned()), 10)?;
+    assert_eq!(page, ["key21", "key22", "key23", "key24"]);
+    assert_eq!(next_cursor, None);
+
+    // the ordered index is rebuilt when the store is reopened
+    drop(ordered);
+    let ordered = KvStore::builder().ordered_index(true).open(ordered_dir.path())?;
+    assert_eq!(scan_all(&ordered, 5)?, expected);
+    Ok(())
+}
+
+// An engine that can only list its keys a page at a time, so that it uses the default
+// scan_prefix
+#[derive(Clone)]
+struct PagedEngine(MemoryKvsEngine);
+
+impl KvsEngine for PagedEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn scan(&self, cursor: Option<String>, limit: usize) -> Result<(Vec<String>, Option<String>)> {
+        self.0.scan(cursor, limit)
+    }
+}
+
+// scan_prefix should return the keys that start with the prefix, in ascending order, from every
+// engine
+#[test]
+fn scan_prefix() -> Result<()> {
+    fn check<E: KvsEngine>(engine: &E) -> Result<()> {
+        let users: Vec<String> = (0..1500).map(|i| format!("user:{:04}", i)).collect();
+        for key in users.iter().rev().chain(["group:1", "user", "users:1", "a"].map(String::from).iter()) {
+            engine.set(key.clone(), "value".to_owned())?;
+        }
+        engine.remove("user:0003".to_owned())?;
+
+        let expected: Vec<String> = users.iter().filter(|key| *key != "user:0003").cloned().collect();
+        assert_eq!(engine.scan_prefix("user:".to_owned())?, expected);
+        let tens: Vec<String> = (10..20).map(|i| format!("user:{:04}", i)).collect();
+        assert_eq!(engine.scan_prefix("user:001".to_owned())?, tens);
+        assert_eq!(engine.scan_prefix("users".to_owned())?, ["users:1"]);
+        assert_eq!(engine.scan_prefix("g".to_owned())?, ["group:1"]);
+        assert!(engine.scan_prefix("missing".to_owned())?.is_empty());
+        assert_eq!(engine.scan_prefix(String::new())?.len(), 1503);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    // keys that aren't valid UTF-8 are skipped
+    store.set_raw(b"user:\xff".to_vec(), b"value".to_vec())?;
+    assert_eq!(store.scan_prefix("user:".to_owned())?.len(), 1499);
+
+    let ordered_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&KvStore::builder().ordered_index(true).open(ordered_dir.path())?)?;
+    check(&MemoryKvsEngine::new())?;
+    check(&PagedEngine(MemoryKvsEngine::new()))?;
+    Ok(())
+}
+
+// tags should be stored with their values and survive a reopen and compaction
+#[test]
+fn tagged_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_tagged("json".to_owned(), r#"{"a":1}"#.to_owned(), 1)?;
+    store.set_tagged("text".to_owned(), "plain".to_owned(), 2)?;
+    store.set("untagged".to_owned(), "value".to_owned())?;
+    store.set_tagged("overwritten".to_owned(), "value".to_owned(), 3)?;
+    store.set("overwritten".to_owned(), "value".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get_tagged("json".to_owned())?, Some((r#"{"a":1}"#.to_owned(), 1)));
+        assert_eq!(store.get_tagged("text".to_owned())?, Some(("plain".to_owned(), 2)));
+        assert_eq!(store.get_tagged("untagged".to_owned())?, Some(("value".to_owned(), 0)));
+        assert_eq!(store.get_tagged("overwritten".to_owned())?, Some(("value".to_owned(), 0)));
+        assert_eq!(store.get_tagged("missing".to_owned())?, None);
+        // the untagged API ignores the tag
+        assert_eq!(store.get("json".to_owned())?, Some(r#"{"a":1}"#.to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Bytes(0))
+        .open(temp_dir.path())?;
+    check(&store)?;
+    store.set_tagged("json".to_owned(), r#"{"a":1}"#.to_owned(), 1)?;
+    assert!(store.stats()?.compactions > 0);
+    check(&store)?;
+
+    // the memory engine stores tags
+    let engine = MemoryKvsEngine::new();
+    engine.set_tagged("key1".to_owned(), "value1".to_owned(), 7)?;
+    assert_eq!(engine.get_tagged("key1".to_owned())?, Some(("value1".to_owned(), 7)));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// a transaction should apply all of its operations, or none of them
+#[test]
+fn transactions_are_all_or_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "10".to_owned())?;
+    store.set("to".to_owned(), "0".to_owned())?;
+
+    store.transaction(|tx| {
+        // reads inside the closure see the state from before the transaction
+        tx.set("from".to_owned(), "5".to_owned());
+        assert_eq!(store.get("from".to_owned())?, Some("10".to_owned()));
+        tx.set("to".to_owned(), "5".to_owned());
+        tx.set("temp".to_owned(), "value".to_owned());
+        tx.remove("temp".to_owned());
+        Ok(())
+    })?;
+    assert_eq!(store.get("from".to_owned())?, Some("5".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("5".to_owned()));
+    assert_eq!(store.get("temp".to_owned())?, None);
+
+    let log_sizes = |dir: &Path| -> u64 {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+    let size = log_sizes(temp_dir.path());
+
+    // nothing is written if the closure fails
+    let result = store.transaction(|tx| {
+        tx.set("from".to_owned(), "0".to_owned());
+        Err(KvsError::StringErr("insufficient funds".to_owned()))
+    });
+    assert!(matches!(result, Err(KvsError::StringErr(_))));
+    // or if one of its removes would fail
+    let result = store.transaction(|tx| {
+        tx.set("to".to_owned(), "10".to_owned());
+        tx.remove("missing".to_owned());
+        Ok(())
+    });
+    assert!(matches!(result, Err(KvsError::KeyNotFound)));
+    assert_eq!(log_sizes(temp_dir.path()), size);
+    assert_eq!(store.get("from".to_owned())?, Some("5".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("5".to_owned()));
+
+    // the transaction is loaded from the log, and survives a compaction
+    drop(store);
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Bytes(0))
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, Some("5".to_owned()));
+    assert_eq!(store.get("temp".to_owned())?, None);
+    store.transaction(|tx| {
+        tx.remove("from".to_owned());
+        tx.set("to".to_owned(), "10".to_owned());
+        Ok(())
+    })?;
+    assert!(store.stats()?.compactions > 0);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, None);
+    assert_eq!(store.get("to".to_owned())?, Some("10".to_owned()));
+
+    // the memory engine supports transactions
+    let engine = MemoryKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let result = engine.transaction(|tx| {
+        tx.set("key2".to_owned(), "value2".to_owned());
+        tx.remove("missing".to_owned());
+        Ok(())
+    });
+    assert!(matches!(result, Err(KvsError::KeyNotFound)));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    engine.transaction(|tx| {
+        tx.remove("key1".to_owned());
+        tx.set("key2".to_owned(), "value2".to_owned());
+        Ok(())
+    })?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// set_many should set every pair, count the values it overwrites as uncompacted, and compact
+// once the pairs cross the compaction trigger
+#[test]
+fn set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Bytes(10_000))
+        .open(temp_dir.path())?;
+    store.set_many((0..1000).map(|i| (format!("key{}", i), format!("value{}", i))).collect())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 1000);
+    assert_eq!(stats.uncompacted_bytes, 0);
+
+    // the last value of a key that appears twice is kept, and the first one is stale
+    store.set_many(vec![
+        ("key0".to_owned(), "first".to_owned()),
+        ("key1".to_owned(), "new".to_owned()),
+        ("key0".to_owned(), "second".to_owned()),
+    ])?;
+    assert_eq!(store.get("key0".to_owned())?, Some("second".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert!(store.stats()?.uncompacted_bytes > 0);
+    store.set_many(vec![])?;
+
+    // overwriting every key crosses the compaction trigger
+    store.set_many((0..1000).map(|i| (format!("key{}", i), "x".repeat(100))).collect())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.key_count, 1000);
+
+    // set_each writes the pairs as a batch too, and reports each of them
+    let results = store.set_each(vec![("key1".to_owned(), "each".to_owned()), ("new".to_owned(), "each".to_owned())]);
+    assert!(matches!(results[..], [Ok(()), Ok(())]));
+    assert_eq!(store.get("new".to_owned())?, Some("each".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(store.get("key999".to_owned())?, Some("x".repeat(100)));
+
+    // the memory engine sets the pairs one at a time
+    let engine = MemoryKvsEngine::new();
+    engine.set_many(vec![("key1".to_owned(), "value1".to_owned()), ("key1".to_owned(), "value2".to_owned())])?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// identical values should be stored once in a store that dedups values, and stay readable
+// through overwrites, removes, compactions and reopens
+#[test]
+fn dedup_values() -> Result<()> {
+    let dedup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dedup = KvStore::builder().dedup_values(true).open(dedup_dir.path())?;
+    let plain = KvStore::open(plain_dir.path())?;
+    let shared = "shared config ".repeat(100);
+    for i in 0..50 {
+        dedup.set(format!("key{}", i), shared.clone())?;
+        plain.set(format!("key{}", i), shared.clone())?;
+    }
+    // short values aren't worth linking to
+    dedup.set("short1".to_owned(), "value".to_owned())?;
+    dedup.set("short2".to_owned(), "value".to_owned())?;
+    assert!(dedup.stats()?.disk_usage * 10 < plain.stats()?.disk_usage);
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 1..50 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(shared.clone()));
+        }
+        assert_eq!(store.get("short2".to_owned())?, Some("value".to_owned()));
+        let mut value = String::new();
+        store
+            .get_reader("key7".to_owned())?
+            .expect("key7 exists")
+            .read_to_string(&mut value)?;
+        assert_eq!(value, shared);
+        Ok(())
+    };
+    check(&dedup)?;
+    // the links have their own tag and write time
+    dedup.set_tagged("tagged".to_owned(), shared.clone(), 3)?;
+    assert_eq!(dedup.get_tagged("tagged".to_owned())?, Some((shared.clone(), 3)));
+    assert_eq!(dedup.get_tagged("key1".to_owned())?, Some((shared.clone(), 0)));
+
+    // the key that first set the shared value is overwritten, the value must survive compaction
+    dedup.set("key0".to_owned(), "another value".to_owned())?;
+    dedup.remove("key49".to_owned())?;
+    drop(dedup);
+    let dedup = KvStore::builder()
+        .dedup_values(true)
+        .compaction_trigger(CompactionTrigger::Bytes(0))
+        .open(dedup_dir.path())?;
+    dedup.set("key49".to_owned(), shared.clone())?;
+    assert!(dedup.stats()?.compactions > 0);
+    check(&dedup)?;
+    assert_eq!(dedup.get("key0".to_owned())?, Some("another value".to_owned()));
+    assert!(dedup.stats()?.disk_usage * 10 < plain.stats()?.disk_usage);
+
+    // a store that doesn't dedup can read, and compact, the links
+    drop(dedup);
+    let store = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Bytes(0))
+        .open(dedup_dir.path())?;
+    check(&store)?;
+    store.remove("key1".to_owned())?;
+    assert!(store.stats()?.compactions > 0);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    for i in 2..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(shared.clone()));
+    }
+    Ok(())
+}
+
+// a store that limits its open logs should close the least recently read log to open another
+#[test]
+fn max_open_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // every time the store is opened a new log is created
+    for gen in 0..5 {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", gen), format!("value{}", gen))?;
+    }
+
+    let open_reads = Arc::new(AtomicUsize::new(0));
+    let store = KvStore::builder()
+        .max_open_logs(2)
+        .file_system(FaultyFs { open_reads: open_reads.clone(), ..FaultyFs::default() })
+        .open(temp_dir.path())?;
+    assert!(open_reads.load(Ordering::SeqCst) <= 2);
+    for _ in 0..3 {
+        for gen in 0..5 {
+            assert_eq!(store.get(format!("key{}", gen))?, Some(format!("value{}", gen)));
+            assert!(open_reads.load(Ordering::SeqCst) <= 2);
+        }
+    }
+    Ok(())
+}
+
+// warming the value cache should serve the warmed keys without reading the logs, and the cache
+// should never return a value that has since been overwritten or removed
+#[test]
+fn warm_value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(matches!(store.warm_cache(vec!["key0".to_owned()]), Err(KvsError::Unsupported(_))));
+    drop(store);
+
+    let store = KvStore::builder().value_cache(2).open(temp_dir.path())?;
+    let keys = vec!["key0".to_owned(), "key1".to_owned(), "key2".to_owned(), "missing".to_owned()];
+    assert_eq!(store.warm_cache(keys)?, 3);
+    // the values are no longer in the log, so only the two most recently warmed keys can be read
+    OpenOptions::new().write(true).open(temp_dir.path().join("1.log"))?.set_len(0)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.get("key0".to_owned()).is_err());
+
+    store.set("key1".to_owned(), "new value".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new value".to_owned()));
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// a store that verifies reads should detect a corrupted value, rather than returning it
+#[test]
+fn verify_on_read() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().verify_on_read(true).open(temp_dir.path());
+    let store = open()?;
+    let binary_value = vec![0xc3, 0x28, 0x00, 0xff];
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_raw(b"binary".to_vec(), binary_value.clone())?;
+    store.transaction(|tx| {
+        tx.set("key2".to_owned(), "value2".to_owned());
+        tx.set("victim".to_owned(), "original".to_owned());
+        Ok(())
+    })?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get_raw(b"binary".to_vec())?, Some(binary_value.clone()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("removed".to_owned())?, None);
+        let mut value = String::new();
+        store.get_reader("key1".to_owned())?.unwrap().read_to_string(&mut value)?;
+        assert_eq!(value, "value1");
+        Ok(())
+    };
+    check(&store)?;
+    // checksums are kept by a compaction
+    drop(store);
+    let store = KvStore::builder()
+        .verify_on_read(true)
+        .compaction_trigger(CompactionTrigger::Bytes(0))
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.stats()?.compactions > 0);
+    check(&store)?;
+    drop(store);
+
+    // flip a byte of a value on disk, without changing the layout of its command
+    let log_path = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .find(|path| {
+            std::fs::read(path).is_ok_and(|contents| contents.windows(8).any(|w| w == b"original"))
+        })
+        .expect("the log holding the value");
+    let mut contents = std::fs::read(&log_path)?;
+    let at = contents.windows(8).position(|w| w == b"original").unwrap();
+    contents[at] = b'O';
+    std::fs::write(&log_path, contents)?;
+
+    let store = open()?;
+    check(&store)?;
+    match store.get("victim".to_owned()) {
+        Err(KvsError::InvalidCommand(msg)) => assert!(msg.contains("checksum mismatch"), "{}", msg),
+        res => panic!("expected a checksum mismatch, got {:?}", res),
+    }
+    assert!(store.get_reader("victim".to_owned()).is_err());
+    drop(store);
+    // without verification the corrupted value is returned
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("victim".to_owned())?, Some("Original".to_owned()));
+    Ok(())
+}
+
+// a store that reads its logs through memory maps should see writes made after a log was
+// mapped, and values moved by a compaction
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .mmap(true)
+        .compaction_trigger(CompactionTrigger::Bytes(4096))
+        .open(temp_dir.path())?;
+    for iter in 0..20 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+            // the current log is mapped by this read, and grows with the next write
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("{}", iter)));
+        }
+    }
+    assert!(store.stats()?.compactions > 0);
+    let reader = store.clone();
+    for key_id in 0..50 {
+        assert_eq!(reader.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+    drop(reader);
+    drop(store);
+
+    let store = KvStore::builder().mmap(true).open(temp_dir.path())?;
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+    Ok(())
+}
+
+// an encrypted store should never write values (or hashed keys) to its logs in plain text, and
+// should only open with the passphrase it was created with
+#[cfg(feature = "crypto")]
+#[test]
+fn encrypted_values() -> Result<()> {
+    let log_bytes = |dir: &Path| -> Vec<u8> {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+            .flat_map(|entry| std::fs::read(entry.path()).unwrap())
+            .collect()
+    };
+    let contains = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).any(|window| window == needle);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .encryption_passphrase("correct horse")
+        .compaction_trigger(CompactionTrigger::Bytes(1024))
+        .open(temp_dir.path())?;
+    for iter in 0..20 {
+        store.set("secret-key".to_owned(), format!("secret-value-{}", iter))?;
+    }
+    store.transaction(|tx| {
+        tx.set("tx-key".to_owned(), "tx-secret".to_owned());
+        Ok(())
+    })?;
+    store.rename("tx-key".to_owned(), "renamed".to_owned())?;
+    assert!(store.stats()?.compactions > 0);
+    assert_eq!(store.get("secret-key".to_owned())?, Some("secret-value-19".to_owned()));
+    assert_eq!(store.get("renamed".to_owned())?, Some("tx-secret".to_owned()));
+    drop(store);
+    let logs = log_bytes(temp_dir.path());
+    assert!(!contains(&logs, b"secret-value") && !contains(&logs, b"tx-secret"));
+    // the keys aren't hashed by default
+    assert!(contains(&logs, b"secret-key"));
+
+    // a wrong passphrase, or none at all, is rejected
+    let wrong = KvStore::builder().encryption_passphrase("wrong").open(temp_dir.path());
+    assert!(matches!(wrong, Err(KvsError::Encryption(_))));
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Encryption(_))));
+    let hashed = KvStore::builder().encryption_passphrase("correct horse").hash_keys(true).open(temp_dir.path());
+    assert!(matches!(hashed, Err(KvsError::Encryption(_))));
+
+    let store = KvStore::builder().encryption_passphrase("correct horse").open(temp_dir.path())?;
+    assert_eq!(store.get("secret-key".to_owned())?, Some("secret-value-19".to_owned()));
+    assert_eq!(store.get_reader("renamed".to_owned())?.map(|reader| reader.len()), Some(9));
+    drop(store);
+
+    // a store holding unencrypted values can't be encrypted
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    let encrypted = KvStore::builder().encryption_passphrase("correct horse").open(temp_dir.path());
+    assert!(matches!(encrypted, Err(KvsError::Encryption(_))));
+
+    // hashed keys can be looked up, but aren't written to the logs
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .encryption_passphrase("correct horse")
+        .hash_keys(true)
+        .open(temp_dir.path())?;
+    store.set("secret-key".to_owned(), "value1".to_owned())?;
+    store.append("secret-key".to_owned(), "value2".to_owned(), None)?;
+    assert_eq!(store.get("secret-key".to_owned())?, Some("value1value2".to_owned()));
+    let (keys, _cursor) = store.scan(None, 10)?;
+    assert_eq!(keys.len(), 1);
+    assert_ne!(keys[0], "secret-key");
+    store.remove("secret-key".to_owned())?;
+    assert_eq!(store.get("secret-key".to_owned())?, None);
+    drop(store);
+    assert!(!contains(&log_bytes(temp_dir.path()), b"secret-key"));
+
+    Ok(())
+}
+
+// renaming a key should move its value and tag, and survive a reopen
+#[test]
+fn rename_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_tagged("staging:x".to_owned(), "value1".to_owned(), 3)?;
+    store.set("prod:x".to_owned(), "old".to_owned())?;
+    store.rename("staging:x".to_owned(), "prod:x".to_owned())?;
+    assert!(matches!(
+        store.rename("staging:x".to_owned(), "prod:y".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.rename("prod:x".to_owned(), "prod:x".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("staging:x".to_owned())?, None);
+        assert_eq!(store.get("prod:y".to_owned())?, None);
+        assert_eq!(store.get_tagged("prod:x".to_owned())?, Some(("value1".to_owned(), 3)));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    let engine = MemoryKvsEngine::new();
+    engine.set_tagged("from".to_owned(), "value1".to_owned(), 2)?;
+    engine.rename("from".to_owned(), "to".to_owned())?;
+    assert_eq!(engine.get("from".to_owned())?, None);
+    assert_eq!(engine.get_tagged("to".to_owned())?, Some(("value1".to_owned(), 2)));
+    assert!(matches!(engine.rename("from".to_owned(), "to".to_owned()), Err(KvsError::KeyNotFound)));
+    Ok(())
+}
+
+// An engine that fails every write while `fail` is set
+#[derive(Clone, Default)]
+struct FailingEngine {
+    engine: MemoryKvsEngine,
+    fail: Arc<AtomicBool>,
+}
+
+impl KvsEngine for FailingEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(KvsError::StringErr("injected set failure".to_owned()));
+        }
+        self.engine.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(KvsError::StringErr("injected remove failure".to_owned()));
+        }
+        self.engine.remove(key)
+    }
+}
+
+// a tee engine should keep both of its engines consistent, rolling back the primary engine if
+// the secondary fails
+#[test]
+fn tee_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let secondary = FailingEngine::default();
+    let engine = TeeEngine::new(KvStore::open(temp_dir.path())?, secondary.clone());
+    for i in 0..10 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.set("key1".to_owned(), "overwritten".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    assert!(matches!(engine.remove("key2".to_owned()), Err(KvsError::KeyNotFound)));
+    // a key the secondary doesn't have yet can be removed
+    engine.primary().set("uncopied".to_owned(), "value".to_owned())?;
+    engine.remove("uncopied".to_owned())?;
+
+    let consistent = |engine: &TeeEngine<KvStore, FailingEngine>| -> Result<()> {
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            assert_eq!(engine.get(key.clone())?, engine.secondary().get(key.clone())?, "{}", key);
+        }
+        assert_eq!(engine.get("uncopied".to_owned())?, None);
+        Ok(())
+    };
+    consistent(&engine)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("overwritten".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    // failed writes to the secondary are rolled back in the primary
+    secondary.fail.store(true, Ordering::SeqCst);
+    assert!(engine.set("key1".to_owned(), "failed".to_owned()).is_err());
+    assert!(engine.set("new".to_owned(), "failed".to_owned()).is_err());
+    assert!(engine.remove("key3".to_owned()).is_err());
+    consistent(&engine)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("overwritten".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("new".to_owned())?, None);
+    Ok(())
+}
+
+// concurrent appends to a value should never be lost
+#[test]
+fn append_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.append("list".to_owned(), "a".to_owned(), Some(",".to_owned()))?, "a");
+    assert_eq!(store.append("list".to_owned(), "b".to_owned(), Some(",".to_owned()))?, "a,b");
+    assert_eq!(store.append("list".to_owned(), "c".to_owned(), None)?, "a,bc");
+    store.set_tagged("tagged".to_owned(), "x".to_owned(), 4)?;
+    store.append("tagged".to_owned(), "y".to_owned(), None)?;
+    assert_eq!(store.get_tagged("tagged".to_owned())?, Some(("xy".to_owned(), 4)));
+
+    concurrent_appends(store.clone())?;
+    concurrent_appends(MemoryKvsEngine::new())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("list".to_owned())?, Some("a,bc".to_owned()));
+    assert_eq!(store.get("counter".to_owned())?.unwrap().split(' ').count(), 8 * 50);
+    Ok(())
+}
+
+// appends to a "counter" key from several threads, and checks that none of the appends are lost
+fn concurrent_appends<E: KvsEngine>(engine: E) -> Result<()> {
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    engine.append("counter".to_owned(), format!("{}-{}", t, i), Some(" ".to_owned())).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let value = engine.get("counter".to_owned())?.unwrap();
+    assert_eq!(value.split(' ').count(), 8 * 50);
+    Ok(())
+}
+
+// a key whose value was truncated from its log should report the key and the log, and be removed
+// from the index if the store removes dangling keys
+#[test]
+fn dangling_index_entries() -> Result<()> {
+    let truncate_last_value = |dir: &Path| -> Result<()> {
+        let log = OpenOptions::new().write(true).open(dir.join("1.log"))?;
+        let len = log.metadata()?.len();
+        log.set_len(len - 4)?;
+        Ok(())
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    truncate_last_value(temp_dir.path())?;
+    for _ in 0..2 {
+        match store.get("key2".to_owned()) {
+            Err(KvsError::InvalidCommand(msg)) => assert!(msg.contains("key: key2 points past the end of log 1")),
+            result => panic!("expected a dangling key error, got {:?}", result),
+        }
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().remove_dangling_keys(true).ordered_index(true).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    truncate_last_value(temp_dir.path())?;
+    assert!(matches!(
+        store.append("key2".to_owned(), "suffix".to_owned(), None),
+        Err(KvsError::InvalidCommand(_))
+    ));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.stats()?.key_count, 1);
+    assert_eq!(store.scan(None, 10)?, (vec!["key1".to_owned()], None));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// the current log should be written to the write-ahead directory, and compaction files to the
+// working directory
+#[test]
+fn separate_wal_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let wal_dir = temp_dir.path().join("wal");
+    let log_gens = |dir: &Path| -> Vec<u64> {
+        let mut gens: Vec<u64> = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().to_str()?.strip_suffix(".log")?.parse().ok())
+            .collect();
+        gens.sort_unstable();
+        gens
+    };
+    let open = |trigger| KvStore::builder().wal_dir(&wal_dir).compaction_trigger(trigger).open(&data_dir);
+
+    let store = open(CompactionTrigger::Bytes(1 << 20))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(log_gens(&data_dir), Vec::<u64>::new());
+    assert_eq!(log_gens(&wal_dir), [1]);
+    drop(store);
+
+    // the first write compacts the logs
+    let store = open(CompactionTrigger::Bytes(0))?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 1);
+    assert_eq!(log_gens(&data_dir), [3]);
+    assert_eq!(log_gens(&wal_dir), [4]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = open(CompactionTrigger::Bytes(1 << 20))?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let store = open(CompactionTrigger::Bytes(1 << 20))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(log_gens(&data_dir), [3]);
+    assert_eq!(log_gens(&wal_dir), [4, 5, 6]);
+    Ok(())
+}
+
+// get_set should return the value it replaced
+#[test]
+fn get_set_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    get_set(store.clone())?;
+    get_set(MemoryKvsEngine::new())?;
+
+    store.set_raw(b"binary".to_vec(), vec![0xc3, 0x28])?;
+    assert!(matches!(store.get_set("binary".to_owned(), "value".to_owned()), Err(KvsError::Utf8Error(_))));
+    assert_eq!(store.get_raw(b"binary".to_vec())?, Some(vec![0xc3, 0x28]));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("0".to_owned()));
+    Ok(())
+}
+
+// resets a "counter" key with get_set
+fn get_set<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.get_set("counter".to_owned(), "5".to_owned())?, None);
+    assert_eq!(engine.get_set("counter".to_owned(), "0".to_owned())?, Some("5".to_owned()));
+    assert_eq!(engine.get("counter".to_owned())?, Some("0".to_owned()));
+    Ok(())
+}
+
+// compact_to should write only the live data into a new directory, that can be opened as a store
+#[test]
+fn compact_to_new_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let copy_dir = temp_dir.path().join("copy");
+    let store = KvStore::builder().dedup_values(true).open(&data_dir)?;
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.set("key2".to_owned(), "shared".to_owned())?;
+    store.set("key3".to_owned(), "shared".to_owned())?;
+    store.set("key4".to_owned(), "removed".to_owned())?;
+    store.remove("key4".to_owned())?;
+    let seq = store.seq();
+
+    store.compact_to(&copy_dir)?;
+    assert_eq!(std::fs::read_to_string(copy_dir.join("engine"))?, "kvs");
+    assert!(copy_dir.join("1.log").exists());
+    assert!(std::fs::metadata(copy_dir.join("1.log"))?.len() < std::fs::metadata(data_dir.join("1.log"))?.len());
+    // a directory that already holds logs isn't overwritten
+    assert!(matches!(store.compact_to(&data_dir), Err(KvsError::StringErr(_))));
+    assert!(matches!(store.compact_to(&copy_dir), Err(KvsError::StringErr(_))));
+
+    // the source store is untouched
+    assert_eq!(store.stats()?.compactions, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+
+    let copy = KvStore::open(&copy_dir)?;
+    assert_eq!(copy.get("key1".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(copy.get("key2".to_owned())?, Some("shared".to_owned()));
+    assert_eq!(copy.get("key3".to_owned())?, Some("shared".to_owned()));
+    assert_eq!(copy.get("key4".to_owned())?, None);
+    assert_eq!(copy.seq(), seq);
+    copy.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(copy.seq(), seq + 1);
+    Ok(())
+}
+
+// a store opened from its index snapshot should have the same contents as one that read every
+// log, including the commands written after the snapshot, and should ignore a stale or corrupt
+// snapshot
+#[test]
+fn index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot_file = temp_dir.path().join("index.snapshot");
+    let open = |index_snapshot: bool| {
+        KvStore::builder()
+            .index_snapshot(index_snapshot)
+            .exclusive(false)
+            .open(temp_dir.path())
+    };
+    // the stats and every value of the store must match those of a store that read every log
+    let check = |store: &KvStore| -> Result<()> {
+        let full = open(false)?;
+        let (stats, full_stats) = (store.stats()?, full.stats()?);
+        assert_eq!(stats.key_count, full_stats.key_count);
+        assert_eq!(stats.uncompacted_bytes, full_stats.uncompacted_bytes);
+        assert_eq!(store.seq(), full.seq());
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, full.get(format!("key{}", key_id))?);
+        }
+        Ok(())
+    };
+
+    let store = open(true)?;
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key1".to_owned())?;
+    assert!(!snapshot_file.exists());
+    drop(store);
+    assert!(snapshot_file.exists());
+
+    let store = open(true)?;
+    check(&store)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // a store that doesn't snapshot its index writes to the newest log the snapshot covers,
+    // which is read from where the snapshot left off
+    let other = open(false)?;
+    drop(store);
+    other.set("key1".to_owned(), "other".to_owned())?;
+    other.remove("key2".to_owned())?;
+    drop(other);
+    let store = open(true)?;
+    check(&store)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("other".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // a store that crashes leaves its snapshot behind, and the logs written after it are read
+    store.set("key3".to_owned(), "crashed".to_owned())?;
+    std::mem::forget(store);
+    let store = open(true)?;
+    check(&store)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("crashed".to_owned()));
+    drop(store);
+
+    // a snapshot of logs that have since been compacted is stale
+    let compacted = KvStore::builder()
+        .compaction_trigger(CompactionTrigger::Ratio(0.5))
+        .compact_on_open(true)
+        .exclusive(false)
+        .open(temp_dir.path())?;
+    assert_eq!(compacted.stats()?.compactions, 1);
+    compacted.set("key4".to_owned(), "compacted".to_owned())?;
+    drop(compacted);
+    let store = open(true)?;
+    check(&store)?;
+    assert_eq!(store.get("key4".to_owned())?, Some("compacted".to_owned()));
+    drop(store);
+
+    // a corrupt snapshot is ignored
+    let mut contents = std::fs::read(&snapshot_file)?;
+    contents[0] ^= 0xff;
+    std::fs::write(&snapshot_file, &contents)?;
+    let store = open(true)?;
+    check(&store)?;
+    assert_eq!(store.get("key4".to_owned())?, Some("compacted".to_owned()));
+    std::fs::write(&snapshot_file, &contents[..10])?;
+    let store = open(true)?;
+    check(&store)?;
+    Ok(())
+}
+
+// len should count the live keys of a store, whether they were written in this session or
+// loaded from the logs
+#[test]
+fn key_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.len(), 99);
+    assert!(!store.is_empty());
+    assert_eq!(store.key_count()?, 99);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 99);
+
+    let engine = MemoryKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.key_count()?, 1);
+    Ok(())
+}
+
+// contains_key should find a key from the index alone, without reading its value from the logs
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    assert!(!store.contains_key("missing".to_owned())?);
+
+    // the value can no longer be read once the logs are emptied, but the key is still found
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            OpenOptions::new().write(true).open(&path)?.set_len(0)?;
+        }
+    }
+    assert!(store.get("key1".to_owned()).is_err());
+    assert!(store.contains_key("key1".to_owned())?);
+
+    let engine = MemoryKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(engine.contains_key("key1".to_owned())?);
+    assert!(!engine.contains_key("missing".to_owned())?);
+    Ok(())
+}
+
+// get_range should only return the bytes in the range, whichever engine and log format the
+// value is read from
+#[test]
+fn get_range_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a log whose command doesn't use the layout written by the store
+    std::fs::write(temp_dir.path().join("1.log"), r#"{"Set":{"value":"legacy value","key":"legacy"}}"#)?;
+    let store = KvStore::open(temp_dir.path())?;
+    get_range(store.clone())?;
+    assert_eq!(store.get_range("legacy".to_owned(), 7, 3)?, Some("val".to_owned()));
+    drop(store);
+    get_range(KvStore::builder().verify_on_read(true).open(temp_dir.path())?)?;
+    get_range(MemoryKvsEngine::new())?;
+    Ok(())
+}
+
+// gets ranges of a large value, and of a value with multi-byte characters
+fn get_range<E: KvsEngine>(engine: E) -> Result<()> {
+    let value = (0..100_000).map(|i| format!("{:08}", i)).collect::<String>();
+    engine.set("large".to_owned(), value.clone())?;
+    engine.set("unicode".to_owned(), "caf\u{e9}!".to_owned())?;
+
+    assert_eq!(engine.get_range("large".to_owned(), 0, 16)?, Some(value[..16].to_owned()));
+    assert_eq!(engine.get_range("large".to_owned(), 400_000, 8)?, Some("00050000".to_owned()));
+    assert_eq!(engine.get_range("large".to_owned(), 799_996, 100)?, Some("9999".to_owned()));
+    assert_eq!(engine.get_range("large".to_owned(), 800_000, 100)?, Some(String::new()));
+    assert_eq!(engine.get_range("large".to_owned(), u64::MAX, u64::MAX)?, Some(String::new()));
+    assert_eq!(engine.get_range("missing".to_owned(), 0, 10)?, None);
+
+    // "é" is 2 bytes long, starting at byte 3
+    assert_eq!(engine.get_range("unicode".to_owned(), 3, 3)?, Some("\u{e9}!".to_owned()));
+    assert!(matches!(engine.get_range("unicode".to_owned(), 0, 4), Err(KvsError::Utf8Error(_))));
+    assert!(matches!(engine.get_range("unicode".to_owned(), 4, 2), Err(KvsError::Utf8Error(_))));
+    Ok(())
+}
+
+// keys set with a TTL should be gone once they expire, whether they are read or reloaded, in
+// either log format
+#[test]
+fn expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a legacy command without an expiry, an expired JSON command, and expiring binary commands
+    let mut log = br#"{"Set":{"key":"legacy","value":"value"}}{"Set":{"expires_at":1,"key":"expired","value":"value"}}"#.to_vec();
+    for (key, expires_at) in [(&b"binary expired"[..], 1_u64), (b"binary lasting", u64::MAX)] {
+        log.push(0x07);
+        log.extend_from_slice(&expires_at.to_le_bytes());
+        log.push(0x01);
+        log.extend_from_slice(&[0; 16]);
+        log.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        log.extend_from_slice(key);
+        log.extend_from_slice(&2_u64.to_le_bytes());
+        log.extend_from_slice(&[0xff, 0xfe]);
+    }
+    std::fs::write(temp_dir.path().join("1.log"), log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.key_count()?, 2);
+    assert_eq!(store.get("legacy".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get_raw(b"binary lasting".to_vec())?, Some(vec![0xff, 0xfe]));
+    assert_eq!(store.get_raw(b"binary expired".to_vec())?, None);
+    assert_eq!(store.get("expired".to_owned())?, None);
+
+    store.set_with_ttl("ephemeral".to_owned(), "value".to_owned(), 0)?;
+    store.set_with_ttl("lasting".to_owned(), "value".to_owned(), 3600)?;
+    store.set_with_ttl("unread".to_owned(), "value".to_owned(), 0)?;
+    store.set_with_ttl("renewed".to_owned(), "value".to_owned(), 0)?;
+    store.set("renewed".to_owned(), "value".to_owned())?;
+    assert_eq!(store.key_count()?, 6);
+    assert_eq!(store.get("ephemeral".to_owned())?, None);
+    assert!(store.get_reader("ephemeral".to_owned())?.is_none());
+    assert!(!store.contains_key("ephemeral".to_owned())?);
+    assert_eq!(store.get("lasting".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get_range("lasting".to_owned(), 1, 2)?, Some("al".to_owned()));
+    assert_eq!(store.get("renewed".to_owned())?, Some("value".to_owned()));
+    // the expired key that wasn't read is only removed when the store is reopened
+    assert_eq!(store.key_count()?, 5);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.key_count()?, 4);
+    assert!(!store.contains_key("unread".to_owned())?);
+    assert_eq!(store.get("lasting".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("renewed".to_owned())?, Some("value".to_owned()));
+
+    // an expired key is neither found nor removed, even before it has been read
+    store.set_with_ttl("contained".to_owned(), "value".to_owned(), 0)?;
+    store.set_with_ttl("removed".to_owned(), "value".to_owned(), 0)?;
+    assert!(!store.contains_key("contained".to_owned())?);
+    assert!(matches!(store.remove("removed".to_owned()), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.key_count()?, 4);
+
+    // the index snapshot doesn't record expiry times, so it isn't written while keys may expire
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().index_snapshot(true).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl("unread".to_owned(), "value".to_owned(), 0)?;
+    drop(store);
+    assert!(!temp_dir.path().join("index.snapshot").exists());
+    let store = KvStore::builder().index_snapshot(true).open(temp_dir.path())?;
+    assert_eq!(store.key_count()?, 1);
+    assert!(!store.contains_key("unread".to_owned())?);
+    Ok(())
+}
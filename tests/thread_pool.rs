@@ -1,5 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -64,7 +66,98 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+// jobs should run in parallel, and spawning a job shouldn't wait for it to finish
+fn spawn_in_parallel<P: ThreadPool>(pool: P) -> Result<()> {
+    const TASK_NUM: usize = 4;
+    const TASK_TIME: Duration = Duration::from_millis(300);
+
+    let wg = WaitGroup::new();
+    let start = Instant::now();
+    for _ in 0..TASK_NUM {
+        let wg = wg.clone();
+        pool.spawn(move || {
+            thread::sleep(TASK_TIME);
+            drop(wg);
+        })
+    }
+    assert!(start.elapsed() < TASK_TIME, "spawning waited for the jobs to finish");
+    wg.wait();
+    assert!(start.elapsed() < TASK_TIME * 2, "the jobs ran sequentially");
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_in_parallel() -> Result<()> {
+    spawn_in_parallel(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_in_parallel() -> Result<()> {
+    spawn_in_parallel(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn shared_queue_thread_pool_names_and_panic_count() -> Result<()> {
+    const TASK_NUM: usize = 10;
+
+    let pool = SharedQueueThreadPool::new(2)?;
+    for _ in 0..TASK_NUM {
+        pool.spawn(move || {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        })
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    pool.spawn(move || {
+        tx.send(std::thread::current().name().map(String::from)).unwrap();
+    });
+    let name = rx.recv().unwrap().expect("worker thread is not named");
+    assert!(name == "kvs-worker-0" || name == "kvs-worker-1");
+
+    // panics are counted while the panicking thread unwinds, which may finish after other tasks
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while pool.panic_count() < TASK_NUM && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(pool.panic_count(), TASK_NUM);
+    Ok(())
+}
+
+// joining a naive pool should wait for every job, including the jobs spawned by other jobs
+#[test]
+fn naive_thread_pool_join_all() -> Result<()> {
+    const TASK_NUM: usize = 100;
+
+    let pool = Arc::new(NaiveThreadPool::new(4)?);
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let (job_pool, counter) = (Arc::clone(&pool), Arc::clone(&counter));
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+            job_pool.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+    }
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+
+    pool.join_all();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * 2);
+    Ok(())
+}